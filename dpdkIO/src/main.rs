@@ -7,10 +7,13 @@
 extern crate lazy_static;
 extern crate pktpuller;
 extern crate ctrlc;
+extern crate libc;
 extern crate sharedring;
 
+mod steering;
+
 use pktpuller::common::Result as PktResult;
-use pktpuller::config::load_config;
+use pktpuller::config::{load_config, PollingConfiguration, SteeringHash};
 use pktpuller::interface::{PmdPort, PacketRx, PacketTx, PortQueue};
 use pktpuller::operators::{Batch, ReceiveBatch};
 use pktpuller::operators::BATCH_SIZE;
@@ -18,24 +21,38 @@ use pktpuller::packets::{Ethernet, Packet, RawPacket};
 use pktpuller::runtime::Runtime;
 use pktpuller::scheduler::Executable;
 use pktpuller::native::mbuf::MBuf;
-use pktpuller::config::{NUM_RXD, NUM_TXD};
+use pktpuller::native::{mbuf_alloc_bulk, mbuf_free_bulk};
 use pktpuller::allocators::CacheAligned;
 
+use sharedring::poll::{AdaptivePoller, PollConfig, PollStats};
 use sharedring::ring_buffer::*;
+use steering::Steering;
 
 use std::thread;
+use std::process;
 use std::sync::{Arc, Mutex};
 use std::fmt::Display;
 
 use std::io::{BufRead, BufReader};
 use std::net::TcpListener;
-use std::sync::atomic::{AtomicBool, Ordering, compiler_fence};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use std::io::stdout;
 use std::io::Write;
 
 const PKT_NUM: u64 = (8 * 1024 * 1024);
 const PRINT_INTER: u64 = (1024 * 1024);
+/// How long the enclaves get to flush in-flight packets on shutdown.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+const STOP_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Toggled by SIGUSR1: pauses/resumes all enclaves without tearing them down.
+static PAUSE_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn toggle_pause(_signal: libc::c_int) {
+    PAUSE_REQUESTED.fetch_xor(true, Ordering::SeqCst);
+}
 
 // pull_count;
 lazy_static!{
@@ -66,100 +83,130 @@ impl Drop for MbufVec {
 }
 
 // This "ports" is essentially "queues"
-fn hostio<T, >(main_port: Arc<PmdPort>, ports: Vec<T>, recvq_ring: &mut Vec<RingBuffer>, sendq_ring: &mut Vec<RingBuffer>, running: Arc<AtomicBool>) -> std::io::Result<u64>
+fn hostio<T, >(main_port: Arc<PmdPort>, ports: Vec<T>, steering: &Steering, poll_config: PollConfig, recvq_ring: &mut Vec<RingBuffer>, sendq_ring: &mut Vec<RingBuffer>, mbufq_ring: &mut Vec<RingBuffer>, freeq_ring: &mut Vec<RingBuffer>, running: Arc<AtomicBool>) -> std::io::Result<u64>
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
 {
     for port in &ports {
         println!("Receiving port {}", port);
     }
+    let rings = recvq_ring.len();
 
     let mut mbufs = MbufVec{ my_mbufs: Vec::<*mut MBuf>::with_capacity(BATCH_SIZE) };
-    let mut pull_count: [u64; 5] = [0u64; 5];
-    let mut pkt_count_from_nic: [u64; 5] = [0u64; 5];
-    let mut pkt_count_from_enclave: [u64; 5] = [0u64; 5];
+    // one bucket per enclave ring; a NIC queue can feed every ring.
+    let mut buckets: Vec<Vec<*mut MBuf>> = (0..rings).map(|_| Vec::with_capacity(BATCH_SIZE * ports.len())).collect();
+    let mut pull_count: Vec<u64> = vec![0u64; ports.len()];
+    let mut pkt_count_from_nic: Vec<u64> = vec![0u64; rings];
+    let mut pkt_count_from_enclave: Vec<u64> = vec![0u64; rings];
+    let mut pkt_count_freed: Vec<u64> = vec![0u64; rings];
+    // packets a paused enclave or a full recvq had no room for.
+    let mut pkt_count_full: Vec<u64> = vec![0u64; rings];
+    // backs off when neither the NIC nor any enclave has packets for us.
+    let mut poller = AdaptivePoller::new(poll_config);
+    let mut recvq_depth: Vec<PollStats> = vec![PollStats::default(); rings];
+    let mut sendq_depth: Vec<PollStats> = vec![PollStats::default(); rings];
+    // room for one extra pointer: the C bulk alloc writes past `cnt` when it is odd.
+    let mut spare = Vec::<*mut MBuf>::with_capacity(BATCH_SIZE + 1);
 
     while running.load(Ordering::SeqCst) {
-        for (index, queue) in ports.iter().enumerate() {
-            let i = index as usize;
-            // hostio only used ports[0];
-            unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) }; 
-
-            let mut recv_pkt_num_from_nic: u32 = 0;
-            // if pkt_count_from_nic < PKT_NUM {
-                // pull packets from NIC; write mbuf pointers to mbufs.     
-                recv_pkt_num_from_nic = match ports[i].recv(mbufs.my_mbufs.as_mut_slice()) {
-                    Ok(received) => {
-                        unsafe{ mbufs.my_mbufs.set_len(received as usize) };
-                        received
-                    }
+        let wanted = if PAUSE_REQUESTED.load(Ordering::Relaxed) { RingState::Paused } else { RingState::Running };
+        for (i, ring) in recvq_ring.iter().enumerate() {
+            let state = ring.state();
+            // a drain/stop started by sgx-runner is never overridden here.
+            // only from the state read, so a drain or stop written in between stays.
+            if (state == RingState::Running || state == RingState::Paused)
+                && state != wanted
+                && ring.transition_state(state, wanted)
+            {
+                println!("Ring {}: {:?} -> {:?}", i, state, wanted);
+            }
+        }
+        // any queue may steer to any ring, so only pull while every enclave reads its recvq;
+        // otherwise leave packets in the NIC queues. An enclave that stopped on its own, after
+        // its packets or its duration, acknowledges a stop nobody requested.
+        let all_running = recvq_ring
+            .iter()
+            .all(|ring| ring.state() == RingState::Running && ring.acked_state() != RingState::Stopped);
+
+        if all_running {
+            for (queue, port) in ports.iter().enumerate() {
+                unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) };
+                // pull packets from NIC; write mbuf pointers to mbufs.
+                let received = match port.recv(mbufs.my_mbufs.as_mut_slice()) {
+                    Ok(received) => received as usize,
                     // the underlying DPDK method `rte_eth_rx_burst` will
                     // never return an error. The error arm is unreachable
                     _ => unreachable!(),
                 };
-                unsafe{ mbufs.my_mbufs.set_len(recv_pkt_num_from_nic as usize) }; 
-            // }
-            // else {
-            //     unsafe{ mbufs.my_mbufs.set_len(0) }; 
-            // }
+                unsafe{ mbufs.my_mbufs.set_len(received) };
+                steering.dispatch(queue, &mbufs.my_mbufs, &mut buckets);
+                pull_count[queue] += 1;
+            }
+            unsafe{ mbufs.my_mbufs.set_len(0) };
+        }
 
-            
-            // push recv_pkt_num_from_nic mbuf pointers to recvq.      
-            if !mbufs.my_mbufs.is_empty() {
-                let mut to_send = mbufs.my_mbufs.len();
-                while to_send > 0 && running.load(Ordering::SeqCst) {
-                    let sent = unsafe{ recvq_ring[i].write_at_tail(std::mem::transmute::<&[*mut MBuf], &[u64]>(mbufs.my_mbufs.as_slice())) };
-                    to_send -= sent;
-                    if to_send > 0 {
-                        mbufs.my_mbufs.drain(..sent);
-                    }
-                }
-                unsafe {
-                    unsafe{ mbufs.my_mbufs.set_len(0) };
-                }
+        let mut moved = 0;
+        for i in 0..rings {
+            // push the steered mbuf pointers to recvq.
+            let recv_pkt_num_from_nic = buckets[i].len();
+            recvq_depth[i].record_depth(recvq_ring[i].depth());
+            // push what fits and drop the rest, like the NIC does when its queue is full;
+            // waiting for room would stall every other ring behind this one.
+            let sent = unsafe{ recvq_ring[i].write_at_tail(std::mem::transmute::<&[*mut MBuf], &[u64]>(buckets[i].as_slice())) };
+            if sent < recv_pkt_num_from_nic {
+                unsafe{ mbuf_free_bulk(buckets[i][sent..].as_mut_ptr(), (recv_pkt_num_from_nic - sent) as i32) };
+                pkt_count_full[i] += (recv_pkt_num_from_nic - sent) as u64;
             }
-            
+            buckets[i].clear();
+            if recv_pkt_num_from_nic != 0 {
+                // once per batch, in case the enclave is sleeping on an empty recvq.
+                recvq_ring[i].ring_doorbell();
+            }
+
             // thread::sleep(std::time::Duration::from_secs(1));// for debugging;
 
-            // hostio only used ports[0];
-            unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) }; 
+            unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) };
 
             // pull packet from sendq;
+            sendq_depth[i].record_depth(sendq_ring[i].depth());
             let recv_pkt_num_from_enclave = unsafe{ sendq_ring[i].read_from_head(std::mem::transmute::<&mut [*mut MBuf], &mut [u64]>(mbufs.my_mbufs.as_mut_slice())) };
-            unsafe{ mbufs.my_mbufs.set_len(recv_pkt_num_from_enclave) }; 
-
-            // Send pacekt to dpdk port;
-            if !mbufs.my_mbufs.is_empty() {
-                let mut to_send = mbufs.my_mbufs.len();
-                while to_send > 0 && running.load(Ordering::SeqCst){
-                    match ports[i].send(mbufs.my_mbufs.as_mut_slice()) {
-                        Ok(sent) => {
-                            let sent = sent as usize;
-                            to_send -= sent;
-                            if to_send > 0 {
-                                mbufs.my_mbufs.drain(..sent);
-                            }
-                        }
-                        // the underlying DPDK method `rte_eth_tx_burst` will
-                        // never return an error. The error arm is unreachable
-                        _ => unreachable!(),
+            unsafe{ mbufs.my_mbufs.set_len(recv_pkt_num_from_enclave) };
+
+            // Send pacekt to dpdk port; rings beyond the queue count share tx queues.
+            let port = &ports[i % ports.len()];
+            while !mbufs.my_mbufs.is_empty() && running.load(Ordering::SeqCst) {
+                match port.send(mbufs.my_mbufs.as_mut_slice()) {
+                    Ok(sent) => {
+                        mbufs.my_mbufs.drain(..sent as usize);
                     }
+                    // the underlying DPDK method `rte_eth_tx_burst` will
+                    // never return an error. The error arm is unreachable
+                    _ => unreachable!(),
                 }
-                unsafe {
-                    unsafe{ mbufs.my_mbufs.set_len(0) };
-                }
             }
+            // the ones the NIC did not take before we were stopped.
+            if !mbufs.my_mbufs.is_empty() {
+                unsafe{ mbuf_free_bulk(mbufs.my_mbufs.as_mut_ptr(), mbufs.my_mbufs.len() as i32) };
+            }
+            unsafe{ mbufs.my_mbufs.set_len(0) };
+
+            // keep the enclave supplied with empty mbufs for the packets it builds itself,
+            // and take back the ones it dropped.
+            refill_mbufq(&mbufq_ring[i], &mut spare);
+            pkt_count_freed[i] += free_from_freeq(&freeq_ring[i], &mut mbufs) as u64;
+
             pkt_count_from_nic[i] += recv_pkt_num_from_nic as u64;
             pkt_count_from_enclave[i] += recv_pkt_num_from_enclave as u64;
-
-            pull_count[i] += 1;
+            moved += recv_pkt_num_from_nic + recv_pkt_num_from_enclave;
 
             if pkt_count_from_enclave[i] % PRINT_INTER == 0 {
                 if pkt_count_from_enclave[i] != 0 && recv_pkt_num_from_enclave != 0 {
                     let (rx, tx) = main_port.stats(0);
-                    println!("Ring {} out-of-enclave: from nic {}, to sgx {}, from sgx {}, to nic {}", i, rx, pkt_count_from_nic[i], pkt_count_from_enclave[i], tx);
+                    println!("Ring {} out-of-enclave: from nic {}, to sgx {}, from sgx {}, to nic {}, dropped by sgx {}, recvq full {}", i, rx, pkt_count_from_nic[i], pkt_count_from_enclave[i], tx, pkt_count_freed[i], pkt_count_full[i]);
                     println!("  recvq: head {} vs. tail {}", recvq_ring[i].head(), recvq_ring[i].tail());
                     println!("  sendq: head {} vs. tail {}", sendq_ring[i].head(), sendq_ring[i].tail());
+                    println!("  recvq depth mean {:.2} max {}, sendq depth mean {:.2} max {}", recvq_depth[i].mean_depth(), recvq_depth[i].depth_max, sendq_depth[i].mean_depth(), sendq_depth[i].depth_max);
+                    println!("  poller: {}", poller.stats());
                 }
             }
         // thread::sleep(std::time::Duration::from_secs(1));
@@ -168,17 +215,139 @@ where
             //     break;
             // }
         }
+        if let Some(timeout) = poller.poll(moved) {
+            // the enclaves ring the doorbells of their sendq; the NIC cannot wake us up, so
+            // the wait is bounded.
+            let woken = RingBuffer::wait_for_doorbells(sendq_ring, timeout);
+            poller.slept(woken);
+        }
+    }
+    println!("poller: {}", poller.stats());
+    for i in 0..rings {
+        println!("Ring {} recvq depth: {}", i, recvq_depth[i]);
+        println!("Ring {} sendq depth: {}", i, sendq_depth[i]);
+    }
+    println!("exit from loop, draining, pulls per queue {:?}", pull_count);
+    // stop feeding the enclaves and flush whatever they still hand back to the NIC; the ones
+    // that have stopped already have nothing left to drain.
+    for ring in recvq_ring.iter().filter(|ring| ring.acked_state() != RingState::Stopped) {
+        ring.request_state(RingState::Draining);
+    }
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    loop {
+        let mut drained = true;
+        for i in 0..rings {
+            let flushed = flush_sendq(&ports[i % ports.len()], &sendq_ring[i], &mut mbufs);
+            pkt_count_from_enclave[i] += flushed as u64;
+            let acked = recvq_ring[i].acked_state();
+            if flushed != 0 || (acked != RingState::Draining && acked != RingState::Stopped) {
+                drained = false;
+            }
+        }
+        if drained {
+            break;
+        }
+        if Instant::now() >= deadline {
+            println!("drain timed out, stopping anyway");
+            break;
+        }
     }
-    println!("exit from loop");
-    // either not break above or have a loop here. 
-    for (i, queue) in ports.iter().enumerate() {
-        compiler_fence(Ordering::Release);
-        recvq_ring[i as usize].set_size(STOP_MARK as usize);
+    for (i, ring) in recvq_ring.iter().enumerate() {
+        ring.request_state(RingState::Stopped);
+        if !ring.wait_for_ack(RingState::Stopped, STOP_ACK_TIMEOUT) {
+            println!("Ring {}: enclave did not acknowledge stop", i);
+        }
+    }
+    for mbufq in mbufq_ring.iter() {
+        drain_mbufq(mbufq, &mut mbufs);
+    }
+    for (i, freeq) in freeq_ring.iter().enumerate() {
+        loop {
+            let freed = free_from_freeq(freeq, &mut mbufs);
+            pkt_count_freed[i] += freed as u64;
+            if freed == 0 {
+                break;
+            }
+        }
+        println!("Ring {}: {} packets dropped by the enclave, {} for a full recvq", i, pkt_count_freed[i], pkt_count_full[i]);
     }
     
     Ok(pkt_count_from_nic.iter().sum())
 }
 
+fn poll_config(polling: &PollingConfiguration) -> PollConfig {
+    PollConfig {
+        adaptive: polling.adaptive,
+        spin_polls: polling.spin_polls,
+        idle: Duration::from_micros(polling.idle_us),
+        max_sleep: Duration::from_micros(polling.max_sleep_us),
+    }
+}
+
+/// Moves everything currently in `sendq` to the NIC. Returns the number of packets sent.
+fn flush_sendq<T: PacketTx>(port: &T, sendq: &RingBuffer, mbufs: &mut MbufVec) -> usize {
+    unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) };
+    let from_enclave = unsafe{ sendq.read_from_head(std::mem::transmute::<&mut [*mut MBuf], &mut [u64]>(mbufs.my_mbufs.as_mut_slice())) };
+    unsafe{ mbufs.my_mbufs.set_len(from_enclave) };
+    while !mbufs.my_mbufs.is_empty() {
+        match port.send(mbufs.my_mbufs.as_mut_slice()) {
+            Ok(sent) => {
+                mbufs.my_mbufs.drain(..sent as usize);
+            }
+            // the underlying DPDK method `rte_eth_tx_burst` will
+            // never return an error. The error arm is unreachable
+            _ => unreachable!(),
+        }
+    }
+    from_enclave
+}
+
+/// Tops `mbufq` back up with empty mbufs from the mempool once the enclave has used half of it.
+fn refill_mbufq(mbufq: &RingBuffer, spare: &mut Vec<*mut MBuf>) {
+    let depth = mbufq.depth();
+    if depth > mbufq.size() / 2 {
+        return;
+    }
+    let wanted = std::cmp::min(mbufq.size() - depth, BATCH_SIZE);
+    if unsafe{ mbuf_alloc_bulk(spare.as_mut_ptr(), 0, wanted as i32) } != 0 {
+        // the mempool is empty for now; received packets take priority.
+        return;
+    }
+    unsafe{ spare.set_len(wanted) };
+    let lent = unsafe{ mbufq.write_at_tail(std::mem::transmute::<&[*mut MBuf], &[u64]>(spare.as_slice())) };
+    // the enclave cannot have taken more than `depth` since we looked, so everything fits.
+    debug_assert_eq!(lent, wanted);
+    if lent < wanted {
+        unsafe{ mbuf_free_bulk(spare[lent..].as_mut_ptr(), (wanted - lent) as i32) };
+    }
+    unsafe{ spare.set_len(0) };
+}
+
+/// Frees up to a batch of the mbufs the enclave dropped. Returns the number freed.
+fn free_from_freeq(freeq: &RingBuffer, mbufs: &mut MbufVec) -> usize {
+    unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) };
+    let dropped = unsafe{ freeq.read_from_head(std::mem::transmute::<&mut [*mut MBuf], &mut [u64]>(mbufs.my_mbufs.as_mut_slice())) };
+    if dropped != 0 {
+        unsafe{ mbuf_free_bulk(mbufs.my_mbufs.as_mut_ptr(), dropped as i32) };
+    }
+    unsafe{ mbufs.my_mbufs.set_len(0) };
+    dropped
+}
+
+/// Returns the mbufs the enclave never took from `mbufq` to the mempool.
+fn drain_mbufq(mbufq: &RingBuffer, mbufs: &mut MbufVec) {
+    loop {
+        unsafe{ mbufs.my_mbufs.set_len(BATCH_SIZE) };
+        let left = unsafe{ mbufq.read_from_head(std::mem::transmute::<&mut [*mut MBuf], &mut [u64]>(mbufs.my_mbufs.as_mut_slice())) };
+        if left != 0 {
+            unsafe{ mbuf_free_bulk(mbufs.my_mbufs.as_mut_ptr(), left as i32) };
+        }
+        unsafe{ mbufs.my_mbufs.set_len(0) };
+        if left == 0 {
+            break;
+        }
+    }
+}
 
 fn main() -> PktResult<()> {
     let running = Arc::new(AtomicBool::new(true));
@@ -187,6 +356,7 @@ fn main() -> PktResult<()> {
         r.store(false, Ordering::SeqCst);
         println!("singnal received");
     }).expect("Error setting Ctrl-C handler");
+    unsafe { libc::signal(libc::SIGUSR1, toggle_pause as libc::sighandler_t) };
 
     let configuration = load_config()?;
     println!("{}", configuration);
@@ -213,18 +383,48 @@ fn main() -> PktResult<()> {
 
     let mut recvq_ring: Vec<RingBuffer> = Vec::new();
     let mut sendq_ring: Vec<RingBuffer> = Vec::new();
-
-    println!("ports number: {}", ports.len());
-
-    for (i, queue) in ports.iter().enumerate() {
-        // Create two shared queue: recvq and sendq in shared memory with name.
-        recvq_ring.push(unsafe{RingBuffer::new_in_heap(NUM_RXD as usize, &format!("{}_{}", RECVQ_PREFIX, i), true)}.unwrap());
-        sendq_ring.push(unsafe{RingBuffer::new_in_heap(NUM_TXD as usize, &format!("{}_{}", SENDQ_PREFIX, i), true)}.unwrap());
-
-        let recvq_addr_u64: u64 = recvq_ring[i].head.my_usize as u64; // *mut usize
-        let sendq_addr_u64: u64 = sendq_ring[i].head.my_usize as u64;
-
-        println!("recvq_addr {}, sendq_addr {}", recvq_addr_u64, sendq_addr_u64);
+    let mut mbufq_ring: Vec<RingBuffer> = Vec::new();
+    let mut freeq_ring: Vec<RingBuffer> = Vec::new();
+
+    // a set of rings per queue of each enclave, as many and as large as sgx-runner attaches
+    // to; the steering hash decides which one a packet goes to.
+    let enclaves = configuration.enclaves.unwrap_or(ports.len());
+    let rings = enclaves * configuration.enclave.queues;
+    if configuration.steering == SteeringHash::Queue {
+        assert_eq!(rings, ports.len(), "queue steering needs one enclave queue per rx queue; pick a flow hash instead");
+    }
+    let mut steering = Steering::new(configuration.steering, rings);
+    if let Some(ike) = configuration.enclave.ike {
+        steering = steering.with_ike(configuration.enclave.queues, ike.queue);
+    }
+    let (rxd, txd) = (configuration.ports[0].rxd as usize, configuration.ports[0].txd as usize);
+
+    println!("ports number: {}, enclaves: {}, rings: {} of {}/{} slots, steering: {:?}", ports.len(), enclaves, rings, rxd, txd, configuration.steering);
+
+    for i in 0..rings {
+        // Create four shared queues in shared memory with name: recvq, sendq, the mbufq
+        // lending empty mbufs to the enclave and the freeq returning the ones it drops.
+        recvq_ring.push(unsafe{RingBuffer::new_in_heap(rxd, &format!("{}_{}", RECVQ_PREFIX, i), true)}.unwrap());
+        sendq_ring.push(unsafe{RingBuffer::new_in_heap(txd, &format!("{}_{}", SENDQ_PREFIX, i), true)}.unwrap());
+        mbufq_ring.push(unsafe{RingBuffer::new_in_heap(rxd, &format!("{}_{}", MBUFQ_PREFIX, i), true)}.unwrap());
+        freeq_ring.push(unsafe{RingBuffer::new_in_heap(txd, &format!("{}_{}", FREEQ_PREFIX, i), true)}.unwrap());
+        recvq_ring[i].check_header(rxd)?;
+        sendq_ring[i].check_header(txd)?;
+        mbufq_ring[i].check_header(rxd)?;
+        freeq_ring[i].check_header(txd)?;
+        // we produce into recvq and mbufq and consume from sendq and freeq; sgx-runner fills in
+        // the other side.
+        recvq_ring[i].set_producer_pid(process::id());
+        sendq_ring[i].set_consumer_pid(process::id());
+        mbufq_ring[i].set_producer_pid(process::id());
+        freeq_ring[i].set_consumer_pid(process::id());
+
+        let recvq_addr_u64: u64 = recvq_ring[i].mem as u64; // start of the shared region
+        let sendq_addr_u64: u64 = sendq_ring[i].mem as u64;
+        let mbufq_addr_u64: u64 = mbufq_ring[i].mem as u64;
+        let freeq_addr_u64: u64 = freeq_ring[i].mem as u64;
+
+        println!("recvq_addr {}, sendq_addr {}, mbufq_addr {}, freeq_addr {}", recvq_addr_u64, sendq_addr_u64, mbufq_addr_u64, freeq_addr_u64);
 
         println!("out-of-enclave: {}, {}, {}, {}", recvq_ring[i].head(), recvq_ring[i].tail(), recvq_ring[i].size(), recvq_ring[i].mask());
         println!("out-of-enclave: {}, {}, {}, {}", recvq_ring[i].head(), recvq_ring[i].tail(), recvq_ring[i].size(), recvq_ring[i].mask());
@@ -233,7 +433,7 @@ fn main() -> PktResult<()> {
 
     // keep pulling packet from DPDK port, and push pkt pointers to recvq
     // keep pulling packet pointers from sendq, and send them out to the DPDK port.
-    client_count = hostio(main_port, ports, &mut recvq_ring, &mut sendq_ring, running).unwrap();
+    client_count = hostio(main_port, ports, &steering, poll_config(&configuration.polling), &mut recvq_ring, &mut sendq_ring, &mut mbufq_ring, &mut freeq_ring, running).unwrap();
 
     println!("{} vs. {}", client_count, server_count);
     Ok(())
@@ -0,0 +1,395 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Software steering of packets pulled from the NIC onto the enclave rings.
+// The hashes are symmetric: swapping source and destination gives the same
+// ring, so both directions of a flow are handled by the same enclave.
+
+use pktpuller::config::SteeringHash;
+use pktpuller::native::mbuf::MBuf;
+use std::slice;
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+const ETHER_TYPE_VLAN: u16 = 0x8100;
+const PROTO_TCP: u8 = 6;
+const PROTO_UDP: u8 = 17;
+const PROTO_SCTP: u8 = 132;
+const IKE_PORT: u16 = 500;
+
+/// RSS key with a 16-bit period, which makes Toeplitz symmetric for the
+/// address and port fields (see Woo & Park, "Scalable TCP Session Monitoring
+/// with Symmetric Receive-side Scaling").
+pub const SYMMETRIC_RSS_KEY: [u8; 40] = [
+    0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a,
+    0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a,
+    0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a, 0x6d, 0x5a,
+];
+
+/// The fields the steering hashes look at.
+#[derive(Debug, PartialEq)]
+pub struct FiveTuple<'a> {
+    pub src_ip: &'a [u8],
+    pub dst_ip: &'a [u8],
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub protocol: u8,
+}
+
+#[inline]
+fn read_u16(frame: &[u8], offset: usize) -> Option<u16> {
+    if frame.len() < offset + 2 {
+        None
+    } else {
+        Some((u16::from(frame[offset]) << 8) | u16::from(frame[offset + 1]))
+    }
+}
+
+/// Extracts the 5-tuple from an Ethernet frame. Non-IP frames and IPv4 headers
+/// shorter than 20 bytes yield `None`; fragments, the first one included, and
+/// protocols without ports get ports 0, so all fragments of a datagram hash alike.
+pub fn parse_five_tuple(frame: &[u8]) -> Option<FiveTuple> {
+    let mut offset = 12;
+    let mut ether_type = read_u16(frame, offset)?;
+    if ether_type == ETHER_TYPE_VLAN {
+        offset += 4;
+        ether_type = read_u16(frame, offset)?;
+    }
+    let l3 = offset + 2;
+
+    let (src_ip, dst_ip, protocol, l4, fragment) = match ether_type {
+        ETHER_TYPE_IPV4 => {
+            if frame.len() < l3 + 20 {
+                return None;
+            }
+            let ihl = (frame[l3] & 0x0f) as usize * 4;
+            if ihl < 20 {
+                return None;
+            }
+            // more fragments set, or a fragment offset.
+            let fragment = read_u16(frame, l3 + 6)? & 0x3fff != 0;
+            (
+                &frame[l3 + 12..l3 + 16],
+                &frame[l3 + 16..l3 + 20],
+                frame[l3 + 9],
+                l3 + ihl,
+                fragment,
+            )
+        }
+        ETHER_TYPE_IPV6 => {
+            if frame.len() < l3 + 40 {
+                return None;
+            }
+            (
+                &frame[l3 + 8..l3 + 24],
+                &frame[l3 + 24..l3 + 40],
+                frame[l3 + 6],
+                l3 + 40,
+                false,
+            )
+        }
+        _ => return None,
+    };
+
+    let has_ports = !fragment
+        && (protocol == PROTO_TCP || protocol == PROTO_UDP || protocol == PROTO_SCTP);
+    let (src_port, dst_port) = if has_ports {
+        (read_u16(frame, l4).unwrap_or(0), read_u16(frame, l4 + 2).unwrap_or(0))
+    } else {
+        (0, 0)
+    };
+
+    Some(FiveTuple {
+        src_ip,
+        dst_ip,
+        src_port,
+        dst_port,
+        protocol,
+    })
+}
+
+/// Microsoft RSS Toeplitz hash. `key` must be at least 4 bytes longer than `input`.
+pub fn toeplitz(key: &[u8], input: &[u8]) -> u32 {
+    let mut result = 0u32;
+    let mut window = (u32::from(key[0]) << 24)
+        | (u32::from(key[1]) << 16)
+        | (u32::from(key[2]) << 8)
+        | u32::from(key[3]);
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                result ^= window;
+            }
+            window <<= 1;
+            if key[i + 4] & (0x80 >> bit) != 0 {
+                window |= 1;
+            }
+        }
+    }
+    result
+}
+
+fn fnv1a(hash: u32, bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(hash, |h, b| (h ^ u32::from(*b)).wrapping_mul(0x0100_0193))
+}
+
+/// Symmetric hash of the 5-tuple.
+pub fn flow_hash(hash: SteeringHash, tuple: &FiveTuple) -> u32 {
+    match hash {
+        SteeringHash::SymmetricToeplitz => {
+            // same input layout as NIC RSS: addresses then ports, no protocol.
+            let mut input = [0u8; 36];
+            let len = tuple.src_ip.len();
+            input[..len].copy_from_slice(tuple.src_ip);
+            input[len..2 * len].copy_from_slice(tuple.dst_ip);
+            input[2 * len] = (tuple.src_port >> 8) as u8;
+            input[2 * len + 1] = tuple.src_port as u8;
+            input[2 * len + 2] = (tuple.dst_port >> 8) as u8;
+            input[2 * len + 3] = tuple.dst_port as u8;
+            toeplitz(&SYMMETRIC_RSS_KEY, &input[..2 * len + 4])
+        }
+        SteeringHash::SortedTuple | SteeringHash::Queue => {
+            let src = (tuple.src_ip, tuple.src_port);
+            let dst = (tuple.dst_ip, tuple.dst_port);
+            let (low, high) = if src <= dst { (src, dst) } else { (dst, src) };
+            let mut h = 0x811c_9dc5;
+            h = fnv1a(h, low.0);
+            h = fnv1a(h, &[(low.1 >> 8) as u8, low.1 as u8]);
+            h = fnv1a(h, high.0);
+            h = fnv1a(h, &[(high.1 >> 8) as u8, high.1 as u8]);
+            fnv1a(h, &[tuple.protocol])
+        }
+    }
+}
+
+/// Picks the enclave ring for each packet coming off the NIC.
+pub struct Steering {
+    hash: SteeringHash,
+    rings: usize,
+    /// Queues of each enclave; its rings follow one another.
+    queues: usize,
+    /// The queue of each enclave that gets the IKE requests, and nothing else.
+    ike: Option<usize>,
+}
+
+impl Steering {
+    pub fn new(hash: SteeringHash, rings: usize) -> Steering {
+        assert!(rings > 0, "need at least one enclave ring");
+        Steering {
+            hash,
+            rings,
+            queues: 1,
+            ike: None,
+        }
+    }
+
+    /// Steers UDP port 500 to queue `queue` of the `queues` of each enclave, and the rest of
+    /// the traffic to the other queues. Fragmented IKE messages have no ports to tell them
+    /// by and go with the traffic.
+    pub fn with_ike(mut self, queues: usize, queue: usize) -> Steering {
+        assert!(self.hash != SteeringHash::Queue, "IKE needs a flow hash");
+        assert!(queue < queues && queues > 1, "IKE needs a queue of its own");
+        assert_eq!(self.rings % queues, 0, "rings of a partial enclave");
+        self.queues = queues;
+        self.ike = Some(queue);
+        self
+    }
+
+    /// Ring for a packet received on NIC queue `queue`. Frames we cannot parse
+    /// go to the first ring that carries traffic.
+    #[inline]
+    pub fn ring_for(&self, queue: usize, mbuf: *mut MBuf) -> usize {
+        if self.hash == SteeringHash::Queue {
+            return queue % self.rings;
+        }
+        let frame = unsafe { slice::from_raw_parts((*mbuf).data_address(0), (*mbuf).data_len()) };
+        self.ring_of(frame)
+    }
+
+    fn ring_of(&self, frame: &[u8]) -> usize {
+        let tuple = match parse_five_tuple(frame) {
+            Some(tuple) => tuple,
+            None => return self.traffic_ring(0),
+        };
+        let hash = flow_hash(self.hash, &tuple) as usize;
+        match self.ike {
+            Some(ike) if tuple.protocol == PROTO_UDP && tuple.dst_port == IKE_PORT => {
+                hash % (self.rings / self.queues) * self.queues + ike
+            }
+            _ => self.traffic_ring(hash),
+        }
+    }
+
+    /// Ring `hash` maps to among those not taken by IKE.
+    #[inline]
+    fn traffic_ring(&self, hash: usize) -> usize {
+        match self.ike {
+            None => hash % self.rings,
+            Some(ike) => {
+                let traffic = self.queues - 1;
+                let slot = hash % (self.rings / self.queues * traffic);
+                let queue = slot % traffic;
+                slot / traffic * self.queues + if queue < ike { queue } else { queue + 1 }
+            }
+        }
+    }
+
+    /// Appends each mbuf in `mbufs` to the bucket of its ring.
+    pub fn dispatch(&self, queue: usize, mbufs: &[*mut MBuf], buckets: &mut [Vec<*mut MBuf>]) {
+        for &mbuf in mbufs {
+            buckets[self.ring_for(queue, mbuf)].push(mbuf);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp_v4(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 20 + 20];
+        frame[12] = 0x08;
+        frame[14] = 0x45;
+        frame[14 + 9] = PROTO_TCP;
+        frame[14 + 12..14 + 16].copy_from_slice(&src);
+        frame[14 + 16..14 + 20].copy_from_slice(&dst);
+        frame[34] = (sport >> 8) as u8;
+        frame[35] = sport as u8;
+        frame[36] = (dport >> 8) as u8;
+        frame[37] = dport as u8;
+        frame
+    }
+
+    fn udp_v6(src: [u8; 16], dst: [u8; 16], sport: u16, dport: u16) -> Vec<u8> {
+        let mut frame = vec![0u8; 14 + 40 + 8];
+        frame[12] = 0x86;
+        frame[13] = 0xdd;
+        frame[14 + 6] = PROTO_UDP;
+        frame[14 + 8..14 + 24].copy_from_slice(&src);
+        frame[14 + 24..14 + 40].copy_from_slice(&dst);
+        frame[54] = (sport >> 8) as u8;
+        frame[55] = sport as u8;
+        frame[56] = (dport >> 8) as u8;
+        frame[57] = dport as u8;
+        frame
+    }
+
+    #[test]
+    fn toeplitz_matches_microsoft_vector() {
+        // "Verifying the RSS Hash Calculation", IPv4 with TCP ports:
+        // 66.9.149.187:2794 -> 161.142.100.80:1766 hashes to 0x51ccc178.
+        let key = [
+            0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3,
+            0x8f, 0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3,
+            0x80, 0x30, 0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+        ];
+        let input = [66, 9, 149, 187, 161, 142, 100, 80, 0x0a, 0xea, 0x06, 0xe6];
+        assert_eq!(toeplitz(&key, &input), 0x51cc_c178);
+    }
+
+    #[test]
+    fn parse_v4_tcp() {
+        let frame = tcp_v4([10, 0, 0, 1], [10, 0, 0, 2], 1234, 80);
+        let tuple = parse_five_tuple(&frame).unwrap();
+        assert_eq!(tuple.src_ip, &[10, 0, 0, 1]);
+        assert_eq!(tuple.dst_ip, &[10, 0, 0, 2]);
+        assert_eq!(tuple.src_port, 1234);
+        assert_eq!(tuple.dst_port, 80);
+        assert_eq!(tuple.protocol, PROTO_TCP);
+    }
+
+    #[test]
+    fn parse_rejects_short_and_non_ip() {
+        assert!(parse_five_tuple(&[0u8; 10]).is_none());
+        let mut arp = tcp_v4([10, 0, 0, 1], [10, 0, 0, 2], 1, 2);
+        arp[12] = 0x08;
+        arp[13] = 0x06;
+        assert!(parse_five_tuple(&arp).is_none());
+    }
+
+    #[test]
+    fn parse_rejects_short_ihl() {
+        let mut frame = tcp_v4([10, 0, 0, 1], [10, 0, 0, 2], 1, 2);
+        frame[14] = 0x44;
+        assert!(parse_five_tuple(&frame).is_none());
+    }
+
+    #[test]
+    fn fragments_hash_on_addresses() {
+        let whole = tcp_v4([10, 0, 0, 1], [10, 0, 0, 2], 1234, 80);
+        let mut first = whole.clone();
+        first[14 + 6] = 0x20;
+        let mut last = whole.clone();
+        last[14 + 6] = 0x00;
+        last[14 + 7] = 0xb9;
+        let first = parse_five_tuple(&first).unwrap();
+        let last = parse_five_tuple(&last).unwrap();
+        assert_eq!((first.src_port, first.dst_port), (0, 0));
+        for hash in [SteeringHash::SymmetricToeplitz, SteeringHash::SortedTuple].iter() {
+            assert_eq!(flow_hash(*hash, &first), flow_hash(*hash, &last), "{:?}", hash);
+        }
+        assert_eq!(parse_five_tuple(&whole).unwrap().dst_port, 80);
+    }
+
+    #[test]
+    fn hashes_are_symmetric() {
+        let hashes = [SteeringHash::SymmetricToeplitz, SteeringHash::SortedTuple];
+        let forward = tcp_v4([192, 168, 1, 7], [8, 8, 4, 4], 40000, 443);
+        let reverse = tcp_v4([8, 8, 4, 4], [192, 168, 1, 7], 443, 40000);
+        let mut a = [0u8; 16];
+        let mut b = [0u8; 16];
+        a[15] = 1;
+        b[0] = 0xfe;
+        b[15] = 2;
+        let forward6 = udp_v6(a, b, 5353, 53);
+        let reverse6 = udp_v6(b, a, 53, 5353);
+        for hash in hashes.iter() {
+            let f = flow_hash(*hash, &parse_five_tuple(&forward).unwrap());
+            let r = flow_hash(*hash, &parse_five_tuple(&reverse).unwrap());
+            assert_eq!(f, r, "{:?} not symmetric for v4", hash);
+            let f = flow_hash(*hash, &parse_five_tuple(&forward6).unwrap());
+            let r = flow_hash(*hash, &parse_five_tuple(&reverse6).unwrap());
+            assert_eq!(f, r, "{:?} not symmetric for v6", hash);
+        }
+    }
+
+    #[test]
+    fn ike_gets_a_queue_of_its_own() {
+        // two enclaves of three queues, IKE on the second of each.
+        let steering = Steering::new(SteeringHash::SortedTuple, 6).with_ike(3, 1);
+        let mut ike = [0usize; 6];
+        let mut traffic = [0usize; 6];
+        for port in 1024..2048u16 {
+            let mut a = [0u8; 16];
+            a[14] = (port >> 8) as u8;
+            a[15] = port as u8;
+            ike[steering.ring_of(&udp_v6(a, [0xfe; 16], IKE_PORT, IKE_PORT))] += 1;
+            traffic[steering.ring_of(&udp_v6(a, [0xfe; 16], port, 4500))] += 1;
+            traffic[steering.ring_of(&tcp_v4([10, 0, 0, 1], [10, 0, 1, 1], port, 80))] += 1;
+        }
+        assert_eq!(ike[0] + ike[2] + ike[3] + ike[5], 0, "IKE off its queue: {:?}", ike);
+        assert!(ike[1] > 0 && ike[4] > 0, "IKE of an enclave lost: {:?}", ike);
+        assert_eq!(traffic[1] + traffic[4], 0, "traffic on the IKE queue: {:?}", traffic);
+        assert!(traffic.iter().enumerate().all(|(i, n)| i % 3 == 1 || *n > 0), "{:?}", traffic);
+        assert_eq!(steering.ring_of(&[0u8; 10]), 0);
+    }
+
+    #[test]
+    fn hashes_spread_flows() {
+        for hash in [SteeringHash::SymmetricToeplitz, SteeringHash::SortedTuple].iter() {
+            let mut counts = [0usize; 4];
+            for port in 1024..5120u16 {
+                let frame = tcp_v4([10, 0, 0, 1], [10, 0, 1, 1], port, 80);
+                counts[flow_hash(*hash, &parse_five_tuple(&frame).unwrap()) as usize % 4] += 1;
+            }
+            for count in counts.iter() {
+                assert!(*count > 512, "{:?} badly skewed: {:?}", hash, counts);
+            }
+        }
+    }
+}
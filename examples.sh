@@ -32,6 +32,7 @@ export examples=(
         examples/maglev-ipsec
         examples/dpi-ipsec
         examples/monitoring-ipsec
+        examples/ipsec-gateway
         examples/macswap-ipsec-sha
         examples/acl-fw-ipsec-sha
         examples/lpm-ipsec-sha
@@ -12,7 +12,7 @@ use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::ip::Flow;
 use netbricks::packets::{Ethernet, Packet, Tcp, RawPacket};
 use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use netbricks::utils::cidr::v4::Ipv4Cidr;
 use netbricks::utils::cidr::Cidr;
 use std::str::FromStr;
@@ -133,7 +133,10 @@ where
         .iter()
         .map(|port| {
             ReceiveBatch::new(port.clone())
+                .map(outer)
+                .esp_decap::<Ipv4>(&SAD)
                 .filter_map(acl_match)
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .sendall(port.clone())
         })
         .collect();
@@ -144,20 +147,16 @@ where
     }
 }
 
-fn acl_match(packet: RawPacket) -> Result<Option<Ipv4>> {
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    // let decrypted_pkt_len = aes_cbc_sha256_decrypt_msbedtls(payload, decrypted_pkt, false).unwrap();
-    let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
+    ethernet.parse::<Ipv4>()
+}
 
-    let flow = get_flow(decrypted_pkt);
+fn acl_match(v4: Ipv4) -> Result<Option<Ipv4>> {
+    let flow = get_flow(v4.get_packet_mut());
     let mut match_res: bool = FLOW_CACHE2.with(|flow_cache2| {
 		let flow_cache2_lived = flow_cache2.borrow();
 		if let Some(s) = flow_cache2_lived.get(&flow) {
@@ -187,9 +186,6 @@ fn acl_match(packet: RawPacket) -> Result<Option<Ipv4>> {
 		}
 	});
 
-    // let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    
     if match_res{   
         return Ok(Some(v4));
     }
@@ -204,6 +200,6 @@ fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
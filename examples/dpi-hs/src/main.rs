@@ -16,7 +16,7 @@ use std::fmt::Display;
 // use colored::*;
 // use std::net::Ipv4Addr;
 use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
 
 mod dpi;
@@ -50,6 +50,6 @@ fn main() -> Result<()> {
     let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
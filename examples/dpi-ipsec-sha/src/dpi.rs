@@ -38,45 +38,25 @@ thread_local! {
     };
 }
 
-pub fn dpi(packet: RawPacket) -> Result<Ipv4> {
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+pub fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    // let payload_str = match str::from_utf8(&payload[20..]) {
-    //     Ok(v) => v,
-    //     Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-    // };
-    // println!("{}", payload_str);
-    // stdout().flush().unwrap();
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
+    ethernet.parse::<Ipv4>()
+}
 
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-
-    // println!("decrypted_pkt_len: {}", decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH);
-    // stdout().flush().unwrap();
+pub fn dpi(v4: Ipv4) -> Result<Ipv4> {
+    let packet = v4.get_packet_mut();
 
     let mut matches = vec![];
     AC.with(|ac| {
-        for mat in ac.borrow().find_iter(&decrypted_pkt[40..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)]) {
+        for mat in ac.borrow().find_iter(&packet[40..]) {
             matches.push((mat.pattern(), mat.start(), mat.end()));
         }
     });
     // println!("{:?}", matches);
     // stdout().flush().unwrap();
 
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-
-    // println!("encrypted_pkt_len: {}", encrypted_pkt_len);
-    // stdout().flush().unwrap();
-
     Ok(v4)
 }
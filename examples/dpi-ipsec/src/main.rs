@@ -10,8 +10,10 @@ use netbricks::common::Result;
 use netbricks::config::load_config;
 use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
+use netbricks::packets::ip::v4::Ipv4;
 use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
+use netbricks::utils::ipsec::{SAD, SPD};
 use std::sync::Arc;
 use std::fmt::Display;
 // use colored::*;
@@ -32,7 +34,10 @@ where
         .iter()
         .map(|port| {
             ReceiveBatch::new(port.clone())
+                .map(outer)
+                .esp_decap::<Ipv4>(&SAD)
                 .map(dpi)
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .send(port.clone())
         })
         .collect();
@@ -47,6 +52,6 @@ fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
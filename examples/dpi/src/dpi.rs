@@ -8,35 +8,39 @@ use std::io::Write;
 use aho_corasick::AhoCorasick;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::cell::RefCell;
 use netbricks::utils::DPIRULES;
 
-const RULE_NUM: usize = (1 << 30); 
+pub const RULE_NUM: usize = (1 << 30); 
 
 /* According to my customized pktgen_zeroloss: */
 // set pkt_size: 48 includes the 4B pkt_idx, 2B burst_size, and 2B identifier;
 // int pkt_size = 48 + sizeof(struct ether_hdr); // 48 + 14 = 62 bytes
 // const PAYLOAD_OFFSET: usize = 62; // payload offset relative to the ethernet header.
 
-thread_local! {
-    pub static AC: RefCell<AhoCorasick> = {
-        let mut rules = vec![];
+/// Builds the automaton `dpi` matches payloads against from the first `rule_num` of
+/// `patterns`, or of DPIRULES if there are none.
+pub fn build_ac(patterns: &[String], rule_num: usize) -> AhoCorasick {
+    let mut rules: Vec<&str> = vec![];
 
+    if patterns.is_empty() {
         for line in DPIRULES.iter() {
             rules.push(line);
         }
-        if RULE_NUM < rules.len() {
-            rules.truncate(RULE_NUM);
+    } else {
+        for pattern in patterns {
+            rules.push(pattern);
         }
-        println!("dpi rules length: {}", rules.len());
-        //let patterns = &["This is", "Yang", "abcedf"];
-        let patterns = &rules;
-        let m = AhoCorasick::new(patterns);
-        RefCell::new(m)
-    };
+    }
+    if rule_num < rules.len() {
+        rules.truncate(rule_num);
+    }
+    println!("dpi rules length: {}", rules.len());
+    //let patterns = &["This is", "Yang", "abcedf"];
+    let patterns = &rules;
+    AhoCorasick::new(patterns)
 }
 
-pub fn dpi(packet: RawPacket) -> Result<Tcp<Ipv4>> {
+pub fn dpi(packet: RawPacket, ac: &AhoCorasick) -> Result<Tcp<Ipv4>> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
     let v4 = ethernet.parse::<Ipv4>()?;
@@ -56,11 +60,9 @@ pub fn dpi(packet: RawPacket) -> Result<Tcp<Ipv4>> {
     // stdout().flush().unwrap();
 
     let mut matches = vec![];
-    AC.with(|ac| {
-        for mat in ac.borrow().find_iter(payload) {
-            matches.push((mat.pattern(), mat.start(), mat.end()));
-        }
-    });
+    for mat in ac.find_iter(payload) {
+        matches.push((mat.pattern(), mat.start(), mat.end()));
+    }
     
     // println!("{:?}", matches);
     // stdout().flush().unwrap();
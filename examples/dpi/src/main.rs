@@ -4,26 +4,80 @@ extern crate fnv;
 extern crate lazy_static;
 extern crate netbricks;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate aho_corasick;
 use self::dpi::*;
+use aho_corasick::AhoCorasick;
 use netbricks::common::Result;
-use netbricks::config::load_config;
+use netbricks::config::{load_nf_config, NfConfig, NfConfigWatcher, TableUpdateTask};
 use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
+use netbricks::utils::{TableReader, VersionedTable};
 use std::fmt::Display;
 // use colored::*;
 // use std::net::Ipv4Addr;
-use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::{Scheduler, StandaloneScheduler};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
+use std::time::Duration;
 
 mod dpi;
 
-fn install<T, S>(ports: Vec<T>, sched: &mut S)
+/// How often the runner is asked for new rules.
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parameters of the DPI, its `[nf]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct DpiConfig {
+    /// Patterns to match payloads against instead of the DPI rules built in, if any.
+    patterns: Vec<String>,
+    /// How many of the patterns to match payloads against.
+    rules: usize,
+}
+
+impl Default for DpiConfig {
+    fn default() -> DpiConfig {
+        DpiConfig {
+            patterns: vec![],
+            rules: RULE_NUM,
+        }
+    }
+}
+
+impl DpiConfig {
+    fn automaton(&self) -> AhoCorasick {
+        build_ac(&self.patterns, self.rules)
+    }
+}
+
+impl NfConfig for DpiConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.rules == 0 {
+            return Err("rules must be at least 1".to_string());
+        }
+        if self.patterns.iter().any(|pattern| pattern.is_empty()) {
+            return Err("patterns must not be empty".to_string());
+        }
+        Ok(())
+    }
+}
+
+fn install<T, S>(ports: Vec<T>, sched: &mut S, dpi_config: &DpiConfig)
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
     S: Scheduler + Sized,
 {
+    // the automaton of the rules, rebuilt whenever the runner has new ones.
+    let ac = Arc::new(VersionedTable::new(dpi_config.automaton()));
+    match NfConfigWatcher::<DpiConfig>::connect() {
+        Ok(watcher) => {
+            sched.add_task(TableUpdateTask::new(watcher, ac.clone(), CONTROL_INTERVAL, DpiConfig::automaton)).unwrap();
+        }
+        Err(e) => println!("No control channel, the rules will not change: {}", e),
+    }
     println!("Receiving started");
     for port in &ports {
         println!("Receiving port {}", port);
@@ -32,8 +86,15 @@ where
     let pipelines: Vec<_> = ports
         .iter()
         .map(|port| {
+            let ac = TableReader::new(ac.clone());
             ReceiveBatch::new(port.clone())
-                .map(dpi)
+                .on_batch({
+                    let ac = ac.clone();
+                    move || {
+                        ac.refresh();
+                    }
+                })
+                .map(move |p| dpi(p, &ac.get()))
                 .send(port.clone())
         })
         .collect();
@@ -45,9 +106,10 @@ where
 }
 
 fn main() -> Result<()> {
-    let configuration = load_config()?;
+    let (configuration, dpi_config) = load_nf_config::<DpiConfig>()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    // will trap in the run() until the rings stop or the duration is up
+    context.run(Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, &dpi_config)), 0);
     Ok(())
 }
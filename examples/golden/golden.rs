@@ -0,0 +1,83 @@
+//! Golden-output test of the example NFs, the `golden` test of each of their packages. It
+//! replays `golden/flows.pcapng` through the package's binary on a `pcap:` port and compares
+//! what the NF sent, frame by frame, with the capture checked in as `<example>/data/golden.pcap`.
+//!
+//! A missing capture fails the test; `UPDATE_GOLDEN=1` records it instead. Look at the new
+//! capture (`tcpdump -r`) before checking it in.
+extern crate netbricks;
+
+use netbricks::utils::pcap::read_pcap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Merged over the built-in defaults through `NETBRICKS_CONFIG`; the port stops the NF once the
+/// input has been replayed and everything the NF made of it has been written.
+const CONFIG: &str = r#"
+[[ports]]
+  name = "pcap:rx={input},tx={output},exit"
+  rx_queues = [0]
+  tx_queues = [0]
+  rxd = 128
+  txd = 128
+  loopback = false
+  tso = false
+  csum = false
+"#;
+
+/// Cargo builds the binaries of a package before its integration tests, next to `deps/`.
+fn binary(nf: &str) -> PathBuf {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.join(format!("{}{}", nf, env::consts::EXE_SUFFIX))
+}
+
+#[test]
+fn output_matches_golden_capture() {
+    check(env!("CARGO_PKG_NAME"), env!("CARGO_MANIFEST_DIR"));
+}
+
+fn check(nf: &str, manifest_dir: &str) {
+    let manifest_dir = Path::new(manifest_dir);
+    let input = manifest_dir.join("../golden/flows.pcapng");
+    let expected = manifest_dir.join("data/golden.pcap");
+    let scratch = env::temp_dir().join(format!("{}-golden-{}", nf, process::id()));
+    fs::create_dir_all(&scratch).unwrap();
+    let output = scratch.join("out.pcap");
+    let config = scratch.join("config.toml");
+    let toml = CONFIG
+        .replace("{input}", &input.display().to_string())
+        .replace("{output}", &output.display().to_string());
+    fs::write(&config, toml).unwrap();
+
+    let status = Command::new(binary(nf))
+        .env("NETBRICKS_CONFIG", &config)
+        .current_dir(manifest_dir)
+        .status()
+        .unwrap();
+    assert!(status.success(), "{} exited with {}", nf, status);
+    let sent = read_pcap(output.to_str().unwrap()).unwrap();
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        fs::create_dir_all(expected.parent().unwrap()).unwrap();
+        fs::copy(&output, &expected).unwrap();
+        eprintln!("{}: recorded {} frames in {}", nf, sent.len(), expected.display());
+    } else {
+        assert!(
+            expected.exists(),
+            "{}: no {}, record it with UPDATE_GOLDEN=1",
+            nf,
+            expected.display()
+        );
+        let want = read_pcap(expected.to_str().unwrap()).unwrap();
+        for (i, (got, want)) in sent.iter().zip(want.iter()).enumerate() {
+            assert_eq!(got, want, "{}: frame {} differs from {}", nf, i, expected.display());
+        }
+        assert_eq!(sent.len(), want.len(), "{}: sent {} frames, expected {}", nf, sent.len(), want.len());
+    }
+    let _ = fs::remove_dir_all(&scratch);
+}
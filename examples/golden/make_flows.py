@@ -0,0 +1,109 @@
+#!/usr/bin/env python3
+"""Writes flows.pcapng, the input the golden tests replay through each example NF.
+
+The capture is deterministic: a handful of TCP flows in both directions, some from or to
+addresses on the acl-fw block list, payloads with and without HTTP keywords for dpi, and a
+couple of frames (ARP, UDP) the TCP-only NFs have to drop. Re-run it only together with
+UPDATE_GOLDEN=1, since every expectation depends on it.
+"""
+import struct
+import sys
+
+ACL_HOSTS = ["103.75.118.230", "104.194.215.118", "107.172.141.10"]
+OTHER_HOSTS = ["10.0.0.5", "10.0.1.7", "192.168.3.20", "172.16.9.9"]
+SERVERS = ["93.184.216.34", "151.101.1.69"]
+PAYLOADS = [
+    b"GET /index.html HTTP/1.1\r\nHost: example.com\r\nUser-Agent: curl/7.58.0\r\n\r\n",
+    b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: 5\r\n\r\nhello",
+    b"",
+    b"\x16\x03\x01\x00\x2a" + bytes(range(42)),
+]
+
+
+def mac(n):
+    return bytes([0x02, 0, 0, 0, 0, n])
+
+
+def ip_bytes(addr):
+    return bytes(int(x) for x in addr.split("."))
+
+
+def checksum(data):
+    if len(data) % 2:
+        data += b"\0"
+    total = sum(struct.unpack("!%dH" % (len(data) // 2), data))
+    while total >> 16:
+        total = (total & 0xFFFF) + (total >> 16)
+    return ~total & 0xFFFF
+
+
+def ipv4(src, dst, proto, payload, ident):
+    header = struct.pack("!BBHHHBBH4s4s", 0x45, 0, 20 + len(payload), ident, 0x4000, 64, proto, 0,
+                         ip_bytes(src), ip_bytes(dst))
+    header = header[:10] + struct.pack("!H", checksum(header)) + header[12:]
+    return header + payload
+
+
+def tcp(src, dst, sport, dport, seq, ack, flags, payload, ident):
+    segment = struct.pack("!HHIIBBHHH", sport, dport, seq, ack, 5 << 4, flags, 65535, 0, 0) + payload
+    pseudo = ip_bytes(src) + ip_bytes(dst) + struct.pack("!BBH", 0, 6, len(segment))
+    segment = segment[:16] + struct.pack("!H", checksum(pseudo + segment)) + segment[18:]
+    return ipv4(src, dst, 6, segment, ident)
+
+
+def udp(src, dst, sport, dport, payload, ident):
+    datagram = struct.pack("!HHHH", sport, dport, 8 + len(payload), 0) + payload
+    return ipv4(src, dst, 17, datagram, ident)
+
+
+def ethernet(src, dst, ethertype, payload):
+    frame = dst + src + struct.pack("!H", ethertype) + payload
+    return frame + b"\0" * max(0, 60 - len(frame))
+
+
+def frames():
+    out = []
+    ident = 1
+    clients = ACL_HOSTS + OTHER_HOSTS
+    for i, client in enumerate(clients):
+        server = SERVERS[i % len(SERVERS)]
+        sport = 40000 + 17 * i
+        exchange = [
+            (client, server, sport, 80, 0x02, b""),
+            (server, client, 80, sport, 0x12, b""),
+            (client, server, sport, 80, 0x10, b""),
+            (client, server, sport, 80, 0x18, PAYLOADS[0]),
+            (server, client, 80, sport, 0x18, PAYLOADS[(i % 3) + 1]),
+            (client, server, sport, 80, 0x11, b""),
+        ]
+        for seq, (src, dst, s, d, flags, payload) in enumerate(exchange):
+            packet = tcp(src, dst, s, d, 1000 * (seq + 1), 500 * seq, flags, payload, ident)
+            mac_src, mac_dst = (mac(1), mac(2)) if src == client else (mac(2), mac(1))
+            out.append(ethernet(mac_src, mac_dst, 0x0800, packet))
+            ident += 1
+    arp = struct.pack("!HHBBH6s4s6s4s", 1, 0x0800, 6, 4, 1, mac(1), ip_bytes(OTHER_HOSTS[0]),
+                      b"\0" * 6, ip_bytes(SERVERS[0]))
+    out.insert(5, ethernet(mac(1), b"\xff" * 6, 0x0806, arp))
+    out.insert(20, ethernet(mac(1), mac(2), 0x0800, udp(OTHER_HOSTS[1], SERVERS[1], 5353, 53, b"\x12\x34" + b"\0" * 10, ident)))
+    return out
+
+
+def block(block_type, body):
+    body += b"\0" * (-len(body) % 4)
+    length = 12 + len(body)
+    return struct.pack("<II", block_type, length) + body + struct.pack("<I", length)
+
+
+def pcapng(packets):
+    data = block(0x0A0D0D0A, struct.pack("<IHHq", 0x1A2B3C4D, 1, 0, -1))
+    data += block(1, struct.pack("<HHI", 1, 0, 65535))
+    for i, packet in enumerate(packets):
+        timestamp = 1_500_000_000_000_000 + 1000 * i
+        data += block(6, struct.pack("<IIIII", 0, timestamp >> 32, timestamp & 0xFFFFFFFF, len(packet), len(packet)) + packet)
+    return data
+
+
+if __name__ == "__main__":
+    path = sys.argv[1] if len(sys.argv) > 1 else "flows.pcapng"
+    with open(path, "wb") as f:
+        f.write(pcapng(frames()))
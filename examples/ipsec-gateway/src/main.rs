@@ -0,0 +1,62 @@
+extern crate netbricks;
+use netbricks::common::Result;
+use netbricks::config::{load_config, IkeConfiguration};
+use netbricks::ike::{IkeResponder, IkeTask};
+use netbricks::interface::{PacketRx, PacketTx};
+use netbricks::operators::{Batch, ReceiveBatch};
+use netbricks::packets::ip::v4::Ipv4;
+use netbricks::packets::{Ethernet, Packet};
+use netbricks::scheduler::{initialize_system, Scheduler, StandaloneScheduler};
+use netbricks::utils::ipsec::{SAD, SPD};
+use std::fmt::Display;
+use std::sync::Arc;
+
+/// Runs the IKE responder on the queue `ike` names, and on the others sends the traffic peers
+/// tunnel in over the child SAs they negotiated back to them: decapsulated, its addresses
+/// swapped, and encapsulated on the outbound SA the SPD picks for the answer.
+fn install<T, S>(ports: Vec<T>, sched: &mut S, ike: &IkeConfiguration)
+where
+    T: PacketRx + PacketTx + Display + Clone + 'static,
+    S: Scheduler + Sized,
+{
+    for (queue, port) in ports.iter().enumerate() {
+        if queue == ike.queue {
+            println!("IKE on port {}", port);
+            let config = ike.ike_config().expect("[ike] was validated with the configuration");
+            let responder = IkeResponder::new(config, &SAD, &SPD).unwrap();
+            sched.add_task(IkeTask::new(port.clone(), responder)).unwrap();
+            continue;
+        }
+        println!("Receiving port {}", port);
+        let pipeline = ReceiveBatch::new(port.clone())
+            .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
+            .esp_decap::<Ipv4>(&SAD)
+            .map(reply)
+            .esp_encap::<Ipv4>(&SAD, &SPD)
+            .send(port.clone());
+        sched.add_task(pipeline).unwrap();
+    }
+}
+
+fn reply(mut v4: Ipv4) -> Result<Ipv4> {
+    let (src, dst) = (v4.src(), v4.dst());
+    v4.set_src(dst);
+    v4.set_dst(src);
+    Ok(v4)
+}
+
+fn main() -> Result<()> {
+    let configuration = load_config()?;
+    println!("{}", configuration);
+    let ike = match configuration.ike.clone() {
+        Some(ike) => ike,
+        None => {
+            println!("No [ike] table, so no SAs to protect traffic with");
+            return Ok(());
+        }
+    };
+    let mut context = initialize_system(&configuration)?;
+    // will trap in the run() and return after finish
+    context.run(Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, &ike)), 0);
+    Ok(())
+}
@@ -130,21 +130,16 @@ thread_local! {
     };
 }
 
-pub fn lpm(packet: RawPacket) -> Result<Ipv4> {
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+pub fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
+    ethernet.parse::<Ipv4>()
+}
 
-    let srcip = get_src_ip(decrypted_pkt);
+pub fn lpm(v4: Ipv4) -> Result<Ipv4> {
+    let srcip = v4.src();
     let port = LOOKUP_TABLE.with(|lookup_table| {
         lookup_table.borrow().lookup_entry(srcip) as u32
     });
@@ -152,9 +147,5 @@ pub fn lpm(packet: RawPacket) -> Result<Ipv4> {
         (*count_ports.borrow_mut())[port as usize] += 1;
     });
 
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-
     Ok(v4)
 }
@@ -2,6 +2,8 @@ use fnv::FnvHasher;
 use netbricks::common::Result;
 use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::{Ethernet, Packet, RawPacket};
+use netbricks::utils::cidr::v4::Ipv4Cidr;
+use netbricks::utils::cidr::Cidr;
 use std::collections::HashMap;
 use std::convert::From;
 use std::hash::BuildHasherDefault;
@@ -103,23 +105,36 @@ impl IPLookup {
     }
 }
 
-thread_local! {
-    pub static LOOKUP_TABLE: RefCell<IPLookup> = {
-        let mut rng = thread_rng();
-        let mut lpm_table = IPLookup::new();
+/// A route of the `[nf]` table: where packets from `prefix` go.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Route {
+    pub prefix: Ipv4Cidr,
+    pub gate: u16,
+}
 
-        for _ in 0..16000 {
-            let a: u8 = rng.sample(Uniform::new_inclusive(0, 255));
-            let b: u8 = rng.sample(Uniform::new_inclusive(0, 255));
-            let c: u8 = rng.sample(Uniform::new_inclusive(0, 255));
-            let d: u8 = rng.sample(Uniform::new_inclusive(0, 255));
-            let port: u16 = rng.sample(Uniform::new_inclusive(0, GATE_NUM - 1));
-            lpm_table.insert_ipv4(Ipv4Addr::new(a, b, c, d), 32, port);
-        }
+/// Builds the table `lpm` looks packets up in from `routes`, and `random_routes` routes of
+/// random /32s to random gates.
+pub fn build_table(routes: &[Route], random_routes: usize) -> IPLookup {
+    let mut rng = thread_rng();
+    let mut lpm_table = IPLookup::new();
 
-        lpm_table.construct_table();
-        RefCell::new(lpm_table)
-    };
+    for _ in 0..random_routes {
+        let a: u8 = rng.sample(Uniform::new_inclusive(0, 255));
+        let b: u8 = rng.sample(Uniform::new_inclusive(0, 255));
+        let c: u8 = rng.sample(Uniform::new_inclusive(0, 255));
+        let d: u8 = rng.sample(Uniform::new_inclusive(0, 255));
+        let port: u16 = rng.sample(Uniform::new_inclusive(0, GATE_NUM - 1));
+        lpm_table.insert_ipv4(Ipv4Addr::new(a, b, c, d), 32, port);
+    }
+    for route in routes {
+        let len = route.prefix.length();
+        // the table expects the host bits clear.
+        let mask = if len == 0 { 0 } else { !0u32 << (32 - len) };
+        lpm_table.insert(u32::from(route.prefix.address()) & mask, len, route.gate);
+    }
+
+    lpm_table.construct_table();
+    lpm_table
 }
 
 thread_local! {
@@ -129,13 +144,11 @@ thread_local! {
     };
 }
 
-pub fn lpm(packet: RawPacket) -> Result<Ipv4> {
+pub fn lpm(packet: RawPacket, lookup_table: &IPLookup) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
     let v4 = ethernet.parse::<Ipv4>()?;
-    let port = LOOKUP_TABLE.with(|lookup_table| {
-        lookup_table.borrow().lookup_entry(v4.src()) as u32
-    });
+    let port = lookup_table.lookup_entry(v4.src()) as u32;
     COUNT_PORTS.with(|count_ports| {
         (*count_ports.borrow_mut())[port as usize] += 1;
     });
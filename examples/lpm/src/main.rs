@@ -4,24 +4,76 @@ extern crate fnv;
 extern crate lazy_static;
 extern crate netbricks;
 extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 use self::lpm::*;
 use netbricks::common::Result;
-use netbricks::config::load_config;
+use netbricks::config::{load_nf_config, NfConfig, NfConfigWatcher, TableUpdateTask};
 use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
+use netbricks::utils::{TableReader, VersionedTable};
 use std::fmt::Display;
 // use colored::*;
 // use std::net::Ipv4Addr;
-use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::{Scheduler, StandaloneScheduler};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
+use std::time::Duration;
 mod lpm;
 
-fn install<T, S>(ports: Vec<T>, sched: &mut S)
+/// How often the runner is asked for new routes.
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parameters of the LPM, its `[nf]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct LpmConfig {
+    /// Where packets go, by their source address.
+    routes: Vec<Route>,
+    /// Routes of random /32s to add to `routes`, to fill the table.
+    random_routes: usize,
+}
+
+impl Default for LpmConfig {
+    fn default() -> LpmConfig {
+        LpmConfig {
+            routes: vec![],
+            random_routes: 16000,
+        }
+    }
+}
+
+impl NfConfig for LpmConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if let Some(route) = self.routes.iter().find(|route| route.gate >= GATE_NUM) {
+            return Err(format!("route to {}: gates go up to {}", route.prefix, GATE_NUM - 1));
+        }
+        Ok(())
+    }
+}
+
+impl LpmConfig {
+    fn lookup_table(&self) -> IPLookup {
+        build_table(&self.routes, self.random_routes)
+    }
+}
+
+fn install<T, S>(ports: Vec<T>, sched: &mut S, lpm_config: &LpmConfig)
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
     S: Scheduler + Sized,
 {
+    // the routes, rebuilt whenever the runner has new ones; the enclave needs room for two
+    // tables while one replaces the other.
+    let lookup_table = Arc::new(VersionedTable::new(lpm_config.lookup_table()));
+    match NfConfigWatcher::<LpmConfig>::connect() {
+        Ok(watcher) => {
+            let update = TableUpdateTask::new(watcher, lookup_table.clone(), CONTROL_INTERVAL, LpmConfig::lookup_table);
+            sched.add_task(update).unwrap();
+        }
+        Err(e) => println!("No control channel, the routes will not change: {}", e),
+    }
     println!("Receiving started");
     for port in &ports {
         println!("Receiving port {}", port);
@@ -30,8 +82,15 @@ where
     let pipelines: Vec<_> = ports
         .iter()
         .map(|port| {
+            let lookup_table = TableReader::new(lookup_table.clone());
             ReceiveBatch::new(port.clone())
-                .map(lpm)
+                .on_batch({
+                    let lookup_table = lookup_table.clone();
+                    move || {
+                        lookup_table.refresh();
+                    }
+                })
+                .map(move |p| lpm(p, &lookup_table.get()))
                 // .group_by(
                 //     |v4| LOOKUP_TABLE.read().unwrap().lookup_entry(v4.src()) as usize,
                 //     |groups| {
@@ -71,9 +130,10 @@ where
 }
 
 fn main() -> Result<()> {
-    let configuration = load_config()?;
+    let (configuration, lpm_config) = load_nf_config::<LpmConfig>()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    // will trap in the run() until the rings stop or the duration is up
+    context.run(Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, &lpm_config)), 0);
     Ok(())
 }
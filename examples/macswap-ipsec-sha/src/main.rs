@@ -5,10 +5,10 @@ use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
 use netbricks::packets::{Ethernet, Packet, RawPacket};
 use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use std::fmt::Display;
 use netbricks::packets::ip::v4::Ipv4;
-use netbricks::utils::ipsec::*;
+use netbricks::utils::ipsec::{SAD, SPD};
 use std::sync::Arc;
 
 
@@ -26,6 +26,8 @@ where
         .map(|port| {
             ReceiveBatch::new(port.clone())
                 .map(macswap)
+                .esp_decap::<Ipv4>(&SAD)
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .send(port.clone())
         })
         .collect();
@@ -39,42 +41,13 @@ where
 fn macswap(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    // let payload_str = match str::from_utf8(&payload[20..]) {
-    //     Ok(v) => v,
-    //     Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
-    // };
-    // println!("{}", payload_str);
-    // stdout().flush().unwrap();
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-
-    // println!("decrypted_pkt_len: {}", decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH);
-    // stdout().flush().unwrap();
-
-    // no matter whether authentication succeeds, you should do encrypt for a fair comparison.
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    
-    // println!("encrypted_pkt_len: {}", encrypted_pkt_len);
-    // stdout().flush().unwrap();
-
-    Ok(v4)
+    ethernet.parse::<Ipv4>()
 }
 
 fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
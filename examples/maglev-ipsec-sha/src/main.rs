@@ -11,7 +11,7 @@ use netbricks::operators::{Batch, ReceiveBatch};
 use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::ip::{Flow, IpPacket};
 use netbricks::packets::{Ethernet, Packet, RawPacket, Tcp};
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use netbricks::scheduler::Scheduler;
 use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr};
@@ -19,8 +19,6 @@ use std::net::{IpAddr, Ipv4Addr};
 // use std::io::Write;
 use std::hash::{BuildHasherDefault, BuildHasher, Hash, Hasher};
 use twox_hash::XxHash;
-use std::slice;
-use std::mem;
 use netbricks::utils::ipsec::*;
 // use std::sync::RwLock;
 // use std::collections::HashMap;
@@ -103,18 +101,13 @@ impl Maglev {
         }
     }
 
-    #[inline]
-    fn flow_as_u8(flow: &Flow) -> &[u8] {
-        let size = mem::size_of::<Flow>();
-        unsafe { slice::from_raw_parts((flow as *const Flow) as *const u8, size) }
-    }
-    
+    /// Hashes the fields of the flow, not its bytes: those include the padding after an
+    /// `IpAddr::V4`, which is not the same in every copy of a flow.
     #[inline]
     fn flow_hash(flow: &Flow) -> u32 {
         let mut hasher = FnvHasher::default();
-        hasher.write(Maglev::flow_as_u8(flow));
+        flow.hash(&mut hasher);
         hasher.finish() as u32
-        // farmhash::hash32(flow_as_u8(flow))
     }
 
     pub fn lookup(&self, flow: &Flow) -> u32 {
@@ -162,7 +155,10 @@ where
         .iter()
         .map(move |port| {
             ReceiveBatch::new(port.clone())
-                .map(|p| lb(p))
+                .map(outer)
+                .esp_decap::<Ipv4>(&SAD)
+                .map(lb)
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .sendall(port.clone())
         })
         .collect();
@@ -173,33 +169,24 @@ where
     }
 }
 
-fn lb(packet: RawPacket) -> Result<Ipv4> {
-// fn lb(packet: RawPacket) -> Result<Ethernet> {
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    
-    let flow = get_flow(decrypted_pkt);
+    ethernet.parse::<Ipv4>()
+}
+
+fn lb(v4: Ipv4) -> Result<Ipv4> {
+    let packet = v4.get_packet_mut();
+    let flow = get_flow(packet);
     let assigned_server = LUT.with(|lut| {
         lut.borrow().lookup(&flow) as u32
     });
-    set_dst_ip(decrypted_pkt, assigned_server);
+    set_dst_ip(packet, assigned_server);
     // tcp.stamp_flow(assigned_server).unwrap();
     // tcp.cascade();
 
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    
     Ok(v4)
 }
 
@@ -207,6 +194,6 @@ fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
@@ -2,32 +2,83 @@ extern crate fnv;
 #[macro_use]
 extern crate lazy_static;
 extern crate netbricks;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 extern crate twox_hash;
 use fnv::FnvHasher;
 use netbricks::common::Result;
-use netbricks::config::load_config;
+use netbricks::config::{load_nf_config, NfConfig, NfConfigWatcher, TableUpdateTask};
 use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
 use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::ip::{Flow, IpPacket};
 use netbricks::packets::{Ethernet, Packet, RawPacket, Tcp};
+use netbricks::utils::{TableReader, VersionedTable};
 use std::fmt::Display;
 use std::net::{IpAddr, Ipv4Addr};
 use std::io::stdout;
 use std::io::Write;
 use std::hash::{BuildHasherDefault, BuildHasher, Hash, Hasher};
 use twox_hash::XxHash;
-use std::slice;
-use std::mem;
 // use std::sync::RwLock;
 // use std::collections::HashMap;
-use std::cell::RefCell;
-use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::{Scheduler, StandaloneScheduler};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
-
+use std::time::Duration;
 
 const ENTRY_NUM: u32 = 65537;
+/// How often the runner is asked for new backends.
+const CONTROL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Parameters of the load balancer, its `[nf]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct MaglevConfig {
+    /// Names of the backends flows are spread over.
+    backends: Vec<String>,
+    /// Size of the lookup table, a prime well above the number of backends.
+    entries: u32,
+}
+
+impl Default for MaglevConfig {
+    fn default() -> MaglevConfig {
+        MaglevConfig {
+            backends: vec!["Larry".to_string(), "Curly".to_string(), "Moe".to_string()],
+            entries: ENTRY_NUM,
+        }
+    }
+}
+
+impl NfConfig for MaglevConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.backends.is_empty() {
+            return Err("no backends".to_string());
+        }
+        // 0x8000 marks an empty slot of the table while it is generated.
+        if self.backends.len() >= 0x8000 {
+            return Err(format!("{} backends, at most {}", self.backends.len(), 0x8000 - 1));
+        }
+        // permutations only cover the whole table when its size is prime.
+        let entries = u64::from(self.entries);
+        let composite = (2..entries).take_while(|d| d * d <= entries).any(|d| entries % d == 0);
+        if self.entries < 2 || composite {
+            return Err(format!("entries must be a prime, not {}", self.entries));
+        }
+        if (self.entries as usize) < self.backends.len() {
+            return Err(format!("{} entries for {} backends", self.entries, self.backends.len()));
+        }
+        Ok(())
+    }
+}
+
+impl MaglevConfig {
+    fn maglev(&self) -> Maglev {
+        let backends: Vec<&str> = self.backends.iter().map(|b| b.as_str()).collect();
+        Maglev::new(&backends, self.entries)
+    }
+}
 
 type FnvHash = BuildHasherDefault<FnvHasher>;
 type XxHashFactory = BuildHasherDefault<XxHash>;
@@ -101,18 +152,13 @@ impl Maglev {
         }
     }
 
-    #[inline]
-    fn flow_as_u8(flow: &Flow) -> &[u8] {
-        let size = mem::size_of::<Flow>();
-        unsafe { slice::from_raw_parts((flow as *const Flow) as *const u8, size) }
-    }
-    
+    /// Hashes the fields of the flow, not its bytes: those include the padding after an
+    /// `IpAddr::V4`, which is not the same in every copy of a flow.
     #[inline]
     fn flow_hash(flow: &Flow) -> u32 {
         let mut hasher = FnvHasher::default();
-        hasher.write(Maglev::flow_as_u8(flow));
+        flow.hash(&mut hasher);
         hasher.finish() as u32
-        // farmhash::hash32(flow_as_u8(flow))
     }
 
     pub fn lookup(&self, flow: &Flow) -> u32 {
@@ -121,15 +167,6 @@ impl Maglev {
     }
 }
 
-thread_local! {
-    pub static LUT: RefCell<Maglev> = {
-        let backends = vec!["Larry", "Curly", "Moe"];
-        // let ct = backends.len();
-        let lut = Maglev::new(&backends, ENTRY_NUM);
-        RefCell::new(lut)
-    };
-}
-
 // lazy_static! {
 //     static ref FLOW_CACHE: Arc<RwLock<HashMap<Flow, u32, FnvHash>>> = {
 //         let m = HashMap::with_hasher(Default::default());
@@ -149,18 +186,33 @@ impl<E: IpPacket> Stamper for Tcp<E> {
     }
 }
 
-fn install<T, S>(ports: Vec<T>, sched: &mut S)
+fn install<T, S>(ports: Vec<T>, sched: &mut S, maglev: &MaglevConfig)
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
     S: Scheduler + Sized,
 {
+    // the lookup table, rebuilt whenever the runner has new backends.
+    let lut = Arc::new(VersionedTable::new(maglev.maglev()));
+    match NfConfigWatcher::<MaglevConfig>::connect() {
+        Ok(watcher) => {
+            sched.add_task(TableUpdateTask::new(watcher, lut.clone(), CONTROL_INTERVAL, MaglevConfig::maglev)).unwrap();
+        }
+        Err(e) => println!("No control channel, the backends will not change: {}", e),
+    }
     println!("Receiving started");
 
     let pipelines: Vec<_> = ports
         .iter()
         .map(|port| {
+            let lut = TableReader::new(lut.clone());
             ReceiveBatch::new(port.clone())
-                .map(|p| lb(p))
+                .on_batch({
+                    let lut = lut.clone();
+                    move || {
+                        lut.refresh();
+                    }
+                })
+                .map(move |p| lb(p, &lut.get()))
                 .sendall(port.clone())
         })
         .collect();
@@ -171,16 +223,14 @@ where
     }
 }
 
-fn lb(packet: RawPacket) -> Result<Tcp<Ipv4>> {
+fn lb(packet: RawPacket, lut: &Maglev) -> Result<Tcp<Ipv4>> {
 // fn lb(packet: RawPacket) -> Result<Ethernet> {
 	let mut ethernet = packet.parse::<Ethernet>()?;
 	ethernet.swap_addresses();
 	let v4 = ethernet.parse::<Ipv4>()?;
 	let mut tcp = v4.parse::<Tcp<Ipv4>>()?;
     let flow = tcp.flow(); // new a Flow structure
-    let assigned_server = LUT.with(|lut| {
-        lut.borrow().lookup(&flow) as u32
-    });
+    let assigned_server = lut.lookup(&flow) as u32;
     tcp.stamp_flow(assigned_server).unwrap();
     tcp.cascade();
 
@@ -211,9 +261,10 @@ fn lb(packet: RawPacket) -> Result<Tcp<Ipv4>> {
 }
 
 fn main() -> Result<()> {
-    let configuration = load_config()?;
+    let (configuration, maglev) = load_nf_config::<MaglevConfig>()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    // will trap in the run() until the rings stop or the duration is up
+    context.run(Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, &maglev)), 0);
     Ok(())
 }
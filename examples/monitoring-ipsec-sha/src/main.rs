@@ -10,7 +10,7 @@ use netbricks::operators::{Batch, ReceiveBatch};
 use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::ip::Flow;
 use netbricks::packets::{Ethernet, Packet, RawPacket, Tcp};
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use netbricks::scheduler::Scheduler;
 use std::collections::HashMap;
 use std::fmt::Display;
@@ -42,7 +42,10 @@ where
         .iter()
         .map(move |port| {
             ReceiveBatch::new(port.clone())
-                .map(|p| monitoring(p))
+                .map(outer)
+                .esp_decap::<Ipv4>(&SAD)
+                .map(monitoring)
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .sendall(port.clone())
         })
         .collect();
@@ -53,35 +56,21 @@ where
     }
 }
 
-fn monitoring(packet: RawPacket) -> Result<Ipv4> {
-    // print!("-4");stdout().flush();
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
+    ethernet.parse::<Ipv4>()
+}
 
-    // println!("before flow_map");stdout().flush().unwrap();
-    let flow = get_flow(decrypted_pkt);
-    // println!("{}", flow);
+fn monitoring(v4: Ipv4) -> Result<Ipv4> {
+    let flow = get_flow(v4.get_packet_mut());
     FLOW_MAP.with(|flow_map| {
-        // println!("inside flow_map");stdout().flush().unwrap();
         // println!("{}", flow);stdout().flush().unwrap();
         *((*flow_map.borrow_mut()).entry(flow).or_insert(0)) += 1;
     });
-    // println!("after flow_map");stdout().flush().unwrap();
 
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    
     Ok(v4)
 }
 
@@ -89,6 +78,6 @@ fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
@@ -3,6 +3,7 @@ extern crate fnv;
 extern crate lazy_static;
 extern crate netbricks;
 use fnv::FnvHasher;
+use netbricks::checkpoint;
 use netbricks::common::Result;
 use netbricks::config::load_config;
 use netbricks::interface::{PacketRx, PacketTx};
@@ -16,11 +17,16 @@ use std::hash::BuildHasherDefault;
 use std::io::stdout;
 use std::io::Write;
 use std::cell::RefCell;
-use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::{Scheduler, StandaloneScheduler};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
+use std::time::Duration;
 
 
+/// The flow counts of the enclave, as sgx-runner keeps them, see `checkpoint::enclave_name`.
+const CHECKPOINT: &str = "monitoring";
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
+
 type FnvHash = BuildHasherDefault<FnvHasher>;
 
 thread_local! {
@@ -30,13 +36,16 @@ thread_local! {
     };
 }
 
-fn install<T, S>(ports: Vec<T>, sched: &mut S)
+fn install<T, S>(ports: Vec<T>, sched: &mut S, checkpoint: &str)
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
     S: Scheduler + Sized,
 {
     println!("Receiving started");
 
+    // keep counting the flows of the enclave this one replaces.
+    checkpoint::resume(sched, checkpoint, CHECKPOINT_INTERVAL);
+
     let pipelines: Vec<_> = ports
         .iter()
         .map(move |port| {
@@ -69,9 +78,15 @@ fn monitoring(packet: RawPacket) -> Result<Tcp<Ipv4>> {
 }
 
 fn main() -> Result<()> {
+    checkpoint::register("flow_map", &FLOW_MAP);
     let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    let checkpoint = checkpoint::enclave_name(CHECKPOINT, &context.rx_queues);
+    // will trap in the run() until the rings stop or the duration is up
+    context.run(
+        Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, &checkpoint)),
+        0,
+    );
     Ok(())
 }
@@ -11,7 +11,7 @@ use netbricks::packets::ip::v4::Ipv4;
 use netbricks::packets::ip::ProtocolNumbers;
 use netbricks::packets::ip::{Flow, IpPacket};
 use netbricks::packets::{Ethernet, Packet, RawPacket, Tcp};
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::initialize_system;
 use netbricks::scheduler::Scheduler;
 use std::collections::HashMap;
 use std::fmt::Display;
@@ -98,7 +98,10 @@ where
         .iter()
         .map(move |port| {
             ReceiveBatch::new(port.clone())
-                .map(|p| nat(p, Ipv4Addr::new(10, 0, 0, 1)))
+                .map(outer)
+                .esp_decap::<Ipv4>(&SAD)
+                .map(|v4| nat(v4, Ipv4Addr::new(10, 0, 0, 1)))
+                .esp_encap::<Ipv4>(&SAD, &SPD)
                 .sendall(port.clone())
         })
         .collect();
@@ -109,24 +112,17 @@ where
     }
 }
 
-fn nat(packet: RawPacket, nat_ip: Ipv4Addr) -> Result<Ipv4> {
-    // print!("-4");stdout().flush();
+/// The outer IPv4 header of the ESP packet in `packet`, whose frame goes back where it came
+/// from.
+fn outer(packet: RawPacket) -> Result<Ipv4> {
     let mut ethernet = packet.parse::<Ethernet>()?;
     ethernet.swap_addresses();
-    let v4 = ethernet.parse::<Ipv4>()?;
-    let payload: &mut [u8] = v4.get_payload_mut(); // payload.len()
-
-    let esp_hdr: &mut [u8] = &mut [0u8; 8];
-    esp_hdr.copy_from_slice(&payload[0..ESP_HEADER_LENGTH]);
-
-    let decrypted_pkt: &mut [u8] = &mut [0u8; 2000];
-    let decrypted_pkt_len = aes_cbc_sha256_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap(); 
-    // let decrypted_pkt_len = aes_gcm128_decrypt_openssl(payload, decrypted_pkt, false).unwrap();
-    // let decrypted_pkt_len = aes_gcm128_decrypt_mbedtls(payload, decrypted_pkt, false).unwrap();
+    ethernet.parse::<Ipv4>()
+}
 
-    // now decrypted_pkt points to the decrypted Ip header. 
-    
-    let flow = get_flow(decrypted_pkt);
+fn nat(v4: Ipv4, nat_ip: Ipv4Addr) -> Result<Ipv4> {
+    let packet = v4.get_packet_mut();
+    let flow = get_flow(packet);
 
     PORT_MAP.with(|port_map| {
         let port_map_lived = port_map.borrow();
@@ -135,7 +131,7 @@ fn nat(packet: RawPacket, nat_ip: Ipv4Addr) -> Result<Ipv4> {
             Some(s) => {
                 // drop(port_map);
                 // let _ = tcp.stamp_flow(*s);
-                let _ = set_flow(decrypted_pkt, *s); 
+                let _ = set_flow(packet, *s); 
                 // tcp.cascade();
             }
             None => {
@@ -160,17 +156,13 @@ fn nat(packet: RawPacket, nat_ip: Ipv4Addr) -> Result<Ipv4> {
                         port_map_mut_lived.insert(rev_flow, flow.reverse());
                     });
                     // let _ = tcp.stamp_flow(outgoing_flow);
-                    let _ = set_flow(decrypted_pkt, outgoing_flow);
+                    let _ = set_flow(packet, outgoing_flow);
                     // tcp.cascade();
                 }
             }
         }
     });
 
-    let encrypted_pkt_len = aes_cbc_sha256_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_openssl(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    // let encrypted_pkt_len = aes_gcm128_encrypt_mbedtls(&decrypted_pkt[..(decrypted_pkt_len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)], &(*esp_hdr), payload).unwrap();
-    
     Ok(v4)
 }
 
@@ -178,6 +170,6 @@ fn main() -> Result<()> {
 	let configuration = load_config()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    context.run(Arc::new(install), 0); // will trap in the run() until the rings stop or the duration is up
     Ok(())
 }
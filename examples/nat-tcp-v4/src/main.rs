@@ -2,9 +2,13 @@ extern crate fnv;
 #[macro_use]
 extern crate lazy_static;
 extern crate netbricks;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 use fnv::FnvHasher;
+use netbricks::checkpoint;
 use netbricks::common::Result;
-use netbricks::config::load_config;
+use netbricks::config::{load_nf_config, NfConfig};
 use netbricks::interface::{PacketRx, PacketTx};
 use netbricks::operators::{Batch, ReceiveBatch};
 use netbricks::packets::ip::v4::Ipv4;
@@ -19,12 +23,16 @@ use std::sync::atomic::{AtomicU16, Ordering};
 // use std::io::stdout;
 // use std::io::Write;
 use std::cell::RefCell;
-use netbricks::scheduler::Scheduler;
-use netbricks::scheduler::{initialize_system, PKT_NUM};
+use netbricks::scheduler::{Scheduler, StandaloneScheduler};
+use netbricks::scheduler::initialize_system;
 use std::sync::Arc;
+use std::time::Duration;
 
 // const MIN_PORT: u16 = 1024;
 const MAX_PORT: u16 = 65535;
+/// The NAT state of the enclave, as sgx-runner keeps it, see `checkpoint::enclave_name`.
+const CHECKPOINT: &str = "nat-tcp-v4";
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(10);
 
 type FnvHash = BuildHasherDefault<FnvHasher>;
 
@@ -49,7 +57,32 @@ lazy_static! {
 #[derive(Clone, Default)]
 struct Unit;
 
-#[derive(Clone, Copy)]
+/// Parameters of the NAT, its `[nf]` table.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+struct NatConfig {
+    /// The address outgoing flows are rewritten to come from.
+    nat_ip: Ipv4Addr,
+}
+
+impl Default for NatConfig {
+    fn default() -> NatConfig {
+        NatConfig {
+            nat_ip: Ipv4Addr::new(10, 0, 0, 1),
+        }
+    }
+}
+
+impl NfConfig for NatConfig {
+    fn validate(&self) -> std::result::Result<(), String> {
+        if self.nat_ip.is_unspecified() {
+            return Err("nat_ip must not be 0.0.0.0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct FlowUsed {
     pub flow: Flow,
     pub time: u64,
@@ -86,18 +119,23 @@ impl Default for FlowUsed {
     }
 }
 
-fn install<T, S>(ports: Vec<T>, sched: &mut S)
+fn install<T, S>(ports: Vec<T>, sched: &mut S, nat_ip: Ipv4Addr, checkpoint: &str)
 where
     T: PacketRx + PacketTx + Display + Clone + 'static,
     S: Scheduler + Sized,
 {
     println!("Receiving started");
 
+    // carry on with the flows of the enclave this one replaces, if the runner kept them.
+    if checkpoint::resume(sched, checkpoint, CHECKPOINT_INTERVAL) {
+        resume_ports();
+    }
+
     let pipelines: Vec<_> = ports
         .iter()
         .map(move |port| {
             ReceiveBatch::new(port.clone())
-                .map(|p| nat(p, Ipv4Addr::new(10, 0, 0, 1)))
+                .map(move |p| nat(p, nat_ip))
                 .sendall(port.clone())
         })
         .collect();
@@ -108,6 +146,15 @@ where
     }
 }
 
+/// Hands out ports after the ones the restored flows use.
+fn resume_ports() {
+    FLOW_VEC.with(|flow_vec| {
+        if let Some(last) = flow_vec.borrow().iter().rposition(|flow| flow.used) {
+            NEXT_PORT.store((last as u16).saturating_add(1), Ordering::Relaxed);
+        }
+    });
+}
+
 fn nat(packet: RawPacket, nat_ip: Ipv4Addr) -> Result<Tcp<Ipv4>> {
     // print!("-4");stdout().flush();
     let mut ethernet = packet.parse::<Ethernet>()?;
@@ -157,9 +204,17 @@ fn nat(packet: RawPacket, nat_ip: Ipv4Addr) -> Result<Tcp<Ipv4>> {
 }
 
 fn main() -> Result<()> {
-    let configuration = load_config()?;
+    checkpoint::register("port_map", &PORT_MAP);
+    checkpoint::register("flow_vec", &FLOW_VEC);
+    let (configuration, nat_config) = load_nf_config::<NatConfig>()?;
     println!("{}", configuration);
     let mut context = initialize_system(&configuration)?;
-    context.run(Arc::new(install), PKT_NUM); // will trap in the run() and return after finish
+    let nat_ip = nat_config.nat_ip;
+    let checkpoint = checkpoint::enclave_name(CHECKPOINT, &context.rx_queues);
+    // will trap in the run() until the rings stop or the duration is up
+    context.run(
+        Arc::new(move |ports, sched: &mut StandaloneScheduler| install(ports, sched, nat_ip, &checkpoint)),
+        0,
+    );
     Ok(())
 }
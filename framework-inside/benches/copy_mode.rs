@@ -0,0 +1,48 @@
+//! Zero-copy vs copy-in/copy-out `SimulateQueue`s, over a loopback backend so only the cost
+//! of the queue itself is measured. Run with `cargo bench --bench copy_mode`.
+#![feature(test)]
+extern crate netbricks;
+extern crate test;
+
+use netbricks::interface::{LoopbackBackend, PacketRx, PacketTx, SimulateQueue};
+use netbricks::native::mbuf::MBuf;
+use netbricks::operators::BATCH_SIZE;
+use std::sync::Arc;
+use test::Bencher;
+
+/// Receives and sends back one batch of `frame_len` byte packets per iteration.
+fn round_trip(b: &mut Bencher, copy: bool, frame_len: usize) {
+    let loopback = LoopbackBackend::new(BATCH_SIZE);
+    let queue = SimulateQueue::with_copy(Arc::new(loopback.clone()), copy);
+    let frame = vec![0xa5u8; frame_len];
+    for _ in 0..BATCH_SIZE {
+        loopback.inject(&frame);
+    }
+    let mut pkts = vec![0 as *mut MBuf; BATCH_SIZE];
+    b.bytes = (BATCH_SIZE * frame_len) as u64;
+    b.iter(|| {
+        let received = queue.recv(&mut pkts).unwrap() as usize;
+        queue.send(&mut pkts[..received]).unwrap()
+    });
+    loopback.drain_frames();
+}
+
+#[bench]
+fn zero_copy_64(b: &mut Bencher) {
+    round_trip(b, false, 64);
+}
+
+#[bench]
+fn copy_64(b: &mut Bencher) {
+    round_trip(b, true, 64);
+}
+
+#[bench]
+fn zero_copy_1500(b: &mut Bencher) {
+    round_trip(b, false, 1500);
+}
+
+#[bench]
+fn copy_1500(b: &mut Bencher) {
+    round_trip(b, true, 1500);
+}
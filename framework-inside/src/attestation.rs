@@ -0,0 +1,312 @@
+//! Attestation handshake the enclave runs before the host hands it any ring.
+//!
+//! sgx-runner drives it over the connection it opens on localhost:6010, one line per message,
+//! binary fields in hex:
+//!
+//!    runner:  challenge <nonce> <target info>
+//!    enclave: evidence <report> <public key>
+//!    runner:  accept <public key>                    or: reject <reason>
+//!    runner:  rings <recvq> <sendq> <mbufq> <freeq> <ring> <tag>
+//!
+//! The enclave generates an ephemeral P-256 key and binds it to the challenge through the
+//! report data, SHA-256(nonce || public key). The runner has the report quoted and verified and
+//! only then answers with an ephemeral key of its own. Both sides hash the ECDH secret with the
+//! nonce into a session key, and every later message ends with an HMAC-SHA256 tag under it.
+//! `<ring>` numbers the rings among those of all the runner's enclaves.
+//!
+//! The runner then relays the same handshake with the key server, which the runner is not
+//! trusted with the keys of, filling in the target info and quoting the report on the way:
+//!
+//!    key server: challenge <nonce>                   runner adds <target info>
+//!    enclave:    evidence <report> <public key>      runner sends the quote instead
+//!    key server: accept <public key> <signature>     or: reject <reason>
+//!    key server: sealed <ciphertext> <tag>           (secrets, see `provisioning`)
+//!    enclave:    sealed <ciphertext> <tag>           (counter requests, see `provisioning`)
+//!
+//! The key server checks the quote itself and signs SHA-256(nonce || enclave key || its key)
+//! with its identity key, which the enclave checks if it has the key pinned. Sealed messages
+//! are encrypted whole with AES-256-GCM under a channel key derived from the session key, with
+//! a message counter as nonce, so the runner only passes ciphertext on. The enclave seals its
+//! own messages to the key server under a second key, the return key, derived the same way.
+use common::*;
+use failure::Fail;
+use hex;
+use mbedtls::cipher::raw::{Cipher, CipherId, CipherMode, Operation};
+use mbedtls::hash::{Md, Type};
+use mbedtls::pk::{EcGroupId, Pk};
+use mbedtls::rng::Rdrand;
+use protocol::attestation::{
+    seal_nonce, CHANNEL_LABEL, MRENCLAVE_OFFSET, REPORTDATA_OFFSET, REPORT_BODY_LEN, RETURN_LABEL, SEAL_AD, SEAL_TAG_LEN,
+    TAG_LEN,
+};
+#[cfg(target_env = "sgx")]
+use sgx_isa::{Report, Targetinfo};
+use std::io::{BufRead, BufReader, Read, Write};
+
+pub use protocol::attestation::{MOCK_REPORT_KEY, NONCE_LEN, REPORT_LEN, TARGETINFO_LEN};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Attestation failed: {}", _0)]
+pub struct AttestationError(pub String);
+
+fn bad<T>(why: String) -> Result<T> {
+    Err(AttestationError(why).into())
+}
+
+/// Keys shared with sgx-runner or the key server once it has accepted our evidence.
+pub struct Session {
+    key: [u8; 32],
+    channel_key: [u8; 32],
+    return_key: [u8; 32],
+    /// Sealed messages opened so far, which numbers the next one.
+    opened: u64,
+    /// Sealed messages sent so far, which numbers the next one.
+    sealed: u64,
+}
+
+impl Session {
+    fn from_key(key: [u8; 32]) -> Result<Session> {
+        let mut channel_key = [0u8; 32];
+        Md::hmac(Type::Sha256, &key, CHANNEL_LABEL, &mut channel_key)?;
+        let mut return_key = [0u8; 32];
+        Md::hmac(Type::Sha256, &key, RETURN_LABEL, &mut return_key)?;
+        Ok(Session {
+            key,
+            channel_key,
+            return_key,
+            opened: 0,
+            sealed: 0,
+        })
+    }
+
+    /// Derives the session key from our ephemeral key, the runner's and the challenge nonce.
+    fn agree(own: &mut Pk, peer: &Pk, nonce: &[u8]) -> Result<Session> {
+        let mut shared = [0u8; 66];
+        let len = own.agree(peer, &mut shared, &mut Rdrand)?;
+        let mut material = shared[..len].to_vec();
+        material.extend_from_slice(nonce);
+        let mut key = [0u8; 32];
+        Md::hash(Type::Sha256, &material, &mut key)?;
+        Session::from_key(key)
+    }
+
+    /// HMAC-SHA256 of `message` under the session key.
+    pub fn tag(&self, message: &[u8]) -> [u8; TAG_LEN] {
+        let mut tag = [0u8; TAG_LEN];
+        Md::hmac(Type::Sha256, &self.key, message, &mut tag).unwrap();
+        tag
+    }
+
+    /// Reads a `verb` message and checks its tag, which covers the whole line up to it.
+    /// Returns the fields between the verb and the tag.
+    pub fn read_message<S: Read>(&self, stream: &mut BufReader<S>, verb: &str) -> Result<Vec<String>> {
+        let mut fields = read_message(stream, verb)?;
+        let tag = match fields.pop().map(|tag| hex::decode(&tag)) {
+            Some(Ok(tag)) => tag,
+            _ => return bad(format!("{} message without a tag", verb)),
+        };
+        let mut signed = verb.to_string();
+        for field in &fields {
+            signed.push(' ');
+            signed.push_str(field);
+        }
+        let expected = self.tag(signed.as_bytes());
+        // compare in constant time.
+        if tag.len() != TAG_LEN || tag.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+            return bad(format!("bad tag on the {} message", verb));
+        }
+        Ok(fields)
+    }
+
+    /// Reads a sealed message and returns the fields of the line it decrypts to, verb first.
+    /// Sealed messages have to be opened in the order they were sent.
+    pub fn open_message<S: Read>(&mut self, stream: &mut BufReader<S>) -> Result<Vec<String>> {
+        let sealed = read_message(stream, "sealed")?;
+        let ciphertext = decode_field(&sealed, 0, "sealed message", None)?;
+        let tag = decode_field(&sealed, 1, "seal", Some(SEAL_TAG_LEN))?;
+        let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 256)?;
+        cipher.set_key(Operation::Decrypt, &self.channel_key)?;
+        cipher.set_iv(&seal_nonce(self.opened))?;
+        let mut plain = vec![0u8; ciphertext.len()];
+        if cipher.decrypt_auth(SEAL_AD, &ciphertext, &mut plain, &tag).is_err() {
+            return bad(format!("sealed message {} does not open", self.opened));
+        }
+        self.opened += 1;
+        match String::from_utf8(plain) {
+            Ok(line) => Ok(line.split_whitespace().map(String::from).collect()),
+            Err(_) => bad("sealed message is not text".to_string()),
+        }
+    }
+
+    /// Sends `verb` and `fields` encrypted under the return key, which only the key server
+    /// can open.
+    pub fn seal_message<W: Write>(&mut self, stream: &mut W, verb: &str, fields: &[String]) -> Result<()> {
+        let mut line = verb.to_string();
+        for field in fields {
+            line.push(' ');
+            line.push_str(field);
+        }
+        let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 256)?;
+        cipher.set_key(Operation::Encrypt, &self.return_key)?;
+        cipher.set_iv(&seal_nonce(self.sealed))?;
+        let mut ciphertext = vec![0u8; line.len()];
+        let mut tag = [0u8; SEAL_TAG_LEN];
+        cipher.encrypt_auth(SEAL_AD, line.as_bytes(), &mut ciphertext, &mut tag)?;
+        self.sealed += 1;
+        writeln!(stream, "sealed {} {}", hex::encode(&ciphertext), hex::encode(&tag))?;
+        stream.flush()?;
+        Ok(())
+    }
+}
+
+/// Reads one line, which must start with `verb`, and returns the fields after it.
+fn read_message<S: Read>(stream: &mut BufReader<S>, verb: &str) -> Result<Vec<String>> {
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let mut fields = line.split_whitespace().map(String::from);
+    match fields.next() {
+        Some(ref v) if v == verb => Ok(fields.collect()),
+        Some(ref v) if v == "reject" => bad(format!("rejected by the runner: {}", fields.collect::<Vec<_>>().join(" "))),
+        _ => bad(format!("expected a {} message, got {:?}", verb, line.trim())),
+    }
+}
+
+fn decode_field(fields: &[String], index: usize, name: &str, len: Option<usize>) -> Result<Vec<u8>> {
+    let bytes = match fields.get(index).map(|field| hex::decode(field)) {
+        Some(Ok(bytes)) => bytes,
+        _ => return bad(format!("missing or malformed {}", name)),
+    };
+    match len {
+        Some(len) if bytes.len() != len => bad(format!("{} is {} bytes, expected {}", name, bytes.len(), len)),
+        _ => Ok(bytes),
+    }
+}
+
+/// SHA-256(nonce || public key), the first half of the report data.
+pub fn binding(nonce: &[u8], public_key: &[u8]) -> Result<[u8; 32]> {
+    let mut material = nonce.to_vec();
+    material.extend_from_slice(public_key);
+    let mut hash = [0u8; 32];
+    Md::hash(Type::Sha256, &material, &mut hash)?;
+    Ok(hash)
+}
+
+/// A report for the quoting enclave `targetinfo` describes, carrying `reportdata`.
+#[cfg(target_env = "sgx")]
+fn report(targetinfo: &[u8], reportdata: &[u8; 64]) -> Result<Vec<u8>> {
+    let targetinfo = match Targetinfo::try_copy_from(targetinfo) {
+        Some(targetinfo) => targetinfo,
+        None => return bad("malformed target info".to_string()),
+    };
+    let report = Report::for_target(&targetinfo, reportdata);
+    let bytes: &[u8] = report.as_ref();
+    Ok(bytes.to_vec())
+}
+
+/// Outside an enclave nothing can produce a report: make one of the same layout with a zero
+/// measurement, MACed with `MOCK_REPORT_KEY` the way EREPORT MACs with the report key.
+#[cfg(not(target_env = "sgx"))]
+fn report(_targetinfo: &[u8], reportdata: &[u8; 64]) -> Result<Vec<u8>> {
+    let mut report = vec![0u8; REPORT_LEN];
+    report[REPORTDATA_OFFSET..REPORT_BODY_LEN].copy_from_slice(reportdata);
+    let mut mac = [0u8; 32];
+    Md::hmac(Type::Sha256, MOCK_REPORT_KEY, &report[..REPORT_BODY_LEN], &mut mac)?;
+    report[REPORT_LEN - 16..].copy_from_slice(&mac[..16]);
+    Ok(report)
+}
+
+/// The measurement (MRENCLAVE) recorded in `report`.
+pub fn mrenclave(report: &[u8]) -> &[u8] {
+    &report[MRENCLAVE_OFFSET..MRENCLAVE_OFFSET + 32]
+}
+
+/// Answers the runner's challenge with a report bound to a fresh key and waits for it to
+/// accept. Fails if the runner rejects the evidence or the exchange is malformed.
+pub fn respond<S: Read + Write>(stream: &mut BufReader<S>) -> Result<Session> {
+    answer(stream, None)
+}
+
+/// Answers the key server's challenge the runner relays the same way. The key server must have
+/// signed its answer with `key_server`, which the runner does not have; without one pinned the
+/// enclave refuses, unless built with the `unpinned-key-server` feature.
+pub fn respond_to_key_server<S: Read + Write>(stream: &mut BufReader<S>, key_server: Option<&[u8]>) -> Result<Session> {
+    if key_server.is_none() {
+        if !cfg!(feature = "unpinned-key-server") {
+            return bad("no key server pinned, not taking keys from the runner".to_string());
+        }
+        warn!("no key server pinned, taking keys from whoever the runner relays");
+    }
+    answer(stream, key_server)
+}
+
+/// SHA-256(nonce || enclave key || key server key), which the key server signs.
+pub fn accept_digest(nonce: &[u8], own_key: &[u8], peer_key: &[u8]) -> Result<[u8; 32]> {
+    let mut material = nonce.to_vec();
+    material.extend_from_slice(own_key);
+    material.extend_from_slice(peer_key);
+    let mut digest = [0u8; 32];
+    Md::hash(Type::Sha256, &material, &mut digest)?;
+    Ok(digest)
+}
+
+fn answer<S: Read + Write>(stream: &mut BufReader<S>, signer: Option<&[u8]>) -> Result<Session> {
+    let challenge = read_message(stream, "challenge")?;
+    let nonce = decode_field(&challenge, 0, "nonce", Some(NONCE_LEN))?;
+    let targetinfo = decode_field(&challenge, 1, "target info", Some(TARGETINFO_LEN))?;
+
+    let mut key = Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1)?;
+    let public_key = key.write_public_der_vec()?;
+    let mut reportdata = [0u8; 64];
+    reportdata[..32].copy_from_slice(&binding(&nonce, &public_key)?);
+    let report = report(&targetinfo, &reportdata)?;
+    {
+        let out = stream.get_mut();
+        writeln!(out, "evidence {} {}", hex::encode(&report), hex::encode(&public_key))?;
+        out.flush()?;
+    }
+
+    let accept = read_message(stream, "accept")?;
+    let peer_key = decode_field(&accept, 0, "peer key", None)?;
+    if let Some(signer) = signer {
+        let signature = decode_field(&accept, 1, "signature", None)?;
+        let digest = accept_digest(&nonce, &public_key, &peer_key)?;
+        if Pk::from_public_key(signer)?.verify(Type::Sha256, &digest, &signature).is_err() {
+            return bad("accept is not signed by the key server".to_string());
+        }
+    }
+    let peer = Pk::from_public_key(&peer_key)?;
+    Session::agree(&mut key, &peer, &nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_report_carries_reportdata() {
+        let mut reportdata = [0u8; 64];
+        reportdata[..32].copy_from_slice(&binding(&[1; NONCE_LEN], b"public key").unwrap());
+        let report = report(&[0; TARGETINFO_LEN], &reportdata).unwrap();
+        assert_eq!(report.len(), REPORT_LEN);
+        assert_eq!(&report[REPORTDATA_OFFSET..REPORT_BODY_LEN], &reportdata[..]);
+        assert_eq!(mrenclave(&report), &[0u8; 32][..]);
+
+        let mut mac = [0u8; 32];
+        Md::hmac(Type::Sha256, MOCK_REPORT_KEY, &report[..REPORT_BODY_LEN], &mut mac).unwrap();
+        assert_eq!(&report[REPORT_LEN - 16..], &mac[..16]);
+    }
+
+    #[test]
+    fn tagged_messages() {
+        let session = Session::from_key([7; 32]).unwrap();
+        let tag = hex::encode(&session.tag(b"rings 1 2"));
+        let line = format!("rings 1 2 {}\n", tag);
+        let mut stream = BufReader::new(line.as_bytes());
+        assert_eq!(session.read_message(&mut stream, "rings").unwrap(), vec!["1", "2"]);
+
+        let forged = format!("rings 1 3 {}\n", tag);
+        assert!(session.read_message(&mut BufReader::new(forged.as_bytes()), "rings").is_err());
+        let rejected = "reject unknown measurement\n";
+        assert!(read_message(&mut BufReader::new(rejected.as_bytes()), "accept").is_err());
+    }
+}
@@ -0,0 +1,482 @@
+//! Checkpoints of NF state that outlive the enclave.
+//!
+//! NFs keep their flow state, such as the port map of a NAT, in `thread_local!` maps, which
+//! go away whenever the enclave restarts. `register` names such a map; `save` serializes the
+//! registered maps of the calling thread, seals them and hands them to sgx-runner to keep, and
+//! `restore` puts them back after a restart. Checkpoints are sealed with the seal key of the
+//! enclave signer (MRSIGNER) rather than of the measurement, so that an upgraded NF, signed
+//! with the same key, can open the checkpoints of the one it replaces.
+//!
+//! sgx-runner keeps the checkpoints on the host: its `storage` usercall service, which a
+//! `Client<StorageService>` is the `Storage` of. The host could roll NF state back by handing out
+//! an older checkpoint, so each checkpoint also has a monotonic counter, kept by a
+//! `TrustedCounter` the host cannot set back: the key server's, which
+//! `provisioning::trusted_counter` reaches over the attested session the enclave got its keys
+//! on. `save` increments the counter before sealing the checkpoint with the value it got, and
+//! `restore` refuses any checkpoint but the one for the current value. If the enclave dies
+//! between the two, no checkpoint is valid any more: the state is lost rather than rolled back.
+use allocators::CacheAligned;
+use bincode;
+use common::*;
+use failure::Fail;
+use mbedtls::cipher::raw::{Cipher, CipherId, CipherMode, Operation};
+#[cfg(not(target_env = "sgx"))]
+use mbedtls::hash::{Md, Type};
+use mbedtls::rng::{Random, Rdrand};
+use interface::SimulateQueue;
+use provisioning;
+use scheduler::{Executable, Scheduler};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+#[cfg(target_env = "sgx")]
+use sgx_isa::{Keyname, Keypolicy, Keyrequest, Report};
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::sync::RwLock;
+use std::thread::LocalKey;
+use std::time::{Duration, Instant};
+use usercalls::{Client, Service, StorageRequest, StorageResponse, StorageService, UsercallError};
+
+/// Starts every checkpoint, and changes with its layout.
+const MAGIC: &[u8; 8] = b"SBCKPT01";
+const KEYID_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Magic, ISVSVN, CPUSVN, key ID, counter and nonce.
+const HEADER_LEN: usize = 8 + 2 + 16 + KEYID_LEN + 8 + NONCE_LEN;
+
+/// Key the checkpoints made outside an enclave are sealed with. Only good for tests: anyone
+/// can open them.
+#[cfg(not(target_env = "sgx"))]
+pub const MOCK_SEAL_KEY: &[u8] = b"safebricks mock seal key";
+
+#[derive(Debug, Fail)]
+pub enum CheckpointError {
+    #[fail(display = "Checkpoint {} is malformed: {}", _0, _1)]
+    Malformed(String, &'static str),
+    /// Not sealed by an enclave of our signer, or tampered with.
+    #[fail(display = "Checkpoint {} does not open", _0)]
+    Unsealed(String),
+    /// Saved at another value of the counter than its current one: an older checkpoint, or
+    /// none where there should be one.
+    #[fail(display = "Checkpoint {} is from count {:?}, the counter is at {}", _0, _1, _2)]
+    Stale(String, Option<u64>, u64),
+    #[fail(display = "Checkpoint storage failed: {}", _0)]
+    Storage(String),
+}
+
+/// Where checkpoints are kept. Untrusted: anything it hands back is checked.
+pub trait Storage {
+    /// The checkpoint `name`, if there is one.
+    fn load(&mut self, name: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Keeps `blob` as the checkpoint `name`, replacing the one before.
+    fn store(&mut self, name: &str, blob: &[u8]) -> Result<()>;
+}
+
+/// A monotonic counter for each checkpoint, kept out of reach of the host that keeps the
+/// checkpoints.
+pub trait TrustedCounter {
+    /// The value of the counter of checkpoint `name`, 0 until first incremented.
+    fn counter(&mut self, name: &str) -> Result<u64>;
+
+    /// Increments the counter of checkpoint `name` and returns its new value.
+    fn increment(&mut self, name: &str) -> Result<u64>;
+}
+
+/// The checkpoint storage of sgx-runner.
+impl<S: Read + Write> Storage for Client<StorageService, S> {
+    fn load(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.call(&StorageRequest::Load(name.to_string()))? {
+            StorageResponse::Blob(blob) => Ok(blob),
+            _ => Err(UsercallError::UnexpectedResponse(StorageService::NAME).into()),
+        }
+    }
+
+    fn store(&mut self, name: &str, blob: &[u8]) -> Result<()> {
+        match self.call(&StorageRequest::Store(name.to_string(), blob.to_vec()))? {
+            StorageResponse::Stored => Ok(()),
+            _ => Err(UsercallError::UnexpectedResponse(StorageService::NAME).into()),
+        }
+    }
+}
+
+/// Serializes a piece of state of the calling thread.
+type SaveFn = Box<Fn() -> Result<Vec<u8>> + Send + Sync>;
+/// Replaces a piece of state of the calling thread.
+type RestoreFn = Box<Fn(&[u8]) -> Result<()> + Send + Sync>;
+
+/// A registered piece of NF state.
+struct Entry {
+    name: &'static str,
+    save: SaveFn,
+    restore: RestoreFn,
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<Vec<Entry>> = RwLock::new(Vec::new());
+}
+
+/// Adds the thread-local `state` to the checkpoints as `name`, replacing whatever was
+/// registered as `name` before. Each thread saves and restores its own instance.
+pub fn register<T>(name: &'static str, state: &'static LocalKey<RefCell<T>>)
+where
+    T: Serialize + DeserializeOwned + 'static,
+{
+    let entry = Entry {
+        name,
+        save: Box::new(move || state.with(|state| Ok(bincode::serialize(&*state.borrow())?))),
+        restore: Box::new(move |bytes| {
+            let restored = bincode::deserialize::<T>(bytes)?;
+            state.with(|state| *state.borrow_mut() = restored);
+            Ok(())
+        }),
+    };
+    let mut registry = REGISTRY.write().unwrap();
+    registry.retain(|entry| entry.name != name);
+    registry.push(entry);
+}
+
+/// Checkpoint names go into the runner's file names.
+fn check_name(name: &str) -> Result<()> {
+    let fits = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    if name.is_empty() || name.starts_with('.') || !name.chars().all(fits) {
+        return Err(CheckpointError::Malformed(name.to_string(), "names are letters, digits, '-', '_' and '.'").into());
+    }
+    Ok(())
+}
+
+/// The security versions the seal key of a new checkpoint is derived for: our own.
+#[cfg(target_env = "sgx")]
+fn own_svn() -> (u16, [u8; 16]) {
+    let report = Report::for_self();
+    (report.isvsvn, report.cpusvn)
+}
+
+#[cfg(not(target_env = "sgx"))]
+fn own_svn() -> (u16, [u8; 16]) {
+    (0, [0; 16])
+}
+
+/// The seal key of our signer for `keyid`, at security versions no newer than ours.
+#[cfg(target_env = "sgx")]
+fn seal_key(isvsvn: u16, cpusvn: &[u8; 16], keyid: &[u8; KEYID_LEN]) -> Result<[u8; 16]> {
+    let request = Keyrequest {
+        keyname: Keyname::Seal as u16,
+        keypolicy: Keypolicy::MRSIGNER,
+        isvsvn,
+        _reserved1: 0,
+        cpusvn: *cpusvn,
+        // INIT, DEBUG and MODE64BIT, and the reserved MISCSELECT bits, as the SGX SDK has it.
+        attributemask: [0xff00_0000_0000_000b, 0],
+        keyid: *keyid,
+        miscmask: 0xf000_0000,
+        _reserved2: [0; 436],
+    };
+    match request.egetkey() {
+        Ok(key) => Ok(key),
+        Err(e) => Err(CheckpointError::Storage(format!("no seal key: {:?}", e)).into()),
+    }
+}
+
+/// Outside an enclave there is no seal key: derive one from `MOCK_SEAL_KEY` the way EGETKEY
+/// derives one from the key ID and the security versions.
+#[cfg(not(target_env = "sgx"))]
+fn seal_key(isvsvn: u16, cpusvn: &[u8; 16], keyid: &[u8; KEYID_LEN]) -> Result<[u8; 16]> {
+    let mut material = isvsvn.to_be_bytes().to_vec();
+    material.extend_from_slice(cpusvn);
+    material.extend_from_slice(keyid);
+    let mut mac = [0u8; 32];
+    Md::hmac(Type::Sha256, MOCK_SEAL_KEY, &material, &mut mac)?;
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&mac[..16]);
+    Ok(key)
+}
+
+/// Additional data of checkpoint `name`: its name and header, which are not encrypted.
+fn additional_data(name: &str, header: &[u8]) -> Vec<u8> {
+    let mut ad = name.as_bytes().to_vec();
+    ad.push(0);
+    ad.extend_from_slice(header);
+    ad
+}
+
+/// Seals `payload` as checkpoint `name` at count `counter`.
+fn seal(name: &str, counter: u64, payload: &[u8]) -> Result<Vec<u8>> {
+    let (isvsvn, cpusvn) = own_svn();
+    // a fresh key for every checkpoint, so the nonce never repeats under one.
+    let mut keyid = [0u8; KEYID_LEN];
+    Rdrand.random(&mut keyid)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    Rdrand.random(&mut nonce)?;
+
+    let mut blob = MAGIC.to_vec();
+    blob.extend_from_slice(&isvsvn.to_be_bytes());
+    blob.extend_from_slice(&cpusvn);
+    blob.extend_from_slice(&keyid);
+    blob.extend_from_slice(&counter.to_be_bytes());
+    blob.extend_from_slice(&nonce);
+    let ad = additional_data(name, &blob);
+
+    let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 128)?;
+    cipher.set_key(Operation::Encrypt, &seal_key(isvsvn, &cpusvn, &keyid)?)?;
+    cipher.set_iv(&nonce)?;
+    let mut ciphertext = vec![0u8; payload.len()];
+    let mut tag = [0u8; TAG_LEN];
+    cipher.encrypt_auth(&ad, payload, &mut ciphertext, &mut tag)?;
+    blob.extend_from_slice(&ciphertext);
+    blob.extend_from_slice(&tag);
+    Ok(blob)
+}
+
+/// Opens checkpoint `name`. Returns the count it was saved at and its payload.
+fn unseal(name: &str, blob: &[u8]) -> Result<(u64, Vec<u8>)> {
+    let malformed = |why| CheckpointError::Malformed(name.to_string(), why).into();
+    if blob.len() < HEADER_LEN + TAG_LEN {
+        return Err(malformed("too short"));
+    }
+    if &blob[..8] != MAGIC {
+        return Err(malformed("not a checkpoint, or of another version"));
+    }
+    let (header, rest) = blob.split_at(HEADER_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let isvsvn = u16::from_be_bytes([header[8], header[9]]);
+    let mut cpusvn = [0u8; 16];
+    cpusvn.copy_from_slice(&header[10..26]);
+    let mut keyid = [0u8; KEYID_LEN];
+    keyid.copy_from_slice(&header[26..(26 + KEYID_LEN)]);
+    let mut counter = [0u8; 8];
+    counter.copy_from_slice(&header[(26 + KEYID_LEN)..(34 + KEYID_LEN)]);
+    let nonce = &header[(34 + KEYID_LEN)..];
+
+    // an enclave of a newer version than ours sealed it, or the host made the versions up.
+    let key = match seal_key(isvsvn, &cpusvn, &keyid) {
+        Ok(key) => key,
+        Err(_) => return Err(CheckpointError::Unsealed(name.to_string()).into()),
+    };
+    let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 128)?;
+    cipher.set_key(Operation::Decrypt, &key)?;
+    cipher.set_iv(nonce)?;
+    let mut payload = vec![0u8; ciphertext.len()];
+    if cipher.decrypt_auth(&additional_data(name, header), ciphertext, &mut payload, tag).is_err() {
+        return Err(CheckpointError::Unsealed(name.to_string()).into());
+    }
+    Ok((u64::from_be_bytes(counter), payload))
+}
+
+/// Saves the registered state of the calling thread to `storage` as checkpoint `name`, at the
+/// next value of its counter in `counter`. Returns how many pieces of state it saved.
+pub fn save<S: Storage, C: TrustedCounter>(storage: &mut S, counter: &mut C, name: &str) -> Result<usize> {
+    check_name(name)?;
+    let states = {
+        let registry = REGISTRY.read().unwrap();
+        let mut states = Vec::with_capacity(registry.len());
+        for entry in registry.iter() {
+            states.push((entry.name.to_string(), (entry.save)()?));
+        }
+        states
+    };
+    let payload = bincode::serialize(&states)?;
+    // the counter goes first: a checkpoint stored at the old count would be valid forever.
+    let count = counter.increment(name)?;
+    storage.store(name, &seal(name, count, &payload)?)?;
+    Ok(states.len())
+}
+
+/// Restores the registered state of the calling thread from checkpoint `name` in `storage`.
+/// Returns how many pieces of state it restored, or `None` if there has never been such a
+/// checkpoint. Fails, and restores nothing, if the checkpoint is not the latest `counter` has.
+pub fn restore<S: Storage, C: TrustedCounter>(storage: &mut S, counter: &mut C, name: &str) -> Result<Option<usize>> {
+    check_name(name)?;
+    let latest = counter.counter(name)?;
+    let blob = match storage.load(name)? {
+        Some(blob) => blob,
+        None if latest == 0 => return Ok(None),
+        None => return Err(CheckpointError::Stale(name.to_string(), None, latest).into()),
+    };
+    let (count, payload) = unseal(name, &blob)?;
+    if count != latest {
+        return Err(CheckpointError::Stale(name.to_string(), Some(count), latest).into());
+    }
+    let states = bincode::deserialize::<Vec<(String, Vec<u8>)>>(&payload)?;
+
+    let registry = REGISTRY.read().unwrap();
+    let mut restored = 0;
+    for (state, bytes) in states {
+        match registry.iter().find(|entry| entry.name == state) {
+            Some(entry) => {
+                (entry.restore)(&bytes)?;
+                restored += 1;
+            }
+            // an NF may well drop state in an upgrade.
+            None => info!("checkpoint {} has state {}, which nothing registered", name, state),
+        }
+    }
+    Ok(Some(restored))
+}
+
+/// `nf`, numbered after the first ring of `queues`: the name of the enclave's own checkpoint, as
+/// all enclaves of a runner share its storage and the key server's counters.
+pub fn enclave_name(nf: &str, queues: &[CacheAligned<SimulateQueue>]) -> String {
+    match queues.iter().filter_map(|queue| queue.ring()).min() {
+        Some(ring) => format!("{}-{}", nf, ring),
+        None => nf.to_string(),
+    }
+}
+
+/// Restores checkpoint `name` of the calling thread, which the enclave this one replaces saved,
+/// and has `sched` save it every `interval` from then on, with sgx-runner's storage and the key
+/// server's counters. Returns whether there was state to restore. Without storage or a key
+/// server the state lives as long as the enclave.
+pub fn resume<S: Scheduler>(sched: &mut S, name: &str, interval: Duration) -> bool {
+    let mut counter = match provisioning::trusted_counter() {
+        Some(counter) => counter,
+        None => {
+            warn!("no key server to count checkpoints, {} will not survive a restart", name);
+            return false;
+        }
+    };
+    let mut storage = match Client::<StorageService>::connect() {
+        Ok(storage) => storage,
+        Err(e) => {
+            warn!("no checkpoint storage, {} will not survive a restart: {}", name, e);
+            return false;
+        }
+    };
+    let restored = match restore(&mut storage, &mut counter, name) {
+        Ok(restored) => restored.is_some(),
+        Err(e) => {
+            warn!("starting without checkpoint {}: {}", name, e);
+            false
+        }
+    };
+    if let Err(e) = sched.add_task(CheckpointTask::new(storage, counter, name, interval)) {
+        warn_chain!(&e);
+    }
+    restored
+}
+
+/// Saves checkpoint `name` of the thread it runs on every `interval`, as a task of its
+/// scheduler.
+pub struct CheckpointTask<S: Storage, C: TrustedCounter> {
+    storage: S,
+    counter: C,
+    name: String,
+    interval: Duration,
+    last: Instant,
+}
+
+impl<S: Storage, C: TrustedCounter> CheckpointTask<S, C> {
+    pub fn new(storage: S, counter: C, name: &str, interval: Duration) -> CheckpointTask<S, C> {
+        CheckpointTask {
+            storage,
+            counter,
+            name: name.to_string(),
+            interval,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl<S: Storage, C: TrustedCounter> Executable for CheckpointTask<S, C> {
+    /// Handles no packets, so always returns 0.
+    fn execute(&mut self) -> usize {
+        if self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            if let Err(e) = save(&mut self.storage, &mut self.counter, &self.name) {
+                warn_chain!(&e);
+            }
+        }
+        0
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    thread_local! {
+        static PORTS: RefCell<HashMap<u16, (u32, u16)>> = RefCell::new(HashMap::new());
+    }
+
+    /// Storage and counters that keep everything in memory, and let tests meddle with it.
+    #[derive(Default)]
+    struct MemoryStorage {
+        blobs: HashMap<String, Vec<u8>>,
+    }
+
+    #[derive(Default)]
+    struct MemoryCounter {
+        counters: HashMap<String, u64>,
+    }
+
+    impl Storage for MemoryStorage {
+        fn load(&mut self, name: &str) -> Result<Option<Vec<u8>>> {
+            Ok(self.blobs.get(name).cloned())
+        }
+
+        fn store(&mut self, name: &str, blob: &[u8]) -> Result<()> {
+            self.blobs.insert(name.to_string(), blob.to_vec());
+            Ok(())
+        }
+    }
+
+    impl TrustedCounter for MemoryCounter {
+        fn counter(&mut self, name: &str) -> Result<u64> {
+            Ok(self.counters.get(name).cloned().unwrap_or(0))
+        }
+
+        fn increment(&mut self, name: &str) -> Result<u64> {
+            let counter = self.counters.entry(name.to_string()).or_insert(0);
+            *counter += 1;
+            Ok(*counter)
+        }
+    }
+
+    #[test]
+    fn state_survives_only_the_latest_checkpoint() {
+        register("test.ports", &PORTS);
+        let mut storage = MemoryStorage::default();
+        let mut counter = MemoryCounter::default();
+        assert_eq!(restore(&mut storage, &mut counter, "nat-0").unwrap(), None);
+
+        PORTS.with(|ports| ports.borrow_mut().insert(1024, (0x0a00_0001, 80)));
+        assert!(save(&mut storage, &mut counter, "nat-0").unwrap() >= 1);
+        let first = storage.blobs["nat-0"].clone();
+        PORTS.with(|ports| ports.borrow_mut().insert(1025, (0x0a00_0002, 443)));
+        save(&mut storage, &mut counter, "nat-0").unwrap();
+
+        // as after a restart.
+        PORTS.with(|ports| ports.borrow_mut().clear());
+        assert!(restore(&mut storage, &mut counter, "nat-0").unwrap().unwrap() >= 1);
+        PORTS.with(|ports| assert_eq!(ports.borrow().get(&1025), Some(&(0x0a00_0002, 443))));
+
+        // the host hands out the first checkpoint again.
+        storage.blobs.insert("nat-0".to_string(), first);
+        match restore(&mut storage, &mut counter, "nat-0") {
+            Err(e) => assert!(e.to_string().contains("from count Some(1), the counter is at 2")),
+            Ok(_) => panic!("stale checkpoint restored"),
+        }
+        storage.blobs.remove("nat-0");
+        assert!(restore(&mut storage, &mut counter, "nat-0").is_err());
+
+        // checkpoints are bound to their name.
+        save(&mut storage, &mut counter, "nat-0").unwrap();
+        let blob = storage.blobs["nat-0"].clone();
+        storage.blobs.insert("nat-1".to_string(), blob.clone());
+        counter.counters.insert("nat-1".to_string(), counter.counters["nat-0"]);
+        assert!(restore(&mut storage, &mut counter, "nat-1").unwrap_err().to_string().contains("does not open"));
+
+        let mut tampered = blob;
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        storage.blobs.insert("nat-0".to_string(), tampered);
+        assert!(restore(&mut storage, &mut counter, "nat-0").unwrap_err().to_string().contains("does not open"));
+        assert!(save(&mut storage, &mut counter, "../nat-0").is_err());
+    }
+}
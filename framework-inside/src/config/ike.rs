@@ -0,0 +1,145 @@
+use hex;
+use ike::{IkeConfig, Identity, TrafficSelector};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+/// The IKE responder an NF runs, its `[ike]` table; see `ike::IkeTask`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct IkeConfiguration {
+    /// Index of the rx queue IKE requests come in on, counting the queues of all ports in
+    /// order. The queue runs nothing but the responder; dpdkIO steers UDP port 500 to it,
+    /// and nothing else.
+    pub queue: usize,
+    /// Our identity: an IP address, or else a domain name.
+    pub id: String,
+    /// Pre-shared key peers may authenticate with.
+    pub psk: Option<String>,
+    /// Hex DER of our certificate and of its private key, to sign with.
+    pub certificate: Option<String>,
+    pub key: Option<String>,
+    /// Hex DER of the CA that issues the certificates of peers that sign.
+    pub ca: Option<String>,
+    /// What traffic child SAs may carry, first match first.
+    pub policies: Vec<IkePolicy>,
+    /// Half-open IKE SAs past which initiators have to return a cookie, past which their
+    /// requests are dropped, and seconds one may stay half-open.
+    pub cookie_threshold: usize,
+    pub half_open_limit: usize,
+    pub half_open_timeout: u64,
+}
+
+/// Traffic between `remote`, behind peers, and `local`, behind us, as IPv4 prefixes.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct IkePolicy {
+    pub remote: String,
+    pub local: String,
+}
+
+impl Default for IkeConfiguration {
+    fn default() -> IkeConfiguration {
+        IkeConfiguration {
+            queue: 0,
+            id: String::new(),
+            psk: None,
+            certificate: None,
+            key: None,
+            ca: None,
+            policies: Vec::new(),
+            cookie_threshold: 64,
+            half_open_limit: 1024,
+            half_open_timeout: 30,
+        }
+    }
+}
+
+/// Any traffic of the prefix `cidr`.
+fn selector(cidr: &str) -> Result<TrafficSelector, String> {
+    let invalid = || format!("{} is not an IPv4 prefix", cidr);
+    let mut parts = cidr.splitn(2, '/');
+    let address: Ipv4Addr = parts.next().and_then(|address| address.parse().ok()).ok_or_else(invalid)?;
+    let length: u32 = parts.next().and_then(|length| length.parse().ok()).ok_or_else(invalid)?;
+    if length > 32 {
+        return Err(invalid());
+    }
+    let host_bits = u32::max_value().checked_shr(length).unwrap_or(0);
+    let start = u32::from(address) & !host_bits;
+    Ok(TrafficSelector::range(
+        IpAddr::V4(Ipv4Addr::from(start)),
+        IpAddr::V4(Ipv4Addr::from(start | host_bits)),
+    ))
+}
+
+fn der(what: &str, value: &Option<String>) -> Result<Option<Vec<u8>>, String> {
+    match *value {
+        Some(ref value) => hex::decode(value).map(Some).map_err(|e| format!("{}: {}", what, e)),
+        None => Ok(None),
+    }
+}
+
+impl IkeConfiguration {
+    /// The configuration of the responder, or what is wrong with it.
+    pub fn ike_config(&self) -> Result<IkeConfig, String> {
+        let id = match self.id.parse::<IpAddr>() {
+            Ok(address) => Identity::address(address),
+            Err(_) if !self.id.is_empty() => Identity::fqdn(&self.id),
+            Err(_) => return Err("no id".to_string()),
+        };
+        let mut config = IkeConfig::new(id).with_half_open(
+            self.cookie_threshold,
+            self.half_open_limit,
+            Duration::from_secs(self.half_open_timeout),
+        );
+        if let Some(ref psk) = self.psk {
+            config = config.with_psk(psk.as_bytes());
+        }
+        match (der("certificate", &self.certificate)?, der("key", &self.key)?) {
+            (Some(certificate), Some(key)) => config = config.with_certificate(&certificate, &key),
+            (None, None) => {}
+            _ => return Err("a certificate needs its key, and the other way round".to_string()),
+        }
+        if let Some(ca) = der("ca", &self.ca)? {
+            config = config.with_ca(&ca);
+        }
+        if self.psk.is_none() && self.ca.is_none() {
+            return Err("peers cannot authenticate without a psk or a ca".to_string());
+        }
+        if self.policies.is_empty() {
+            return Err("no policies, so no child SAs".to_string());
+        }
+        for policy in &self.policies {
+            config = config.with_policy(selector(&policy.remote)?, selector(&policy.local)?);
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefixes_to_selectors() {
+        let range = |start: [u8; 4], end: [u8; 4]| {
+            TrafficSelector::range(IpAddr::V4(Ipv4Addr::from(start)), IpAddr::V4(Ipv4Addr::from(end)))
+        };
+        assert_eq!(selector("10.0.1.7/24"), Ok(range([10, 0, 1, 0], [10, 0, 1, 255])));
+        assert_eq!(selector("0.0.0.0/0"), Ok(range([0, 0, 0, 0], [255, 255, 255, 255])));
+        assert_eq!(selector("10.0.1.7/32"), Ok(range([10, 0, 1, 7], [10, 0, 1, 7])));
+        assert!(selector("10.0.1.0/33").is_err() && selector("10.0.1.0").is_err());
+
+        let mut ike = IkeConfiguration {
+            id: "192.0.2.1".to_string(),
+            psk: Some("secret".to_string()),
+            ..IkeConfiguration::default()
+        };
+        assert!(ike.ike_config().is_err());
+        ike.policies.push(IkePolicy {
+            remote: "10.0.1.0/24".to_string(),
+            local: "10.0.2.0/24".to_string(),
+        });
+        assert!(ike.ike_config().is_ok());
+        ike.certificate = Some("3000".to_string());
+        assert!(ike.ike_config().is_err());
+    }
+}
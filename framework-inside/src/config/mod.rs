@@ -1,6 +1,18 @@
 use config_rs::{Config, ConfigError, File, FileFormat, Source, Value};
+use heap_ring::poll::PollConfig;
+use interface::BackendSpec;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fmt;
+use std::io::{Read, Write};
+use std::time::Duration;
+use usercalls::{Client, ConfigService};
+
+pub use self::ike::*;
+pub use self::watch::*;
+mod ike;
+mod watch;
 
 pub const DEFAULT_POOL_SIZE: u32 = 2048 - 1;
 pub const DEFAULT_CACHE_SIZE: u32 = 32;
@@ -38,6 +50,210 @@ pub struct NetBricksConfiguration {
     pub cache_size: u32,
     /// Custom DPDK arguments.
     pub dpdk_args: Option<String>,
+    /// Backoff of the shared-ring poller.
+    #[serde(default)]
+    pub polling: PollingConfiguration,
+    /// How the scheduler of a core shares it among its tasks.
+    #[serde(default)]
+    pub scheduler: SchedulerConfiguration,
+    /// Packets a pipeline receives and processes at a time, at most the ring size of its port.
+    pub batch_size: usize,
+    /// Seconds the scheduler runs for; 0 runs it until it is stopped otherwise.
+    #[serde(default)]
+    pub duration: u64,
+    /// Parameters of the NF, see `nf_config` and `nf_param`.
+    #[serde(default)]
+    pub nf: HashMap<String, Value>,
+    /// The IKE responder of the NF, if it runs one.
+    #[serde(default)]
+    pub ike: Option<IkeConfiguration>,
+}
+
+/// Typed parameters of an NF, from the `[nf]` table of its configuration
+///
+/// An NF declares a struct of its parameters that derives `Deserialize`, with
+/// `#[serde(default)]` and a `Default` impl so that unset parameters keep their defaults, and
+/// checks what the types cannot in `validate`. `load_nf_config` loads it along with the rest.
+pub trait NfConfig: DeserializeOwned {
+    /// What is wrong with the parameters, beyond their types.
+    fn validate(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// The `[nf]` table as it is, for NFs without a struct of their own.
+impl NfConfig for HashMap<String, Value> {}
+
+impl NetBricksConfiguration {
+    /// The `[nf]` table as `T`, validated.
+    pub fn nf_config<T: NfConfig>(&self) -> Result<T, ConfigError> {
+        let nf: T = Value::new(None, self.nf.clone()).try_into()?;
+        nf.validate().map_err(|e| ConfigError::Message(format!("[nf]: {}", e)))?;
+        Ok(nf)
+    }
+
+    /// NF parameter `key` of the `[nf]` table, if set.
+    pub fn nf_param<'de, T: Deserialize<'de>>(&self, key: &str) -> Result<Option<T>, ConfigError> {
+        match self.nf.get(key) {
+            Some(value) => value.clone().try_into().map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Checks what the enclave cannot run with, rather than failing on it later.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+        if self.ports.is_empty() {
+            problems.push("no ports".to_string());
+        }
+        if self.batch_size == 0 {
+            problems.push("batch_size is 0".to_string());
+        }
+        if self.scheduler.policy == SchedulingPolicy::Drr && self.scheduler.quantum == 0 {
+            problems.push("scheduler quantum is 0".to_string());
+        }
+        if self.scheduler.idle_runs > 0 && self.scheduler.idle_rounds == 0 {
+            problems.push("scheduler idle_rounds is 0".to_string());
+        }
+        for port in &self.ports {
+            if let Err(e) = BackendSpec::parse(&port.name) {
+                problems.push(e.to_string());
+            }
+            if port.rx_queues.is_empty() {
+                problems.push(format!("port {} has no rx queues", port.name));
+            }
+            for &(what, slots) in [("rxd", port.rxd), ("txd", port.txd)].iter() {
+                // the shared rings are a power of 2 slots.
+                if slots <= 0 || slots & (slots - 1) != 0 {
+                    problems.push(format!("{} of port {} is {}, not a power of 2", what, port.name, slots));
+                }
+            }
+            if self.batch_size > port.rxd as usize {
+                problems.push(format!("batch_size {} exceeds rxd {} of port {}", self.batch_size, port.rxd, port.name));
+            }
+        }
+        if let Some(ref ike) = self.ike {
+            let queues: usize = self.ports.iter().map(|port| port.rx_queues.len()).sum();
+            if ike.queue >= queues {
+                problems.push(format!("IKE queue {} of {} rx queues", ike.queue, queues));
+            }
+            if let Err(e) = ike.ike_config() {
+                problems.push(format!("[ike]: {}", e));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Message(problems.join("; ")))
+        }
+    }
+}
+
+/// How a scheduler backs off when none of its queues has anything to do.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PollingConfiguration {
+    /// Back off when idle, at the cost of latency on the first packets of a burst. Off by
+    /// default: busy polling, which burns a core even at zero load.
+    pub adaptive: bool,
+    /// Empty polls in a row before starting to back off.
+    pub spin_polls: u32,
+    /// How long the rings must stay empty before sleeping on the doorbell, in microseconds.
+    pub idle_us: u64,
+    /// Upper bound on one sleep, in microseconds.
+    pub max_sleep_us: u64,
+}
+
+impl Default for PollingConfiguration {
+    fn default() -> PollingConfiguration {
+        PollingConfiguration {
+            adaptive: false,
+            spin_polls: 256,
+            idle_us: 1000,
+            max_sleep_us: 1000,
+        }
+    }
+}
+
+impl fmt::Display for PollingConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "adaptive: {}, spin polls: {}, idle: {}us, max sleep: {}us",
+            self.adaptive, self.spin_polls, self.idle_us, self.max_sleep_us,
+        )
+    }
+}
+
+impl<'a> From<&'a PollingConfiguration> for PollConfig {
+    fn from(config: &'a PollingConfiguration) -> PollConfig {
+        PollConfig {
+            adaptive: config.adaptive,
+            spin_polls: config.spin_polls,
+            idle: Duration::from_micros(config.idle_us),
+            max_sleep: Duration::from_micros(config.max_sleep_us),
+        }
+    }
+}
+
+/// Which task the scheduler of a core runs next.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchedulingPolicy {
+    /// Every task in turn, once, whatever it costs.
+    RoundRobin,
+    /// Deficit round robin: every task in turn, for as long as its credit of `quantum` times
+    /// its weight per round lasts.
+    Drr,
+    /// Weighted fair queueing: the task that has had the least of the core for its weight.
+    Wfq,
+}
+
+/// What a run of a task costs it under the `Drr` and `Wfq` policies.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchedulingCost {
+    /// The cycles it took.
+    Cycles,
+    /// The packets it handled, and at least 1.
+    Packets,
+}
+
+/// How the scheduler of a core shares it among its tasks.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SchedulerConfiguration {
+    pub policy: SchedulingPolicy,
+    pub cost: SchedulingCost,
+    /// Credit per round of a task of weight 1 under `Drr`, in units of `cost`.
+    pub quantum: u64,
+    /// Runs in a row without packets after which a task goes to the idle queue; 0 keeps every
+    /// task on the run queue.
+    pub idle_runs: u32,
+    /// Rounds of the run queue between two runs of an idle task.
+    pub idle_rounds: u32,
+}
+
+impl Default for SchedulerConfiguration {
+    fn default() -> SchedulerConfiguration {
+        SchedulerConfiguration {
+            policy: SchedulingPolicy::RoundRobin,
+            cost: SchedulingCost::Cycles,
+            quantum: 50_000,
+            idle_runs: 0,
+            idle_rounds: 8,
+        }
+    }
+}
+
+impl fmt::Display for SchedulerConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "policy: {:?}, cost: {:?}, quantum: {}, idle runs: {}, idle rounds: {}",
+            self.policy, self.cost, self.quantum, self.idle_runs, self.idle_rounds,
+        )
+    }
 }
 
 impl fmt::Display for NetBricksConfiguration {
@@ -49,9 +265,12 @@ impl fmt::Display for NetBricksConfiguration {
             .collect::<Vec<_>>()
             .join("\n");
 
+        let mut nf = self.nf.keys().cloned().collect::<Vec<_>>();
+        nf.sort();
+
         write!(
             f,
-            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}",
+            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}\npolling: {}\nscheduler: {}\nbatch size: {}, duration: {}s, NF parameters: {}",
             self.name,
             self.secondary,
             self.pool_size,
@@ -61,6 +280,11 @@ impl fmt::Display for NetBricksConfiguration {
             self.strict,
             ports,
             self.dpdk_args,
+            self.polling,
+            self.scheduler,
+            self.batch_size,
+            self.duration,
+            nf.join(", "),
         )
     }
 }
@@ -85,13 +309,17 @@ pub struct PortConfiguration {
     pub loopback: bool,
     pub tso: bool,
     pub csum: bool,
+    /// Copy packet bytes into enclave memory on receive and back on send, so the host cannot
+    /// change them while the NF looks at them. Costs two copies per packet.
+    #[serde(default)]
+    pub copy: bool,
 }
 
 impl fmt::Display for PortConfiguration {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "name: {}, rxq: {:?}, txq: {:?}, rxd: {}, txd: {}, loopback: {}, tso: {}, csum: {}",
+            "name: {}, rxq: {:?}, txq: {:?}, rxd: {}, txd: {}, loopback: {}, tso: {}, csum: {}, copy: {}",
             self.name,
             self.rx_queues,
             self.tx_queues,
@@ -100,6 +328,7 @@ impl fmt::Display for PortConfiguration {
             self.loopback,
             self.tso,
             self.csum,
+            self.copy,
         )
     }
 }
@@ -114,6 +343,8 @@ static DEFAULT_TOML: &'static str = r#"
     strict = false
     pool_size = 512
     cache_size = 32
+    batch_size = 32
+    duration = 0
     [[ports]]
         name = "SimulateQueue"
         rx_queues = [0]
@@ -123,16 +354,88 @@ static DEFAULT_TOML: &'static str = r#"
         loopback = false
         tso = false
         csum = false
-    duration = 0
+        copy = false
+    [polling]
+        adaptive = false
+        spin_polls = 256
+        idle_us = 1000
+        max_sleep_us = 1000
+    [scheduler]
+        policy = "round-robin"
+        cost = "cycles"
+        quantum = 50000
+        idle_runs = 0
+        idle_rounds = 8
 "#;
 
+/// Environment variable naming a TOML file merged over the defaults. Only honoured outside
+/// an enclave, e.g. to point the ports at a `loopback`, `pcap:` or `af_packet:` backend.
+pub const CONFIG_FILE_ENV: &str = "NETBRICKS_CONFIG";
+
+/// The document of sgx-runner's `config` usercall service merged over the defaults inside an
+/// enclave.
+pub const RUNNER_DOCUMENT: &str = "netbricks";
+
 /// Loads the configuration
 ///
-/// Configuration can be specified through either a file or command
-/// line. Command line arguments will have precedence over settings
-/// from the configuration file.
+/// Like `load_nf_config`, for NFs without parameters of their own.
 pub fn load_config() -> Result<NetBricksConfiguration, ConfigError> {
+    load_nf_config::<HashMap<String, Value>>().map(|(configuration, _)| configuration)
+}
+
+/// Loads the configuration, and the parameters of the NF from its `[nf]` table
+///
+/// Inside an enclave, sgx-runner hands them over, see `load_config_from`.
+#[cfg(target_env = "sgx")]
+pub fn load_nf_config<T: NfConfig>() -> Result<(NetBricksConfiguration, T), ConfigError> {
+    let mut runner = Client::<ConfigService>::connect().map_err(|e| ConfigError::Message(e.to_string()))?;
+    load_config_from(&mut runner)
+}
+
+/// Loads the configuration, and the parameters of the NF from its `[nf]` table
+///
+/// Outside an enclave, the defaults are merged with the file `CONFIG_FILE_ENV` names, if any.
+#[cfg(not(target_env = "sgx"))]
+pub fn load_nf_config<T: NfConfig>() -> Result<(NetBricksConfiguration, T), ConfigError> {
+    let mut config = Config::new();
+    config.merge(File::from_str(DEFAULT_TOML, FileFormat::Toml))?;
+    if let Ok(path) = ::std::env::var(CONFIG_FILE_ENV) {
+        config.merge(File::new(&path, FileFormat::Toml))?;
+    }
+    parse(config)
+}
+
+/// Loads the configuration sgx-runner has for the enclave, `RUNNER_DOCUMENT`, over the
+/// defaults. What the enclave or the NF cannot run with is reported back to the runner before
+/// failing.
+pub fn load_config_from<T: NfConfig, S: Read + Write>(
+    runner: &mut Client<ConfigService, S>,
+) -> Result<(NetBricksConfiguration, T), ConfigError> {
+    let document = runner
+        .fetch(RUNNER_DOCUMENT)
+        .map_err(|e| ConfigError::Message(e.to_string()))?;
+    let loaded = parse_document(document.as_ref().map(|document| document.as_str()));
+    if let Err(ref e) = loaded {
+        if let Err(report) = runner.reject(RUNNER_DOCUMENT, &e.to_string()) {
+            warn!("could not tell the runner about it: {}", report);
+        }
+    }
+    loaded
+}
+
+/// Parses a document of the runner, if any, over the defaults.
+fn parse_document<T: NfConfig>(document: Option<&str>) -> Result<(NetBricksConfiguration, T), ConfigError> {
     let mut config = Config::new();
     config.merge(File::from_str(DEFAULT_TOML, FileFormat::Toml))?;
-    config.try_into()
+    if let Some(document) = document {
+        config.merge(File::from_str(document, FileFormat::Toml))?;
+    }
+    parse(config)
+}
+
+fn parse<T: NfConfig>(config: Config) -> Result<(NetBricksConfiguration, T), ConfigError> {
+    let configuration: NetBricksConfiguration = config.try_into()?;
+    configuration.validate()?;
+    let nf = configuration.nf_config()?;
+    Ok((configuration, nf))
 }
@@ -0,0 +1,148 @@
+use super::{parse_document, NfConfig, RUNNER_DOCUMENT};
+use config_rs::ConfigError;
+use scheduler::Executable;
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::net::TcpStream;
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use usercalls::{Client, ConfigService};
+use utils::VersionedTable;
+
+/// Watches sgx-runner for new versions of `RUNNER_DOCUMENT` while the NF runs
+///
+/// The runner publishes a new version whenever its configuration file changes. Only the
+/// `[nf]` table of a new version takes effect; ports and the rest stay as the enclave started
+/// with them. Versions the NF cannot run with are reported back to the runner and skipped.
+pub struct NfConfigWatcher<T: NfConfig, S: Read + Write = TcpStream> {
+    runner: Client<ConfigService, S>,
+    version: u64,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T: NfConfig> NfConfigWatcher<T> {
+    /// Watches for the versions after the first, which `load_nf_config` loaded.
+    pub fn connect() -> Result<NfConfigWatcher<T>, ConfigError> {
+        let runner = Client::<ConfigService>::connect().map_err(|e| ConfigError::Message(e.to_string()))?;
+        Ok(NfConfigWatcher::new(runner, 1))
+    }
+}
+
+impl<T: NfConfig, S: Read + Write> NfConfigWatcher<T, S> {
+    /// Watches for the versions after `version`.
+    pub fn new(runner: Client<ConfigService, S>, version: u64) -> NfConfigWatcher<T, S> {
+        NfConfigWatcher {
+            runner,
+            version,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The parameters of the NF in the next version, if the runner has one.
+    pub fn poll(&mut self) -> Result<Option<T>, ConfigError> {
+        let (version, document) = match self
+            .runner
+            .poll(RUNNER_DOCUMENT, self.version)
+            .map_err(|e| ConfigError::Message(e.to_string()))?
+        {
+            Some(update) => update,
+            None => return Ok(None),
+        };
+        // a version that cannot be run with is not tried again.
+        self.version = version;
+        match parse_document::<T>(Some(&document)) {
+            Ok((_, nf)) => Ok(Some(nf)),
+            Err(e) => {
+                if let Err(report) = self.runner.reject(RUNNER_DOCUMENT, &e.to_string()) {
+                    warn!("could not tell the runner about it: {}", report);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// The version of the configuration the parameters last came from.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+}
+
+/// Rebuilds a table of the NF from every new version of its parameters, and publishes it to
+/// the pipelines, as a task of the scheduler
+///
+/// A thread of its own polls the runner and builds the tables, entirely off the data path; the
+/// task only publishes what it built, between batches. The enclave needs a thread to spare for
+/// it, or the tables stay as they are.
+pub struct TableUpdateTask<U: 'static> {
+    table: Arc<VersionedTable<U>>,
+    /// The tables built, with the version of the configuration they were built from.
+    built: Receiver<(u64, U)>,
+}
+
+impl<U: Send> TableUpdateTask<U> {
+    /// Polls `watcher` every `interval`, and publishes what `build` makes of new parameters to
+    /// `table`.
+    pub fn new<T, F, S>(
+        watcher: NfConfigWatcher<T, S>,
+        table: Arc<VersionedTable<U>>,
+        interval: Duration,
+        build: F,
+    ) -> TableUpdateTask<U>
+    where
+        T: NfConfig + 'static,
+        F: Fn(&T) -> U + Send + 'static,
+        S: Read + Write + Send + 'static,
+    {
+        // one table waiting to be published is enough, the builder waits for the task.
+        let (sender, built) = sync_channel(1);
+        let builder = thread::Builder::new()
+            .name("table-builder".to_string())
+            .spawn(move || build_tables(watcher, interval, build, sender));
+        if let Err(e) = builder {
+            warn!("no thread to build tables on, they will not change: {}", e);
+        }
+        TableUpdateTask { table, built }
+    }
+}
+
+/// Builds a table from every new version of the parameters `watcher` finds, until the task is
+/// gone.
+fn build_tables<T: NfConfig, U, F: Fn(&T) -> U, S: Read + Write>(
+    mut watcher: NfConfigWatcher<T, S>,
+    interval: Duration,
+    build: F,
+    built: SyncSender<(u64, U)>,
+) {
+    loop {
+        thread::sleep(interval);
+        match watcher.poll() {
+            Ok(Some(nf)) => {
+                if built.send((watcher.version(), build(&nf))).is_err() {
+                    return;
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("keeping the NF tables: {}", e),
+        }
+    }
+}
+
+impl<U> Executable for TableUpdateTask<U> {
+    /// Handles no packets, so always returns 0.
+    fn execute(&mut self) -> usize {
+        if let Ok((configuration, table)) = self.built.try_recv() {
+            let version = self.table.publish(table);
+            info!(
+                "configuration version {} of the NF is in, as table version {}",
+                configuration, version
+            );
+        }
+        0
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        vec![]
+    }
+}
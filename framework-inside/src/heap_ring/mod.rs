@@ -1,2 +1,3 @@
 pub use self::ring_buffer::*;
 pub mod ring_buffer;
+pub use sharedring::poll;
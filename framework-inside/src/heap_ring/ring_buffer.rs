@@ -9,20 +9,27 @@ use std::ptr;
 use utils::PAGE_SIZE;
 use std::slice;
 use native::mbuf::MBuf;
-use std::sync::atomic::compiler_fence;
-use std::sync::atomic::Ordering;
-use std::process;
+use std::mem;
+use std::sync::atomic::{self, AtomicU32, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::io::stdout;
 
 pub const sendq_name: &str = "safebricks_sendq";
 pub const recvq_name: &str = "safebricks_recvq";
 pub const mbufq_name: &str = "safebricks_mbufq";
+pub const freeq_name: &str = "safebricks_freeq";
 
 /// Error related to the RingBuffer
 #[derive(Debug, Fail)]
 #[fail(display = "Bad ring size {}, must be a power of 2", _0)]
 struct InvalidRingSize(usize);
 
+/// The shared region does not carry the layout this enclave was built for.
+#[derive(Debug, Fail)]
+#[fail(display = "Shared ring header mismatch: {}", _0)]
+pub struct RingHeaderMismatch(pub String);
+
 #[derive(Clone)]
 struct SuperVec { my_vec: *mut (*mut MBuf) }
 
@@ -45,11 +52,101 @@ impl Drop for SuperUsize {
     }
 }
 
-pub const STOP_MARK: u32 = 0xabcdefff;
+
+/// Size of a cache line on the machines we run on.
+pub const CACHE_LINE_SIZE: usize = 64;
+const WORDS_PER_LINE: isize = (CACHE_LINE_SIZE / 8) as isize;
+
+/// "SBRING" followed by two zero bytes, stored at the very start of every shared ring.
+pub const RING_MAGIC: u64 = 0x5342_5249_4e47_0000;
+/// Bumped whenever the shared layout changes; both sides must agree on it.
+pub const RING_LAYOUT_VERSION: u32 = 3;
+
+// Layout of the ring meta-data, must stay in sync with sharedring::ring_buffer.
+// The header, the control area and the doorbell come first, then head (consumer cursor) and
+// tail (producer cursor) each own a cache line.
+const HEADER_OFFSET: isize = 0;
+const CONTROL_OFFSET: isize = WORDS_PER_LINE;
+const DOORBELL_OFFSET: isize = 2 * WORDS_PER_LINE;
+const HEAD_OFFSET: isize = 3 * WORDS_PER_LINE;
+const TAIL_OFFSET: isize = 4 * WORDS_PER_LINE;
+const SIZE_OFFSET: isize = 5 * WORDS_PER_LINE;
+const MASK_OFFSET: isize = 5 * WORDS_PER_LINE + 1;
+const SLOTS_OFFSET: isize = 6 * WORDS_PER_LINE;
+
+/// Header at the start of the shared region, written by dpdkIO. Mirrors
+/// sharedring::ring_buffer::RingHeader.
+#[repr(C)]
+pub struct RingHeader {
+    /// Always `RING_MAGIC`.
+    pub magic: u64,
+    /// Always `RING_LAYOUT_VERSION`.
+    pub version: u32,
+    /// Bytes per slot.
+    pub slot_width: u32,
+    /// Number of slots.
+    pub capacity: u64,
+    /// Pid of the process writing at the tail, 0 if not attached yet.
+    pub producer_pid: AtomicU32,
+    /// Pid of the process reading from the head, 0 if not attached yet.
+    pub consumer_pid: AtomicU32,
+    /// Set last by the creator, once everything else is initialized.
+    pub ready: AtomicU32,
+}
+
+/// States the host can ask the enclave to move to. Mirrors sharedring::ring_buffer::RingState.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingState {
+    /// Normal operation.
+    Running = 0,
+    /// Stop pulling from recvq but keep the enclave alive.
+    Paused = 1,
+    /// Process what is left in recvq, push it all to sendq, then acknowledge.
+    Draining = 2,
+    /// Shut down.
+    Stopped = 3,
+}
+
+impl RingState {
+    /// Unknown values are treated as Stopped, the only safe reading of a corrupted word.
+    #[inline]
+    pub fn from_u32(state: u32) -> RingState {
+        match state {
+            0 => RingState::Running,
+            1 => RingState::Paused,
+            2 => RingState::Draining,
+            _ => RingState::Stopped,
+        }
+    }
+}
+
+/// Control area following the header. The host drives the pair through the recvq ring's one.
+#[repr(C)]
+pub struct RingControl {
+    /// The `RingState` requested by the host.
+    pub state: AtomicU32,
+    /// The last `RingState` the enclave has acted upon.
+    pub ack: AtomicU32,
+}
+
+/// Doorbell following the control area. Mirrors sharedring::ring_buffer::RingDoorbell.
+///
+/// The enclave cannot make futex calls: as a consumer it waits by yielding until `seq` moves,
+/// and as a producer it only bumps `seq`, so a sleeping host wakes up on its own timeout.
+#[repr(C)]
+pub struct RingDoorbell {
+    /// Number of consumers currently waiting on `seq`.
+    pub sleepers: AtomicU32,
+    /// Bumped by every doorbell ring.
+    pub seq: AtomicU32,
+}
 
 #[derive(Clone)]
 /// A ring buffer which can be used to insert and read ordered data.
 pub struct RingBuffer {
+    /// The start of the shared region, where the header lives.
+    pub mem: *mut u8,
     /// Head, signifies where a consumer should read from.
     pub head: SuperUsize,
     /// Tail, signifies where a producer should write.
@@ -60,11 +157,11 @@ pub struct RingBuffer {
     pub mask: SuperUsize,
     /// A Vec that holds this RingBuffer's data.
     vec: SuperVec,
+    /// Number of slots, as checked at `attach_in_heap`. Every slot access is sized by it, never
+    /// by the mask in host memory, which the host could enlarge afterwards.
+    capacity: usize,
 }
 
-// once receiving ctrl+c signal, pktpuller will set size to 0xabcdefff, 
-// in this way, the enclave can know it should stop itself. 
-
 impl Drop for RingBuffer {
     fn drop(&mut self) {
         unsafe {
@@ -75,37 +172,199 @@ impl Drop for RingBuffer {
 unsafe impl Sync for RingBuffer {}
 unsafe impl Send for RingBuffer {}
 
+/// Whether `len` bytes at `p` lie in host memory, outside the enclave.
+#[cfg(target_env = "sgx")]
+fn in_host_memory(p: *const u8, len: usize) -> bool {
+    ::std::os::fortanix_sgx::mem::is_user_range(p, len)
+}
+
+#[cfg(not(target_env = "sgx"))]
+fn in_host_memory(_p: *const u8, _len: usize) -> bool {
+    true
+}
+
 #[cfg_attr(feature = "dev", allow(len_without_is_empty))]
 impl RingBuffer {
     /// Create a new wrapping ring buffer. The ring buffer size is specified in bytes and must be a power of 2. 
     /// bytes is the number of bytes of RingBuffer::vec
-    /// we will require additional 16 bytes to store the meta-data for this ring.
+    /// the meta-data for this ring occupies the cache lines in front of RingBuffer::vec.
+    /// The header found at queue_addr_u64 is validated before the ring is handed out, and the
+    /// whole ring has to lie in host memory, so that the host cannot have us use it to read or
+    /// write enclave memory.
     pub unsafe fn attach_in_heap(bytes: usize, queue_addr_u64: u64) -> Result<RingBuffer>{
-        if bytes & (bytes - 1) != 0 {
+        if bytes == 0 || bytes & (bytes - 1) != 0 {
             // We need pages to be a power of 2.
             return Err(InvalidRingSize(bytes).into());
         }
+        if queue_addr_u64 == 0 || queue_addr_u64 % (CACHE_LINE_SIZE as u64) != 0 {
+            return Err(RingHeaderMismatch(format!("bad ring address {:#x}", queue_addr_u64)).into());
+        }
 
-        let head_addr = queue_addr_u64 as (*mut u32);
-        let address = head_addr as *mut u8;
+        let address = queue_addr_u64 as *mut u8;
+        let region = (SLOTS_OFFSET as usize) * mem::size_of::<usize>()
+            + bytes.checked_mul(mem::size_of::<*mut MBuf>()).ok_or(InvalidRingSize(bytes))?;
+        if !in_host_memory(address, region) {
+            return Err(RingHeaderMismatch(format!("ring at {:#x} is not in host memory", queue_addr_u64)).into());
+        }
 
-        Ok(RingBuffer {
-            head: SuperUsize{ my_usize: (address as *mut usize) },
-            tail: SuperUsize{ my_usize: (address as *mut usize).offset(1) }, 
-            size: SuperUsize{ my_usize: (address as *mut usize).offset(2) },
-            mask: SuperUsize{ my_usize: (address as *mut usize).offset(3) },
-            vec: SuperVec{ my_vec: (address as *mut usize).offset(4) as (*mut (*mut MBuf))},
-        })
+        let ring = RingBuffer {
+            mem: address,
+            head: SuperUsize{ my_usize: (address as *mut usize).offset(HEAD_OFFSET) },
+            tail: SuperUsize{ my_usize: (address as *mut usize).offset(TAIL_OFFSET) }, 
+            size: SuperUsize{ my_usize: (address as *mut usize).offset(SIZE_OFFSET) },
+            mask: SuperUsize{ my_usize: (address as *mut usize).offset(MASK_OFFSET) },
+            vec: SuperVec{ my_vec: (address as *mut usize).offset(SLOTS_OFFSET) as (*mut (*mut MBuf))},
+            capacity: bytes,
+        };
+        ring.check_header(bytes)?;
+        Ok(ring)
+    }
+
+    /// Checks that the shared region holds a ready ring of `ring_size` slots in the layout this
+    /// enclave was built for.
+    pub fn check_header(&self, ring_size: usize) -> Result<()> {
+        let header = self.header();
+        if header.ready.load(Ordering::Acquire) == 0 {
+            return Err(RingHeaderMismatch("ring is not ready".to_string()).into());
+        }
+        if header.magic != RING_MAGIC {
+            return Err(RingHeaderMismatch(format!("bad magic {:#x}", header.magic)).into());
+        }
+        if header.version != RING_LAYOUT_VERSION {
+            return Err(RingHeaderMismatch(format!(
+                "layout version {}, expected {}",
+                header.version, RING_LAYOUT_VERSION
+            )).into());
+        }
+        if header.slot_width as usize != mem::size_of::<*mut MBuf>() {
+            return Err(RingHeaderMismatch(format!(
+                "slot width {}, expected {}",
+                header.slot_width,
+                mem::size_of::<*mut MBuf>()
+            )).into());
+        }
+        if header.capacity != ring_size as u64 || self.mask() != ring_size - 1 {
+            return Err(RingHeaderMismatch(format!(
+                "capacity {}, expected {}",
+                header.capacity, ring_size
+            )).into());
+        }
+        Ok(())
+    }
+
+    /// The header at the start of the shared region.
+    #[inline]
+    pub fn header(&self) -> &RingHeader {
+        unsafe { &*((self.mem as *mut usize).offset(HEADER_OFFSET) as *const RingHeader) }
+    }
+
+    /// The control area following the header.
+    #[inline]
+    pub fn control(&self) -> &RingControl {
+        unsafe { &*((self.mem as *mut usize).offset(CONTROL_OFFSET) as *const RingControl) }
+    }
+
+    /// The state currently requested by the host.
+    #[inline]
+    pub fn state(&self) -> RingState {
+        RingState::from_u32(self.control().state.load(Ordering::Acquire))
+    }
+
+    /// The last state acknowledged to the host.
+    #[inline]
+    pub fn acked_state(&self) -> RingState {
+        RingState::from_u32(self.control().ack.load(Ordering::Acquire))
+    }
+
+    /// Acknowledge to the host that `state` has been acted upon.
+    #[inline]
+    pub fn ack_state(&self, state: RingState) {
+        self.control().ack.store(state as u32, Ordering::Release);
+    }
+
+    /// The doorbell following the control area.
+    #[inline]
+    pub fn doorbell(&self) -> &RingDoorbell {
+        unsafe { &*((self.mem as *mut usize).offset(DOORBELL_OFFSET) as *const RingDoorbell) }
+    }
+
+    /// Producer side: tell a sleeping consumer there is new data. Call once per batch, after
+    /// the writes; it is a single load when nobody sleeps.
+    #[inline]
+    pub fn ring_doorbell(&self) {
+        atomic::fence(Ordering::SeqCst);
+        let doorbell = self.doorbell();
+        if doorbell.sleepers.load(Ordering::Relaxed) != 0 {
+            doorbell.seq.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Consumer side: wait until the host rings the doorbell, the ring is not empty, the host
+    /// requests another state, or `timeout` passes. Returns true unless it timed out.
+    pub fn wait_for_doorbell(&self, timeout: Duration) -> bool {
+        let doorbell = self.doorbell();
+        let seq = doorbell.seq.load(Ordering::Acquire);
+        let state = self.state();
+        doorbell.sleepers.fetch_add(1, Ordering::Relaxed);
+        atomic::fence(Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let woken = loop {
+            if doorbell.seq.load(Ordering::Acquire) != seq || self.depth() != 0 || self.state() != state {
+                break true;
+            }
+            if Instant::now() >= deadline {
+                break false;
+            }
+            // hands the thread back to the host until the next check.
+            thread::yield_now();
+        };
+        doorbell.sleepers.fetch_sub(1, Ordering::Relaxed);
+        woken
+    }
+
+    /// Number of slots currently filled, as seen by either side.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        let head = self.head_atomic().load(Ordering::Acquire);
+        self.tail_atomic().load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    /// The consumer cursor. Only the consumer ever stores to it.
+    #[inline]
+    fn head_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.head.my_usize as *const AtomicUsize) }
+    }
+
+    /// The producer cursor. Only the producer ever stores to it.
+    #[inline]
+    fn tail_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.tail.my_usize as *const AtomicUsize) }
+    }
+
+    #[inline]
+    fn size_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.size.my_usize as *const AtomicUsize) }
+    }
+
+    #[inline]
+    fn mask_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.mask.my_usize as *const AtomicUsize) }
+    }
+
+    /// Number of slots, the one checked when the ring was attached.
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.capacity
     }
 
 
     #[inline]
     pub fn head(&self) -> usize{
-        unsafe{(*self.head.my_usize)}
+        self.head_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_head(&self, new_head: usize){
-        unsafe{*self.head.my_usize = new_head;}
+        self.head_atomic().store(new_head, Ordering::Release);
     }
     #[inline]
     pub fn wrapping_sub_head(&self, delta: usize)
@@ -120,11 +379,11 @@ impl RingBuffer {
 
     #[inline]
     pub fn tail(&self) -> usize{
-        unsafe{(*self.tail.my_usize)}
+        self.tail_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_tail(&self, new_tail: usize){
-        unsafe{*self.tail.my_usize = new_tail;}
+        self.tail_atomic().store(new_tail, Ordering::Release);
     }
     #[inline]
     pub fn wrapping_sub_tail(&self, delta: usize)
@@ -139,57 +398,64 @@ impl RingBuffer {
 
     #[inline]
     pub fn size(&self) -> usize{
-        unsafe{(*self.size.my_usize)}
+        self.size_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_size(&self, new_size: usize){
-        unsafe{*self.size.my_usize = new_size;}
+        self.size_atomic().store(new_size, Ordering::Release);
     }
 
     #[inline]
     pub fn mask(&self) -> usize{
-        unsafe{(*self.mask.my_usize)}
+        self.mask_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_mask(&self, new_mask: usize){
-        unsafe{*self.mask.my_usize = new_mask;}
+        self.mask_atomic().store(new_mask, Ordering::Release);
     }
     
-    /// Read from the buffer, incrementing the read head. Returns bytes read.
+    /// Read from the buffer, incrementing the read head. Returns slots read.
+    /// Must only be called by the single consumer of this ring.
     #[inline]
     pub fn read_from_head(&self, mbufs: &mut [*mut MBuf]) -> usize {
-		let ring_size = self.size();
-        if ring_size == STOP_MARK as usize {
-            process::exit(1);
-        }
-        let available = self.tail().wrapping_sub(self.head());
+        // head is ours, so a relaxed load is enough; the acquire on tail pairs with the
+        // producer's release and makes the slots it published visible to us.
+        let head = self.head_atomic().load(Ordering::Relaxed);
+        let tail = self.tail_atomic().load(Ordering::Acquire);
+        // the cursors are the host's to write; whatever they say, never more than a ring.
+        let available = min(tail.wrapping_sub(head), self.capacity());
         let to_read = min(mbufs.len(), available);
-        let offset = self.head() & self.mask();
+        let offset = head & (self.capacity() - 1);
         let reads = self.wrapped_read(offset, &mut mbufs[..to_read]);
-        compiler_fence(Ordering::Release);
-        self.wrapping_add_head(reads);
+        // release: the slots must be copied out before the producer can see them as free.
+        self.head_atomic().store(head.wrapping_add(reads), Ordering::Release);
         reads
     }
 
     /// Write data at the end of the buffer. The amount of data written might be smaller than input.
+    /// Must only be called by the single producer of this ring.
     #[inline]
     pub fn write_at_tail(&self, mbufs: &[*mut MBuf]) -> usize {
-        let available = self.size().wrapping_add(self.head()).wrapping_sub(self.tail());
+        // tail is ours; the acquire on head pairs with the consumer's release so we never
+        // overwrite a slot that is still being read.
+        let tail = self.tail_atomic().load(Ordering::Relaxed);
+        let head = self.head_atomic().load(Ordering::Acquire);
+        // no room at all if the host's head claims more slots in use than the ring has.
+        let available = self.capacity().saturating_sub(tail.wrapping_sub(head));
         let to_write = min(mbufs.len(), available);
-        let offset = self.tail() & self.mask();
+        let offset = tail & (self.capacity() - 1);
         let writes = self.wrapped_write(offset, &mbufs[..to_write]);
-        compiler_fence(Ordering::Release);
-        self.wrapping_add_tail(writes);
+        // release: the slots must be filled before the consumer can see the new tail.
+        self.tail_atomic().store(tail.wrapping_add(writes), Ordering::Release);
         writes
     }
 
     /// Reads data from self.vec, wrapping around the end of the Vec if necessary. Returns the
-    /// number of bytes written.
+    /// number of slots read.
     fn wrapped_read(&self, offset: usize, mbufs: &mut [*mut MBuf]) -> usize {
-        let mut bytes: usize = 0;
-        let ring_size = self.size();
-        assert!(offset < ring_size);
-        assert!(mbufs.len() <= ring_size);
+        // both are bounded by the cached capacity, see the callers.
+        let ring_size = self.capacity();
+        debug_assert!(offset < ring_size && mbufs.len() <= ring_size);
 
         let mut bytes = min(ring_size - offset, mbufs.len());
         if bytes != 0 {
@@ -204,12 +470,11 @@ impl RingBuffer {
     }
 
     /// Writes data to self.vec[offset..], wrapping around the end of the Vec if necessary. Returns
-    /// the number of bytes written.
+    /// the number of slots written.
     fn wrapped_write(&self, offset: usize, mbufs: &[*mut MBuf]) -> usize {
-        let mut bytes: usize = 0;
-        let ring_size = self.size();
-        assert!(offset < ring_size);
-        assert!(mbufs.len() <= ring_size);
+        // both are bounded by the cached capacity, see the callers.
+        let ring_size = self.capacity();
+        debug_assert!(offset < ring_size && mbufs.len() <= ring_size);
 
         let mut bytes = min(ring_size - offset, mbufs.len());
         if bytes != 0 {
@@ -0,0 +1,253 @@
+//! The cryptography of IKE SAs: HMAC-SHA256 as PRF, ECDH over P-256 (group 19, RFC 5903), and
+//! SK payloads encrypted with AES-CBC and authenticated with HMAC-SHA256-128 (RFC 4868).
+use super::message::*;
+use super::IkeError;
+use common::*;
+use mbedtls::cipher::raw::{Cipher, CipherId, CipherMode, CipherPadding, Operation};
+use mbedtls::hash::{Md, Type};
+use mbedtls::pk::{EcGroupId, Pk};
+use mbedtls::rng::{Random, Rdrand};
+use std::ptr;
+
+pub const PRF_LEN: usize = 32;
+/// HMAC-SHA256 truncated to 128 bits.
+pub const ICV_LEN: usize = 16;
+const BLOCK_LEN: usize = 16;
+/// A group 19 public value: the x and y coordinates of the point.
+pub const KE_LEN: usize = 64;
+
+/// The DER SubjectPublicKeyInfo of a P-256 key up to its point, which follows uncompressed.
+const P256_SPKI_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce,
+    0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+/// Tag of an uncompressed point.
+const UNCOMPRESSED: u8 = 0x04;
+
+pub fn prf(key: &[u8], data: &[u8]) -> Result<[u8; PRF_LEN]> {
+    let mut out = [0u8; PRF_LEN];
+    Md::hmac(Type::Sha256, key, data, &mut out)?;
+    Ok(out)
+}
+
+/// The first `len` bytes of prf+ (section 2.13).
+pub fn prf_plus(key: &[u8], seed: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len + PRF_LEN);
+    let mut block = Vec::new();
+    for counter in 1..=255u8 {
+        if out.len() >= len {
+            break;
+        }
+        block.extend_from_slice(seed);
+        block.push(counter);
+        block = prf(key, &block)?.to_vec();
+        out.extend_from_slice(&block);
+    }
+    if out.len() < len {
+        return Err(IkeError::Crypto("prf+ asked for too many bytes").into());
+    }
+    out.truncate(len);
+    Ok(out)
+}
+
+/// Overwrites `key` with zeros, which the compiler cannot leave out.
+pub fn zero(key: &mut [u8]) {
+    for byte in key.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+}
+
+/// An ephemeral group 19 key pair.
+pub struct KeyExchange(Pk);
+
+impl KeyExchange {
+    pub fn new() -> Result<KeyExchange> {
+        Ok(KeyExchange(Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1)?))
+    }
+
+    /// Our public value, as the KE payload carries it.
+    pub fn public(&mut self) -> Result<Vec<u8>> {
+        let der = self.0.write_public_der_vec()?;
+        if der.len() != P256_SPKI_PREFIX.len() + 1 + KE_LEN {
+            return Err(IkeError::Crypto("unexpected public key encoding").into());
+        }
+        Ok(der[(der.len() - KE_LEN)..].to_vec())
+    }
+
+    /// The shared secret with the peer whose public value is `peer`: the x coordinate of the
+    /// shared point. Fails if `peer` is not on the curve.
+    pub fn agree(&mut self, peer: &[u8]) -> Result<Vec<u8>> {
+        if peer.len() != KE_LEN {
+            return Err(IkeError::Malformed("KE data of the wrong length").into());
+        }
+        let mut der = P256_SPKI_PREFIX.to_vec();
+        der.push(UNCOMPRESSED);
+        der.extend_from_slice(peer);
+        let peer = Pk::from_public_key(&der)?;
+        let mut shared = [0u8; 66];
+        let len = self.0.agree(&peer, &mut shared, &mut Rdrand)?;
+        let secret = shared[..len].to_vec();
+        zero(&mut shared);
+        Ok(secret)
+    }
+}
+
+/// AES-CBC without padding under `key`, from `iv`.
+fn aes_cbc(key: &[u8], operation: Operation, iv: &[u8]) -> Result<Cipher> {
+    let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::CBC, (key.len() * 8) as u32)?;
+    cipher.set_key(operation, key)?;
+    cipher.set_iv(iv)?;
+    cipher.set_padding(CipherPadding::None)?;
+    Ok(cipher)
+}
+
+/// The keys of an IKE SA (section 2.14).
+pub struct IkeKeys {
+    /// Child SA keys are derived from this one.
+    pub d: Vec<u8>,
+    ai: Vec<u8>,
+    ar: Vec<u8>,
+    ei: Vec<u8>,
+    er: Vec<u8>,
+    /// The AUTH payloads cover the ID payloads under these.
+    pub pi: Vec<u8>,
+    pub pr: Vec<u8>,
+}
+
+impl IkeKeys {
+    /// Derives the keys of IKE SA `spi_i`/`spi_r` from SKEYSEED, for AES keys of `enc_len`
+    /// bytes.
+    pub fn derive(skeyseed: &[u8], ni: &[u8], nr: &[u8], spi_i: u64, spi_r: u64, enc_len: usize) -> Result<IkeKeys> {
+        let mut seed = [ni, nr].concat();
+        seed.extend_from_slice(&spi_i.to_be_bytes());
+        seed.extend_from_slice(&spi_r.to_be_bytes());
+        let mut material = prf_plus(skeyseed, &seed, 5 * PRF_LEN + 2 * enc_len)?;
+        let keys = {
+            let mut at = 0;
+            let mut next = |len: usize| {
+                at += len;
+                material[(at - len)..at].to_vec()
+            };
+            IkeKeys {
+                d: next(PRF_LEN),
+                ai: next(PRF_LEN),
+                ar: next(PRF_LEN),
+                ei: next(enc_len),
+                er: next(enc_len),
+                pi: next(PRF_LEN),
+                pr: next(PRF_LEN),
+            }
+        };
+        zero(&mut material);
+        Ok(keys)
+    }
+
+    /// Puts `inner`, payloads the first of which is of type `first`, in an SK payload behind
+    /// `header`. `initiator` says which end sends the message.
+    pub fn seal(&self, initiator: bool, mut header: Header, first: u8, inner: &[u8]) -> Result<Vec<u8>> {
+        let (enc, integ) = if initiator { (&self.ei, &self.ai) } else { (&self.er, &self.ar) };
+        // the pad length ends the plaintext, which is padded to whole blocks.
+        let padded = (inner.len() + 1 + BLOCK_LEN - 1) / BLOCK_LEN * BLOCK_LEN;
+        let mut plain = inner.to_vec();
+        plain.resize(padded, 0);
+        plain[padded - 1] = (padded - inner.len() - 1) as u8;
+
+        let sk_len = PAYLOAD_HEADER_LEN + BLOCK_LEN + padded + ICV_LEN;
+        header.next_payload = PAYLOAD_SK;
+        header.length = (HEADER_LEN + sk_len) as u32;
+        let mut msg = Vec::with_capacity(HEADER_LEN + sk_len + BLOCK_LEN);
+        header.write(&mut msg);
+        msg.extend_from_slice(&[first, 0]);
+        msg.extend_from_slice(&(sk_len as u16).to_be_bytes());
+        let mut iv = [0u8; BLOCK_LEN];
+        Rdrand.random(&mut iv)?;
+        msg.extend_from_slice(&iv);
+
+        // CBC wants a block of room beyond the ciphertext.
+        let at = msg.len();
+        msg.resize(at + padded + BLOCK_LEN, 0);
+        let len = aes_cbc(enc, Operation::Encrypt, &iv)?.encrypt(&plain, &mut msg[at..])?;
+        zero(&mut plain);
+        if len != padded {
+            return Err(IkeError::Crypto("AES-CBC encryption").into());
+        }
+        msg.truncate(at + padded);
+        let icv = prf(integ, &msg)?;
+        msg.extend_from_slice(&icv[..ICV_LEN]);
+        Ok(msg)
+    }
+
+    /// Checks the ICV of `msg`, whose payloads are all in an SK payload, and decrypts them.
+    /// `initiator` says which end sent the message. Returns the type of the first payload and
+    /// the payloads.
+    pub fn open(&self, initiator: bool, msg: &[u8]) -> Result<(u8, Vec<u8>)> {
+        let (enc, integ) = if initiator { (&self.ei, &self.ai) } else { (&self.er, &self.ar) };
+        let header = Header::parse(msg)?;
+        let at = HEADER_LEN + PAYLOAD_HEADER_LEN + BLOCK_LEN;
+        if header.next_payload != PAYLOAD_SK || msg.len() < at + ICV_LEN || (msg.len() - at - ICV_LEN) % BLOCK_LEN != 0 {
+            return Err(IkeError::Malformed("expected an SK payload").into());
+        }
+        let signed = msg.len() - ICV_LEN;
+        let icv = prf(integ, &msg[..signed])?;
+        // compare in constant time.
+        if icv[..ICV_LEN].iter().zip(&msg[signed..]).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+            return Err(IkeError::Integrity.into());
+        }
+
+        let ciphertext = &msg[at..signed];
+        let mut plain = vec![0u8; ciphertext.len() + BLOCK_LEN];
+        let iv = &msg[(at - BLOCK_LEN)..at];
+        let len = aes_cbc(enc, Operation::Decrypt, iv)?.decrypt(ciphertext, &mut plain)?;
+        if len != ciphertext.len() || len == 0 || plain[len - 1] as usize >= len {
+            return Err(IkeError::Integrity.into());
+        }
+        let inner_len = len - 1 - plain[len - 1] as usize;
+        plain.truncate(inner_len);
+        Ok((msg[HEADER_LEN], plain))
+    }
+}
+
+impl Drop for IkeKeys {
+    fn drop(&mut self) {
+        for key in [&mut self.d, &mut self.ai, &mut self.ar, &mut self.ei, &mut self.er, &mut self.pi, &mut self.pr].iter_mut() {
+            zero(key);
+        }
+    }
+}
+
+/// The SHA-256 hash of `data`, which signatures are made over.
+pub fn sha256(data: &[u8]) -> Result<[u8; 32]> {
+    let mut hash = [0u8; 32];
+    Md::hash(Type::Sha256, data, &mut hash)?;
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sk_payload_round_trip() {
+        let keys = IkeKeys::derive(&[1; 32], &[2; 32], &[3; 32], 0x0102, 0x0304, 16).unwrap();
+        let header = Header {
+            spi_i: 0x0102,
+            spi_r: 0x0304,
+            next_payload: NO_NEXT_PAYLOAD,
+            exchange: INFORMATIONAL,
+            flags: FLAG_RESPONSE,
+            message_id: 1,
+            length: 0,
+        };
+        for len in [0usize, 15, 16, 17, 100].iter() {
+            let inner = vec![0x5a; *len];
+            let msg = keys.seal(false, header, PAYLOAD_NONCE, &inner).unwrap();
+            assert_eq!((msg.len() - HEADER_LEN - PAYLOAD_HEADER_LEN - ICV_LEN) % 16, 0);
+            assert_eq!(keys.open(false, &msg).unwrap(), (PAYLOAD_NONCE, inner));
+            // the other direction has other keys.
+            assert!(keys.open(true, &msg).is_err());
+            let mut tampered = msg.clone();
+            tampered[HEADER_LEN + 8] ^= 1;
+            assert!(keys.open(false, &tampered).is_err());
+        }
+    }
+}
@@ -0,0 +1,752 @@
+//! IKEv2 messages (RFC 7296 section 3): the header, and the payloads the responder reads and
+//! writes. The SK payload, which carries the others once the IKE SA is up, is `crypto`'s.
+use super::IkeError;
+use common::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const HEADER_LEN: usize = 28;
+/// The generic payload header: next payload, critical bit, payload length.
+pub const PAYLOAD_HEADER_LEN: usize = 4;
+/// Major version 2, minor version 0.
+const VERSION: u8 = 0x20;
+
+// exchange types
+pub const IKE_SA_INIT: u8 = 34;
+pub const IKE_AUTH: u8 = 35;
+pub const CREATE_CHILD_SA: u8 = 36;
+pub const INFORMATIONAL: u8 = 37;
+
+// header flags
+pub const FLAG_INITIATOR: u8 = 0x08;
+pub const FLAG_RESPONSE: u8 = 0x20;
+
+// payload types
+pub const NO_NEXT_PAYLOAD: u8 = 0;
+pub const PAYLOAD_SA: u8 = 33;
+pub const PAYLOAD_KE: u8 = 34;
+pub const PAYLOAD_IDI: u8 = 35;
+pub const PAYLOAD_IDR: u8 = 36;
+pub const PAYLOAD_CERT: u8 = 37;
+pub const PAYLOAD_CERTREQ: u8 = 38;
+pub const PAYLOAD_AUTH: u8 = 39;
+pub const PAYLOAD_NONCE: u8 = 40;
+pub const PAYLOAD_NOTIFY: u8 = 41;
+pub const PAYLOAD_DELETE: u8 = 42;
+pub const PAYLOAD_TSI: u8 = 44;
+pub const PAYLOAD_TSR: u8 = 45;
+pub const PAYLOAD_SK: u8 = 46;
+
+// protocol IDs
+pub const PROTO_IKE: u8 = 1;
+pub const PROTO_ESP: u8 = 3;
+
+// transform types
+pub const TRANSFORM_ENCR: u8 = 1;
+pub const TRANSFORM_PRF: u8 = 2;
+pub const TRANSFORM_INTEG: u8 = 3;
+pub const TRANSFORM_DH: u8 = 4;
+pub const TRANSFORM_ESN: u8 = 5;
+
+// transform IDs
+pub const ENCR_AES_CBC: u16 = 12;
+pub const ENCR_AES_GCM_16: u16 = 20;
+pub const ENCR_CHACHA20_POLY1305: u16 = 28;
+pub const PRF_HMAC_SHA2_256: u16 = 5;
+pub const AUTH_HMAC_SHA2_256_128: u16 = 12;
+pub const DH_NONE: u16 = 0;
+pub const DH_ECP256: u16 = 19;
+
+/// The Key Length transform attribute, in its type/value form.
+const ATTRIBUTE_KEY_LENGTH: u16 = 0x800e;
+
+// identification types
+pub const ID_IPV4_ADDR: u8 = 1;
+pub const ID_FQDN: u8 = 2;
+pub const ID_RFC822_ADDR: u8 = 3;
+pub const ID_IPV6_ADDR: u8 = 5;
+pub const ID_DER_ASN1_DN: u8 = 9;
+pub const ID_KEY_ID: u8 = 11;
+
+// authentication methods
+pub const AUTH_SHARED_KEY: u8 = 2;
+pub const AUTH_DIGITAL_SIGNATURE: u8 = 14;
+
+pub const CERT_X509_SIGNATURE: u8 = 4;
+
+// notify message types
+pub const NOTIFY_UNSUPPORTED_CRITICAL_PAYLOAD: u16 = 1;
+pub const NOTIFY_INVALID_SYNTAX: u16 = 7;
+pub const NOTIFY_NO_PROPOSAL_CHOSEN: u16 = 14;
+pub const NOTIFY_INVALID_KE_PAYLOAD: u16 = 17;
+pub const NOTIFY_AUTHENTICATION_FAILED: u16 = 24;
+pub const NOTIFY_TS_UNACCEPTABLE: u16 = 38;
+pub const NOTIFY_CHILD_SA_NOT_FOUND: u16 = 44;
+pub const NOTIFY_COOKIE: u16 = 16390;
+pub const NOTIFY_REKEY_SA: u16 = 16393;
+
+// traffic selector types
+const TS_IPV4_ADDR_RANGE: u8 = 7;
+const TS_IPV6_ADDR_RANGE: u8 = 8;
+
+fn malformed<T>(what: &'static str) -> Result<T> {
+    Err(IkeError::Malformed(what).into())
+}
+
+/// Takes the fields of a payload off its body, front to back.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if len > self.0.len() {
+            return malformed("payload truncated");
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from(bytes[0]) << 8 | u16::from(bytes[1]))
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let rest = self.0;
+        self.0 = &[];
+        rest
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &byte| acc << 8 | u64::from(byte))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub spi_i: u64,
+    pub spi_r: u64,
+    pub next_payload: u8,
+    pub exchange: u8,
+    pub flags: u8,
+    pub message_id: u32,
+    /// Length of the whole message.
+    pub length: u32,
+}
+
+impl Header {
+    pub fn parse(msg: &[u8]) -> Result<Header> {
+        if msg.len() < HEADER_LEN {
+            return malformed("short header");
+        }
+        if msg[17] >> 4 != VERSION >> 4 {
+            return malformed("not IKEv2");
+        }
+        Ok(Header {
+            spi_i: be(&msg[0..8]),
+            spi_r: be(&msg[8..16]),
+            next_payload: msg[16],
+            exchange: msg[18],
+            flags: msg[19],
+            message_id: be(&msg[20..24]) as u32,
+            length: be(&msg[24..28]) as u32,
+        })
+    }
+
+    /// The header of our response to this request; the length is filled in with the payloads.
+    pub fn response(&self) -> Header {
+        Header {
+            next_payload: NO_NEXT_PAYLOAD,
+            flags: FLAG_RESPONSE,
+            length: 0,
+            ..*self
+        }
+    }
+
+    pub fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.spi_i.to_be_bytes());
+        out.extend_from_slice(&self.spi_r.to_be_bytes());
+        out.extend_from_slice(&[self.next_payload, VERSION, self.exchange, self.flags]);
+        out.extend_from_slice(&self.message_id.to_be_bytes());
+        out.extend_from_slice(&self.length.to_be_bytes());
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transform {
+    pub kind: u8,
+    pub id: u16,
+    /// Key length in bits, for ciphers that take several.
+    pub key_len: Option<u16>,
+}
+
+impl Transform {
+    pub fn new(kind: u8, id: u16) -> Transform {
+        Transform { kind, id, key_len: None }
+    }
+
+    pub fn with_key_len(kind: u8, id: u16, key_len: u16) -> Transform {
+        Transform {
+            key_len: Some(key_len),
+            ..Transform::new(kind, id)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proposal {
+    pub number: u8,
+    pub protocol: u8,
+    pub spi: Vec<u8>,
+    pub transforms: Vec<Transform>,
+}
+
+/// The identity of an ID payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Identity {
+    pub kind: u8,
+    pub data: Vec<u8>,
+}
+
+impl Identity {
+    pub fn fqdn(name: &str) -> Identity {
+        Identity {
+            kind: ID_FQDN,
+            data: name.as_bytes().to_vec(),
+        }
+    }
+
+    pub fn address(address: IpAddr) -> Identity {
+        match address {
+            IpAddr::V4(address) => Identity {
+                kind: ID_IPV4_ADDR,
+                data: address.octets().to_vec(),
+            },
+            IpAddr::V6(address) => Identity {
+                kind: ID_IPV6_ADDR,
+                data: address.octets().to_vec(),
+            },
+        }
+    }
+
+    /// The ID payload without its generic header, which the AUTH payload covers.
+    pub fn body(&self) -> Vec<u8> {
+        let mut body = vec![self.kind, 0, 0, 0];
+        body.extend_from_slice(&self.data);
+        body
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notify {
+    pub protocol: u8,
+    pub spi: Vec<u8>,
+    pub kind: u16,
+    pub data: Vec<u8>,
+}
+
+impl Notify {
+    /// A notify about the IKE SA or the message as a whole.
+    pub fn new(kind: u16, data: Vec<u8>) -> Notify {
+        Notify {
+            protocol: 0,
+            spi: Vec::new(),
+            kind,
+            data,
+        }
+    }
+}
+
+/// Deletes SAs of `protocol`: child SAs by SPI, or the IKE SA the message is for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delete {
+    pub protocol: u8,
+    pub spis: Vec<Vec<u8>>,
+}
+
+/// A range of addresses, ports and an IP protocol; protocol and ports 0 to 65535 are any.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrafficSelector {
+    pub protocol: u8,
+    pub start_port: u16,
+    pub end_port: u16,
+    pub start: IpAddr,
+    pub end: IpAddr,
+}
+
+impl TrafficSelector {
+    /// Any traffic between `start` and `end`.
+    pub fn range(start: IpAddr, end: IpAddr) -> TrafficSelector {
+        TrafficSelector {
+            protocol: 0,
+            start_port: 0,
+            end_port: 65535,
+            start,
+            end,
+        }
+    }
+
+    /// The traffic both selectors cover, if any.
+    pub fn intersect(&self, other: &TrafficSelector) -> Option<TrafficSelector> {
+        if self.start.is_ipv4() != other.start.is_ipv4() {
+            return None;
+        }
+        let protocol = match (self.protocol, other.protocol) {
+            (0, protocol) | (protocol, 0) => protocol,
+            (a, b) if a == b => a,
+            _ => return None,
+        };
+        let selector = TrafficSelector {
+            protocol,
+            start_port: self.start_port.max(other.start_port),
+            end_port: self.end_port.min(other.end_port),
+            start: self.start.max(other.start),
+            end: self.end.min(other.end),
+        };
+        if selector.start_port > selector.end_port || selector.start > selector.end {
+            return None;
+        }
+        Some(selector)
+    }
+
+    fn parse(reader: &mut Reader) -> Result<Option<TrafficSelector>> {
+        let kind = reader.u8()?;
+        let protocol = reader.u8()?;
+        let len = reader.u16()? as usize;
+        let mut selector = Reader(reader.take(len.saturating_sub(4))?);
+        let (start_port, end_port) = (selector.u16()?, selector.u16()?);
+        let (start, end) = match kind {
+            TS_IPV4_ADDR_RANGE if len == 16 => {
+                let (start, end) = (be(selector.take(4)?), be(selector.take(4)?));
+                (IpAddr::V4(Ipv4Addr::from(start as u32)), IpAddr::V4(Ipv4Addr::from(end as u32)))
+            }
+            TS_IPV6_ADDR_RANGE if len == 40 => {
+                let mut start = [0u8; 16];
+                let mut end = [0u8; 16];
+                start.copy_from_slice(selector.take(16)?);
+                end.copy_from_slice(selector.take(16)?);
+                (IpAddr::V6(Ipv6Addr::from(start)), IpAddr::V6(Ipv6Addr::from(end)))
+            }
+            TS_IPV4_ADDR_RANGE | TS_IPV6_ADDR_RANGE => return malformed("traffic selector of the wrong length"),
+            // selectors of other kinds are skipped.
+            _ => return Ok(None),
+        };
+        Ok(Some(TrafficSelector {
+            protocol,
+            start_port,
+            end_port,
+            start,
+            end,
+        }))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        let (kind, len) = match self.start {
+            IpAddr::V4(_) => (TS_IPV4_ADDR_RANGE, 16u16),
+            IpAddr::V6(_) => (TS_IPV6_ADDR_RANGE, 40u16),
+        };
+        out.extend_from_slice(&[kind, self.protocol]);
+        out.extend_from_slice(&len.to_be_bytes());
+        out.extend_from_slice(&self.start_port.to_be_bytes());
+        out.extend_from_slice(&self.end_port.to_be_bytes());
+        for address in [self.start, self.end].iter() {
+            match *address {
+                IpAddr::V4(address) => out.extend_from_slice(&address.octets()),
+                IpAddr::V6(address) => out.extend_from_slice(&address.octets()),
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Payload {
+    Sa(Vec<Proposal>),
+    /// Diffie-Hellman group and public value.
+    Ke(u16, Vec<u8>),
+    IdI(Identity),
+    IdR(Identity),
+    /// Certificate encoding and certificate.
+    Cert(u8, Vec<u8>),
+    CertReq(u8, Vec<u8>),
+    /// Authentication method and data.
+    Auth(u8, Vec<u8>),
+    Nonce(Vec<u8>),
+    Notify(Notify),
+    Delete(Delete),
+    TsI(Vec<TrafficSelector>),
+    TsR(Vec<TrafficSelector>),
+}
+
+impl Payload {
+    pub fn kind(&self) -> u8 {
+        match *self {
+            Payload::Sa(_) => PAYLOAD_SA,
+            Payload::Ke(..) => PAYLOAD_KE,
+            Payload::IdI(_) => PAYLOAD_IDI,
+            Payload::IdR(_) => PAYLOAD_IDR,
+            Payload::Cert(..) => PAYLOAD_CERT,
+            Payload::CertReq(..) => PAYLOAD_CERTREQ,
+            Payload::Auth(..) => PAYLOAD_AUTH,
+            Payload::Nonce(_) => PAYLOAD_NONCE,
+            Payload::Notify(_) => PAYLOAD_NOTIFY,
+            Payload::Delete(_) => PAYLOAD_DELETE,
+            Payload::TsI(_) => PAYLOAD_TSI,
+            Payload::TsR(_) => PAYLOAD_TSR,
+        }
+    }
+
+    /// Decodes the body of a payload of type `kind`. Returns `None` for types we do not know.
+    fn parse(kind: u8, body: &[u8]) -> Result<Option<Payload>> {
+        let mut reader = Reader(body);
+        let payload = match kind {
+            PAYLOAD_SA => Payload::Sa(parse_proposals(&mut reader)?),
+            PAYLOAD_KE => {
+                let group = reader.u16()?;
+                reader.take(2)?;
+                Payload::Ke(group, reader.rest().to_vec())
+            }
+            PAYLOAD_IDI | PAYLOAD_IDR => {
+                let id_type = reader.u8()?;
+                reader.take(3)?;
+                let identity = Identity {
+                    kind: id_type,
+                    data: reader.rest().to_vec(),
+                };
+                if kind == PAYLOAD_IDI {
+                    Payload::IdI(identity)
+                } else {
+                    Payload::IdR(identity)
+                }
+            }
+            PAYLOAD_CERT => Payload::Cert(reader.u8()?, reader.rest().to_vec()),
+            PAYLOAD_CERTREQ => Payload::CertReq(reader.u8()?, reader.rest().to_vec()),
+            PAYLOAD_AUTH => {
+                let method = reader.u8()?;
+                reader.take(3)?;
+                Payload::Auth(method, reader.rest().to_vec())
+            }
+            PAYLOAD_NONCE => {
+                // section 2.10: between 16 and 256 bytes.
+                if body.len() < 16 || body.len() > 256 {
+                    return malformed("nonce of the wrong length");
+                }
+                Payload::Nonce(body.to_vec())
+            }
+            PAYLOAD_NOTIFY => {
+                let protocol = reader.u8()?;
+                let spi_len = reader.u8()? as usize;
+                let kind = reader.u16()?;
+                Payload::Notify(Notify {
+                    protocol,
+                    kind,
+                    spi: reader.take(spi_len)?.to_vec(),
+                    data: reader.rest().to_vec(),
+                })
+            }
+            PAYLOAD_DELETE => {
+                let protocol = reader.u8()?;
+                let spi_len = reader.u8()? as usize;
+                let count = reader.u16()?;
+                let mut spis = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    spis.push(reader.take(spi_len)?.to_vec());
+                }
+                Payload::Delete(Delete { protocol, spis })
+            }
+            PAYLOAD_TSI | PAYLOAD_TSR => {
+                let count = reader.u8()?;
+                reader.take(3)?;
+                let mut selectors = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    if let Some(selector) = TrafficSelector::parse(&mut reader)? {
+                        selectors.push(selector);
+                    }
+                }
+                if kind == PAYLOAD_TSI {
+                    Payload::TsI(selectors)
+                } else {
+                    Payload::TsR(selectors)
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(payload))
+    }
+
+    fn write_body(&self, out: &mut Vec<u8>) {
+        match *self {
+            Payload::Sa(ref proposals) => write_proposals(proposals, out),
+            Payload::Ke(group, ref data) => {
+                out.extend_from_slice(&group.to_be_bytes());
+                out.extend_from_slice(&[0, 0]);
+                out.extend_from_slice(data);
+            }
+            Payload::IdI(ref identity) | Payload::IdR(ref identity) => out.extend_from_slice(&identity.body()),
+            Payload::Cert(encoding, ref data) | Payload::CertReq(encoding, ref data) => {
+                out.push(encoding);
+                out.extend_from_slice(data);
+            }
+            Payload::Auth(method, ref data) => {
+                out.extend_from_slice(&[method, 0, 0, 0]);
+                out.extend_from_slice(data);
+            }
+            Payload::Nonce(ref nonce) => out.extend_from_slice(nonce),
+            Payload::Notify(ref notify) => {
+                out.extend_from_slice(&[notify.protocol, notify.spi.len() as u8]);
+                out.extend_from_slice(&notify.kind.to_be_bytes());
+                out.extend_from_slice(&notify.spi);
+                out.extend_from_slice(&notify.data);
+            }
+            Payload::Delete(ref delete) => {
+                let spi_len = delete.spis.first().map_or(0, |spi| spi.len());
+                out.extend_from_slice(&[delete.protocol, spi_len as u8]);
+                out.extend_from_slice(&(delete.spis.len() as u16).to_be_bytes());
+                for spi in &delete.spis {
+                    out.extend_from_slice(spi);
+                }
+            }
+            Payload::TsI(ref selectors) | Payload::TsR(ref selectors) => {
+                out.extend_from_slice(&[selectors.len() as u8, 0, 0, 0]);
+                for selector in selectors {
+                    selector.write(out);
+                }
+            }
+        }
+    }
+}
+
+fn parse_proposals(reader: &mut Reader) -> Result<Vec<Proposal>> {
+    let mut proposals = Vec::new();
+    while !reader.is_empty() {
+        let last = reader.u8()? == 0;
+        reader.take(1)?;
+        let len = reader.u16()? as usize;
+        let mut proposal = Reader(reader.take(len.saturating_sub(4))?);
+        let number = proposal.u8()?;
+        let protocol = proposal.u8()?;
+        let spi_len = proposal.u8()? as usize;
+        let count = proposal.u8()?;
+        let spi = proposal.take(spi_len)?.to_vec();
+        let mut transforms = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            proposal.take(2)?;
+            let len = proposal.u16()? as usize;
+            let mut transform = Reader(proposal.take(len.saturating_sub(4))?);
+            let kind = transform.u8()?;
+            transform.take(1)?;
+            let id = transform.u16()?;
+            let mut key_len = None;
+            while !transform.is_empty() {
+                let attribute = transform.u16()?;
+                if attribute & 0x8000 != 0 {
+                    let value = transform.u16()?;
+                    if attribute == ATTRIBUTE_KEY_LENGTH {
+                        key_len = Some(value);
+                    }
+                } else {
+                    // type/length/value attributes, none of which we know.
+                    let len = transform.u16()? as usize;
+                    transform.take(len)?;
+                }
+            }
+            transforms.push(Transform { kind, id, key_len });
+        }
+        proposals.push(Proposal {
+            number,
+            protocol,
+            spi,
+            transforms,
+        });
+        if last {
+            break;
+        }
+    }
+    Ok(proposals)
+}
+
+fn write_proposals(proposals: &[Proposal], out: &mut Vec<u8>) {
+    for (i, proposal) in proposals.iter().enumerate() {
+        let start = out.len();
+        out.extend_from_slice(&[if i + 1 == proposals.len() { 0 } else { 2 }, 0, 0, 0]);
+        out.extend_from_slice(&[
+            proposal.number,
+            proposal.protocol,
+            proposal.spi.len() as u8,
+            proposal.transforms.len() as u8,
+        ]);
+        out.extend_from_slice(&proposal.spi);
+        for (j, transform) in proposal.transforms.iter().enumerate() {
+            let last = j + 1 == proposal.transforms.len();
+            let len: u16 = if transform.key_len.is_some() { 12 } else { 8 };
+            out.extend_from_slice(&[if last { 0 } else { 3 }, 0]);
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(&[transform.kind, 0]);
+            out.extend_from_slice(&transform.id.to_be_bytes());
+            if let Some(key_len) = transform.key_len {
+                out.extend_from_slice(&ATTRIBUTE_KEY_LENGTH.to_be_bytes());
+                out.extend_from_slice(&key_len.to_be_bytes());
+            }
+        }
+        let len = (out.len() - start) as u16;
+        out[(start + 2)..(start + 4)].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Decodes the chain of payloads in `data`, the first of which is of type `first`. Unknown
+/// payloads are skipped unless they are critical.
+pub fn parse_payloads(first: u8, data: &[u8]) -> Result<Vec<Payload>> {
+    let mut payloads = Vec::new();
+    let (mut kind, mut rest) = (first, data);
+    while kind != NO_NEXT_PAYLOAD {
+        if rest.len() < PAYLOAD_HEADER_LEN {
+            return malformed("payload header truncated");
+        }
+        let next = rest[0];
+        let critical = rest[1] & 0x80 != 0;
+        let len = be(&rest[2..4]) as usize;
+        if len < PAYLOAD_HEADER_LEN || len > rest.len() {
+            return malformed("payload of the wrong length");
+        }
+        match Payload::parse(kind, &rest[PAYLOAD_HEADER_LEN..len])? {
+            Some(payload) => payloads.push(payload),
+            None if critical => return Err(IkeError::UnsupportedCriticalPayload(kind).into()),
+            None => {}
+        }
+        kind = next;
+        rest = &rest[len..];
+    }
+    Ok(payloads)
+}
+
+/// Encodes `payloads` back to back. Returns the type of the first one and the encoding.
+pub fn encode_payloads(payloads: &[Payload]) -> (u8, Vec<u8>) {
+    let mut out = Vec::new();
+    for (i, payload) in payloads.iter().enumerate() {
+        let next = payloads.get(i + 1).map_or(NO_NEXT_PAYLOAD, Payload::kind);
+        let start = out.len();
+        out.extend_from_slice(&[next, 0, 0, 0]);
+        payload.write_body(&mut out);
+        let len = (out.len() - start) as u16;
+        out[(start + 2)..(start + 4)].copy_from_slice(&len.to_be_bytes());
+    }
+    (payloads.first().map_or(NO_NEXT_PAYLOAD, Payload::kind), out)
+}
+
+/// An unprotected message: `header`, with its next payload and length set, and `payloads`.
+pub fn encode(mut header: Header, payloads: &[Payload]) -> Vec<u8> {
+    let (first, body) = encode_payloads(payloads);
+    header.next_payload = first;
+    header.length = (HEADER_LEN + body.len()) as u32;
+    let mut msg = Vec::with_capacity(header.length as usize);
+    header.write(&mut msg);
+    msg.extend_from_slice(&body);
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payloads_round_trip() {
+        let payloads = vec![
+            Payload::Sa(vec![
+                Proposal {
+                    number: 1,
+                    protocol: PROTO_ESP,
+                    spi: vec![1, 2, 3, 4],
+                    transforms: vec![
+                        Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 256),
+                        Transform::new(TRANSFORM_ESN, 1),
+                    ],
+                },
+                Proposal {
+                    number: 2,
+                    protocol: PROTO_ESP,
+                    spi: vec![1, 2, 3, 4],
+                    transforms: vec![Transform::new(TRANSFORM_ENCR, ENCR_CHACHA20_POLY1305)],
+                },
+            ]),
+            Payload::Nonce(vec![7; 32]),
+            Payload::IdI(Identity::fqdn("initiator.example")),
+            Payload::Notify(Notify {
+                protocol: PROTO_ESP,
+                spi: vec![0, 0, 0x60, 0x01],
+                kind: NOTIFY_REKEY_SA,
+                data: Vec::new(),
+            }),
+            Payload::Delete(Delete {
+                protocol: PROTO_ESP,
+                spis: vec![vec![0, 0, 0x60, 0x01], vec![0, 0, 0x60, 0x02]],
+            }),
+            Payload::TsI(vec![TrafficSelector {
+                protocol: 0,
+                start_port: 0,
+                end_port: 65535,
+                start: "10.0.1.0".parse().unwrap(),
+                end: "10.0.1.255".parse().unwrap(),
+            }]),
+            Payload::TsR(vec![TrafficSelector {
+                protocol: 17,
+                start_port: 500,
+                end_port: 500,
+                start: "2001:db8::".parse().unwrap(),
+                end: "2001:db8::ffff".parse().unwrap(),
+            }]),
+        ];
+        let header = Header {
+            spi_i: 0x0102_0304_0506_0708,
+            spi_r: 0,
+            next_payload: NO_NEXT_PAYLOAD,
+            exchange: IKE_SA_INIT,
+            flags: FLAG_INITIATOR,
+            message_id: 0,
+            length: 0,
+        };
+        let msg = encode(header, &payloads);
+        let parsed = Header::parse(&msg).unwrap();
+        assert_eq!(parsed.length as usize, msg.len());
+        assert_eq!(parsed.next_payload, PAYLOAD_SA);
+        assert_eq!(parse_payloads(parsed.next_payload, &msg[HEADER_LEN..]).unwrap(), payloads);
+    }
+
+    #[test]
+    fn selectors_narrow() {
+        let proposed = TrafficSelector {
+            protocol: 6,
+            start_port: 1000,
+            end_port: 2000,
+            start: "10.0.0.0".parse().unwrap(),
+            end: "10.0.255.255".parse().unwrap(),
+        };
+        let allowed = TrafficSelector::range("10.0.1.0".parse().unwrap(), "10.0.1.255".parse().unwrap());
+        let narrowed = proposed.intersect(&allowed).unwrap();
+        assert_eq!((narrowed.protocol, narrowed.start_port, narrowed.end_port), (6, 1000, 2000));
+        assert_eq!((narrowed.start, narrowed.end), (allowed.start, allowed.end));
+        let elsewhere = TrafficSelector::range("10.1.0.0".parse().unwrap(), "10.1.0.255".parse().unwrap());
+        assert_eq!(proposed.intersect(&elsewhere), None);
+        let v6 = TrafficSelector::range("::".parse().unwrap(), "ffff::".parse().unwrap());
+        assert_eq!(proposed.intersect(&v6), None);
+        assert_eq!(proposed.intersect(&TrafficSelector { protocol: 17, ..allowed }), None);
+    }
+
+    #[test]
+    fn unknown_payloads() {
+        // a vendor ID, skipped, then a nonce.
+        let mut data = vec![PAYLOAD_NONCE, 0, 0, 8, 1, 2, 3, 4, 0, 0, 0, 20];
+        data.extend_from_slice(&[9; 16]);
+        assert_eq!(parse_payloads(43, &data).unwrap(), vec![Payload::Nonce(vec![9; 16])]);
+        data[1] = 0x80;
+        match parse_payloads(43, &data).unwrap_err().downcast::<IkeError>() {
+            Ok(IkeError::UnsupportedCriticalPayload(43)) => {}
+            other => panic!("critical payload accepted: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,61 @@
+//! Minimal IKEv2 (RFC 7296) responder, so peers can negotiate the SAs of `utils::ipsec`
+//! instead of having sgx-runner provision them.
+//!
+//! `IkeTask` runs the responder as a control task on the scheduler, answering the UDP/500
+//! traffic of a port. It supports:
+//!
+//!  * IKE_SA_INIT with group 19 (P-256) Diffie-Hellman and HMAC-SHA256 as PRF. The IKE SA
+//!    itself is protected with AES-CBC and HMAC-SHA256-128.
+//!  * IKE_AUTH with a pre-shared key, or with X.509 certificates and RFC 7427 signatures.
+//!  * CREATE_CHILD_SA for more child SAs, and to rekey child SAs and the IKE SA.
+//!  * INFORMATIONAL exchanges that delete child SAs or the IKE SA.
+//!
+//! Child SAs use any of the `EspCipher` suites. Their keys go to `provisioning` and the SAs to
+//! the SAD the data path uses, in both directions, as if sgx-runner had provisioned them, and
+//! their traffic selectors, narrowed to the configured policies, to the SPD `esp_encap` looks
+//! outbound traffic up in. Past a number of half-open IKE SAs, initiators have to return a
+//! cookie first (section 2.6). The responder never initiates an exchange, and there is no NAT
+//! traversal or EAP.
+use failure::Fail;
+
+pub use self::message::{Identity, TrafficSelector};
+pub use self::responder::{ChildSa, IkeConfig, IkeResponder};
+pub use self::task::IkeTask;
+
+mod crypto;
+mod message;
+mod responder;
+mod task;
+mod x509;
+
+/// The port IKE runs on.
+pub const IKE_PORT: u16 = 500;
+
+#[derive(Debug, Fail)]
+pub enum IkeError {
+    #[fail(display = "Malformed IKE message: {}", _0)]
+    Malformed(&'static str),
+    #[fail(display = "Unsupported critical payload {}", _0)]
+    UnsupportedCriticalPayload(u8),
+    /// The initiator proposed nothing we support.
+    #[fail(display = "No acceptable proposal")]
+    NoProposalChosen,
+    /// The KE payload is for another group than group 19.
+    #[fail(display = "Unsupported Diffie-Hellman group {}", _0)]
+    InvalidKePayload(u16),
+    #[fail(display = "Authentication failed: {}", _0)]
+    AuthenticationFailed(String),
+    #[fail(display = "No traffic selectors")]
+    TsUnacceptable,
+    /// A REKEY_SA notify names a child SA the IKE SA does not have.
+    #[fail(display = "No child SA {:#010x}", _0)]
+    ChildSaNotFound(u32),
+    /// The message is for an IKE SA we do not have, by our SPI.
+    #[fail(display = "No IKE SA {:#018x}", _0)]
+    UnknownSa(u64),
+    /// The SK payload fails its ICV check or does not decrypt.
+    #[fail(display = "IKE message fails its integrity check")]
+    Integrity,
+    #[fail(display = "IKE crypto failed: {}", _0)]
+    Crypto(&'static str),
+}
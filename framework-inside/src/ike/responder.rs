@@ -0,0 +1,903 @@
+//! The exchanges of RFC 7296, from the responder's side, and the IKE SAs they set up.
+use super::crypto::*;
+use super::message::*;
+use super::x509;
+use super::IkeError;
+use common::*;
+use failure::Error;
+use fnv::FnvHashMap;
+use mbedtls::hash::Type;
+use mbedtls::pk::{Pk, Type as PkType};
+use mbedtls::rng::{Random, Rdrand};
+use mbedtls::x509::{Certificate, LinkedCertificate};
+use packets::ip::ProtocolNumber;
+use provisioning::{self, SaKeys, SALT_LEN};
+use std::mem;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use utils::ipsec::{
+    range_cidrs, Direction, EspCipher, Policy, PolicyAction, SecurityAssociation, SecurityAssociationDatabase,
+    SecurityPolicyDatabase,
+};
+
+const NONCE_LEN: usize = 32;
+/// What the pre-shared key is hashed with into the key of the AUTH payloads (section 2.15).
+const KEY_PAD: &[u8] = b"Key Pad for IKEv2";
+/// Room for an RSA-4096 signature.
+const MAX_SIGNATURE_LEN: usize = 512;
+/// The RFC 7427 AlgorithmIdentifiers of the signatures we make and check.
+const SHA256_WITH_RSA: &[u8] = &[
+    0x30, 0x0d, 0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b, 0x05, 0x00,
+];
+const ECDSA_WITH_SHA256: &[u8] = &[0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+/// How we authenticate to peers, how they have to authenticate to us, and what traffic their
+/// child SAs may carry.
+#[derive(Clone)]
+pub struct IkeConfig {
+    /// Our identity, which the IDr payload carries.
+    pub id: Identity,
+    psk: Option<Vec<u8>>,
+    /// DER certificate and private key.
+    certificate: Option<(Vec<u8>, Vec<u8>)>,
+    /// DER certificate of the CA peer certificates have to be issued by.
+    ca: Option<Vec<u8>>,
+    /// The traffic of the peer's side and of ours that child SAs may carry.
+    policies: Vec<(TrafficSelector, TrafficSelector)>,
+    /// Half-open IKE SAs past which initiators have to return a cookie, past which their
+    /// requests are dropped, and how long one may stay half-open.
+    cookie_threshold: usize,
+    half_open_limit: usize,
+    half_open_timeout: Duration,
+}
+
+impl IkeConfig {
+    pub fn new(id: Identity) -> IkeConfig {
+        IkeConfig {
+            id,
+            psk: None,
+            certificate: None,
+            ca: None,
+            policies: Vec::new(),
+            cookie_threshold: 64,
+            half_open_limit: 1024,
+            half_open_timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// Peers may authenticate with `psk`, and we do when they do.
+    pub fn with_psk(mut self, psk: &[u8]) -> IkeConfig {
+        self.psk = Some(psk.to_vec());
+        self
+    }
+
+    /// We sign with `key` and send `certificate`, both DER, when peers sign too or there is no
+    /// pre-shared key.
+    pub fn with_certificate(mut self, certificate: &[u8], key: &[u8]) -> IkeConfig {
+        self.certificate = Some((certificate.to_vec(), key.to_vec()));
+        self
+    }
+
+    /// Peers may sign, with a certificate `ca` issued for the identity of their IDi payload.
+    pub fn with_ca(mut self, ca: &[u8]) -> IkeConfig {
+        self.ca = Some(ca.to_vec());
+        self
+    }
+
+    /// Child SAs may carry traffic between `remote`, behind the peer, and `local`, behind us.
+    /// The traffic selectors initiators propose are narrowed to the first policy they share
+    /// traffic with; without any policy, no child SA is set up.
+    pub fn with_policy(mut self, remote: TrafficSelector, local: TrafficSelector) -> IkeConfig {
+        self.policies.push((remote, local));
+        self
+    }
+
+    /// Past `cookie_threshold` half-open IKE SAs, initiators have to return a cookie; past
+    /// `limit`, their requests are dropped. Half-open SAs are forgotten after `timeout`.
+    pub fn with_half_open(mut self, cookie_threshold: usize, limit: usize, timeout: Duration) -> IkeConfig {
+        self.cookie_threshold = cookie_threshold;
+        self.half_open_limit = limit;
+        self.half_open_timeout = timeout;
+        self
+    }
+
+    /// The traffic selectors of the initiator and of the responder, narrowed to the first
+    /// policy that leaves something of both.
+    fn narrow(&self, ts_i: &[TrafficSelector], ts_r: &[TrafficSelector]) -> Option<(Vec<TrafficSelector>, Vec<TrafficSelector>)> {
+        let within = |selectors: &[TrafficSelector], policy: &TrafficSelector| -> Vec<TrafficSelector> {
+            selectors.iter().filter_map(|selector| selector.intersect(policy)).collect()
+        };
+        self.policies
+            .iter()
+            .map(|&(ref remote, ref local)| (within(ts_i, remote), within(ts_r, local)))
+            .find(|&(ref ts_i, ref ts_r)| !ts_i.is_empty() && !ts_r.is_empty())
+    }
+}
+
+/// A child SA negotiated with a peer, which the data path knows as an inbound and an outbound
+/// SA.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChildSa {
+    /// The SPI of the packets the peer sends, which we picked.
+    pub inbound_spi: u32,
+    /// The SPI of the packets we send, which the peer picked.
+    pub outbound_spi: u32,
+    pub cipher: EspCipher,
+    pub esn: bool,
+    /// The traffic of the initiator and of the responder, narrowed to our policy.
+    pub ts_i: Vec<TrafficSelector>,
+    pub ts_r: Vec<TrafficSelector>,
+}
+
+impl ChildSa {
+    /// The SPD policies that send our traffic to the initiator over the child SA. The SPD
+    /// only knows IPv4, so IPv6 selectors have none.
+    fn policies(&self) -> Vec<Policy> {
+        fn ports(selector: &TrafficSelector) -> Option<(u16, u16)> {
+            match (selector.start_port, selector.end_port) {
+                (0, 65535) => None,
+                ports => Some(ports),
+            }
+        }
+        let mut policies = Vec::new();
+        for local in &self.ts_r {
+            for remote in &self.ts_i {
+                let (local_range, remote_range) = match (local.start, local.end, remote.start, remote.end) {
+                    (IpAddr::V4(a), IpAddr::V4(b), IpAddr::V4(c), IpAddr::V4(d)) => (range_cidrs(a, b), range_cidrs(c, d)),
+                    _ => continue,
+                };
+                let protocol = match (local.protocol, remote.protocol) {
+                    (0, 0) => None,
+                    (0, protocol) | (protocol, 0) => Some(ProtocolNumber::new(protocol)),
+                    (a, b) if a == b => Some(ProtocolNumber::new(a)),
+                    // no packet is of both protocols.
+                    _ => continue,
+                };
+                for src in &local_range {
+                    for dst in &remote_range {
+                        policies.push(Policy {
+                            src: src.clone(),
+                            dst: dst.clone(),
+                            protocol,
+                            src_ports: ports(local),
+                            dst_ports: ports(remote),
+                            action: PolicyAction::Protect(self.outbound_spi),
+                        });
+                    }
+                }
+            }
+        }
+        policies
+    }
+}
+
+struct IkeSa {
+    spi_i: u64,
+    spi_r: u64,
+    peer: IpAddr,
+    local: IpAddr,
+    keys: IkeKeys,
+    /// When IKE_SA_INIT set the SA up, for half-open ones to expire.
+    created: Instant,
+    /// Whether IKE_AUTH went through.
+    established: bool,
+    /// The IKE_SA_INIT messages, which the AUTH payloads cover.
+    init_request: Vec<u8>,
+    init_response: Vec<u8>,
+    ni: Vec<u8>,
+    nr: Vec<u8>,
+    /// The message ID of the next request.
+    next_id: u32,
+    /// The last response, sent again if its request is.
+    last_response: Option<(u32, Vec<u8>)>,
+    children: Vec<ChildSa>,
+    /// Set once the SA is to be forgotten, after the response.
+    deleted: bool,
+}
+
+/// The first of `payloads` that `pick` takes.
+fn find<'a, T, F: Fn(&'a Payload) -> Option<T>>(payloads: &'a [Payload], pick: F) -> Option<T> {
+    payloads.iter().filter_map(pick).next()
+}
+
+/// Like `find`, failing with `missing` if there is no such payload.
+fn need<'a, T, F: Fn(&'a Payload) -> Option<T>>(payloads: &'a [Payload], missing: &'static str, pick: F) -> Result<T> {
+    find(payloads, pick).ok_or_else(|| IkeError::Malformed(missing).into())
+}
+
+fn nonce(payload: &Payload) -> Option<&[u8]> {
+    match *payload {
+        Payload::Nonce(ref nonce) => Some(nonce),
+        _ => None,
+    }
+}
+
+fn proposals(payload: &Payload) -> Option<&[Proposal]> {
+    match *payload {
+        Payload::Sa(ref proposals) => Some(proposals),
+        _ => None,
+    }
+}
+
+fn ke(payload: &Payload) -> Option<(u16, &[u8])> {
+    match *payload {
+        Payload::Ke(group, ref data) => Some((group, data)),
+        _ => None,
+    }
+}
+
+fn spi32(spi: &[u8]) -> Option<u32> {
+    if spi.len() == 4 {
+        Some(spi.iter().fold(0, |acc, &byte| acc << 8 | u32::from(byte)))
+    } else {
+        None
+    }
+}
+
+fn random_nonce() -> Result<Vec<u8>> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    Rdrand.random(&mut nonce)?;
+    Ok(nonce)
+}
+
+fn authentication_failed(why: &str) -> Error {
+    IkeError::AuthenticationFailed(why.to_string()).into()
+}
+
+/// The notify that tells the initiator why its request failed, if it is to be told.
+fn notification(error: &Error) -> Option<Notify> {
+    let (kind, data) = match *error.downcast_ref::<IkeError>()? {
+        IkeError::Malformed(_) => (NOTIFY_INVALID_SYNTAX, Vec::new()),
+        IkeError::UnsupportedCriticalPayload(payload) => (NOTIFY_UNSUPPORTED_CRITICAL_PAYLOAD, vec![payload]),
+        IkeError::NoProposalChosen => (NOTIFY_NO_PROPOSAL_CHOSEN, Vec::new()),
+        // the group we want instead.
+        IkeError::InvalidKePayload(_) => (NOTIFY_INVALID_KE_PAYLOAD, DH_ECP256.to_be_bytes().to_vec()),
+        IkeError::AuthenticationFailed(_) => (NOTIFY_AUTHENTICATION_FAILED, Vec::new()),
+        IkeError::TsUnacceptable => (NOTIFY_TS_UNACCEPTABLE, Vec::new()),
+        IkeError::ChildSaNotFound(spi) => {
+            return Some(Notify {
+                protocol: PROTO_ESP,
+                spi: spi.to_be_bytes().to_vec(),
+                kind: NOTIFY_CHILD_SA_NOT_FOUND,
+                data: Vec::new(),
+            })
+        }
+        _ => return None,
+    };
+    Some(Notify::new(kind, data))
+}
+
+/// The first proposal for an IKE SA that we support, cut down to the transforms we take, and
+/// the length of its AES keys.
+fn choose_ike(proposals: &[Proposal]) -> Result<(Proposal, usize)> {
+    for proposal in proposals.iter().filter(|proposal| proposal.protocol == PROTO_IKE) {
+        let pick = |kind, pick: &Fn(&Transform) -> bool| {
+            proposal.transforms.iter().find(|transform| transform.kind == kind && pick(transform)).cloned()
+        };
+        let chosen = (
+            pick(TRANSFORM_ENCR, &|encr| encr.id == ENCR_AES_CBC && (encr.key_len == Some(128) || encr.key_len == Some(256))),
+            pick(TRANSFORM_PRF, &|prf| prf.id == PRF_HMAC_SHA2_256),
+            pick(TRANSFORM_INTEG, &|integ| integ.id == AUTH_HMAC_SHA2_256_128),
+            pick(TRANSFORM_DH, &|dh| dh.id == DH_ECP256),
+        );
+        if let (Some(encr), Some(prf), Some(integ), Some(dh)) = chosen {
+            let enc_len = encr.key_len.unwrap_or(0) as usize / 8;
+            let chosen = Proposal {
+                transforms: vec![encr, prf, integ, dh],
+                ..proposal.clone()
+            };
+            return Ok((chosen, enc_len));
+        }
+    }
+    Err(IkeError::NoProposalChosen.into())
+}
+
+/// A child SA the initiator proposed and we support.
+struct ChildProposal {
+    /// The initiator's proposal, cut down to the transforms we take.
+    proposal: Proposal,
+    /// The SPI the initiator wants our packets to have.
+    spi: u32,
+    cipher: EspCipher,
+    esn: bool,
+    /// Length of the encryption keys, with the salt of AEAD ones.
+    enc_len: usize,
+}
+
+/// The first proposal for an ESP child SA that we support. `pfs` says whether the request has
+/// a KE payload, `None` if Diffie-Hellman transforms are to be ignored, as in IKE_AUTH.
+fn choose_esp(proposals: &[Proposal], pfs: Option<bool>) -> Result<ChildProposal> {
+    for proposal in proposals.iter().filter(|proposal| proposal.protocol == PROTO_ESP) {
+        let spi = match spi32(&proposal.spi) {
+            Some(spi) => spi,
+            None => continue,
+        };
+        let offers = |kind, id| proposal.transforms.iter().any(|transform| transform.kind == kind && transform.id == id);
+        let mut chosen = Vec::new();
+        let mut cipher = None;
+        for encr in proposal.transforms.iter().filter(|transform| transform.kind == TRANSFORM_ENCR) {
+            cipher = match (encr.id, encr.key_len) {
+                (ENCR_AES_CBC, Some(128)) | (ENCR_AES_CBC, Some(256)) if offers(TRANSFORM_INTEG, AUTH_HMAC_SHA2_256_128) => {
+                    Some((EspCipher::AesCbcSha256, encr.key_len.unwrap() as usize / 8))
+                }
+                (ENCR_AES_GCM_16, Some(128)) => Some((EspCipher::AesGcm128, 16 + SALT_LEN)),
+                (ENCR_AES_GCM_16, Some(256)) => Some((EspCipher::AesGcm256, 32 + SALT_LEN)),
+                (ENCR_CHACHA20_POLY1305, None) => Some((EspCipher::ChaCha20Poly1305, 32 + SALT_LEN)),
+                _ => None,
+            };
+            if cipher.is_some() {
+                chosen.push(encr.clone());
+                break;
+            }
+        }
+        let (cipher, enc_len) = match cipher {
+            Some(cipher) => cipher,
+            None => continue,
+        };
+        if cipher == EspCipher::AesCbcSha256 {
+            chosen.push(Transform::new(TRANSFORM_INTEG, AUTH_HMAC_SHA2_256_128));
+        }
+        if let Some(pfs) = pfs {
+            let offers_dh = proposal.transforms.iter().any(|transform| transform.kind == TRANSFORM_DH);
+            match (pfs, offers_dh) {
+                (true, _) if offers(TRANSFORM_DH, DH_ECP256) => chosen.push(Transform::new(TRANSFORM_DH, DH_ECP256)),
+                (false, true) if offers(TRANSFORM_DH, DH_NONE) => chosen.push(Transform::new(TRANSFORM_DH, DH_NONE)),
+                (false, false) => {}
+                _ => continue,
+            }
+        }
+        // section 3.3.2: responders pick one ESN transform. Without one, there are no ESNs.
+        let esn = proposal
+            .transforms
+            .iter()
+            .find(|transform| transform.kind == TRANSFORM_ESN && transform.id <= 1)
+            .map(|transform| transform.id == 1);
+        if let Some(esn) = esn {
+            chosen.push(Transform::new(TRANSFORM_ESN, esn as u16));
+        }
+        return Ok(ChildProposal {
+            proposal: Proposal {
+                transforms: chosen,
+                ..proposal.clone()
+            },
+            spi,
+            cipher,
+            esn: esn.unwrap_or(false),
+            enc_len,
+        });
+    }
+    Err(IkeError::NoProposalChosen.into())
+}
+
+/// Answers the IKE requests of any number of peers, and installs the child SAs they negotiate
+/// in `provisioning`, the SAD and the SPD.
+pub struct IkeResponder {
+    config: IkeConfig,
+    sad: &'static SecurityAssociationDatabase,
+    spd: &'static SecurityPolicyDatabase,
+    /// The IKE SAs by our SPI, established or still being set up.
+    sas: FnvHashMap<u64, IkeSa>,
+    /// What cookies are made with, so that we need not remember them.
+    cookie_secret: Vec<u8>,
+}
+
+impl IkeResponder {
+    pub fn new(
+        config: IkeConfig,
+        sad: &'static SecurityAssociationDatabase,
+        spd: &'static SecurityPolicyDatabase,
+    ) -> Result<IkeResponder> {
+        Ok(IkeResponder {
+            config,
+            sad,
+            spd,
+            sas: FnvHashMap::default(),
+            cookie_secret: random_nonce()?,
+        })
+    }
+
+    /// The child SAs up, for the SPD to point at.
+    pub fn children(&self) -> Vec<ChildSa> {
+        self.sas.values().flat_map(|sa| sa.children.iter().cloned()).collect()
+    }
+
+    /// Number of IKE SAs, established or being set up.
+    pub fn ike_sas(&self) -> usize {
+        self.sas.len()
+    }
+
+    /// Answers the IKE request `msg` that `peer` sent to `local`. Returns the response, if the
+    /// request deserves one: responses, malformed requests and requests that fail their
+    /// integrity check get none.
+    pub fn handle(&mut self, local: IpAddr, peer: IpAddr, msg: &[u8]) -> Option<Vec<u8>> {
+        let header = match Header::parse(msg) {
+            Ok(header) => header,
+            Err(e) => {
+                debug!("IKE message from {} dropped: {}", peer, e);
+                return None;
+            }
+        };
+        if header.flags & FLAG_RESPONSE != 0 || header.length as usize != msg.len() {
+            return None;
+        }
+        let response = if header.exchange == IKE_SA_INIT {
+            self.sa_init(local, peer, &header, msg).or_else(|e| match notification(&e) {
+                Some(notify) => {
+                    info!("IKE_SA_INIT from {} refused: {}", peer, e);
+                    Ok(Some(encode(header.response(), &[Payload::Notify(notify)])))
+                }
+                None => Err(e),
+            })
+        } else {
+            self.protected(&header, msg)
+        };
+        response.unwrap_or_else(|e| {
+            info!("IKE request from {} dropped: {}", peer, e);
+            None
+        })
+    }
+
+    fn new_ike_spi(&self) -> Result<u64> {
+        loop {
+            let mut spi = [0u8; 8];
+            Rdrand.random(&mut spi)?;
+            let spi = u64::from_be_bytes(spi);
+            if spi != 0 && !self.sas.contains_key(&spi) {
+                return Ok(spi);
+            }
+        }
+    }
+
+    /// An SPI for an inbound SA that no SA has yet, keys included: those are looked up by SPI
+    /// alone. `outbound` is the SPI of the other half of the child SA.
+    fn new_child_spi(&self, outbound: u32) -> Result<u32> {
+        loop {
+            let mut spi = [0u8; 4];
+            Rdrand.random(&mut spi)?;
+            let spi = u32::from_be_bytes(spi);
+            // SPIs up to 255 are reserved.
+            if spi > 255 && spi != outbound && provisioning::lookup(spi).is_none() && self.sad.get(spi, Direction::Inbound).is_none() {
+                return Ok(spi);
+            }
+        }
+    }
+
+    /// The cookie an initiator has to return (section 2.6), which only we can make and which
+    /// ties its request to its address.
+    fn cookie(&self, peer: IpAddr, spi_i: u64, ni: &[u8]) -> Result<Vec<u8>> {
+        let mut data = ni.to_vec();
+        match peer {
+            IpAddr::V4(peer) => data.extend_from_slice(&peer.octets()),
+            IpAddr::V6(peer) => data.extend_from_slice(&peer.octets()),
+        }
+        data.extend_from_slice(&spi_i.to_be_bytes());
+        Ok(prf(&self.cookie_secret, &data)?.to_vec())
+    }
+
+    fn sa_init(&mut self, local: IpAddr, peer: IpAddr, header: &Header, msg: &[u8]) -> Result<Option<Vec<u8>>> {
+        if header.spi_r != 0 || header.message_id != 0 {
+            return Err(IkeError::Malformed("IKE_SA_INIT for an existing IKE SA").into());
+        }
+        let timeout = self.config.half_open_timeout;
+        self.sas.retain(|_, sa| sa.established || sa.created.elapsed() < timeout);
+        // a retransmitted request gets the same response.
+        if let Some(sa) = self.sas.values().find(|sa| sa.spi_i == header.spi_i && sa.peer == peer && !sa.established) {
+            return Ok(if sa.init_request == msg { Some(sa.init_response.clone()) } else { None });
+        }
+        let half_open = self.sas.values().filter(|sa| !sa.established).count();
+        if half_open >= self.config.half_open_limit {
+            debug!("IKE_SA_INIT from {} dropped: {} IKE SAs half-open", peer, half_open);
+            return Ok(None);
+        }
+        let payloads = parse_payloads(header.next_payload, &msg[HEADER_LEN..])?;
+        if half_open >= self.config.cookie_threshold {
+            // the cookie comes first, and the rest of the request as it was.
+            let cookie = self.cookie(peer, header.spi_i, need(&payloads, "no nonce", nonce)?)?;
+            let returned = match payloads.first() {
+                Some(&Payload::Notify(ref notify)) if notify.kind == NOTIFY_COOKIE => Some(&notify.data),
+                _ => None,
+            };
+            let valid = returned.map_or(false, |returned| {
+                returned.len() == cookie.len() && returned.iter().zip(cookie.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+            });
+            if !valid {
+                return Ok(Some(encode(header.response(), &[Payload::Notify(Notify::new(NOTIFY_COOKIE, cookie))])));
+            }
+        }
+        let (chosen, enc_len) = choose_ike(need(&payloads, "no SA payload", proposals)?)?;
+        let (group, public) = need(&payloads, "no KE payload", ke)?;
+        if group != DH_ECP256 {
+            return Err(IkeError::InvalidKePayload(group).into());
+        }
+        let ni = need(&payloads, "no nonce", nonce)?;
+
+        let mut dh = KeyExchange::new()?;
+        let mut shared = dh.agree(public)?;
+        let nr = random_nonce()?;
+        let spi_r = self.new_ike_spi()?;
+        let skeyseed = prf(&[ni, &nr].concat(), &shared)?;
+        zero(&mut shared);
+        let keys = IkeKeys::derive(&skeyseed, ni, &nr, header.spi_i, spi_r, enc_len)?;
+
+        let response = encode(
+            Header { spi_r, ..header.response() },
+            &[Payload::Sa(vec![chosen]), Payload::Ke(DH_ECP256, dh.public()?), Payload::Nonce(nr.clone())],
+        );
+        self.sas.insert(
+            spi_r,
+            IkeSa {
+                spi_i: header.spi_i,
+                spi_r,
+                peer,
+                local,
+                keys,
+                created: Instant::now(),
+                established: false,
+                init_request: msg.to_vec(),
+                init_response: response.clone(),
+                ni: ni.to_vec(),
+                nr,
+                next_id: 1,
+                last_response: None,
+                children: Vec::new(),
+                deleted: false,
+            },
+        );
+        Ok(Some(response))
+    }
+
+    fn protected(&mut self, header: &Header, msg: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut sa = match self.sas.remove(&header.spi_r) {
+            Some(sa) => sa,
+            None => return Err(IkeError::UnknownSa(header.spi_r).into()),
+        };
+        let response = if sa.spi_i == header.spi_i {
+            self.answer(&mut sa, header, msg)
+        } else {
+            Err(IkeError::UnknownSa(header.spi_r).into())
+        };
+        if !sa.deleted {
+            self.sas.insert(sa.spi_r, sa);
+        }
+        response
+    }
+
+    fn answer(&mut self, sa: &mut IkeSa, header: &Header, msg: &[u8]) -> Result<Option<Vec<u8>>> {
+        match sa.last_response {
+            Some((id, ref response)) if id == header.message_id => return Ok(Some(response.clone())),
+            _ if header.message_id != sa.next_id => return Ok(None),
+            _ => {}
+        }
+        let (first, inner) = sa.keys.open(true, msg)?;
+        let payloads = parse_payloads(first, &inner).and_then(|payloads| match header.exchange {
+            IKE_AUTH if !sa.established => self.ike_auth(sa, &payloads),
+            CREATE_CHILD_SA if sa.established => self.create_child_sa(sa, &payloads),
+            INFORMATIONAL if sa.established => self.informational(sa, &payloads),
+            _ => Err(IkeError::Malformed("unexpected exchange").into()),
+        });
+        let payloads = match payloads {
+            Ok(payloads) => payloads,
+            Err(e) => {
+                let notify = match notification(&e) {
+                    Some(notify) => notify,
+                    None => return Err(e),
+                };
+                info!("IKE SA {:#018x}: request {} refused: {}", sa.spi_r, header.message_id, e);
+                // an IKE SA whose peer does not authenticate is not set up.
+                if notify.kind == NOTIFY_AUTHENTICATION_FAILED {
+                    sa.deleted = true;
+                }
+                vec![Payload::Notify(notify)]
+            }
+        };
+        let (first, inner) = encode_payloads(&payloads);
+        let response = sa.keys.seal(false, header.response(), first, &inner)?;
+        sa.next_id += 1;
+        sa.last_response = Some((header.message_id, response.clone()));
+        Ok(Some(response))
+    }
+
+    fn ike_auth(&mut self, sa: &mut IkeSa, payloads: &[Payload]) -> Result<Vec<Payload>> {
+        let id_i = need(payloads, "no IDi payload", |payload| match *payload {
+            Payload::IdI(ref id) => Some(id),
+            _ => None,
+        })?;
+        let (method, auth) = need(payloads, "no AUTH payload", |payload| match *payload {
+            Payload::Auth(method, ref auth) => Some((method, &auth[..])),
+            _ => None,
+        })?;
+        // the initiator signs its IKE_SA_INIT request, our nonce and its identity, and we
+        // our response, its nonce and our identity.
+        let mut signed = [&sa.init_request[..], &sa.nr].concat();
+        signed.extend_from_slice(&prf(&sa.keys.pi, &id_i.body())?);
+        self.verify(id_i, method, auth, payloads, &signed)?;
+        let mut signed = [&sa.init_response[..], &sa.ni].concat();
+        signed.extend_from_slice(&prf(&sa.keys.pr, &self.config.id.body())?);
+        let mut response = vec![Payload::IdR(self.config.id.clone())];
+        response.extend(self.authenticate(method, &signed)?);
+
+        sa.established = true;
+        sa.init_request = Vec::new();
+        sa.init_response = Vec::new();
+        info!("IKE SA {:#018x} with {} established", sa.spi_r, sa.peer);
+
+        // without the child SA that comes with it, the IKE SA is still up.
+        let seed = [&sa.ni[..], &sa.nr].concat();
+        match self.new_child(sa, payloads, None, &seed) {
+            Ok((sa_payload, ts)) => {
+                response.push(sa_payload);
+                response.extend(ts);
+            }
+            Err(e) => match notification(&e) {
+                Some(notify) => response.push(Payload::Notify(notify)),
+                None => return Err(e),
+            },
+        }
+        Ok(response)
+    }
+
+    /// Checks the AUTH payload of the initiator with identity `id`, `data` by `method` over
+    /// `signed`.
+    fn verify(&self, id: &Identity, method: u8, data: &[u8], payloads: &[Payload], signed: &[u8]) -> Result<()> {
+        match method {
+            AUTH_SHARED_KEY => {
+                let psk = self.config.psk.as_ref().ok_or_else(|| authentication_failed("no pre-shared key"))?;
+                let expected = prf(&prf(psk, KEY_PAD)?, signed)?;
+                // compare in constant time.
+                if data.len() != PRF_LEN || data.iter().zip(expected.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+                    return Err(authentication_failed("wrong pre-shared key"));
+                }
+                Ok(())
+            }
+            AUTH_DIGITAL_SIGNATURE => {
+                let ca = self.config.ca.as_ref().ok_or_else(|| authentication_failed("no CA to check certificates with"))?;
+                let der = find(payloads, |payload| match *payload {
+                    Payload::Cert(CERT_X509_SIGNATURE, ref certificate) => Some(certificate),
+                    _ => None,
+                })
+                .ok_or_else(|| authentication_failed("no certificate"))?;
+                // the AlgorithmIdentifier, behind its length, then the signature.
+                let (algorithm, signature) = match data.split_first() {
+                    Some((&len, rest)) if rest.len() > len as usize => rest.split_at(len as usize),
+                    _ => return Err(authentication_failed("malformed signature")),
+                };
+                if algorithm != SHA256_WITH_RSA && algorithm != ECDSA_WITH_SHA256 {
+                    return Err(authentication_failed("unsupported signature algorithm"));
+                }
+                let mut ca = Certificate::from_der(ca)?;
+                let mut certificate = Certificate::from_der(der).map_err(|_| authentication_failed("malformed certificate"))?;
+                if LinkedCertificate::verify(&mut certificate, &mut ca, None).is_err() {
+                    return Err(authentication_failed("certificate not issued by our CA"));
+                }
+                if !x509::certifies(der, id) {
+                    return Err(authentication_failed("identity not in the certificate"));
+                }
+                if certificate.public_key_mut().verify(Type::Sha256, &sha256(signed)?, signature).is_err() {
+                    return Err(authentication_failed("bad signature"));
+                }
+                Ok(())
+            }
+            _ => Err(authentication_failed(&format!("unsupported method {}", method))),
+        }
+    }
+
+    /// Our AUTH payload over `signed`, with our certificate if we sign. We sign if the peer,
+    /// which authenticated with `method`, did too, or if there is no pre-shared key.
+    fn authenticate(&self, method: u8, signed: &[u8]) -> Result<Vec<Payload>> {
+        match self.config.certificate {
+            Some((ref certificate, ref key)) if method == AUTH_DIGITAL_SIGNATURE || self.config.psk.is_none() => {
+                let mut key = Pk::from_private_key(key, None)?;
+                let algorithm = match key.pk_type() {
+                    PkType::Rsa => SHA256_WITH_RSA,
+                    _ => ECDSA_WITH_SHA256,
+                };
+                let mut signature = [0u8; MAX_SIGNATURE_LEN];
+                let len = key.sign(Type::Sha256, &sha256(signed)?, &mut signature, &mut Rdrand)?;
+                let mut data = vec![algorithm.len() as u8];
+                data.extend_from_slice(algorithm);
+                data.extend_from_slice(&signature[..len]);
+                Ok(vec![
+                    Payload::Cert(CERT_X509_SIGNATURE, certificate.clone()),
+                    Payload::Auth(AUTH_DIGITAL_SIGNATURE, data),
+                ])
+            }
+            _ => match self.config.psk {
+                Some(ref psk) => Ok(vec![Payload::Auth(AUTH_SHARED_KEY, prf(&prf(psk, KEY_PAD)?, signed)?.to_vec())]),
+                None => Err(authentication_failed("nothing to authenticate ourselves with")),
+            },
+        }
+    }
+
+    /// Sets up the child SA `payloads` propose, with KEYMAT from `seed` (section 2.17).
+    /// Returns the SA payload and the TS payloads of the response.
+    fn new_child(&mut self, sa: &mut IkeSa, payloads: &[Payload], pfs: Option<bool>, seed: &[u8]) -> Result<(Payload, Vec<Payload>)> {
+        let mut chosen = choose_esp(need(payloads, "no SA payload", proposals)?, pfs)?;
+        let ts_i = find(payloads, |payload| match *payload {
+            Payload::TsI(ref ts) if !ts.is_empty() => Some(ts.clone()),
+            _ => None,
+        });
+        let ts_r = find(payloads, |payload| match *payload {
+            Payload::TsR(ref ts) if !ts.is_empty() => Some(ts.clone()),
+            _ => None,
+        });
+        let (ts_i, ts_r) = match (ts_i, ts_r) {
+            (Some(ts_i), Some(ts_r)) => self.config.narrow(&ts_i, &ts_r).ok_or(IkeError::TsUnacceptable)?,
+            _ => return Err(IkeError::TsUnacceptable.into()),
+        };
+        let outbound_spi = chosen.spi;
+        if provisioning::lookup(outbound_spi).is_some() {
+            warn!("{} wants SPI {:#010x}, which is taken", sa.peer, outbound_spi);
+            return Err(IkeError::NoProposalChosen.into());
+        }
+        let inbound_spi = self.new_child_spi(outbound_spi)?;
+
+        // the keys of the initiator's packets come first, encryption before integrity.
+        let enc_len = chosen.enc_len;
+        let auth_len = if chosen.cipher == EspCipher::AesCbcSha256 { PRF_LEN } else { 0 };
+        let mut keymat = prf_plus(&sa.keys.d, seed, 2 * (enc_len + auth_len))?;
+        {
+            let (initiator, responder) = keymat.split_at(enc_len + auth_len);
+            provisioning::install(SaKeys::new(inbound_spi, initiator[..enc_len].to_vec(), initiator[enc_len..].to_vec())?);
+            provisioning::install(SaKeys::new(outbound_spi, responder[..enc_len].to_vec(), responder[enc_len..].to_vec())?);
+        }
+        zero(&mut keymat);
+        let (cipher, esn) = (chosen.cipher, chosen.esn);
+        self.sad.install(SecurityAssociation::new(inbound_spi, Direction::Inbound, cipher).with_esn(esn).with_tunnel(sa.peer, sa.local));
+        self.sad.install(SecurityAssociation::new(outbound_spi, Direction::Outbound, cipher).with_esn(esn).with_tunnel(sa.local, sa.peer));
+        info!(
+            "child SA {:#010x}/{:#010x} ({}) with {} installed",
+            inbound_spi,
+            outbound_spi,
+            cipher.suite().name(),
+            sa.peer
+        );
+
+        let child = ChildSa {
+            inbound_spi,
+            outbound_spi,
+            cipher,
+            esn,
+            ts_i: ts_i.clone(),
+            ts_r: ts_r.clone(),
+        };
+        // ahead of older policies, so a rekeyed child SA takes the traffic over.
+        self.spd.add_first(child.policies());
+        sa.children.push(child);
+        chosen.proposal.spi = inbound_spi.to_be_bytes().to_vec();
+        Ok((Payload::Sa(vec![chosen.proposal]), vec![Payload::TsI(ts_i), Payload::TsR(ts_r)]))
+    }
+
+    fn remove_child(&self, child: &ChildSa) {
+        provisioning::remove(child.inbound_spi);
+        provisioning::remove(child.outbound_spi);
+        self.sad.remove(child.inbound_spi, Direction::Inbound);
+        self.sad.remove(child.outbound_spi, Direction::Outbound);
+        self.spd.remove_sa(child.outbound_spi);
+        info!("child SA {:#010x}/{:#010x} removed", child.inbound_spi, child.outbound_spi);
+    }
+
+    /// Sets up another child SA, or rekeys one or the IKE SA. A rekeyed SA stays up until the
+    /// initiator deletes it.
+    fn create_child_sa(&mut self, sa: &mut IkeSa, payloads: &[Payload]) -> Result<Vec<Payload>> {
+        let ni = need(payloads, "no nonce", nonce)?;
+        let nr = random_nonce()?;
+        let (dh, mut shared) = match find(payloads, ke) {
+            Some((DH_ECP256, public)) => {
+                let mut dh = KeyExchange::new()?;
+                let shared = dh.agree(public)?;
+                (Some(dh), shared)
+            }
+            Some((group, _)) => return Err(IkeError::InvalidKePayload(group).into()),
+            None => (None, Vec::new()),
+        };
+        let seed = [&shared[..], ni, &nr].concat();
+        zero(&mut shared);
+        let proposals = need(payloads, "no SA payload", proposals)?;
+        if proposals.iter().any(|proposal| proposal.protocol == PROTO_IKE) {
+            return self.rekey_ike_sa(sa, proposals, ni, nr, dh, &seed);
+        }
+
+        // REKEY_SA names the child SA by the SPI of the packets we send.
+        let rekeyed = find(payloads, |payload| match *payload {
+            Payload::Notify(ref notify) if notify.kind == NOTIFY_REKEY_SA => Some(spi32(&notify.spi).unwrap_or(0)),
+            _ => None,
+        });
+        if let Some(spi) = rekeyed {
+            if !sa.children.iter().any(|child| child.outbound_spi == spi) {
+                return Err(IkeError::ChildSaNotFound(spi).into());
+            }
+        }
+        let (sa_payload, ts) = self.new_child(sa, payloads, Some(dh.is_some()), &seed)?;
+        let mut response = vec![sa_payload, Payload::Nonce(nr)];
+        if let Some(mut dh) = dh {
+            response.push(Payload::Ke(DH_ECP256, dh.public()?));
+        }
+        response.extend(ts);
+        Ok(response)
+    }
+
+    /// Sets up the IKE SA that replaces `sa` and takes its child SAs over (section 2.18).
+    /// `seed` is the new shared secret and the nonces.
+    fn rekey_ike_sa(
+        &mut self,
+        sa: &mut IkeSa,
+        proposals: &[Proposal],
+        ni: &[u8],
+        nr: Vec<u8>,
+        dh: Option<KeyExchange>,
+        seed: &[u8],
+    ) -> Result<Vec<Payload>> {
+        let mut dh = dh.ok_or(IkeError::Malformed("IKE SA rekeyed without a KE payload"))?;
+        let (mut chosen, enc_len) = choose_ike(proposals)?;
+        if chosen.spi.len() != 8 {
+            return Err(IkeError::Malformed("IKE SA proposal without an SPI").into());
+        }
+        let spi_i = chosen.spi.iter().fold(0, |acc, &byte| acc << 8 | u64::from(byte));
+        let spi_r = self.new_ike_spi()?;
+        let skeyseed = prf(&sa.keys.d, seed)?;
+        let keys = IkeKeys::derive(&skeyseed, ni, &nr, spi_i, spi_r, enc_len)?;
+        chosen.spi = spi_r.to_be_bytes().to_vec();
+        info!("IKE SA {:#018x} with {} rekeyed to {:#018x}", sa.spi_r, sa.peer, spi_r);
+        self.sas.insert(
+            spi_r,
+            IkeSa {
+                spi_i,
+                spi_r,
+                peer: sa.peer,
+                local: sa.local,
+                keys,
+                created: Instant::now(),
+                established: true,
+                init_request: Vec::new(),
+                init_response: Vec::new(),
+                ni: ni.to_vec(),
+                nr: nr.clone(),
+                next_id: 0,
+                last_response: None,
+                children: mem::replace(&mut sa.children, Vec::new()),
+                deleted: false,
+            },
+        );
+        Ok(vec![Payload::Sa(vec![chosen]), Payload::Nonce(nr), Payload::Ke(DH_ECP256, dh.public()?)])
+    }
+
+    /// Deletes the child SAs or the IKE SA the request says; other requests are answered with
+    /// an empty response, as liveness checks are.
+    fn informational(&mut self, sa: &mut IkeSa, payloads: &[Payload]) -> Result<Vec<Payload>> {
+        let mut deleted = Vec::new();
+        for delete in payloads.iter().filter_map(|payload| match *payload {
+            Payload::Delete(ref delete) => Some(delete),
+            _ => None,
+        }) {
+            match delete.protocol {
+                PROTO_IKE => {
+                    for child in sa.children.drain(..) {
+                        self.remove_child(&child);
+                    }
+                    sa.deleted = true;
+                    info!("IKE SA {:#018x} with {} deleted", sa.spi_r, sa.peer);
+                    return Ok(Vec::new());
+                }
+                // the peer names child SAs by the SPIs of the packets it receives, and so do we.
+                PROTO_ESP => {
+                    for spi in delete.spis.iter().filter_map(|spi| spi32(spi)) {
+                        if let Some(at) = sa.children.iter().position(|child| child.outbound_spi == spi) {
+                            let child = sa.children.remove(at);
+                            self.remove_child(&child);
+                            deleted.push(child.inbound_spi.to_be_bytes().to_vec());
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        if deleted.is_empty() {
+            Ok(Vec::new())
+        } else {
+            Ok(vec![Payload::Delete(Delete {
+                protocol: PROTO_ESP,
+                spis: deleted,
+            })])
+        }
+    }
+}
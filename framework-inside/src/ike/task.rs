@@ -0,0 +1,710 @@
+use super::{IkeResponder, IKE_PORT};
+use common::*;
+use interface::{PacketRx, PacketTx};
+use native::mbuf::MBuf;
+use native::mbuf_free_bulk;
+use operators::batch_size;
+use packets::ethernet::EtherTypes;
+use packets::ip::v4::Ipv4;
+use packets::ip::v6::Ipv6;
+use packets::ip::{IpPacket, ProtocolNumbers, TunnelPacket};
+use packets::{buffer, Ethernet, Fixed, Packet, RawPacket, Udp, UdpHeader};
+use scheduler::Executable;
+use std::ptr;
+
+/// Runs an `IkeResponder` on the UDP/500 traffic of a port, which should get nothing else:
+/// other packets are dropped. Responses go out the way the requests came in. It takes as many
+/// packets at a time as the pipelines, see `operators::set_batch_size`.
+pub struct IkeTask<T: PacketRx + PacketTx> {
+    port: T,
+    responder: IkeResponder,
+    pkts: Vec<*mut MBuf>,
+}
+
+impl<T: PacketRx + PacketTx> IkeTask<T> {
+    pub fn new(port: T, responder: IkeResponder) -> IkeTask<T> {
+        IkeTask {
+            port,
+            responder,
+            pkts: vec![ptr::null_mut(); batch_size()],
+        }
+    }
+
+    pub fn responder(&self) -> &IkeResponder {
+        &self.responder
+    }
+
+    pub fn responder_mut(&mut self) -> &mut IkeResponder {
+        &mut self.responder
+    }
+}
+
+/// Turns the request in `mbuf` into its response. Returns false if the packet is to be dropped.
+fn answer(responder: &mut IkeResponder, mbuf: *mut MBuf) -> Result<bool> {
+    let ethernet = RawPacket::from_mbuf(mbuf).parse::<Ethernet>()?;
+    match ethernet.ether_type() {
+        EtherTypes::Ipv4 => answer_ip::<Ipv4>(responder, ethernet),
+        EtherTypes::Ipv6 => answer_ip::<Ipv6>(responder, ethernet),
+        _ => Ok(false),
+    }
+}
+
+/// Answers the IKE request in the UDP datagram that `ethernet` carries in a `T`, rewriting the
+/// frame into the response.
+fn answer_ip<T: TunnelPacket>(responder: &mut IkeResponder, ethernet: Ethernet) -> Result<bool> {
+    let mbuf = ethernet.mbuf();
+    let ip = ethernet.parse::<T>()?;
+    // the response goes out with a header of its own, so options are not worth supporting.
+    if ip.next_proto() != ProtocolNumbers::Udp || ip.header_len() != T::Header::size() {
+        return Ok(false);
+    }
+    let (local, peer) = (ip.dst(), ip.src());
+    let udp = ip.parse::<Udp<T>>()?;
+    let (offset, len) = (udp.payload_offset(), (udp.length() as usize).saturating_sub(udp.header_len()));
+    if udp.dst_port() != IKE_PORT || offset + len > unsafe { (*mbuf).data_len() } {
+        return Ok(false);
+    }
+    let peer_port = udp.src_port();
+    let response = {
+        let request = unsafe { &*buffer::read_slice::<u8>(mbuf, offset, len)? };
+        match responder.handle(local, peer, request) {
+            Some(response) => response,
+            None => return Ok(false),
+        }
+    };
+
+    let data_len = unsafe { (*mbuf).data_len() };
+    if data_len > offset + response.len() {
+        buffer::trim(mbuf, offset + response.len())?;
+    } else if data_len < offset + response.len() {
+        buffer::alloc(mbuf, data_len, offset + response.len() - data_len)?;
+    }
+    buffer::write_slice(mbuf, offset, &response)?;
+    let mut ethernet = RawPacket::from_mbuf(mbuf).parse::<Ethernet>()?;
+    ethernet.swap_addresses();
+    let mut ip = ethernet.parse::<T>()?;
+    ip.init_tunnel_header(local, peer, ProtocolNumbers::Udp, UdpHeader::size() + response.len())?;
+    let mut udp = ip.parse::<Udp<T>>()?;
+    udp.set_src_port(IKE_PORT);
+    udp.set_dst_port(peer_port);
+    udp.cascade();
+    Ok(true)
+}
+
+impl<T: PacketRx + PacketTx> Executable for IkeTask<T> {
+    fn execute(&mut self) -> usize {
+        let received = match self.port.recv(&mut self.pkts) {
+            Ok(received) => received as usize,
+            Err(e) => {
+                warn_chain!(&e);
+                return 0;
+            }
+        };
+        let mut responses = Vec::with_capacity(received);
+        let mut drops = Vec::new();
+        for &mbuf in &self.pkts[..received] {
+            match answer(&mut self.responder, mbuf) {
+                Ok(true) => responses.push(mbuf),
+                Ok(false) => drops.push(mbuf),
+                Err(e) => {
+                    warn_chain!(&e);
+                    drops.push(mbuf);
+                }
+            }
+        }
+
+        let sent = responses.len();
+        let mut unsent = &mut responses[..];
+        while !unsent.is_empty() {
+            match self.port.send(unsent) {
+                Ok(count) if count > 0 => unsent = &mut unsent[count as usize..],
+                Ok(_) => break,
+                Err(e) => {
+                    warn_chain!(&e);
+                    break;
+                }
+            }
+        }
+        drops.extend_from_slice(unsent);
+        if !drops.is_empty() {
+            unsafe {
+                mbuf_free_bulk(drops.as_mut_ptr(), drops.len() as i32);
+            }
+        }
+        sent
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        vec![]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::crypto::*;
+    use super::super::message::*;
+    use super::super::{IkeConfig, Identity, TrafficSelector};
+    use super::*;
+    use allocators::CacheAligned;
+    use interface::{LoopbackBackend, SimulateQueue};
+    use mbedtls::hash::Type;
+    use mbedtls::pk::Pk;
+    use mbedtls::rng::Rdrand;
+    use mbedtls::x509::Certificate;
+    use packets::checksum;
+    use provisioning;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use utils::ipsec::{Direction, EspCipher, PolicyAction, SecurityPolicyDatabase, SAD};
+
+    const PSK: &[u8] = b"correct horse battery staple";
+    /// A CA, which issued certificates for initiator.example (also 192.0.2.2) and
+    /// responder.example (also 192.0.2.1), and a CA that did not.
+    const CA: &[u8] = include_bytes!("testdata/ca.der");
+    const OTHER_CA: &[u8] = include_bytes!("testdata/other-ca.der");
+    const INITIATOR_CERT: &[u8] = include_bytes!("testdata/initiator.der");
+    const INITIATOR_KEY: &[u8] = include_bytes!("testdata/initiator.key.der");
+    const RESPONDER_CERT: &[u8] = include_bytes!("testdata/responder.der");
+    const RESPONDER_KEY: &[u8] = include_bytes!("testdata/responder.key.der");
+    const ECDSA_WITH_SHA256: &[u8] = &[0x30, 0x0a, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+
+    fn local() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))
+    }
+
+    fn peer() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))
+    }
+
+    fn ipv4(a: u8, b: u8, c: u8, d: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+    }
+
+    /// Takes any traffic within 10.0.0.0/16 on either side, and PSK authentication.
+    fn config() -> IkeConfig {
+        let any = TrafficSelector::range(ipv4(10, 0, 0, 0), ipv4(10, 0, 255, 255));
+        IkeConfig::new(Identity::fqdn("responder.example"))
+            .with_psk(PSK)
+            .with_policy(any.clone(), any)
+    }
+
+    fn task(loopback: &LoopbackBackend) -> IkeTask<CacheAligned<SimulateQueue>> {
+        task_with(loopback, config()).0
+    }
+
+    /// A task with `config`, and the SPD it installs policies in.
+    fn task_with(
+        loopback: &LoopbackBackend,
+        config: IkeConfig,
+    ) -> (IkeTask<CacheAligned<SimulateQueue>>, &'static SecurityPolicyDatabase) {
+        let spd = Box::leak(Box::new(SecurityPolicyDatabase::new()));
+        let responder = IkeResponder::new(config, &SAD, spd).unwrap();
+        (IkeTask::new(SimulateQueue::new(Arc::new(loopback.clone())), responder), spd)
+    }
+
+    /// Sends `msg` from the peer's port 4500, and returns whether anything came back.
+    fn send(task: &mut IkeTask<CacheAligned<SimulateQueue>>, loopback: &LoopbackBackend, msg: &[u8]) -> bool {
+        let mut frame = vec![2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2, 0x08, 0x00];
+        frame.extend_from_slice(&[0x45, 0, 0, 0, 0, 0, 0, 0, 64, 17, 0, 0, 192, 0, 2, 2, 192, 0, 2, 1]);
+        frame[16..18].copy_from_slice(&((28 + msg.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0x11, 0x94, 0x01, 0xf4]);
+        frame.extend_from_slice(&((8 + msg.len()) as u16).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(msg);
+        assert!(loopback.inject(&frame));
+        task.execute() == 1
+    }
+
+    /// Sends `msg` from the peer's port 4500 and returns what comes back.
+    fn exchange(task: &mut IkeTask<CacheAligned<SimulateQueue>>, loopback: &LoopbackBackend, msg: &[u8]) -> Vec<u8> {
+        assert!(send(task, loopback, msg));
+
+        let frames = loopback.drain_frames();
+        assert_eq!(frames.len(), 1);
+        let response = &frames[0];
+        assert_eq!(response[..12], [2, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(response[26..34], [192, 0, 2, 1, 192, 0, 2, 2]);
+        assert_eq!(checksum::compute(0, &response[14..34]), 0);
+        assert_eq!(response[34..38], [0x01, 0xf4, 0x11, 0x94]);
+        assert_eq!(u16::from_be_bytes([response[38], response[39]]) as usize, response.len() - 34);
+        response[42..].to_vec()
+    }
+
+    /// The initiator's end of an IKE SA.
+    struct Initiator {
+        spi_i: u64,
+        spi_r: u64,
+        keys: IkeKeys,
+        init_request: Vec<u8>,
+        init_response: Vec<u8>,
+        ni: Vec<u8>,
+        nr: Vec<u8>,
+    }
+
+    impl Initiator {
+        fn header(&self, exchange: u8, message_id: u32) -> Header {
+            Header {
+                spi_i: self.spi_i,
+                spi_r: self.spi_r,
+                next_payload: NO_NEXT_PAYLOAD,
+                exchange,
+                flags: FLAG_INITIATOR,
+                message_id,
+                length: 0,
+            }
+        }
+
+        fn request(&self, exchange: u8, message_id: u32, payloads: &[Payload]) -> Vec<u8> {
+            let (first, inner) = encode_payloads(payloads);
+            self.keys.seal(true, self.header(exchange, message_id), first, &inner).unwrap()
+        }
+
+        fn response(&self, msg: &[u8]) -> Vec<Payload> {
+            let (first, inner) = self.keys.open(false, msg).unwrap();
+            parse_payloads(first, &inner).unwrap()
+        }
+    }
+
+    /// An IKE_SA_INIT request with nonce `ni`, returning `cookie` if any.
+    fn init_request(spi_i: u64, dh: &mut KeyExchange, ni: &[u8], cookie: Option<&[u8]>) -> Vec<u8> {
+        let proposal = Proposal {
+            number: 1,
+            protocol: PROTO_IKE,
+            spi: Vec::new(),
+            transforms: vec![
+                Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_CBC, 256),
+                Transform::new(TRANSFORM_PRF, PRF_HMAC_SHA2_256),
+                Transform::new(TRANSFORM_INTEG, AUTH_HMAC_SHA2_256_128),
+                Transform::new(TRANSFORM_DH, DH_ECP256),
+            ],
+        };
+        let header = Header {
+            spi_i,
+            spi_r: 0,
+            next_payload: NO_NEXT_PAYLOAD,
+            exchange: IKE_SA_INIT,
+            flags: FLAG_INITIATOR,
+            message_id: 0,
+            length: 0,
+        };
+        let mut payloads = Vec::new();
+        if let Some(cookie) = cookie {
+            payloads.push(Payload::Notify(Notify::new(NOTIFY_COOKIE, cookie.to_vec())));
+        }
+        payloads.push(Payload::Sa(vec![proposal]));
+        payloads.push(Payload::Ke(DH_ECP256, dh.public().unwrap()));
+        payloads.push(Payload::Nonce(ni.to_vec()));
+        encode(header, &payloads)
+    }
+
+    fn sa_init(task: &mut IkeTask<CacheAligned<SimulateQueue>>, loopback: &LoopbackBackend, spi_i: u64) -> Initiator {
+        sa_init_with_cookie(task, loopback, spi_i, None)
+    }
+
+    fn sa_init_with_cookie(
+        task: &mut IkeTask<CacheAligned<SimulateQueue>>,
+        loopback: &LoopbackBackend,
+        spi_i: u64,
+        cookie: Option<&[u8]>,
+    ) -> Initiator {
+        let mut dh = KeyExchange::new().unwrap();
+        let ni = vec![0x11; 32];
+        let init_request = init_request(spi_i, &mut dh, &ni, cookie);
+        let init_response = exchange(task, loopback, &init_request);
+        // a retransmitted request gets the same response.
+        assert_eq!(exchange(task, loopback, &init_request), init_response);
+
+        let response = Header::parse(&init_response).unwrap();
+        assert_eq!((response.spi_i, response.flags), (spi_i, FLAG_RESPONSE));
+        let payloads = parse_payloads(response.next_payload, &init_response[HEADER_LEN..]).unwrap();
+        let (mut shared, nr) = match payloads[..] {
+            [Payload::Sa(_), Payload::Ke(DH_ECP256, ref ke), Payload::Nonce(ref nr)] => (dh.agree(ke).unwrap(), nr.clone()),
+            ref other => panic!("unexpected IKE_SA_INIT response {:?}", other),
+        };
+        let skeyseed = prf(&[&ni[..], &nr].concat(), &shared).unwrap();
+        zero(&mut shared);
+        Initiator {
+            spi_i,
+            spi_r: response.spi_r,
+            keys: IkeKeys::derive(&skeyseed, &ni, &nr, spi_i, response.spi_r, 32).unwrap(),
+            init_request,
+            init_response,
+            ni,
+            nr,
+        }
+    }
+
+    fn auth(initiator: &Initiator, psk: &[u8], id: &Identity) -> Vec<u8> {
+        let key = prf(psk, b"Key Pad for IKEv2").unwrap();
+        let mut signed = initiator.init_request.clone();
+        signed.extend_from_slice(&initiator.nr);
+        signed.extend_from_slice(&prf(&initiator.keys.pi, &id.body()).unwrap());
+        prf(&key, &signed).unwrap().to_vec()
+    }
+
+    fn esp_proposal(spi: u32, transforms: Vec<Transform>) -> Payload {
+        Payload::Sa(vec![Proposal {
+            number: 1,
+            protocol: PROTO_ESP,
+            spi: spi.to_be_bytes().to_vec(),
+            transforms,
+        }])
+    }
+
+    fn selectors(net: u8) -> Vec<TrafficSelector> {
+        vec![TrafficSelector {
+            protocol: 0,
+            start_port: 0,
+            end_port: 65535,
+            start: IpAddr::V4(Ipv4Addr::new(10, 0, net, 0)),
+            end: IpAddr::V4(Ipv4Addr::new(10, 0, net, 255)),
+        }]
+    }
+
+    /// Our inbound SPI, from the SA payload of a response.
+    fn chosen_spi(payloads: &[Payload]) -> u32 {
+        match payloads.iter().find(|payload| payload.kind() == PAYLOAD_SA) {
+            Some(Payload::Sa(proposals)) => u32::from_be_bytes([proposals[0].spi[0], proposals[0].spi[1], proposals[0].spi[2], proposals[0].spi[3]]),
+            _ => panic!("no SA payload in {:?}", payloads),
+        }
+    }
+
+    /// Checks that both halves of child SA `inbound`/`outbound` are installed, with `keymat`.
+    fn assert_installed(inbound: u32, outbound: u32, cipher: EspCipher, keymat: &[u8]) {
+        let (initiator, responder) = keymat.split_at(keymat.len() / 2);
+        assert_eq!(provisioning::lookup(inbound).unwrap().enc_key, initiator);
+        assert_eq!(provisioning::lookup(outbound).unwrap().enc_key, responder);
+        let sa = SAD.get(inbound, Direction::Inbound).unwrap();
+        assert_eq!(sa.lock().unwrap().tunnel, Some((peer(), local())));
+        assert_eq!(sa.lock().unwrap().cipher, cipher);
+        let sa = SAD.get(outbound, Direction::Outbound).unwrap();
+        assert_eq!(sa.lock().unwrap().tunnel, Some((local(), peer())));
+    }
+
+    #[test]
+    fn psk_child_sa_rekey_and_delete() {
+        let loopback = LoopbackBackend::new(4);
+        let mut task = task(&loopback);
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0001);
+
+        let id = Identity::fqdn("initiator.example");
+        let request = initiator.request(
+            IKE_AUTH,
+            1,
+            &[
+                Payload::IdI(id.clone()),
+                Payload::Auth(AUTH_SHARED_KEY, auth(&initiator, PSK, &id)),
+                esp_proposal(
+                    0x6001,
+                    vec![
+                        Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 256),
+                        Transform::new(TRANSFORM_ESN, 1),
+                        Transform::new(TRANSFORM_ESN, 0),
+                    ],
+                ),
+                Payload::TsI(selectors(1)),
+                Payload::TsR(selectors(2)),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        let id_r = Identity::fqdn("responder.example");
+        assert_eq!(response[0], Payload::IdR(id_r.clone()));
+        let mut signed = initiator.init_response.clone();
+        signed.extend_from_slice(&initiator.ni);
+        signed.extend_from_slice(&prf(&initiator.keys.pr, &id_r.body()).unwrap());
+        let expected = prf(&prf(PSK, b"Key Pad for IKEv2").unwrap(), &signed).unwrap().to_vec();
+        assert_eq!(response[1], Payload::Auth(AUTH_SHARED_KEY, expected));
+        assert_eq!(response[3..], [Payload::TsI(selectors(1)), Payload::TsR(selectors(2))]);
+        let first = chosen_spi(&response);
+        let keymat = prf_plus(&initiator.keys.d, &[&initiator.ni[..], &initiator.nr].concat(), 2 * 36).unwrap();
+        assert_installed(first, 0x6001, EspCipher::AesGcm256, &keymat);
+        assert!(SAD.get(first, Direction::Inbound).unwrap().lock().unwrap().esn);
+
+        // rekey the child SA, with PFS and another cipher.
+        let mut dh = KeyExchange::new().unwrap();
+        let ni = vec![0x22; 32];
+        let mut rekey = Notify::new(NOTIFY_REKEY_SA, Vec::new());
+        rekey.protocol = PROTO_ESP;
+        rekey.spi = 0x6001u32.to_be_bytes().to_vec();
+        let request = initiator.request(
+            CREATE_CHILD_SA,
+            2,
+            &[
+                Payload::Notify(rekey),
+                esp_proposal(
+                    0x6002,
+                    vec![
+                        Transform::new(TRANSFORM_ENCR, ENCR_CHACHA20_POLY1305),
+                        Transform::new(TRANSFORM_DH, DH_ECP256),
+                    ],
+                ),
+                Payload::Nonce(ni.clone()),
+                Payload::Ke(DH_ECP256, dh.public().unwrap()),
+                Payload::TsI(selectors(1)),
+                Payload::TsR(selectors(2)),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        let second = chosen_spi(&response);
+        let seed = match response[..] {
+            [Payload::Sa(_), Payload::Nonce(ref nr), Payload::Ke(DH_ECP256, ref ke), ..] => {
+                [&dh.agree(ke).unwrap()[..], &ni, nr].concat()
+            }
+            ref other => panic!("unexpected CREATE_CHILD_SA response {:?}", other),
+        };
+        let keymat = prf_plus(&initiator.keys.d, &seed, 2 * 36).unwrap();
+        assert_installed(second, 0x6002, EspCipher::ChaCha20Poly1305, &keymat);
+        assert_eq!(task.responder().children().len(), 2);
+
+        // the initiator deletes the old child SA, by the SPI it receives on.
+        let request = initiator.request(
+            INFORMATIONAL,
+            3,
+            &[Payload::Delete(Delete {
+                protocol: PROTO_ESP,
+                spis: vec![0x6001u32.to_be_bytes().to_vec()],
+            })],
+        );
+        let response = exchange(&mut task, &loopback, &request);
+        assert_eq!(
+            initiator.response(&response),
+            [Payload::Delete(Delete {
+                protocol: PROTO_ESP,
+                spis: vec![first.to_be_bytes().to_vec()],
+            })]
+        );
+        // a retransmitted request gets the same response.
+        assert_eq!(exchange(&mut task, &loopback, &request), response);
+        assert!(provisioning::lookup(first).is_none() && provisioning::lookup(0x6001).is_none());
+        assert!(SAD.get(first, Direction::Inbound).is_none() && SAD.get(0x6001, Direction::Outbound).is_none());
+        let children = task.responder().children();
+        assert_eq!((children.len(), children[0].inbound_spi), (1, second));
+
+        // and then the IKE SA, which takes the remaining child SA with it.
+        let request = initiator.request(
+            INFORMATIONAL,
+            4,
+            &[Payload::Delete(Delete {
+                protocol: PROTO_IKE,
+                spis: Vec::new(),
+            })],
+        );
+        assert!(initiator.response(&exchange(&mut task, &loopback, &request)).is_empty());
+        assert_eq!(task.responder().ike_sas(), 0);
+        assert!(provisioning::lookup(second).is_none() && SAD.get(0x6002, Direction::Outbound).is_none());
+    }
+
+    #[test]
+    fn wrong_psk() {
+        let loopback = LoopbackBackend::new(4);
+        let mut task = task(&loopback);
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0002);
+        let id = Identity::address(peer());
+        let request = initiator.request(
+            IKE_AUTH,
+            1,
+            &[
+                Payload::IdI(id.clone()),
+                Payload::Auth(AUTH_SHARED_KEY, auth(&initiator, b"wrong", &id)),
+                esp_proposal(0x6003, vec![Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 128)]),
+                Payload::TsI(selectors(1)),
+                Payload::TsR(selectors(2)),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        assert_eq!(response, [Payload::Notify(Notify::new(NOTIFY_AUTHENTICATION_FAILED, Vec::new()))]);
+        assert_eq!(task.responder().ike_sas(), 0);
+        assert!(provisioning::lookup(0x6003).is_none());
+    }
+
+    /// The IKE_AUTH request of an initiator that signs as `id` with the certificate of
+    /// initiator.example.
+    fn signed_auth(initiator: &Initiator, id: &Identity, spi: u32) -> Vec<u8> {
+        let mut signed = initiator.init_request.clone();
+        signed.extend_from_slice(&initiator.nr);
+        signed.extend_from_slice(&prf(&initiator.keys.pi, &id.body()).unwrap());
+        let mut key = Pk::from_private_key(INITIATOR_KEY, None).unwrap();
+        let mut signature = [0u8; 512];
+        let len = key.sign(Type::Sha256, &sha256(&signed).unwrap(), &mut signature, &mut Rdrand).unwrap();
+        let mut data = vec![ECDSA_WITH_SHA256.len() as u8];
+        data.extend_from_slice(ECDSA_WITH_SHA256);
+        data.extend_from_slice(&signature[..len]);
+        initiator.request(
+            IKE_AUTH,
+            1,
+            &[
+                Payload::IdI(id.clone()),
+                Payload::Cert(CERT_X509_SIGNATURE, INITIATOR_CERT.to_vec()),
+                Payload::Auth(AUTH_DIGITAL_SIGNATURE, data),
+                esp_proposal(spi, vec![Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 128)]),
+                Payload::TsI(selectors(1)),
+                Payload::TsR(selectors(2)),
+            ],
+        )
+    }
+
+    #[test]
+    fn certificates() {
+        let loopback = LoopbackBackend::new(4);
+        let config = config().with_ca(CA).with_certificate(RESPONDER_CERT, RESPONDER_KEY);
+        let (mut task, _) = task_with(&loopback, config.clone());
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0004);
+        let id = Identity::address(peer());
+        let response = initiator.response(&exchange(&mut task, &loopback, &signed_auth(&initiator, &id, 0x6004)));
+        let id_r = Identity::fqdn("responder.example");
+        assert_eq!(response[..2], [Payload::IdR(id_r.clone()), Payload::Cert(CERT_X509_SIGNATURE, RESPONDER_CERT.to_vec())]);
+        // we sign back, with the key of our certificate.
+        let mut signed = initiator.init_response.clone();
+        signed.extend_from_slice(&initiator.ni);
+        signed.extend_from_slice(&prf(&initiator.keys.pr, &id_r.body()).unwrap());
+        match response[2] {
+            Payload::Auth(AUTH_DIGITAL_SIGNATURE, ref data) => {
+                assert_eq!(data[1..data[0] as usize + 1], *ECDSA_WITH_SHA256);
+                let mut certificate = Certificate::from_der(RESPONDER_CERT).unwrap();
+                let signature = &data[data[0] as usize + 1..];
+                assert!(certificate.public_key_mut().verify(Type::Sha256, &sha256(&signed).unwrap(), signature).is_ok());
+            }
+            ref other => panic!("unexpected AUTH payload {:?}", other),
+        }
+        assert!(SAD.get(0x6004, Direction::Outbound).is_some());
+
+        // the certificate is not for the identity the initiator claims.
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0005);
+        let id = Identity::fqdn("responder.example");
+        let response = initiator.response(&exchange(&mut task, &loopback, &signed_auth(&initiator, &id, 0x6005)));
+        assert_eq!(response, [Payload::Notify(Notify::new(NOTIFY_AUTHENTICATION_FAILED, Vec::new()))]);
+        assert!(SAD.get(0x6005, Direction::Outbound).is_none());
+
+        // nor is it issued by the CA we trust.
+        let (mut task, _) = task_with(&loopback, config.with_ca(OTHER_CA));
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0006);
+        let id = Identity::fqdn("initiator.example");
+        let response = initiator.response(&exchange(&mut task, &loopback, &signed_auth(&initiator, &id, 0x6006)));
+        assert_eq!(response, [Payload::Notify(Notify::new(NOTIFY_AUTHENTICATION_FAILED, Vec::new()))]);
+        assert_eq!(task.responder().ike_sas(), 0);
+    }
+
+    #[test]
+    fn selectors_narrowed_to_policy() {
+        let loopback = LoopbackBackend::new(4);
+        let config = IkeConfig::new(Identity::fqdn("responder.example")).with_psk(PSK).with_policy(
+            TrafficSelector::range(ipv4(10, 0, 1, 0), ipv4(10, 0, 1, 255)),
+            TrafficSelector::range(ipv4(10, 0, 2, 0), ipv4(10, 0, 2, 127)),
+        );
+        let (mut task, spd) = task_with(&loopback, config);
+        let initiator = sa_init(&mut task, &loopback, 0x6000_0000_0000_0007);
+        let id = Identity::fqdn("initiator.example");
+        let udp = TrafficSelector {
+            protocol: 17,
+            ..selectors(2)[0].clone()
+        };
+        let request = initiator.request(
+            IKE_AUTH,
+            1,
+            &[
+                Payload::IdI(id.clone()),
+                Payload::Auth(AUTH_SHARED_KEY, auth(&initiator, PSK, &id)),
+                esp_proposal(0x6007, vec![Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 128)]),
+                Payload::TsI(vec![TrafficSelector::range(ipv4(10, 0, 0, 0), ipv4(10, 0, 255, 255))]),
+                Payload::TsR(vec![udp]),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        let narrowed = TrafficSelector {
+            protocol: 17,
+            ..TrafficSelector::range(ipv4(10, 0, 2, 0), ipv4(10, 0, 2, 127))
+        };
+        assert_eq!(response[3..], [Payload::TsI(selectors(1)), Payload::TsR(vec![narrowed])]);
+
+        // our UDP traffic within the selectors goes out on the child SA, and nothing else.
+        let select = |src: IpAddr, dst: IpAddr, protocol| spd.select_ports(src, dst, protocol, Some((4000, 53)));
+        assert_eq!(select(ipv4(10, 0, 2, 5), ipv4(10, 0, 1, 9), ProtocolNumbers::Udp), PolicyAction::Protect(0x6007));
+        assert_eq!(select(ipv4(10, 0, 2, 200), ipv4(10, 0, 1, 9), ProtocolNumbers::Udp), PolicyAction::Discard);
+        assert_eq!(select(ipv4(10, 0, 2, 5), ipv4(10, 0, 3, 9), ProtocolNumbers::Udp), PolicyAction::Discard);
+        assert_eq!(select(ipv4(10, 0, 2, 5), ipv4(10, 0, 1, 9), ProtocolNumbers::Tcp), PolicyAction::Discard);
+
+        // traffic no policy covers gets no child SA, but the IKE SA stays up.
+        let request = initiator.request(
+            CREATE_CHILD_SA,
+            2,
+            &[
+                esp_proposal(0x6008, vec![Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 128)]),
+                Payload::Nonce(vec![0x33; 32]),
+                Payload::TsI(selectors(3)),
+                Payload::TsR(selectors(2)),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        assert_eq!(response, [Payload::Notify(Notify::new(NOTIFY_TS_UNACCEPTABLE, Vec::new()))]);
+        assert!(SAD.get(0x6008, Direction::Outbound).is_none());
+
+        // deleting the IKE SA takes the policies of its child SA with it.
+        let request = initiator.request(
+            INFORMATIONAL,
+            3,
+            &[Payload::Delete(Delete {
+                protocol: PROTO_IKE,
+                spis: Vec::new(),
+            })],
+        );
+        exchange(&mut task, &loopback, &request);
+        assert_eq!(select(ipv4(10, 0, 2, 5), ipv4(10, 0, 1, 9), ProtocolNumbers::Udp), PolicyAction::Discard);
+    }
+
+    #[test]
+    fn cookies_past_the_threshold() {
+        let loopback = LoopbackBackend::new(4);
+        let (mut task, _) = task_with(&loopback, config().with_half_open(0, 16, Duration::from_secs(30)));
+        let spi_i = 0x6000_0000_0000_0009;
+        let mut dh = KeyExchange::new().unwrap();
+        let cookie_of = |response: &[u8]| {
+            let header = Header::parse(response).unwrap();
+            assert_eq!(header.spi_r, 0);
+            match parse_payloads(header.next_payload, &response[HEADER_LEN..]).unwrap()[..] {
+                [Payload::Notify(ref notify)] if notify.kind == NOTIFY_COOKIE => notify.data.clone(),
+                ref other => panic!("no cookie in {:?}", other),
+            }
+        };
+        let cookie = cookie_of(&exchange(&mut task, &loopback, &init_request(spi_i, &mut dh, &[0x11; 32], None)));
+        assert_eq!(task.responder().ike_sas(), 0);
+        // a wrong cookie does no better.
+        let wrong = init_request(spi_i, &mut dh, &[0x11; 32], Some(&[0u8; 32]));
+        assert_eq!(cookie_of(&exchange(&mut task, &loopback, &wrong)), cookie);
+        assert_eq!(task.responder().ike_sas(), 0);
+
+        let initiator = sa_init_with_cookie(&mut task, &loopback, spi_i, Some(&cookie));
+        assert_eq!(task.responder().ike_sas(), 1);
+        let id = Identity::fqdn("initiator.example");
+        let request = initiator.request(
+            IKE_AUTH,
+            1,
+            &[
+                Payload::IdI(id.clone()),
+                Payload::Auth(AUTH_SHARED_KEY, auth(&initiator, PSK, &id)),
+                esp_proposal(0x6009, vec![Transform::with_key_len(TRANSFORM_ENCR, ENCR_AES_GCM_16, 128)]),
+                Payload::TsI(selectors(1)),
+                Payload::TsR(selectors(2)),
+            ],
+        );
+        let response = initiator.response(&exchange(&mut task, &loopback, &request));
+        assert_eq!(response[0], Payload::IdR(Identity::fqdn("responder.example")));
+    }
+
+    #[test]
+    fn half_open_sas_are_capped_and_expire() {
+        let loopback = LoopbackBackend::new(4);
+        let (mut task, _) = task_with(&loopback, config().with_half_open(16, 1, Duration::from_millis(100)));
+        sa_init(&mut task, &loopback, 0x6000_0000_0000_000a);
+        let mut dh = KeyExchange::new().unwrap();
+        let request = init_request(0x6000_0000_0000_000b, &mut dh, &[0x11; 32], None);
+        assert!(!send(&mut task, &loopback, &request));
+        assert!(loopback.drain_frames().is_empty());
+        assert_eq!(task.responder().ike_sas(), 1);
+
+        thread::sleep(Duration::from_millis(150));
+        sa_init(&mut task, &loopback, 0x6000_0000_0000_000b);
+        assert_eq!(task.responder().ike_sas(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! Just enough of X.509 (RFC 5280) to tell whether a certificate is for an IKE identity: its
+//! subject and its subjectAltName extension. mbedtls has already parsed the certificate by the
+//! time we look, so anything unexpected simply matches nothing.
+use super::message::*;
+
+const SEQUENCE: u8 = 0x30;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const VERSION: u8 = 0xa0;
+const EXTENSIONS: u8 = 0xa3;
+/// id-ce-subjectAltName, 2.5.29.17.
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+/// The GeneralName choices we match identities against.
+const RFC822_NAME: u8 = 0x81;
+const DNS_NAME: u8 = 0x82;
+const DIRECTORY_NAME: u8 = 0xa4;
+const IP_ADDRESS: u8 = 0x87;
+
+/// A DER element, with its tag and length in `raw`.
+struct Tlv<'a> {
+    tag: u8,
+    raw: &'a [u8],
+    content: &'a [u8],
+}
+
+/// The first element of `data`, and what follows it.
+fn read(data: &[u8]) -> Option<(Tlv, &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (first as usize, rest),
+        0x81..=0x83 => {
+            let octets = (first & 0x7f) as usize;
+            if rest.len() < octets {
+                return None;
+            }
+            let len = rest[..octets].iter().fold(0, |acc, &byte| acc << 8 | byte as usize);
+            (len, &rest[octets..])
+        }
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = data.len() - rest.len();
+    let tlv = Tlv {
+        tag,
+        raw: &data[..header + len],
+        content: &rest[..len],
+    };
+    Some((tlv, &rest[len..]))
+}
+
+/// The elements of `data`, up to the first malformed one.
+fn elements(mut data: &[u8]) -> Vec<Tlv> {
+    let mut elements = Vec::new();
+    while let Some((tlv, rest)) = read(data) {
+        elements.push(tlv);
+        data = rest;
+    }
+    elements
+}
+
+/// The subject Name of certificate `der` and the GeneralNames of its subjectAltName.
+fn names(der: &[u8]) -> Option<(&[u8], Vec<Tlv>)> {
+    let (certificate, _) = read(der)?;
+    let (tbs, _) = read(certificate.content)?;
+    if certificate.tag != SEQUENCE || tbs.tag != SEQUENCE {
+        return None;
+    }
+    let mut fields = elements(tbs.content);
+    if fields.first().map(|field| field.tag) == Some(VERSION) {
+        fields.remove(0);
+    }
+    // serialNumber, signature, issuer, validity, then subject.
+    let subject = fields.get(4)?.raw;
+    let mut alt_names = Vec::new();
+    if let Some(extensions) = fields.iter().find(|field| field.tag == EXTENSIONS) {
+        let (extensions, _) = read(extensions.content)?;
+        for extension in elements(extensions.content) {
+            let parts = elements(extension.content);
+            let is_alt_name = parts.first().map_or(false, |oid| oid.tag == OID && oid.content == SUBJECT_ALT_NAME);
+            // extnValue comes last, behind the optional critical flag.
+            match parts.last() {
+                Some(value) if is_alt_name && value.tag == OCTET_STRING => {
+                    let (names, _) = read(value.content)?;
+                    alt_names.extend(elements(names.content));
+                }
+                _ => {}
+            }
+        }
+    }
+    Some((subject, alt_names))
+}
+
+/// Whether certificate `der` is for `id`: a distinguished name has to be the subject, other
+/// identities one of the subjectAltNames (RFC 4945, section 3.1). Identities of other kinds,
+/// such as key IDs, never are.
+pub fn certifies(der: &[u8], id: &Identity) -> bool {
+    let (subject, alt_names) = match names(der) {
+        Some(names) => names,
+        None => return false,
+    };
+    let alt_name = |tag: u8, matches: &Fn(&[u8]) -> bool| alt_names.iter().any(|name| name.tag == tag && matches(name.content));
+    match id.kind {
+        ID_DER_ASN1_DN => subject == &id.data[..] || alt_name(DIRECTORY_NAME, &|name| name == &id.data[..]),
+        ID_FQDN => alt_name(DNS_NAME, &|name| name.eq_ignore_ascii_case(&id.data)),
+        ID_RFC822_ADDR => alt_name(RFC822_NAME, &|name| name.eq_ignore_ascii_case(&id.data)),
+        ID_IPV4_ADDR | ID_IPV6_ADDR => alt_name(IP_ADDRESS, &|name| name == &id.data[..]),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::IpAddr;
+
+    const INITIATOR: &[u8] = include_bytes!("testdata/initiator.der");
+
+    #[test]
+    fn identities_in_certificates() {
+        let dn = Identity {
+            kind: ID_DER_ASN1_DN,
+            data: INITIATOR[127..183].to_vec(),
+        };
+        let email = Identity {
+            kind: ID_RFC822_ADDR,
+            data: b"IKE@initiator.example".to_vec(),
+        };
+        assert!(certifies(INITIATOR, &dn));
+        assert!(certifies(INITIATOR, &email));
+        assert!(certifies(INITIATOR, &Identity::fqdn("Initiator.Example")));
+        assert!(certifies(INITIATOR, &Identity::address("192.0.2.2".parse::<IpAddr>().unwrap())));
+
+        assert!(!certifies(INITIATOR, &Identity::fqdn("responder.example")));
+        assert!(!certifies(INITIATOR, &Identity::address("192.0.2.1".parse::<IpAddr>().unwrap())));
+        let key_id = Identity {
+            kind: ID_KEY_ID,
+            data: b"initiator.example".to_vec(),
+        };
+        assert!(!certifies(INITIATOR, &key_id));
+        assert!(!certifies(&INITIATOR[..200], &Identity::fqdn("initiator.example")));
+    }
+}
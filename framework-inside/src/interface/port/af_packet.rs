@@ -0,0 +1,121 @@
+use super::QueueBackend;
+use common::*;
+use failure::Fail;
+use libc;
+use native::mbuf::{MBuf, MAX_MBUF_SIZE};
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::mem;
+
+/// `sll_pkttype` of frames we sent ourselves; the socket sees those too.
+const PACKET_OUTGOING: u8 = 4;
+
+#[derive(Debug, Fail)]
+#[fail(display = "AF_PACKET socket on {}: {}", _0, _1)]
+pub struct AfPacketError(String, String);
+
+/// Raw `AF_PACKET` socket bound to one Linux interface, for running an NF on a plain box
+/// (e.g. on a veth pair) without DPDK. Needs CAP_NET_RAW.
+pub struct AfPacketBackend {
+    ifname: String,
+    fd: libc::c_int,
+}
+
+impl fmt::Display for AfPacketBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "af_packet {}", self.ifname)
+    }
+}
+
+impl Drop for AfPacketBackend {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl AfPacketBackend {
+    pub fn new(ifname: &str) -> Result<AfPacketBackend> {
+        let error = |what: &str| AfPacketError(ifname.to_string(), format!("{}: {}", what, io::Error::last_os_error()));
+        let c_name = CString::new(ifname).map_err(|_| AfPacketError(ifname.to_string(), "bad name".to_string()))?;
+        let protocol = (libc::ETH_P_ALL as u16).to_be() as libc::c_int;
+        unsafe {
+            let ifindex = libc::if_nametoindex(c_name.as_ptr());
+            if ifindex == 0 {
+                return Err(error("no such interface").into());
+            }
+            let fd = libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_NONBLOCK, protocol);
+            if fd < 0 {
+                return Err(error("socket").into());
+            }
+            let mut addr: libc::sockaddr_ll = mem::zeroed();
+            addr.sll_family = libc::AF_PACKET as u16;
+            addr.sll_protocol = protocol as u16;
+            addr.sll_ifindex = ifindex as i32;
+            let ret = libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+            );
+            if ret != 0 {
+                let err = error("bind");
+                libc::close(fd);
+                return Err(err.into());
+            }
+            Ok(AfPacketBackend {
+                ifname: ifname.to_string(),
+                fd,
+            })
+        }
+    }
+}
+
+impl QueueBackend for AfPacketBackend {
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut count = 0;
+        let mut mbuf = MBuf::alloc_heap();
+        while count < pkts.len() {
+            let mut addr: libc::sockaddr_ll = unsafe { mem::zeroed() };
+            let mut addr_len = mem::size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+            let received = unsafe {
+                libc::recvfrom(
+                    self.fd,
+                    (*mbuf).data_address(0) as *mut libc::c_void,
+                    MAX_MBUF_SIZE as usize,
+                    0,
+                    &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                    &mut addr_len,
+                )
+            };
+            if received < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::Interrupted {
+                    break;
+                }
+                unsafe { MBuf::free_heap(mbuf) };
+                return Err(err.into());
+            }
+            if addr.sll_pkttype == PACKET_OUTGOING {
+                continue;
+            }
+            unsafe { (*mbuf).add_data_end(received as usize) };
+            pkts[count] = mbuf;
+            count += 1;
+            mbuf = MBuf::alloc_heap();
+        }
+        unsafe { MBuf::free_heap(mbuf) };
+        Ok(count as u32)
+    }
+
+    /// Frames the kernel refuses (e.g. a full socket buffer) are dropped.
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        for &mbuf in pkts.iter() {
+            unsafe {
+                let frame = (*mbuf).data();
+                libc::send(self.fd, frame.as_ptr() as *const libc::c_void, frame.len(), 0);
+                MBuf::free_heap(mbuf);
+            }
+        }
+        Ok(pkts.len() as u32)
+    }
+}
@@ -0,0 +1,137 @@
+//! Copy-in/copy-out of packet bytes, for ports configured with `copy = true`.
+//!
+//! By default only mbuf pointers cross into the enclave and the NF works on bytes in the
+//! host's hugepages, which the host can change under its feet. In copy mode a `SimulateQueue`
+//! copies every received packet onto the enclave heap and hands the host mbuf straight back,
+//! then copies what the NF sends into a fresh host mbuf on the way out.
+use native::mbuf::{MBuf, MAX_MBUF_SIZE};
+use native::mbuf_pool;
+use native::mbuf_alloc;
+use operators::BATCH_SIZE;
+use std::cmp::min;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Packets dropped on the way in, for pointing outside host memory, or on the way out, for
+/// want of a host mbuf to copy them to.
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of packets copy mode has dropped, see `DROPPED`.
+pub fn copy_drops() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Whether `len` bytes at `p` lie in host memory, outside the enclave.
+#[cfg(target_env = "sgx")]
+fn in_host_memory(p: *const u8, len: usize) -> bool {
+    ::std::os::fortanix_sgx::mem::is_user_range(p, len)
+}
+
+#[cfg(not(target_env = "sgx"))]
+fn in_host_memory(_p: *const u8, _len: usize) -> bool {
+    true
+}
+
+/// Whether `mbuf` lives on the enclave heap. Inside an enclave this goes by its address rather
+/// than by the `pool` field, which the host can set as it likes on its own mbufs, see
+/// `MBuf::is_heap`. Only these are ever freed to the enclave heap.
+#[inline]
+fn on_enclave_heap(mbuf: *mut MBuf) -> bool {
+    unsafe { (*mbuf).is_heap() }
+}
+
+/// Copies the bytes of host mbuf `mbuf` onto the enclave heap, or returns null if they are not
+/// all in host memory.
+unsafe fn copy_from_host(mbuf: *mut MBuf) -> *mut MBuf {
+    // the offset and length are read once, and the copy is bounded by the size of a heap mbuf
+    // whatever the host wrote there.
+    let data = (*mbuf).data_address(0) as *const u8;
+    let len = min((*mbuf).data_len(), MAX_MBUF_SIZE as usize);
+    if !in_host_memory(data, len) {
+        return ptr::null_mut();
+    }
+    MBuf::alloc_heap_with(slice::from_raw_parts(data, len))
+}
+
+/// Replaces every mbuf of `pkts` by a copy on the enclave heap and frees the originals. Host
+/// mbufs whose bytes are not all in host memory are dropped. Returns how many packets are
+/// left, at the front of `pkts`.
+pub(crate) fn copy_in(pkts: &mut [*mut MBuf]) -> usize {
+    let mut originals = [ptr::null_mut(); BATCH_SIZE];
+    let mut kept = 0;
+    for start in (0..pkts.len()).step_by(BATCH_SIZE) {
+        let mut returned = 0;
+        for i in start..min(pkts.len(), start + BATCH_SIZE) {
+            let original = pkts[i];
+            let copy = unsafe {
+                if on_enclave_heap(original) {
+                    let copy = MBuf::alloc_heap_with((*original).data());
+                    MBuf::free_heap(original);
+                    copy
+                } else if in_host_memory(original as *const u8, mem::size_of::<MBuf>()) {
+                    originals[returned] = original;
+                    returned += 1;
+                    copy_from_host(original)
+                } else {
+                    // not an mbuf the host could have handed over, nor one to hand back.
+                    ptr::null_mut()
+                }
+            };
+            if copy.is_null() {
+                DROPPED.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            pkts[kept] = copy;
+            kept += 1;
+        }
+        if returned > 0 {
+            mbuf_pool::give_back(&originals[..returned]);
+        }
+    }
+    kept
+}
+
+/// Replaces every heap mbuf of `pkts` by a copy in a newly allocated mbuf, which comes from
+/// dpdkIO when it lends us some. Mbufs that already live in host memory are left alone, and
+/// heap mbufs are dropped once no mbuf can be allocated. The dropped ones are moved to the
+/// front of `pkts`, and their number is returned.
+pub(crate) fn copy_out(pkts: &mut [*mut MBuf]) -> usize {
+    let mut dropped = 0;
+    for slot in pkts.iter_mut() {
+        let private = *slot;
+        // anything outside the enclave is the host's, to leave alone and never to free here.
+        if !on_enclave_heap(private) {
+            continue;
+        }
+        let host = mbuf_alloc();
+        unsafe {
+            if !host.is_null() {
+                let data = (*private).data();
+                let len = min(data.len(), (*host).buf_len() - (*host).data_off as usize);
+                ptr::copy_nonoverlapping(data.as_ptr(), (*host).data_address(0), len);
+                (*host).add_data_end(len);
+            } else {
+                dropped += 1;
+            }
+            MBuf::free_heap(private);
+        }
+        *slot = host;
+    }
+    if dropped > 0 {
+        DROPPED.fetch_add(dropped, Ordering::Relaxed);
+        // keeps the order of the rest, which the backend may take only part of.
+        let mut back = pkts.len();
+        for i in (0..pkts.len()).rev() {
+            if !pkts[i].is_null() {
+                back -= 1;
+                pkts[back] = pkts[i];
+            }
+        }
+        for slot in &mut pkts[..back] {
+            *slot = ptr::null_mut();
+        }
+    }
+    dropped
+}
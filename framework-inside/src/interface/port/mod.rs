@@ -1,12 +1,66 @@
+#[cfg(all(target_os = "linux", not(target_env = "sgx")))]
+pub use self::af_packet::*;
+pub use self::copy::copy_drops;
+pub use self::loopback::*;
+#[cfg(not(target_env = "sgx"))]
+pub use self::pcap::*;
+pub use self::ring_backend::*;
 pub use self::sim_port::*;
 use allocators::*;
 use common::*;
+use heap_ring::poll::PollStats;
 use interface::{PacketRx, PacketTx};
 use native::mbuf::MBuf;
+use std::fmt;
 use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
 
+#[cfg(all(target_os = "linux", not(target_env = "sgx")))]
+mod af_packet;
+mod copy;
+mod loopback;
+#[cfg(not(target_env = "sgx"))]
+mod pcap;
+mod ring_backend;
 mod sim_port;
 
+/// Where a `SimulateQueue` gets its packets from and hands them to. Which one is used is
+/// picked from `PortConfiguration.name`, see `BackendSpec`.
+pub trait QueueBackend: Send + Sync + fmt::Display {
+    /// Fills the front of `pkts` with received mbufs and returns how many.
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32>;
+
+    /// Takes ownership of the mbufs at the front of `pkts` and returns how many it took.
+    /// Callers retry the rest, so a backend that cannot make progress must drop instead.
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32>;
+
+    /// Polling and queue-depth statistics, for backends that poll a ring.
+    fn poll_stats(&self) -> Option<PollStats> {
+        None
+    }
+
+    /// Sleeps until packets may have come in, or `timeout` passes. Returns true unless it timed
+    /// out. Backends that cannot tell return false at once.
+    fn wait_for_packets(&self, _timeout: Duration) -> bool {
+        false
+    }
+
+    /// Whether whoever feeds the backend wants the NF to stop, e.g. the host through the
+    /// control area of the shared rings.
+    fn stop_requested(&self) -> bool {
+        false
+    }
+
+    /// Called once the scheduler has stopped, for `stop_requested` or on its own, e.g. after
+    /// its packets or its duration.
+    fn acknowledge_stop(&self) {}
+
+    /// The number the host gave the rings of the queue, if it shares rings with the enclave.
+    fn ring(&self) -> Option<u64> {
+        None
+    }
+}
+
 /// Statistics for PMD port.
 struct PortStats {
     pub stats: AtomicUsize,
@@ -0,0 +1,127 @@
+use super::QueueBackend;
+use common::*;
+use interface::{PacketRx, PacketTx};
+use native::mbuf::MBuf;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use utils::pcap::*;
+
+/// Replays a pcap or pcapng file on `recv` and writes whatever is sent to a pcap file, see
+/// `PcapOptions`. Either side is optional: without an input nothing is received, without an
+/// output sent packets are just freed.
+pub struct PcapPort {
+    options: PcapOptions,
+    replay: Mutex<PcapReplay>,
+    capture: Option<Mutex<BufWriter<File>>>,
+    /// Set by the first empty poll after the last frame.
+    drained: AtomicBool,
+}
+
+impl fmt::Display for PcapPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pcap rx {:?}, tx {:?}", self.options.rx, self.options.tx)
+    }
+}
+
+impl PcapPort {
+    pub fn new(options: &PcapOptions) -> Result<PcapPort> {
+        let frames = match options.rx {
+            Some(ref path) => read_pcap(path)?,
+            None => Vec::new(),
+        };
+        let capture = match options.tx {
+            Some(ref path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                write_pcap_header(&mut out)?;
+                out.flush()?;
+                Some(Mutex::new(out))
+            }
+            None => None,
+        };
+        Ok(PcapPort {
+            options: options.clone(),
+            replay: Mutex::new(PcapReplay::new(frames, options)),
+            capture,
+            drained: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether every frame of the input has been received, as many times as asked for.
+    pub fn replay_done(&self) -> bool {
+        self.replay.lock().unwrap().done()
+    }
+}
+
+impl QueueBackend for PcapPort {
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut replay = self.replay.lock().unwrap();
+        let allowed = replay.allowance(pkts.len());
+        let mut count = 0;
+        while count < allowed {
+            match replay.next_frame() {
+                Some(frame) => pkts[count] = MBuf::alloc_heap_with(frame),
+                None => break,
+            }
+            count += 1;
+        }
+        // an empty poll after the last frame means the scheduler has already sent everything
+        // the NF made of it.
+        if count == 0 && replay.done() && self.options.rx.is_some() {
+            self.drained.store(true, Ordering::Relaxed);
+        }
+        Ok(count as u32)
+    }
+
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let written = match self.capture {
+            Some(ref capture) => {
+                let mut out = capture.lock().unwrap();
+                let now = SystemTime::now();
+                pkts.iter()
+                    .try_for_each(|&mbuf| write_pcap_record(&mut *out, unsafe { (*mbuf).data() }, now))
+                    // keep the file readable even if the NF never exits cleanly.
+                    .and_then(|_| out.flush().map_err(Into::into))
+            }
+            None => Ok(()),
+        };
+        // the packets are ours either way, a failed write must not leak them.
+        for &mbuf in pkts.iter() {
+            unsafe { MBuf::free_heap(mbuf) };
+        }
+        written?;
+        Ok(pkts.len() as u32)
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.options.exit_when_done && self.drained.load(Ordering::Relaxed)
+    }
+
+    fn acknowledge_stop(&self) {
+        if self.stop_requested() {
+            info!("pcap replay of {:?} done, stopping", self.options.rx);
+        }
+        if let Some(ref capture) = self.capture {
+            if let Err(e) = capture.lock().unwrap().flush() {
+                warn!("pcap capture {:?}: {}", self.options.tx, e);
+            }
+        }
+    }
+}
+
+impl PacketRx for PcapPort {
+    #[inline]
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        QueueBackend::recv(self, pkts)
+    }
+}
+
+impl PacketTx for PcapPort {
+    #[inline]
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        QueueBackend::send(self, pkts)
+    }
+}
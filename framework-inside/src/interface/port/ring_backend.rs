@@ -0,0 +1,187 @@
+use super::QueueBackend;
+use attestation;
+use common::*;
+use heap_ring::poll::{PollCounters, PollStats};
+use heap_ring::ring_buffer::*;
+use native::mbuf::MBuf;
+use native::mbuf_pool;
+use provisioning;
+use std::fmt;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The recvq/sendq pair shared with dpdkIO. The ring addresses are handed over by sgx-runner
+/// through the fake HAProxy connection on localhost:6010, together with the mbufq that
+/// `native::mbuf_pool` allocates from and the freeq it returns dropped mbufs to.
+#[derive(Clone)]
+pub struct SharedRingBackend {
+    recvq_ring: RingBuffer,
+    sendq_ring: RingBuffer,
+    /// Polls of recvq; shared by the clones the scheduler hands around, which all run on the
+    /// thread of its scheduler.
+    counters: Arc<PollCounters>,
+    /// The number sgx-runner gave the rings, unique among the queues of its enclaves.
+    ring: Option<u64>,
+}
+
+impl fmt::Display for SharedRingBackend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "shared ring {:p}/{:p}", self.recvq_ring.mem, self.sendq_ring.mem)
+    }
+}
+
+impl SharedRingBackend {
+    /// Waits for sgx-runner to connect, attests to it and attaches to the rings it sends for
+    /// `queue`, of `rxd` and `txd` slots. The IPsec keys of the key server it relays next are installed in
+    /// `provisioning`, which keeps the session for the key server's checkpoint counters.
+    pub fn connect(queue: usize, rxd: usize, txd: usize) -> Result<SharedRingBackend> {
+        let listener = TcpListener::bind("localhost:6010")?;
+        let (stream, peer_addr) = listener.accept()?;
+        let peer_addr = peer_addr.to_string();
+        let local_addr = stream.local_addr()?;
+        eprintln!(
+            "App:: accept  - local address is {}, peer address is {}",
+            local_addr, peer_addr
+        );
+
+        let mut reader = BufReader::new(stream);
+        // the runner only sends the ring addresses once it has verified our report.
+        let session = attestation::respond(&mut reader)?;
+        let queue_addr = session
+            .read_message(&mut reader, "rings")?
+            .iter()
+            .map(|s| s.parse::<u64>())
+            .collect::<::std::result::Result<Vec<u64>, _>>()?;
+        debug!("queue {}: rings at {:?}", queue, queue_addr);
+        let (sas, counters) = provisioning::receive(reader, provisioning::key_server()?.as_ref().map(Vec::as_slice))?;
+        println!("{} IPsec SAs provisioned", sas);
+        if let Some(counters) = counters {
+            provisioning::keep_counter_session(counters);
+        }
+        if queue_addr.len() < 2 {
+            return Err(RingHeaderMismatch(format!("expected at least 2 ring addresses, got {:?}", queue_addr)).into());
+        }
+
+        drop(listener);
+        // refuse to run on rings whose header does not match, rather than corrupting packets.
+        let recvq_ring = unsafe{ RingBuffer::attach_in_heap(rxd, queue_addr[0])? };
+        let sendq_ring = unsafe{ RingBuffer::attach_in_heap(txd, queue_addr[1])? };
+        match queue_addr.get(2) {
+            Some(&mbufq_addr) => {
+                mbuf_pool::lend_from(queue, unsafe { RingBuffer::attach_in_heap(rxd, mbufq_addr)? });
+            }
+            None => warn!("no mbufq from sgx-runner, the enclave cannot allocate packets"),
+        }
+        match queue_addr.get(3) {
+            Some(&freeq_addr) => {
+                mbuf_pool::return_to(queue, unsafe { RingBuffer::attach_in_heap(txd, freeq_addr)? });
+            }
+            None => warn!("no freeq from sgx-runner, dropped packets will leak"),
+        }
+        Ok(SharedRingBackend {
+            recvq_ring, 
+            sendq_ring,
+            counters: Arc::new(PollCounters::default()),
+            ring: queue_addr.get(4).cloned(),
+        })
+    }
+
+    /// Pushes `pkts` to sendq, waiting for room if the host falls behind.
+    #[inline]
+    fn push_to_sendq(&self, pkts: &[*mut MBuf]) {
+        let len = pkts.len();
+        let mut cur_sent = 0;
+        // push len mbuf pointers to sendq.
+        if !pkts.is_empty() {
+            while cur_sent < len {
+                let sent = self.sendq_ring.write_at_tail(&pkts[cur_sent..]);
+                cur_sent += sent;
+            }
+            self.sendq_ring.ring_doorbell();
+        }
+    }
+}
+
+impl QueueBackend for SharedRingBackend {
+    #[inline]
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        // the host drives us through the control area of recvq.
+        match self.recvq_ring.state() {
+            RingState::Running => {}
+            RingState::Paused => {
+                self.recvq_ring.ack_state(RingState::Paused);
+                return Ok(0);
+            }
+            RingState::Draining => {
+                // the previous batches have already been pushed to sendq by the time we come
+                // back here, so an empty recvq means everything has been flushed.
+                let drained = self.recvq_ring.read_from_head(pkts);
+                if drained == 0 {
+                    self.recvq_ring.ack_state(RingState::Draining);
+                }
+                return Ok(drained as u32);
+            }
+            // the scheduler stops at the end of the round, see `stop_requested`.
+            RingState::Stopped => return Ok(0),
+        }
+        if self.recvq_ring.acked_state() != RingState::Running {
+            // back from a pause.
+            self.recvq_ring.ack_state(RingState::Running);
+        }
+
+        // pull packet from recvq;
+        let depth = self.recvq_ring.depth();
+        let received = self.recvq_ring.read_from_head(pkts);
+        self.counters.record_poll(received, depth);
+        Ok(received as u32)
+    }
+
+    #[inline]
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let len = pkts.len();
+        // the host cannot reach mbufs on the enclave heap, those can only be dropped.
+        if pkts.iter().any(|&mbuf| unsafe { (*mbuf).is_heap() }) {
+            warn!("dropping packets allocated on the enclave heap, they cannot leave through sendq");
+            let shared: Vec<*mut MBuf> = pkts
+                .iter()
+                .cloned()
+                .filter(|&mbuf| unsafe {
+                    let heap = (*mbuf).is_heap();
+                    if heap {
+                        MBuf::free_heap(mbuf);
+                    }
+                    !heap
+                })
+                .collect();
+            self.push_to_sendq(&shared);
+        } else {
+            self.push_to_sendq(pkts);
+        }
+        Ok(len as u32)
+    }
+
+    fn poll_stats(&self) -> Option<PollStats> {
+        Some(self.counters.stats())
+    }
+
+    /// The state changes of the host ring the doorbell too, so a pause ends the wait.
+    fn wait_for_packets(&self, timeout: Duration) -> bool {
+        self.recvq_ring.wait_for_doorbell(timeout)
+    }
+
+    fn stop_requested(&self) -> bool {
+        self.recvq_ring.state() == RingState::Stopped
+    }
+
+    /// Also when the NF stops on its own, so the host stops feeding the rings.
+    fn acknowledge_stop(&self) {
+        println!("recvq poller: {}", self.counters.stats());
+        self.recvq_ring.ack_state(RingState::Stopped);
+    }
+
+    fn ring(&self) -> Option<u64> {
+        self.ring
+    }
+}
@@ -1,34 +1,73 @@
 use super::super::{PacketRx, PacketTx};
-use super::PortStats;
+use super::*;
 use allocators::*;
 use common::*;
-use native::mbuf::{MBuf, MAX_MBUF_SIZE};
-use native::{mbuf_alloc_bulk, mbuf_free_bulk};
+use config::PortConfiguration;
+use failure::Fail;
+use heap_ring::poll::PollStats;
+use native::mbuf::MBuf;
+use native::mbuf_pool;
 use std::fmt;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use config::{PortConfiguration, NUM_RXD, NUM_TXD};
-use operators::BATCH_SIZE;
+use std::time::Duration;
+use utils::pcap::PcapOptions;
 
-use std::io::stdout;
-use std::io::Write;
+#[derive(Debug, Fail)]
+#[fail(display = "Bad port name {:?}: {}", _0, _1)]
+pub struct BadPortName(String, String);
 
-use std::io::{BufRead, BufReader};
-use std::net::TcpListener;
-use std::thread;
-use std::sync::Mutex;
-use std::slice;
+/// Backend a port name selects.
+///
+///    loopback: in-process queue, sent packets come back on receive.
+///    pcap:rx=<file>,tx=<file>[,pps=<n>][,loop=<n>][,exit]: replay a capture, write sent
+///        packets to another one, see `PcapOptions`.
+///    af_packet:<ifname>: raw socket on a Linux interface.
+///    SimulateQueue: the rings shared with dpdkIO, the default.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendSpec {
+    SharedRing,
+    Loopback,
+    Pcap(PcapOptions),
+    AfPacket(String),
+}
 
-use heap_ring::ring_buffer::*;
+impl BackendSpec {
+    pub fn parse(name: &str) -> Result<BackendSpec> {
+        let bad = |why: &str| BadPortName(name.to_string(), why.to_string());
+        if name == "loopback" {
+            return Ok(BackendSpec::Loopback);
+        }
+        if name.starts_with("pcap:") {
+            return Ok(BackendSpec::Pcap(PcapOptions::parse(&name["pcap:".len()..])?));
+        }
+        if name.starts_with("af_packet:") {
+            let ifname = &name["af_packet:".len()..];
+            if ifname.is_empty() {
+                return Err(bad("missing interface name").into());
+            }
+            return Ok(BackendSpec::AfPacket(ifname.to_string()));
+        }
+        if name == "SimulateQueue" {
+            return Ok(BackendSpec::SharedRing);
+        }
+        Err(bad("not SimulateQueue, loopback, pcap:... or af_packet:...").into())
+    }
+}
 
 pub struct SimulatePort {
     stats_rx: Arc<CacheAligned<PortStats>>,
     stats_tx: Arc<CacheAligned<PortStats>>,
+    spec: BackendSpec,
+    copy: bool,
+    /// Ring slots of each queue.
+    rxd: usize,
+    txd: usize,
 }
 
 impl fmt::Debug for SimulatePort {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Simulate port")
+        write!(f, "Simulate port ({:?})", self.spec)
     }
 }
 
@@ -36,33 +75,34 @@ impl fmt::Debug for SimulatePort {
 pub struct SimulateQueue {
     stats_rx: Arc<CacheAligned<PortStats>>,
     stats_tx: Arc<CacheAligned<PortStats>>,
-    recvq_ring: RingBuffer,
-    sendq_ring: RingBuffer,
+    backend: Arc<QueueBackend>,
+    /// Copy packet bytes into enclave memory and back, see `copy`.
+    copy: bool,
+    /// The number of the queue on its port, which picks the mbufs of `mbuf_pool` its packets
+    /// use.
+    queue: usize,
 }
 
 impl fmt::Display for SimulateQueue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Simulate queue")
+        if self.copy {
+            write!(f, "Simulate queue ({}, copying)", self.backend)
+        } else {
+            write!(f, "Simulate queue ({})", self.backend)
+        }
     }
 }
 
 impl PacketTx for SimulateQueue {
     #[inline]
     fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
-        let len = pkts.len();
-        let update = self.stats_tx.stats.load(Ordering::Relaxed) + len as usize;
+        mbuf_pool::select(self.queue);
+        // a retried tail has been copied out already, those are left alone.
+        let dropped = if self.copy { copy::copy_out(pkts) } else { 0 };
+        let sent = self.backend.send(&mut pkts[dropped..])?;
+        let update = self.stats_tx.stats.load(Ordering::Relaxed) + sent as usize;
         self.stats_tx.stats.store(update, Ordering::Relaxed);
-
-        let mut cur_sent = 0;
-        // push len mbuf pointers to sendq.
-        if !pkts.is_empty() {
-            while cur_sent < len {
-                let sent = self.sendq_ring.write_at_tail(&mut pkts[cur_sent..]);
-                cur_sent += sent;
-            }
-        }
-        // mbuf_free_bulk(pkts.as_mut_ptr(), len);
-        Ok(len as u32)
+        Ok(dropped as u32 + sent)
     }
 }
 
@@ -71,73 +111,99 @@ impl PacketRx for SimulateQueue {
     /// called).
     #[inline]
     fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
-        // pull packet from recvq;
-        let recv_pkt_num_from_enclave = self.recvq_ring.read_from_head(pkts);
-        // if recv_pkt_num_from_enclave != 0{
-            // println!("{}, {}, {}", recv_pkt_num_from_enclave, self.recvq_ring.tail(), self.recvq_ring.head());
-        // }
-
-        // let status = mbuf_alloc_bulk(pkts.as_mut_ptr(), MAX_MBUF_SIZE, len);
-        // println!("recv1 {}", status); stdout().flush().unwrap();
-        // let alloced = if status == 0 { len } else { 0 };
-        let alloced = recv_pkt_num_from_enclave;
-        let update = self.stats_rx.stats.load(Ordering::Relaxed) + alloced as usize;
+        // what the pipeline allocates and drops until it sends is of this queue.
+        mbuf_pool::select(self.queue);
+        let mut received = self.backend.recv(pkts)?;
+        if self.copy {
+            received = copy::copy_in(&mut pkts[..received as usize]) as u32;
+        }
+        let update = self.stats_rx.stats.load(Ordering::Relaxed) + received as usize;
         self.stats_rx.stats.store(update, Ordering::Relaxed);
-        // println!("rx {}, tx {}", self.stats_rx.stats.load(Ordering::Relaxed), self.stats_tx.stats.load(Ordering::Relaxed)); stdout().flush().unwrap();
-        
-		Ok(alloced as u32)
+		Ok(received)
     }
 }
 
-fn fib(n: u64) -> u64{
-    if n == 0{
-        return 0;
+impl SimulateQueue {
+    /// A queue on top of `backend`, with its own counters. Handy to drive a pipeline from a
+    /// test through a `LoopbackBackend`.
+    pub fn new(backend: Arc<QueueBackend>) -> CacheAligned<SimulateQueue> {
+        SimulateQueue::with_copy(backend, false)
+    }
+
+    /// Like `new`, copying packet bytes into enclave memory on receive and back on send if
+    /// `copy` is set.
+    pub fn with_copy(backend: Arc<QueueBackend>, copy: bool) -> CacheAligned<SimulateQueue> {
+        CacheAligned::allocate(SimulateQueue {
+            stats_rx: Arc::new(PortStats::new()),
+            stats_tx: Arc::new(PortStats::new()),
+            backend,
+            copy,
+            queue: 0,
+        })
+    }
+
+    /// Polling and queue-depth statistics, if the backend polls a ring.
+    pub fn poll_stats(&self) -> Option<PollStats> {
+        self.backend.poll_stats()
+    }
+
+    /// Sleeps until packets may have come in, or `timeout` passes, see
+    /// `QueueBackend::wait_for_packets`.
+    pub fn wait_for_packets(&self, timeout: Duration) -> bool {
+        self.backend.wait_for_packets(timeout)
     }
-    else if n == 1{
-        return 1;
+
+    /// Whether the backend wants the NF to stop.
+    pub fn stop_requested(&self) -> bool {
+        self.backend.stop_requested()
+    }
+
+    /// Tells the backend the NF has stopped, see `QueueBackend::acknowledge_stop`.
+    pub fn acknowledge_stop(&self) {
+        self.backend.acknowledge_stop()
     }
-    else{
-        return fib(n - 1) + fib(n - 2); 
+
+    /// The number sgx-runner gave the rings of the queue, if the backend is shared rings.
+    pub fn ring(&self) -> Option<u64> {
+        self.backend.ring()
     }
 }
 
 impl SimulatePort {
-    pub fn new(port_config: &PortConfiguration) -> Result<Arc<SimulatePort>> {        
+    pub fn new(port_config: &PortConfiguration) -> Result<Arc<SimulatePort>> {
         Ok(Arc::new(SimulatePort {
             stats_rx: Arc::new(PortStats::new()),
             stats_tx: Arc::new(PortStats::new()),
+            spec: BackendSpec::parse(&port_config.name)?,
+            copy: port_config.copy,
+            rxd: port_config.rxd as usize,
+            txd: port_config.txd as usize,
         }))
     }
 
-    pub fn new_simulate_queue(&self, _queue: i32) -> Result<CacheAligned<SimulateQueue>> {
-        let listener = TcpListener::bind("localhost:6010")?;
-        let (stream, peer_addr) = listener.accept()?;
-        let peer_addr = peer_addr.to_string();
-        let local_addr = stream.local_addr()?;
-        println!("new_simulate_queue");
-        eprintln!(
-            "App:: accept  - local address is {}, peer address is {}",
-            local_addr, peer_addr
-        );
-
-        let mut reader = BufReader::new(stream);
-        let mut message = String::new();
-        
-        let read_bytes = reader.read_line(&mut message)?;
-        print!("{}", message);
-        let queue_addr: Vec<u64> = 
-                message.trim().split(' ')
-            .map(|s| s.parse().unwrap())
-            .collect();
-        println!("{:?}", queue_addr);
-            // fib(30);
-
-        drop(listener);
+    pub fn new_simulate_queue(&self, queue: i32) -> Result<CacheAligned<SimulateQueue>> {
+        let backend: Arc<QueueBackend> = match self.spec {
+            BackendSpec::SharedRing => Arc::new(SharedRingBackend::connect(queue as usize, self.rxd, self.txd)?),
+            BackendSpec::Loopback => Arc::new(LoopbackBackend::new(self.rxd)),
+            // every queue would see the same packets.
+            _ if queue != 0 => {
+                return Err(BadPortName(format!("{:?}", self.spec), "supports a single queue only".to_string()).into());
+            }
+            #[cfg(not(target_env = "sgx"))]
+            BackendSpec::Pcap(ref options) => Arc::new(PcapPort::new(options)?),
+            #[cfg(all(target_os = "linux", not(target_env = "sgx")))]
+            BackendSpec::AfPacket(ref ifname) => Arc::new(AfPacketBackend::new(ifname)?),
+            #[allow(unreachable_patterns)]
+            _ => {
+                return Err(BadPortName(format!("{:?}", self.spec), "not available in an enclave".to_string()).into());
+            }
+        };
         Ok(CacheAligned::allocate(SimulateQueue {
             stats_rx: self.stats_rx.clone(),
             stats_tx: self.stats_tx.clone(),
-            recvq_ring: unsafe{ RingBuffer::attach_in_heap((NUM_RXD) as usize, queue_addr[0]).unwrap() }, 
-            sendq_ring: unsafe{ RingBuffer::attach_in_heap((NUM_TXD) as usize, queue_addr[1]).unwrap() },
+            backend,
+            copy: self.copy,
+            queue: queue as usize,
         }))
     }
 
@@ -149,3 +215,136 @@ impl SimulatePort {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use utils::pcap::*;
+
+    #[test]
+    fn parse_port_names() {
+        assert_eq!(BackendSpec::parse("SimulateQueue").unwrap(), BackendSpec::SharedRing);
+        assert_eq!(BackendSpec::parse("loopback").unwrap(), BackendSpec::Loopback);
+        assert_eq!(
+            BackendSpec::parse("pcap:rx=in.pcap,tx=out.pcap").unwrap(),
+            BackendSpec::Pcap(PcapOptions {
+                rx: Some("in.pcap".to_string()),
+                tx: Some("out.pcap".to_string()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            BackendSpec::parse("pcap:tx=out.pcap").unwrap(),
+            BackendSpec::Pcap(PcapOptions { tx: Some("out.pcap".to_string()), ..Default::default() })
+        );
+        assert_eq!(BackendSpec::parse("af_packet:veth0").unwrap(), BackendSpec::AfPacket("veth0".to_string()));
+        assert!(BackendSpec::parse("pcap:").is_err());
+        assert!(BackendSpec::parse("pcap:in.pcap").is_err());
+        assert!(BackendSpec::parse("af_packet:").is_err());
+        assert!(BackendSpec::parse("0000:02:00.0").is_err());
+        assert!(BackendSpec::parse("simulatequeue").is_err());
+    }
+
+    #[test]
+    fn loopback_round_trip() {
+        let loopback = LoopbackBackend::new(4);
+        let queue = SimulateQueue::new(Arc::new(loopback.clone()));
+        for i in 0..5u8 {
+            assert_eq!(loopback.inject(&[i; 60]), i < 4);
+        }
+
+        let mut pkts = vec![0 as *mut MBuf; 8];
+        assert_eq!(queue.recv(&mut pkts).unwrap(), 4);
+        assert!(loopback.is_empty());
+        assert_eq!(unsafe { (*pkts[2]).data() }, &[2u8; 60][..]);
+        assert_eq!(queue.send(&mut pkts[..4]).unwrap(), 4);
+
+        let frames = loopback.drain_frames();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[3], vec![3u8; 60]);
+        assert!(queue.poll_stats().is_none());
+    }
+
+    #[test]
+    fn copy_mode_round_trip() {
+        let loopback = LoopbackBackend::new(4);
+        let queue = SimulateQueue::with_copy(Arc::new(loopback.clone()), true);
+        assert!(loopback.inject(&[7; 60]));
+        assert!(loopback.inject(&[8; 1500]));
+
+        let mut pkts = vec![0 as *mut MBuf; 4];
+        assert_eq!(queue.recv(&mut pkts).unwrap(), 2);
+        unsafe {
+            assert!((*pkts[0]).is_heap());
+            assert_eq!((*pkts[1]).data(), &[8u8; 1500][..]);
+            // the NF rewrites its private copy; the change has to make it out.
+            *(*pkts[0]).data_address(0) = 9;
+        }
+        assert_eq!(queue.send(&mut pkts[..2]).unwrap(), 2);
+
+        let frames = loopback.drain_frames();
+        assert_eq!(frames[0][..2], [9, 7]);
+        assert_eq!(frames[1], vec![8u8; 1500]);
+    }
+
+    #[test]
+    fn pcap_replay_and_capture() {
+        let dir = ::std::env::temp_dir();
+        let input = dir.join(format!("sim_port_in_{}.pcap", ::std::process::id()));
+        let output = dir.join(format!("sim_port_out_{}.pcap", ::std::process::id()));
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 64 + i as usize]).collect();
+        {
+            let mut file = ::std::fs::File::create(&input).unwrap();
+            write_pcap_header(&mut file).unwrap();
+            for frame in &frames {
+                write_pcap_record(&mut file, frame, ::std::time::SystemTime::now()).unwrap();
+            }
+        }
+
+        let spec = format!("rx={},tx={},loop=2,exit", input.display(), output.display());
+        let port = Arc::new(PcapPort::new(&PcapOptions::parse(&spec).unwrap()).unwrap());
+        let queue = SimulateQueue::new(port.clone());
+        let mut pkts = vec![0 as *mut MBuf; 4];
+        assert_eq!(queue.recv(&mut pkts).unwrap(), 4);
+        assert_eq!(queue.send(&mut pkts).unwrap(), 4);
+        assert!(!port.replay_done());
+        assert_eq!(queue.recv(&mut pkts).unwrap(), 2);
+        assert!(port.replay_done());
+        assert_eq!(queue.send(&mut pkts[..2]).unwrap(), 2);
+        // the NF stops once it has come back empty-handed after the last frame.
+        assert!(!queue.stop_requested());
+        assert_eq!(queue.recv(&mut pkts).unwrap(), 0);
+        assert!(queue.stop_requested());
+        queue.acknowledge_stop();
+
+        let twice: Vec<Vec<u8>> = frames.iter().chain(frames.iter()).cloned().collect();
+        assert_eq!(read_pcap(output.to_str().unwrap()).unwrap(), twice);
+        let _ = ::std::fs::remove_file(&input);
+        let _ = ::std::fs::remove_file(&output);
+    }
+
+    #[test]
+    fn pcap_pacing() {
+        let input = ::std::env::temp_dir().join(format!("sim_port_paced_{}.pcap", ::std::process::id()));
+        {
+            let mut file = ::std::fs::File::create(&input).unwrap();
+            write_pcap_header(&mut file).unwrap();
+            write_pcap_record(&mut file, &[0; 60], ::std::time::SystemTime::now()).unwrap();
+        }
+        let spec = format!("rx={},pps=100,loop=0", input.display());
+        let port = Arc::new(PcapPort::new(&PcapOptions::parse(&spec).unwrap()).unwrap());
+        let queue = SimulateQueue::new(port.clone());
+        let mut pkts = vec![0 as *mut MBuf; 32];
+        let start = ::std::time::Instant::now();
+        let mut received = 0;
+        while received < 6 {
+            let n = queue.recv(&mut pkts).unwrap();
+            queue.send(&mut pkts[..n as usize]).unwrap();
+            received += n;
+        }
+        // 100 pps: the sixth packet is due after 50ms.
+        assert!(start.elapsed() >= ::std::time::Duration::from_millis(50));
+        assert!(!port.replay_done());
+        let _ = ::std::fs::remove_file(&input);
+    }
+}
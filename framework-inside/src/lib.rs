@@ -13,6 +13,7 @@
 
 // For cache aware allocation
 extern crate alloc;
+extern crate bincode;
 extern crate config as config_rs;
 extern crate crossbeam;
 #[cfg_attr(test, macro_use)]
@@ -25,11 +26,14 @@ extern crate lazy_static;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate pcapfile;
+extern crate protocol;
 #[cfg(unix)]
 extern crate regex;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate sharedring;
 extern crate twox_hash;
 
 #[cfg(test)]
@@ -38,11 +42,15 @@ extern crate proptest;
 
 // extern crate openssl;
 extern crate mbedtls;
+#[cfg(target_env = "sgx")]
+extern crate sgx_isa;
 
 // need these first so other modules in netbricks can use the macros
 #[macro_use]
 pub mod common;
 pub mod allocators;
+pub mod attestation;
+pub mod checkpoint;
 pub mod config;
 pub mod interface;
 pub mod scheduler;
@@ -51,9 +59,12 @@ pub mod native;
 pub mod native_include;
 pub mod operators;
 pub mod packets;
+pub mod provisioning;
 // pub mod shared_state;
 // pub mod state;
 // pub mod shared_ring;
+pub mod usercalls;
 pub mod utils;
 // pub mod runtime;
 pub mod heap_ring;
+pub mod ike;
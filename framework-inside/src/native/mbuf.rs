@@ -1,7 +1,23 @@
 use super::super::native_include as ldpdk;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cmp::min;
+use std::mem;
+use std::ptr;
 // use self::ldpdk::*;
 pub type MBuf = ldpdk::rte_mbuf;
 pub const MAX_MBUF_SIZE: u16 = 2048;
+/// Headroom left in front of the data of a heap mbuf, same as DPDK's default.
+pub const MBUF_HEADROOM: u16 = 128;
+
+/// Layout of a heap mbuf: the mbuf header immediately followed by its buffer.
+#[inline]
+fn heap_mbuf_layout() -> Layout {
+    Layout::from_size_align(
+        mem::size_of::<MBuf>() + (MBUF_HEADROOM + MAX_MBUF_SIZE) as usize,
+        mem::align_of::<MBuf>().max(64),
+    )
+    .unwrap()
+}
 
 impl Drop for MBuf {
     fn drop(&mut self) {
@@ -54,6 +70,63 @@ impl MBuf {
     //     }
     // }
 
+    /// Allocates an empty mbuf on the enclave heap, for ports that do not get their packets
+    /// from DPDK. Free them with `free_heap`.
+    pub fn alloc_heap() -> *mut MBuf {
+        unsafe {
+            let mbuf = alloc_zeroed(heap_mbuf_layout()) as *mut MBuf;
+            assert!(!mbuf.is_null(), "out of memory allocating an mbuf");
+            (*mbuf).buf_addr = mbuf.offset(1) as *mut _;
+            (*mbuf).buf_len = MBUF_HEADROOM + MAX_MBUF_SIZE;
+            (*mbuf).data_off = MBUF_HEADROOM;
+            (*mbuf).nb_segs = 1;
+            (*mbuf).__bindgen_anon_1.refcnt = 1;
+            mbuf
+        }
+    }
+
+    /// Allocates a heap mbuf holding a copy of `data`, truncated to `MAX_MBUF_SIZE`.
+    pub fn alloc_heap_with(data: &[u8]) -> *mut MBuf {
+        let mbuf = MBuf::alloc_heap();
+        unsafe {
+            let len = min(data.len(), MAX_MBUF_SIZE as usize);
+            ptr::copy_nonoverlapping(data.as_ptr(), (*mbuf).data_address(0), len);
+            (*mbuf).add_data_end(len);
+        }
+        mbuf
+    }
+
+    /// Frees an mbuf obtained from `alloc_heap`. Does nothing for DPDK mbufs, which are the
+    /// host's to free, see `is_heap`.
+    pub unsafe fn free_heap(mbuf: *mut MBuf) {
+        if !mbuf.is_null() && (*mbuf).is_heap() {
+            dealloc(mbuf as *mut u8, heap_mbuf_layout());
+        }
+    }
+
+    /// Whether this mbuf lives on the enclave heap rather than in a DPDK mempool. Inside an
+    /// enclave this goes by the address of the whole heap mbuf, never by its `pool` field,
+    /// which the host can set as it likes on the mbufs it lends us.
+    #[cfg(target_env = "sgx")]
+    #[inline]
+    pub fn is_heap(&self) -> bool {
+        let start = self as *const MBuf as *const u8;
+        ::std::os::fortanix_sgx::mem::is_enclave_range(start, heap_mbuf_layout().size())
+    }
+
+    /// Whether this mbuf lives on the enclave heap rather than in a DPDK mempool.
+    #[cfg(not(target_env = "sgx"))]
+    #[inline]
+    pub fn is_heap(&self) -> bool {
+        self.pool.is_null()
+    }
+
+    /// The packet bytes of this (single segment) mbuf.
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.data_address(0), self.data_len()) }
+    }
+
     #[inline]
     pub fn write_metadata_slot(mbuf: *mut MBuf, slot: usize, value: usize) {
         unsafe {
@@ -0,0 +1,141 @@
+//! Mbufs lent to the enclave by dpdkIO, and handed back to it.
+//!
+//! The enclave cannot take mbufs from the host's DPDK mempool itself, so dpdkIO keeps a third
+//! ring per queue of the enclave, the mbufq, topped up with empty mbufs from that mempool.
+//! Packets built from them leave through sendq like received ones, and the host transmits and
+//! frees them as usual. Ports without an mbufq (pcap, loopback, ...) allocate on the enclave
+//! heap instead.
+//!
+//! Nor can the enclave free a DPDK mbuf. Packets it drops go back on a fourth ring per queue,
+//! the freeq, which dpdkIO drains and bulk-frees.
+//!
+//! A thread takes mbufs from, and gives them back to, the rings of the queue it last `select`ed:
+//! that of the packets it is processing. Those of another queue do if that one has none.
+use heap_ring::ring_buffer::RingBuffer;
+use native::mbuf::MBuf;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ptr;
+use std::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// Mbufs taken off the mbufq at once, so small allocations do not touch the ring every time.
+const STASH_REFILL: usize = 32;
+
+struct LentPool {
+    mbufq: RingBuffer,
+    stash: Vec<*mut MBuf>,
+}
+
+// the mbufs belong to whoever holds the lock.
+unsafe impl Send for LentPool {}
+
+struct FreeRing(RingBuffer);
+
+unsafe impl Send for FreeRing {}
+
+lazy_static! {
+    static ref LENT_POOLS: Mutex<BTreeMap<usize, LentPool>> = Mutex::new(BTreeMap::new());
+    static ref FREEQS: Mutex<BTreeMap<usize, FreeRing>> = Mutex::new(BTreeMap::new());
+}
+
+thread_local! {
+    static QUEUE: Cell<usize> = Cell::new(0);
+}
+
+/// The rings of the queue this thread selected, or of another queue if it has none.
+fn of_queue<T>(rings: &mut BTreeMap<usize, T>) -> Option<&mut T> {
+    let queue = QUEUE.with(Cell::get);
+    if rings.contains_key(&queue) {
+        rings.get_mut(&queue)
+    } else {
+        rings.values_mut().next()
+    }
+}
+
+/// Attempts `give_back` makes at a full freeq before it gives up on the host.
+const GIVE_BACK_RETRIES: usize = 1024;
+
+/// DPDK mbufs dropped while no freeq was attached, or while the host left it full; the host
+/// never gets those back.
+static LEAKED: AtomicUsize = AtomicUsize::new(0);
+
+/// Allocate from `mbufq` for the packets of `queue` from now on. Attaching another mbufq to
+/// the queue replaces it.
+pub fn lend_from(queue: usize, mbufq: RingBuffer) {
+    let pool = LentPool {
+        mbufq,
+        stash: Vec::with_capacity(STASH_REFILL * 2),
+    };
+    if LENT_POOLS.lock().unwrap().insert(queue, pool).is_some() {
+        warn!("replacing the lent mbuf pool of queue {}", queue);
+    }
+}
+
+/// Take and give back the mbufs of `queue` on this thread, until another is selected.
+#[inline]
+pub fn select(queue: usize) {
+    QUEUE.with(|current| current.set(queue));
+}
+
+/// Whether mbufs come from dpdkIO rather than from the enclave heap.
+pub fn is_lent() -> bool {
+    !LENT_POOLS.lock().unwrap().is_empty()
+}
+
+/// Fills all of `out` with lent mbufs, or none of it if the mbufq runs dry. `None` when no
+/// mbufq is attached.
+pub(crate) fn take(out: &mut [*mut MBuf]) -> Option<bool> {
+    let mut pools = LENT_POOLS.lock().unwrap();
+    let pool = of_queue(&mut pools)?;
+    let have = pool.stash.len();
+    if have < out.len() {
+        pool.stash.resize(have + out.len().max(STASH_REFILL), ptr::null_mut());
+        let got = pool.mbufq.read_from_head(&mut pool.stash[have..]);
+        pool.stash.truncate(have + got);
+    }
+    if pool.stash.len() < out.len() {
+        return Some(false);
+    }
+    let from = pool.stash.len() - out.len();
+    out.copy_from_slice(&pool.stash[from..]);
+    pool.stash.truncate(from);
+    Some(true)
+}
+
+/// Hand the dropped DPDK mbufs of `queue` back through `freeq` from now on.
+pub fn return_to(queue: usize, freeq: RingBuffer) {
+    if FREEQS.lock().unwrap().insert(queue, FreeRing(freeq)).is_some() {
+        warn!("replacing the freeq of queue {}", queue);
+    }
+}
+
+/// Number of DPDK mbufs dropped without a freeq to return them to, or that did not fit it.
+pub fn leaked() -> usize {
+    LEAKED.load(Ordering::Relaxed)
+}
+
+/// Pushes `mbufs`, none of which may live on the enclave heap, to the freeq, retrying a while
+/// if the host falls behind. Those still left after that, or all of them without a freeq, are
+/// leaked.
+pub(crate) fn give_back(mbufs: &[*mut MBuf]) {
+    let mut rings = FREEQS.lock().unwrap();
+    let returned = match of_queue(&mut rings) {
+        Some(&mut FreeRing(ref freeq)) => {
+            let mut returned = 0;
+            for _ in 0..GIVE_BACK_RETRIES {
+                returned += freeq.write_at_tail(&mbufs[returned..]);
+                if returned == mbufs.len() {
+                    return;
+                }
+                spin_loop_hint();
+            }
+            returned
+        }
+        None => 0,
+    };
+    drop(rings);
+    if LEAKED.fetch_add(mbufs.len() - returned, Ordering::Relaxed) == 0 {
+        warn!("dropped mbufs are leaked, sgx-runner attached no freeq or dpdkIO does not drain it");
+    }
+}
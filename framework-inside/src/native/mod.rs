@@ -1,21 +1,72 @@
 pub mod mbuf;
+pub mod mbuf_pool;
 use self::mbuf::MBuf;
+use std::ptr;
+use std::slice;
 
+/// Fills `array` with `cnt` empty mbufs holding `len` bytes each, taken from the mbufs dpdkIO
+/// lends us or, without an mbufq, from the enclave heap. Returns 0 on success and -1 if the
+/// lent pool is exhausted, in which case nothing is allocated, like `rte_pktmbuf_alloc_bulk`.
 pub fn mbuf_alloc_bulk(array: *mut *mut MBuf, len: u16, cnt: i32) -> i32
 {
-    unsafe{        
-        for i in 0..cnt {
-            // *(array.offset(i as isize)) = &mut MBuf::new(len as u32);
+    let mbufs = unsafe { slice::from_raw_parts_mut(array, cnt as usize) };
+    match mbuf_pool::take(mbufs) {
+        Some(true) => {}
+        Some(false) => return -1,
+        None => {
+            for slot in mbufs.iter_mut() {
+                *slot = MBuf::alloc_heap();
+            }
+        }
+    }
+    for &mbuf in mbufs.iter() {
+        unsafe {
+            (*mbuf).data_len = len;
+            (*mbuf).pkt_len = u32::from(len);
         }
     }
     0
 }
+
+/// Allocates one empty mbuf, see `mbuf_alloc_bulk`. Returns null if none is left.
+pub fn mbuf_alloc() -> *mut MBuf {
+    let mut mbuf = ptr::null_mut();
+    if mbuf_alloc_bulk(&mut mbuf, 0, 1) == 0 {
+        mbuf
+    } else {
+        ptr::null_mut()
+    }
+}
+
+/// DPDK mbufs `mbuf_free_bulk` collects before handing them back at once.
+const FREE_CHUNK: usize = 64;
+
+/// Frees the first `cnt` entries of `array`. Heap mbufs go back to the enclave heap, anything
+/// else to dpdkIO through the freeq, see `mbuf_pool` and `MBuf::is_heap`.
 pub fn mbuf_free_bulk(array: *mut *mut MBuf, cnt: i32) -> i32
 {
-    unsafe{        
-        for i in 0..cnt {
-            // drop(*(array.offset(i as isize)));
+    let mbufs = unsafe { slice::from_raw_parts(array, cnt as usize) };
+    let mut shared = [ptr::null_mut(); FREE_CHUNK];
+    let mut count = 0;
+    for &mbuf in mbufs.iter() {
+        if mbuf.is_null() {
+            continue;
+        }
+        unsafe {
+            if (*mbuf).is_heap() {
+                MBuf::free_heap(mbuf);
+                continue;
+            }
+        }
+        shared[count] = mbuf;
+        count += 1;
+        if count == FREE_CHUNK {
+            mbuf_pool::give_back(&shared);
+            count = 0;
         }
     }
+    if count > 0 {
+        mbuf_pool::give_back(&shared[..count]);
+    }
     0
-}
\ No newline at end of file
+}
@@ -0,0 +1,283 @@
+use super::{Batch, PacketError, BATCH_SIZE};
+use failure::Error;
+use native::mbuf::MAX_MBUF_SIZE;
+use packets::ip::TunnelPacket;
+use packets::{buffer, Packet};
+use std::collections::VecDeque;
+use std::mem;
+use utils::ipsec::{CryptoError, EspJob, SecurityAssociationDatabase};
+
+/// Room for the output of a packet: the largest frame, with ESP overhead and a cipher block.
+const SCRATCH_LEN: usize = MAX_MBUF_SIZE as usize + 64;
+
+/// What `crypto_map` does to the IP payload of a packet.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EspOp {
+    /// Checks and decrypts the ESP packet the payload is, and puts the plaintext in its place.
+    Decrypt,
+    /// Encrypts the payload with outbound SA `spi` into an ESP packet that takes its place.
+    Encrypt(u32),
+}
+
+/// Batched ESP operator
+///
+/// Unlike the lazily-evaluated operators, receives the whole batch of its
+/// source at once and encrypts and decrypts the packets together, so that the
+/// packets of an SA share its lookup and cipher context (see
+/// `SecurityAssociationDatabase::encrypt_batch`). The frames are resized to
+/// their new payloads and cascaded. Packets that fail are aborted.
+///
+/// The payloads are taken as they are, as `SecurityAssociationDatabase::encrypt`
+/// and `decrypt` take them: the ESP trailer is the pipeline's to add before
+/// encrypting and to check after decrypting, and so is the protocol of the IP
+/// header. `esp_encap` and `esp_decap` do all of it for tunnel mode.
+pub struct CryptoMapBatch<B: Batch, F>
+where
+    B::Item: TunnelPacket,
+    F: FnMut(&B::Item) -> Option<EspOp>,
+{
+    source: B,
+    sad: &'static SecurityAssociationDatabase,
+    select: F,
+    packets: VecDeque<Result<B::Item, PacketError>>,
+    /// The operations of the batch, as (position in the batch, operation, payload offset,
+    /// payload length), the jobs they make and their results. Kept, like the output buffers,
+    /// from batch to batch.
+    ops: Vec<(usize, EspOp, usize, usize)>,
+    jobs: Vec<EspJob<'static>>,
+    results: Vec<Result<usize, CryptoError>>,
+    scratch: Vec<Vec<u8>>,
+}
+
+impl<B: Batch, F> CryptoMapBatch<B, F>
+where
+    B::Item: TunnelPacket,
+    F: FnMut(&B::Item) -> Option<EspOp>,
+{
+    #[inline]
+    pub fn new(source: B, sad: &'static SecurityAssociationDatabase, select: F) -> Self {
+        CryptoMapBatch {
+            source,
+            sad,
+            select,
+            packets: VecDeque::with_capacity(BATCH_SIZE),
+            ops: Vec::with_capacity(BATCH_SIZE),
+            jobs: Vec::with_capacity(BATCH_SIZE),
+            results: Vec::with_capacity(BATCH_SIZE),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Runs the operations `select` picks on the packets received.
+    fn process(&mut self) {
+        self.ops.clear();
+        for (at, packet) in self.packets.iter().enumerate() {
+            if let Ok(ref packet) = *packet {
+                if let Some(op) = (self.select)(packet) {
+                    let len = packet.total_len().saturating_sub(packet.header_len());
+                    self.ops.push((at, op, packet.payload_offset(), len));
+                }
+            }
+        }
+        // decryptions first, then encryptions, each in the order of the batch.
+        self.ops.sort_unstable_by_key(|&(at, op, _, _)| (op != EspOp::Decrypt, at));
+        let decrypts = self.ops.iter().take_while(|&&(_, op, _, _)| op == EspOp::Decrypt).count();
+        if self.ops.len() > self.scratch.len() {
+            self.scratch.resize(self.ops.len(), vec![0u8; SCRATCH_LEN]);
+        }
+
+        self.results.clear();
+        {
+            let mut jobs = recycle(mem::replace(&mut self.jobs, Vec::new()));
+            for (&(at, op, offset, len), output) in self.ops.iter().zip(self.scratch.iter_mut()) {
+                let mbuf = match self.packets[at] {
+                    Ok(ref packet) => packet.mbuf(),
+                    Err(_) => unreachable!(),
+                };
+                let spi = match op {
+                    EspOp::Decrypt => 0,
+                    EspOp::Encrypt(spi) => spi,
+                };
+                // short frames are padded on the wire; the IP header has the real length.
+                if offset + len > unsafe { (*mbuf).data_len() } || len + 64 > SCRATCH_LEN {
+                    let mut job = EspJob::new(spi, &[], output);
+                    job.result = Err(CryptoError::PktlenError);
+                    jobs.push(job);
+                    continue;
+                }
+                let input = unsafe { &*buffer::read_slice::<u8>(mbuf, offset, len).unwrap() };
+                jobs.push(EspJob::new(spi, input, output));
+            }
+            {
+                let (decrypt, encrypt) = jobs.split_at_mut(decrypts);
+                self.sad.decrypt_batch(decrypt);
+                self.sad.encrypt_batch(encrypt);
+            }
+            self.results.extend(jobs.drain(..).map(|job| job.result));
+            self.jobs = recycle(jobs);
+        }
+
+        for (k, (&(at, _, offset, _), result)) in self.ops.iter().zip(self.results.drain(..)).enumerate() {
+            let scratch = &self.scratch[k];
+            let aborted = match self.packets[at] {
+                Ok(ref mut packet) => result
+                    .map_err(Error::from)
+                    .and_then(|len| replace_payload(packet, offset, &scratch[..len]))
+                    .err()
+                    .map(|e| PacketError::Abort(packet.mbuf(), e)),
+                Err(_) => unreachable!(),
+            };
+            if let Some(aborted) = aborted {
+                self.packets[at] = Err(aborted);
+            }
+        }
+    }
+}
+
+/// `jobs`, emptied, to hold jobs of another lifetime, so that its room is kept from batch to
+/// batch.
+fn recycle<'a, 'b>(mut jobs: Vec<EspJob<'a>>) -> Vec<EspJob<'b>> {
+    jobs.clear();
+    // an empty vector borrows nothing.
+    unsafe { mem::transmute(jobs) }
+}
+
+/// Puts `payload` in place of everything from `offset` on in the frame of `packet`.
+fn replace_payload<T: TunnelPacket>(packet: &mut T, offset: usize, payload: &[u8]) -> Result<(), Error> {
+    let mbuf = packet.mbuf();
+    let data_len = unsafe { (*mbuf).data_len() };
+    let to_len = offset + payload.len();
+    if to_len > data_len {
+        buffer::alloc(mbuf, data_len, to_len - data_len)?;
+    } else if to_len < data_len {
+        buffer::trim(mbuf, to_len)?;
+    }
+    buffer::write_slice(mbuf, offset, payload)?;
+    packet.cascade();
+    Ok(())
+}
+
+impl<B: Batch, F> Batch for CryptoMapBatch<B, F>
+where
+    B::Item: TunnelPacket,
+    F: FnMut(&B::Item) -> Option<EspOp>,
+{
+    type Item = B::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Result<Self::Item, PacketError>> {
+        self.packets.pop_front()
+    }
+
+    #[inline]
+    fn receive(&mut self) {
+        self.source.receive();
+        self.packets.clear();
+        while let Some(item) = self.source.next() {
+            self.packets.push_back(item);
+        }
+        self.process();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packets::ip::v4::Ipv4;
+    use packets::ip::{IpPacket, ProtocolNumbers};
+    use packets::{Ethernet, RawPacket};
+    use provisioning::{self, SaKeys};
+    use std::net::Ipv4Addr;
+    use utils::ipsec::{Direction, EspCipher, SecurityAssociation, SAD};
+
+    /// One batch of parsed packets.
+    struct Packets<T: Packet>(Vec<T>);
+
+    impl<T: Packet> Batch for Packets<T> {
+        type Item = T;
+
+        fn next(&mut self) -> Option<Result<T, PacketError>> {
+            self.0.pop().map(Ok)
+        }
+
+        fn receive(&mut self) {}
+    }
+
+    /// A UDP packet to 10.0.2.`host` with 5 bytes of payload.
+    fn frame(host: u8) -> Vec<u8> {
+        let mut frame = vec![0u8; 47];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14..24].copy_from_slice(&[0x45, 0, 0, 33, 0, 0, 0, 0, 64, 17]);
+        frame[26..34].copy_from_slice(&[10, 0, 1, 1, 10, 0, 2, host]);
+        frame[34..47].copy_from_slice(&[0x30, 0x39, 0x30, 0x39, 0, 13, 0, 0, b'h', b'e', b'l', b'l', b'o']);
+        frame
+    }
+
+    fn parse(frame: &[u8]) -> Ipv4 {
+        RawPacket::from_bytes(frame).unwrap().parse::<Ethernet>().unwrap().parse::<Ipv4>().unwrap()
+    }
+
+    fn host(packet: &Ipv4) -> u8 {
+        packet.dst().octets()[3]
+    }
+
+    #[test]
+    fn batch_round_trip() {
+        let ciphers = [(0x7001, EspCipher::AesGcm128), (0x7002, EspCipher::ChaCha20Poly1305)];
+        for &(spi, cipher) in ciphers.iter() {
+            let enc_key = if cipher == EspCipher::AesGcm128 { vec![0x33; 20] } else { vec![0x33; 36] };
+            provisioning::install(SaKeys::new(spi, enc_key, Vec::new()).unwrap());
+            SAD.install(SecurityAssociation::new(spi, Direction::Outbound, cipher));
+            SAD.install(SecurityAssociation::new(spi, Direction::Inbound, cipher));
+
+            // host 4 goes out in the clear, and host 5 has no SA.
+            let frames: Vec<_> = (1..6).map(frame).collect();
+            let mut encrypt = Packets(frames.iter().map(|frame| parse(frame)).collect()).crypto_map(&SAD, |packet| {
+                match host(packet) {
+                    4 => None,
+                    5 => Some(EspOp::Encrypt(0x70ff)),
+                    _ => Some(EspOp::Encrypt(spi)),
+                }
+            });
+            encrypt.receive();
+            let mut protected = Vec::new();
+            while let Some(item) = encrypt.next() {
+                match item {
+                    Ok(packet) => protected.push(packet),
+                    Err(PacketError::Abort(mbuf, _)) => {
+                        assert_eq!(unsafe { (*mbuf).data() }, &frames[4][..]);
+                    }
+                    Err(_) => panic!("packet neither encrypted nor aborted"),
+                }
+            }
+            assert_eq!(protected.len(), 4);
+            for packet in protected.iter() {
+                if host(packet) == 4 {
+                    assert_eq!(packet.total_len(), 33);
+                } else {
+                    assert_eq!(packet.total_len(), 20 + 13 + cipher.overhead());
+                    assert_eq!(packet.len(), packet.total_len());
+                }
+            }
+
+            let mut decrypt = Packets(protected).crypto_map(&SAD, |packet| {
+                if host(packet) == 4 {
+                    None
+                } else {
+                    Some(EspOp::Decrypt)
+                }
+            });
+            decrypt.receive();
+            let mut hosts = Vec::new();
+            while let Some(item) = decrypt.next() {
+                let packet = item.ok().unwrap();
+                assert_eq!(packet.next_proto(), ProtocolNumbers::Udp);
+                let at = host(&packet) as usize - 1;
+                assert_eq!(unsafe { (*packet.mbuf()).data() }, &frames[at][..]);
+                hosts.push(at + 1);
+            }
+            hosts.sort();
+            assert_eq!(hosts, vec![1, 2, 3, 4]);
+        }
+    }
+}
@@ -0,0 +1,356 @@
+use super::{Batch, PacketError};
+use failure::Error;
+use native::mbuf::MBuf;
+use packets::ip::{IpPacket, ProtocolNumber, ProtocolNumbers, TunnelPacket};
+use packets::{buffer, Ethernet, Fixed, Packet, RawPacket};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::ptr;
+use utils::ipsec::{
+    esp_spi, CryptoError, Direction, PolicyAction, SecurityAssociationDatabase, SecurityPolicyDatabase,
+    ESP_TRAILER_LENGTH, MAX_PKT_SIZE,
+};
+
+thread_local! {
+    /// Where packets are decrypted to, and put together with their trailer to be encrypted.
+    static SCRATCH: RefCell<Vec<u8>> = RefCell::new(vec![0u8; MAX_PKT_SIZE]);
+}
+
+/// Puts the IP packet in front of the Ethernet header, `len` bytes into the frame, back to
+/// back with it, and gives up the `len` bytes before it.
+unsafe fn close_up(mbuf: *mut MBuf, ethernet_len: usize, len: usize) {
+    ptr::copy((*mbuf).data_address(0), (*mbuf).data_address(len), ethernet_len);
+    (*mbuf).remove_data_beginning(len);
+}
+
+/// The source and destination port of the TCP or UDP packet `packet`, which the SPD may select
+/// on.
+fn ports<T: TunnelPacket>(packet: &T) -> Option<(u16, u16)> {
+    let protocol = packet.next_proto();
+    if protocol != ProtocolNumbers::Tcp && protocol != ProtocolNumbers::Udp {
+        return None;
+    }
+    let ports = unsafe { &*buffer::read_slice::<u8>(packet.mbuf(), packet.payload_offset(), 4).ok()? };
+    Some((u16::from_be_bytes([ports[0], ports[1]]), u16::from_be_bytes([ports[2], ports[3]])))
+}
+
+/// Reparses the frame in `mbuf` as an Ethernet frame carrying a `T`.
+fn reparse<T: TunnelPacket>(mbuf: *mut MBuf) -> Result<T, Error> {
+    let mut ethernet = RawPacket::from_mbuf(mbuf).parse::<Ethernet>()?;
+    ethernet.set_ether_type(T::ETHER_TYPE);
+    ethernet.parse::<T>()
+}
+
+/// Lazily-evaluate ESP tunnel-mode decapsulation operator
+///
+/// Packets that are not ESP are dropped, and so are the dummy packets of
+/// RFC 4303 section 2.6. On error, the packet is marked as aborted and will
+/// short-circuit the remainder of the pipeline.
+pub struct EspDecapBatch<B: Batch, T: TunnelPacket>
+where
+    B::Item: TunnelPacket,
+{
+    source: B,
+    sad: &'static SecurityAssociationDatabase,
+    phantom: PhantomData<T>,
+}
+
+impl<B: Batch, T: TunnelPacket> EspDecapBatch<B, T>
+where
+    B::Item: TunnelPacket,
+{
+    #[inline]
+    pub fn new(source: B, sad: &'static SecurityAssociationDatabase) -> Self {
+        EspDecapBatch {
+            source,
+            sad,
+            phantom: PhantomData,
+        }
+    }
+
+    fn decap(&self, outer: B::Item) -> Result<Option<T>, Error> {
+        let mbuf = outer.mbuf();
+        let ethernet_len = outer.offset();
+        let esp_offset = outer.payload_offset();
+        // short frames are padded on the wire; the outer header has the real length.
+        let esp_len = outer.total_len().saturating_sub(outer.header_len());
+        if esp_offset + esp_len > unsafe { (*mbuf).data_len() } {
+            return Err(CryptoError::PktlenError.into());
+        }
+        let esp = unsafe { &*buffer::read_slice::<u8>(mbuf, esp_offset, esp_len)? };
+        let spi = esp_spi(esp);
+
+        let inner_len = SCRATCH.with(|scratch| -> Result<Option<usize>, Error> {
+            let mut scratch = scratch.borrow_mut();
+            let len = self.sad.decrypt(esp, &mut scratch)?;
+            if len < ESP_TRAILER_LENGTH {
+                return Err(CryptoError::BadTrailer(spi).into());
+            }
+            let pad_len = scratch[len - 2] as usize;
+            let next_header = ProtocolNumber::new(scratch[len - 1]);
+            let inner_len = match len.checked_sub(ESP_TRAILER_LENGTH + pad_len) {
+                Some(inner_len) => inner_len,
+                None => return Err(CryptoError::BadTrailer(spi).into()),
+            };
+            // the default padding is 1, 2, 3, ..., which the receiver is to check.
+            let padding = &scratch[inner_len..(inner_len + pad_len)];
+            if padding.iter().enumerate().any(|(i, &byte)| byte as usize != i + 1) {
+                return Err(CryptoError::BadTrailer(spi).into());
+            }
+            if next_header == ProtocolNumbers::Ipv6NoNxt {
+                return Ok(None);
+            }
+            if next_header != T::PROTOCOL {
+                return Err(CryptoError::UnexpectedNextHeader(spi, next_header, T::PROTOCOL).into());
+            }
+            // the inner packet takes the place of the ESP header.
+            buffer::write_slice(mbuf, esp_offset, &scratch[..inner_len])?;
+            Ok(Some(inner_len))
+        })?;
+
+        match inner_len {
+            Some(inner_len) => {
+                buffer::trim(mbuf, esp_offset + inner_len)?;
+                unsafe { close_up(mbuf, ethernet_len, outer.header_len()) };
+                reparse::<T>(mbuf).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl<B: Batch, T: TunnelPacket> Batch for EspDecapBatch<B, T>
+where
+    B::Item: TunnelPacket,
+{
+    type Item = T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Result<Self::Item, PacketError>> {
+        self.source.next().map(|item| match item {
+            Ok(outer) => {
+                let mbuf = outer.mbuf();
+                if outer.next_proto() != ProtocolNumbers::Esp {
+                    return Err(PacketError::Drop(mbuf));
+                }
+                match self.decap(outer) {
+                    Ok(Some(inner)) => Ok(inner),
+                    Ok(None) => Err(PacketError::Drop(mbuf)),
+                    Err(e) => Err(PacketError::Abort(mbuf, e)),
+                }
+            }
+            Err(e) => Err(e),
+        })
+    }
+
+    #[inline]
+    fn receive(&mut self) {
+        self.source.receive();
+    }
+}
+
+/// Lazily-evaluate ESP tunnel-mode encapsulation operator
+///
+/// The security policy database decides what happens to each packet: packets
+/// to protect are encapsulated with the tunnel endpoints of their outbound SA,
+/// packets to bypass it are emitted as they are, and the rest are dropped. On
+/// error, the packet is marked as aborted and will short-circuit the remainder
+/// of the pipeline.
+pub struct EspEncapBatch<B: Batch, T: TunnelPacket>
+where
+    B::Item: TunnelPacket,
+{
+    source: B,
+    sad: &'static SecurityAssociationDatabase,
+    spd: &'static SecurityPolicyDatabase,
+    phantom: PhantomData<T>,
+}
+
+impl<B: Batch, T: TunnelPacket> EspEncapBatch<B, T>
+where
+    B::Item: TunnelPacket,
+{
+    #[inline]
+    pub fn new(source: B, sad: &'static SecurityAssociationDatabase, spd: &'static SecurityPolicyDatabase) -> Self {
+        EspEncapBatch {
+            source,
+            sad,
+            spd,
+            phantom: PhantomData,
+        }
+    }
+
+    fn encap(&self, inner: B::Item, spi: u32) -> Result<T, Error> {
+        let sa = self.sad.get(spi, Direction::Outbound).ok_or(CryptoError::UnknownSa(spi))?;
+        let (cipher, tunnel) = {
+            let sa = sa.lock().unwrap();
+            (sa.cipher, sa.tunnel)
+        };
+        let (src, dst) = tunnel.ok_or(CryptoError::NoTunnel(spi))?;
+
+        let mbuf = inner.mbuf();
+        let ethernet_len = inner.offset();
+        let inner_len = inner.total_len();
+        let payload_len = inner_len + cipher.trailer_len(inner_len);
+        let esp_len = payload_len + cipher.overhead();
+        let outer_len = T::Header::size();
+        if ethernet_len + inner_len > unsafe { (*mbuf).data_len() } || outer_len + esp_len > MAX_PKT_SIZE {
+            return Err(CryptoError::PktlenError.into());
+        }
+
+        SCRATCH.with(|scratch| -> Result<(), Error> {
+            let mut scratch = scratch.borrow_mut();
+            let plain = &mut scratch[..payload_len];
+            plain[..inner_len].copy_from_slice(unsafe { &*buffer::read_slice::<u8>(mbuf, ethernet_len, inner_len)? });
+            let pad_len = payload_len - inner_len - ESP_TRAILER_LENGTH;
+            for (i, byte) in plain[inner_len..(inner_len + pad_len)].iter_mut().enumerate() {
+                *byte = (i + 1) as u8;
+            }
+            plain[payload_len - 2] = pad_len as u8;
+            plain[payload_len - 1] = <B::Item as TunnelPacket>::PROTOCOL.0;
+
+            // room for the outer and the ESP header in front, and for the rest behind.
+            unsafe {
+                let prepend = outer_len + cipher.header_len();
+                if (*mbuf).add_data_beginning(prepend) == 0 {
+                    return Err(buffer::BufferError::NotResized.into());
+                }
+                ptr::copy((*mbuf).data_address(prepend), (*mbuf).data_address(0), ethernet_len);
+                let frame_len = ethernet_len + outer_len + esp_len;
+                let data_len = (*mbuf).data_len();
+                if frame_len > data_len && (*mbuf).add_data_end(frame_len - data_len) == 0 {
+                    return Err(buffer::BufferError::NotResized.into());
+                }
+                if frame_len < data_len {
+                    (*mbuf).remove_data_end(data_len - frame_len);
+                }
+            }
+            let output = unsafe { &mut *buffer::read_slice::<u8>(mbuf, ethernet_len + outer_len, esp_len)? };
+            self.sad.encrypt(spi, plain, output)?;
+            Ok(())
+        })?;
+
+        let mut outer = reparse::<T>(mbuf)?;
+        outer.init_tunnel_header(src, dst, ProtocolNumbers::Esp, esp_len)?;
+        Ok(outer)
+    }
+}
+
+impl<B: Batch, T: TunnelPacket> Batch for EspEncapBatch<B, T>
+where
+    B::Item: TunnelPacket,
+{
+    type Item = T;
+
+    #[inline]
+    fn next(&mut self) -> Option<Result<Self::Item, PacketError>> {
+        self.source.next().map(|item| match item {
+            Ok(inner) => {
+                let mbuf = inner.mbuf();
+                match self.spd.select_ports(inner.src(), inner.dst(), inner.next_proto(), ports(&inner)) {
+                    PolicyAction::Protect(spi) => self.encap(inner, spi).map_err(|e| PacketError::Abort(mbuf, e)),
+                    PolicyAction::Bypass => Err(PacketError::Emit(mbuf)),
+                    PolicyAction::Discard => Err(PacketError::Drop(mbuf)),
+                }
+            }
+            Err(e) => Err(e),
+        })
+    }
+
+    #[inline]
+    fn receive(&mut self) {
+        self.source.receive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packets::checksum;
+    use packets::ethernet::EtherTypes;
+    use packets::ip::v4::Ipv4;
+    use provisioning::{self, SaKeys};
+    use std::net::{IpAddr, Ipv4Addr};
+    use utils::ipsec::{EspCipher, Policy, SecurityAssociation, SAD};
+
+    /// One batch of parsed packets.
+    struct Packets<T: Packet>(Vec<T>);
+
+    impl<T: Packet> Batch for Packets<T> {
+        type Item = T;
+
+        fn next(&mut self) -> Option<Result<T, PacketError>> {
+            self.0.pop().map(Ok)
+        }
+
+        fn receive(&mut self) {}
+    }
+
+    /// A UDP packet with 5 bytes of payload from `src` to `dst`, padded to the shortest
+    /// Ethernet frame.
+    fn frame(src: [u8; 4], dst: [u8; 4]) -> Vec<u8> {
+        let mut frame = vec![0u8; 60];
+        frame[..6].copy_from_slice(&[2, 0, 0, 0, 0, 1]);
+        frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, 2]);
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14..24].copy_from_slice(&[0x45, 0, 0, 33, 0, 0, 0, 0, 64, 17]);
+        frame[26..30].copy_from_slice(&src);
+        frame[30..34].copy_from_slice(&dst);
+        frame[34..47].copy_from_slice(&[0x30, 0x39, 0x30, 0x39, 0, 13, 0, 0, b'h', b'e', b'l', b'l', b'o']);
+        frame
+    }
+
+    fn parse(frame: &[u8]) -> Ipv4 {
+        RawPacket::from_bytes(frame).unwrap().parse::<Ethernet>().unwrap().parse::<Ipv4>().unwrap()
+    }
+
+    #[test]
+    fn tunnel_round_trip() {
+        let (local, remote) = (IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+        let ciphers = [
+            (0x5001, EspCipher::AesGcm128),
+            (0x5002, EspCipher::AesCbcSha256),
+            (0x5003, EspCipher::ChaCha20Poly1305),
+        ];
+        for &(spi, cipher) in ciphers.iter() {
+            let (enc_key, auth_key) = match cipher {
+                EspCipher::AesCbcSha256 => (vec![0x11; 16], vec![0x22; 32]),
+                EspCipher::AesGcm128 => (vec![0x11; 20], Vec::new()),
+                EspCipher::AesGcm256 | EspCipher::ChaCha20Poly1305 => (vec![0x11; 36], Vec::new()),
+            };
+            provisioning::install(SaKeys::new(spi, enc_key, auth_key).unwrap());
+            SAD.install(SecurityAssociation::new(spi, Direction::Outbound, cipher).with_tunnel(local, remote));
+            SAD.install(SecurityAssociation::new(spi, Direction::Inbound, cipher));
+            // esp_encap takes the SPD for good.
+            let spd: &'static SecurityPolicyDatabase = Box::leak(Box::new(SecurityPolicyDatabase::new()));
+            spd.add(Policy {
+                dst: Some("10.0.2.0/24".parse().unwrap()),
+                ..Policy::new(PolicyAction::Protect(spi))
+            });
+            spd.add(Policy::new(PolicyAction::Bypass));
+
+            let plain = frame([10, 0, 1, 1], [10, 0, 2, 7]);
+            let mut encap = Packets(vec![parse(&plain)]).esp_encap::<Ipv4>(&SAD, spd);
+            let outer = encap.next().unwrap().unwrap();
+            assert_eq!(outer.envelope().ether_type(), EtherTypes::Ipv4);
+            assert_eq!((IpPacket::src(&outer), IpPacket::dst(&outer)), (local, remote));
+            assert_eq!(outer.next_proto(), ProtocolNumbers::Esp);
+            assert_eq!(outer.total_len(), 20 + 33 + cipher.trailer_len(33) + cipher.overhead());
+            assert_eq!(outer.len(), outer.total_len());
+            let header = unsafe { &*buffer::read_slice::<u8>(outer.mbuf(), outer.offset(), 20).unwrap() };
+            assert_eq!(checksum::compute(0, header), 0);
+
+            let mut decap = Packets(vec![outer]).esp_decap::<Ipv4>(&SAD);
+            let inner = decap.next().unwrap().unwrap();
+            assert_eq!(inner.dst(), Ipv4Addr::new(10, 0, 2, 7));
+            // the frame padding does not survive the tunnel.
+            assert_eq!(unsafe { (*inner.mbuf()).data() }, &plain[..47]);
+
+            let mut bypassed = Packets(vec![parse(&frame([10, 0, 1, 1], [10, 0, 3, 7]))]).esp_encap::<Ipv4>(&SAD, spd);
+            match bypassed.next() {
+                Some(Err(PacketError::Emit(_))) => {}
+                other => panic!("bypassed packet not emitted: {:?}", other.map(|result| result.is_ok())),
+            }
+        }
+    }
+}
@@ -4,7 +4,9 @@ use packets::Packet;
 /// Lazily-evaluated filter operator
 ///
 /// If the predicate evaluates to `false`, the packet is marked as
-/// dropped and will short-circuit the remainder of the pipeline.
+/// dropped and will short-circuit the remainder of the pipeline. The
+/// `SendBatch` at the end of the pipeline then hands its mbuf back to
+/// dpdkIO through the freeq.
 pub struct FilterBatch<B: Batch, P>
 where
     P: FnMut(&B::Item) -> bool,
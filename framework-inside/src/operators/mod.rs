@@ -1,25 +1,33 @@
 use failure::Error;
 use native::mbuf::MBuf;
+use packets::ip::TunnelPacket;
 use packets::Packet;
 use std::collections::HashMap;
 use interface::PacketTx;
+use utils::ipsec::{SecurityAssociationDatabase, SecurityPolicyDatabase};
+pub use self::crypto_map_batch::*;
 pub use self::emit_batch::*;
+pub use self::esp_batch::*;
 pub use self::filter_batch::*;
 pub use self::filtermap_batch::*;
 pub use self::foreach_batch::*;
 pub use self::groupby_batch::*;
 pub use self::map_batch::*;
+pub use self::on_batch::*;
 pub use self::queue_batch::*;
 pub use self::receive_batch::*;
 pub use self::send_batch::*;
 pub use self::sendall_batch::*;
 
+mod crypto_map_batch;
 mod emit_batch;
+mod esp_batch;
 mod filter_batch;
 mod filtermap_batch;
 mod foreach_batch;
 mod groupby_batch;
 mod map_batch;
+mod on_batch;
 mod queue_batch;
 mod receive_batch;
 mod send_batch;
@@ -90,6 +98,19 @@ pub trait Batch {
         ForEachBatch::new(self, fun)
     }
 
+    /// Appends an on_batch operator to the end of the pipeline
+    ///
+    /// Use for side-effects once per batch, such as refreshing a `TableReader`
+    /// so that all the packets of a batch see the same version of a table.
+    #[inline]
+    fn on_batch<F>(self, fun: F) -> OnBatchBatch<Self, F>
+    where
+        F: FnMut(),
+        Self: Sized,
+    {
+        OnBatchBatch::new(self, fun)
+    }
+
     /// Appends a group_by operator to the end of the pipeline
     ///
     /// * `selector` - a function that receives a reference to `B::Item` and
@@ -128,6 +149,75 @@ pub trait Batch {
         GroupByBatch::new(self, selector, composer)
     }
 
+    /// Appends an esp_decap operator to the end of the pipeline
+    ///
+    /// Takes the ESP packets of tunnel-mode SAs apart: checks and decrypts
+    /// them with the inbound SAs of `sad`, and strips the outer header, the
+    /// ESP header, the padding and the ICV, leaving the inner `T` packet in
+    /// the frame. Packets that are not ESP are dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let batch = batch
+    ///     .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
+    ///     .esp_decap::<Ipv4>(&SAD);
+    /// ```
+    #[inline]
+    fn esp_decap<T: TunnelPacket>(self, sad: &'static SecurityAssociationDatabase) -> EspDecapBatch<Self, T>
+    where
+        Self::Item: TunnelPacket,
+        Self: Sized,
+    {
+        EspDecapBatch::new(self, sad)
+    }
+
+    /// Appends an esp_encap operator to the end of the pipeline
+    ///
+    /// Puts the packets `spd` protects in ESP tunnel mode: encrypts them with
+    /// their outbound SA in `sad`, with padding and ICV, behind an outer `T`
+    /// header between the tunnel endpoints of the SA. Packets `spd` bypasses
+    /// are emitted as they are, and the ones it discards are dropped.
+    #[inline]
+    fn esp_encap<T: TunnelPacket>(
+        self,
+        sad: &'static SecurityAssociationDatabase,
+        spd: &'static SecurityPolicyDatabase,
+    ) -> EspEncapBatch<Self, T>
+    where
+        Self::Item: TunnelPacket,
+        Self: Sized,
+    {
+        EspEncapBatch::new(self, sad, spd)
+    }
+
+    /// Appends a crypto_map operator to the end of the pipeline
+    ///
+    /// Encrypts or decrypts the IP payloads of the whole batch at once, as
+    /// `select` decides for each packet, with the SAs of `sad`. Packets of
+    /// the same SA share its lookup and cipher context, so the cost per
+    /// packet goes down as batches get bigger.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// let batch = batch
+    ///     .map(|packet| packet.parse::<Ethernet>()?.parse::<Ipv4>())
+    ///     .crypto_map(&SAD, |packet| match packet.protocol() {
+    ///         ProtocolNumbers::Esp => Some(EspOp::Decrypt),
+    ///         _ => None,
+    ///     });
+    /// ```
+    #[inline]
+    fn crypto_map<F>(self, sad: &'static SecurityAssociationDatabase, select: F) -> CryptoMapBatch<Self, F>
+    where
+        Self::Item: TunnelPacket,
+        F: FnMut(&Self::Item) -> Option<EspOp>,
+        Self: Sized,
+    {
+        CryptoMapBatch::new(self, sad, select)
+    }
+
     /// Appends a emit operator to the end of the pipeline
     ///
     /// Use when processing is complete and no further modifications are necessary.
@@ -0,0 +1,41 @@
+use super::{Batch, PacketError};
+
+/// Lazily-evaluate on_batch operator
+///
+/// Calls a function each time a new batch is received, before any of its
+/// packets go down the pipeline.
+pub struct OnBatchBatch<B: Batch, F>
+where
+    F: FnMut(),
+{
+    source: B,
+    fun: F,
+}
+
+impl<B: Batch, F> OnBatchBatch<B, F>
+where
+    F: FnMut(),
+{
+    #[inline]
+    pub fn new(source: B, fun: F) -> Self {
+        OnBatchBatch { source, fun }
+    }
+}
+
+impl<B: Batch, F> Batch for OnBatchBatch<B, F>
+where
+    F: FnMut(),
+{
+    type Item = B::Item;
+
+    #[inline]
+    fn next(&mut self) -> Option<Result<Self::Item, PacketError>> {
+        self.source.next()
+    }
+
+    #[inline]
+    fn receive(&mut self) {
+        self.source.receive();
+        (self.fun)();
+    }
+}
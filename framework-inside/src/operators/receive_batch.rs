@@ -5,9 +5,23 @@ use interface::PacketRx;
 
 use std::io::stdout;
 use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub const BATCH_SIZE: usize = 32;
 
+/// Packets a `ReceiveBatch` takes at a time, see `set_batch_size`.
+static RECEIVE_SIZE: AtomicUsize = AtomicUsize::new(BATCH_SIZE);
+
+/// Sets how many packets the `ReceiveBatch`es created from now on take at a time,
+/// `BATCH_SIZE` until set. `initialize_system` sets it from the configuration.
+pub fn set_batch_size(size: usize) {
+    RECEIVE_SIZE.store(size.max(1), Ordering::Relaxed);
+}
+
+pub fn batch_size() -> usize {
+    RECEIVE_SIZE.load(Ordering::Relaxed)
+}
+
 /// Receive operator
 ///
 /// Marks the start of a pipeline.
@@ -15,15 +29,18 @@ pub struct ReceiveBatch<Rx: PacketRx> {
     port: Rx,
     buffers: Vec<*mut MBuf>,
     index: usize,
+    size: usize,
 }
 
 impl<Rx: PacketRx> ReceiveBatch<Rx> {
     #[inline]
     pub fn new(port: Rx) -> Self {
+        let size = batch_size();
         ReceiveBatch {
             port,
-            buffers: Vec::<*mut MBuf>::with_capacity(BATCH_SIZE),
+            buffers: Vec::<*mut MBuf>::with_capacity(size),
             index: 0,
+            size,
         }
     }
 }
@@ -48,8 +65,7 @@ impl<Rx: PacketRx> Batch for ReceiveBatch<Rx> {
     #[inline]
     fn receive(&mut self) {
         unsafe {
-            let capacity = self.buffers.capacity();
-            self.buffers.set_len(capacity);
+            self.buffers.set_len(self.size);
             match self.port.recv(self.buffers.as_mut_slice()) {
                 Ok(received) => {
                     self.buffers.set_len(received as usize);
@@ -78,7 +78,7 @@ impl<B: Batch, Tx: PacketTx> Executable for SendBatch<B, Tx> {
             let len = drop_q.len();
             let ptr = drop_q.as_mut_ptr();
             unsafe {
-                // never have a non-zero return
+                // never have a non-zero return; DPDK mbufs go back to dpdkIO through the freeq.
                 mbuf_free_bulk(ptr, len as i32);
                 drop_q.set_len(0);
             }
@@ -1,7 +1,8 @@
 use common::Result;
 use failure::Fail;
 use packets::checksum::PseudoHeader;
-use packets::Packet;
+use packets::ethernet::EtherType;
+use packets::{Ethernet, Packet};
 use std::fmt;
 use std::net::IpAddr;
 
@@ -11,7 +12,7 @@ pub mod v6;
 /// Assigned internet protocol number
 ///
 /// From https://www.iana.org/assignments/protocol-numbers/protocol-numbers.xhtml
-#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[repr(C, packed)]
 pub struct ProtocolNumber(pub u8);
 
@@ -41,6 +42,18 @@ pub mod ProtocolNumbers {
 
     // Internet Control Message Protocol for IPv4
     pub const Icmpv4: ProtocolNumber = ProtocolNumber(0x01);
+
+    // IPv4 encapsulated in IP
+    pub const Ipv4: ProtocolNumber = ProtocolNumber(0x04);
+
+    // IPv6 encapsulated in IP
+    pub const Ipv6: ProtocolNumber = ProtocolNumber(0x29);
+
+    // Encapsulating Security Payload
+    pub const Esp: ProtocolNumber = ProtocolNumber(0x32);
+
+    // No next header, e.g. in the dummy packets of an ESP tunnel
+    pub const Ipv6NoNxt: ProtocolNumber = ProtocolNumber(0x3B);
 }
 
 impl fmt::Display for ProtocolNumber {
@@ -53,6 +66,7 @@ impl fmt::Display for ProtocolNumber {
                 ProtocolNumbers::Udp => "UDP".to_string(),
                 ProtocolNumbers::Ipv6Route => "IPv6 Route".to_string(),
                 ProtocolNumbers::Icmpv6 => "ICMPv6".to_string(),
+                ProtocolNumbers::Esp => "ESP".to_string(),
                 _ => format!("0x{:02x}", self.0),
             }
         )
@@ -89,8 +103,34 @@ pub trait IpPacket: Packet {
     fn pseudo_header(&self, packet_len: u16, protocol: ProtocolNumber) -> PseudoHeader;
 }
 
+/// IP packets that go in an IP tunnel, or carry one
+///
+/// Implemented by `Ipv4` and `Ipv6`, for the ESP tunnel operators.
+pub trait TunnelPacket: IpPacket + Packet<Envelope = Ethernet> {
+    /// The ether type of frames carrying the packet
+    const ETHER_TYPE: EtherType;
+
+    /// The protocol number of the packet inside another IP packet
+    const PROTOCOL: ProtocolNumber;
+
+    /// Returns the length of the packet according to its header
+    ///
+    /// Unlike `len`, excludes any padding of the Ethernet frame.
+    fn total_len(&self) -> usize;
+
+    /// Overwrites the header with a fresh one for a tunnel from `src` to
+    /// `dst`, followed by `payload_len` bytes of `protocol`
+    fn init_tunnel_header(
+        &mut self,
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: ProtocolNumber,
+        payload_len: usize,
+    ) -> Result<()>;
+}
+
 /// 5-tuple IP connection identifier
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Flow {
     src_ip: IpAddr,
     dst_ip: IpAddr,
@@ -206,6 +246,7 @@ mod tests {
         assert_eq!("UDP", ProtocolNumbers::Udp.to_string());
         assert_eq!("IPv6 Route", ProtocolNumbers::Ipv6Route.to_string());
         assert_eq!("ICMPv6", ProtocolNumbers::Icmpv6.to_string());
+        assert_eq!("ESP", ProtocolNumbers::Esp.to_string());
         assert_eq!("0x00", ProtocolNumber::new(0).to_string());
     }
 }
@@ -1,7 +1,8 @@
 use common::Result;
 use native::mbuf::MBuf;
-use packets::checksum::PseudoHeader;
-use packets::ip::{IpAddrMismatchError, IpPacket, ProtocolNumber};
+use packets::checksum::{self, PseudoHeader};
+use packets::ethernet::{EtherType, EtherTypes};
+use packets::ip::{IpAddrMismatchError, IpPacket, ProtocolNumber, ProtocolNumbers, TunnelPacket};
 use packets::{buffer, Ethernet, Fixed, Header, Packet};
 use std::fmt;
 use std::net::{IpAddr, Ipv4Addr};
@@ -254,6 +255,15 @@ impl Ipv4 {
         }
     }
 
+    /// The whole IPv4 packet, header and payload, as in the frame.
+    #[inline]
+    pub fn get_packet_mut(&self) -> &mut [u8] {
+        unsafe {
+            let len = self.data_len() - self.offset;
+            slice::from_raw_parts_mut((*self.mbuf).data_address(self.offset), len)
+        }
+    }
+
     #[inline]
     pub fn version(&self) -> u8 {
         // Protocol Version, should always be `4`
@@ -579,3 +589,40 @@ impl IpPacket for Ipv4 {
         }
     }
 }
+
+impl TunnelPacket for Ipv4 {
+    const ETHER_TYPE: EtherType = EtherTypes::Ipv4;
+    const PROTOCOL: ProtocolNumber = ProtocolNumbers::Ipv4;
+
+    #[inline]
+    fn total_len(&self) -> usize {
+        self.total_length() as usize
+    }
+
+    fn init_tunnel_header(
+        &mut self,
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: ProtocolNumber,
+        payload_len: usize,
+    ) -> Result<()> {
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                *self.header_mut() = Ipv4Header {
+                    version_ihl: 4 << 4 | 5,
+                    total_length: u16::to_be((Self::Header::size() + payload_len) as u16),
+                    ttl: 64,
+                    protocol: protocol.0,
+                    src,
+                    dst,
+                    ..Default::default()
+                };
+                let header = unsafe { slice::from_raw_parts(self.header as *const u8, Self::Header::size()) };
+                let checksum = checksum::compute(0, header);
+                self.set_checksum(checksum);
+                Ok(())
+            }
+            _ => Err(IpAddrMismatchError.into()),
+        }
+    }
+}
@@ -1,7 +1,8 @@
 use common::Result;
 use native::mbuf::MBuf;
 use packets::checksum::PseudoHeader;
-use packets::ip::{IpAddrMismatchError, IpPacket, ProtocolNumber};
+use packets::ethernet::{EtherType, EtherTypes};
+use packets::ip::{IpAddrMismatchError, IpPacket, ProtocolNumber, ProtocolNumbers, TunnelPacket};
 use packets::{buffer, Ethernet, Fixed, Header, Packet};
 use std::fmt;
 use std::net::{IpAddr, Ipv6Addr};
@@ -396,3 +397,36 @@ impl IpPacket for Ipv6 {
 }
 
 impl Ipv6Packet for Ipv6 {}
+
+impl TunnelPacket for Ipv6 {
+    const ETHER_TYPE: EtherType = EtherTypes::Ipv6;
+    const PROTOCOL: ProtocolNumber = ProtocolNumbers::Ipv6;
+
+    #[inline]
+    fn total_len(&self) -> usize {
+        Self::Header::size() + self.payload_length() as usize
+    }
+
+    fn init_tunnel_header(
+        &mut self,
+        src: IpAddr,
+        dst: IpAddr,
+        protocol: ProtocolNumber,
+        payload_len: usize,
+    ) -> Result<()> {
+        match (src, dst) {
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                *self.header_mut() = Ipv6Header {
+                    payload_length: u16::to_be(payload_len as u16),
+                    next_header: protocol.0,
+                    hop_limit: 64,
+                    src,
+                    dst,
+                    ..Default::default()
+                };
+                Ok(())
+            }
+            _ => Err(IpAddrMismatchError.into()),
+        }
+    }
+}
@@ -145,6 +145,18 @@ pub trait Packet {
     /// Deparses the packet and returns its envelope
     fn deparse(self) -> Self::Envelope;
 
+    /// Copies the packet into a newly allocated message buffer, parsed
+    /// up to the same layer
+    ///
+    /// The copy is independent of the original, e.g. to mirror a packet
+    /// or to answer it while still forwarding it.
+    fn deep_clone(&self) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::do_parse(self.envelope().deep_clone()?)
+    }
+
     /// Resets the parsed packet back to raw packet
     fn reset(self) -> RawPacket
     where
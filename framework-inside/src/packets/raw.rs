@@ -1,4 +1,5 @@
 use common::Result;
+use native;
 use native::mbuf::MBuf;
 use packets::{buffer, Header, Packet};
 
@@ -31,24 +32,25 @@ impl PartialEq for RawPacket {
 
 impl RawPacket {
     /// Creates a new packet by allocating a new buffer
-    // pub fn new() -> Result<Self> {
-    //     unsafe {
-    //         let mbuf = zcsi::mbuf_alloc();
-    //         if mbuf.is_null() {
-    //             Err(buffer::BufferError::FailAlloc.into())
-    //         } else {
-    //             Ok(RawPacket { mbuf })
-    //         }
-    //     }
-    // }
+    ///
+    /// Inside the enclave the buffer is one of the mbufs lent by dpdkIO,
+    /// see `native::mbuf_pool`, so the packet can be sent like a received one.
+    pub fn new() -> Result<Self> {
+        let mbuf = native::mbuf_alloc();
+        if mbuf.is_null() {
+            Err(buffer::BufferError::FailAlloc.into())
+        } else {
+            Ok(RawPacket { mbuf })
+        }
+    }
 
     /// Creates a new packet and initialize the buffer with a byte array
-    // pub fn from_bytes(data: &[u8]) -> Result<Self> {
-    //     let packet = RawPacket::new()?;
-    //     buffer::alloc(packet.mbuf, 0, data.len())?;
-    //     buffer::write_slice(packet.mbuf, 0, data)?;
-    //     Ok(packet)
-    // }
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let packet = RawPacket::new()?;
+        buffer::alloc(packet.mbuf, 0, data.len())?;
+        buffer::write_slice(packet.mbuf, 0, data)?;
+        Ok(packet)
+    }
 
     /// Creates a new packet from a MBuf
     pub fn from_mbuf(mbuf: *mut MBuf) -> Self {
@@ -137,9 +139,43 @@ impl Packet for RawPacket {
     fn deparse(self) -> Self::Envelope {
         self
     }
+
+    #[inline]
+    fn deep_clone(&self) -> Result<Self> {
+        RawPacket::from_bytes(unsafe { (*self.mbuf).data() })
+    }
 }
 
 // because packet holds a raw pointer, by default, rust will deem
 // the struct to be not sendable. explicitly implement the `Send`
 // trait to ensure raw packets can go across thread boundaries.
 unsafe impl Send for RawPacket {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packets::Ethernet;
+
+    // outside an mbufq the buffers come from the enclave heap.
+    #[test]
+    fn new_raw_packet() {
+        let packet = RawPacket::new().unwrap();
+        assert_eq!(packet.len(), 0);
+        native::mbuf_free_bulk(&mut packet.mbuf(), 1);
+    }
+
+    #[test]
+    fn clone_parsed_packet() {
+        let mut frame = vec![0u8; 60];
+        frame[..6].copy_from_slice(&[2, 0, 0, 0, 0, 1]);
+        frame[6..12].copy_from_slice(&[2, 0, 0, 0, 0, 2]);
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        let ethernet = RawPacket::from_bytes(&frame).unwrap().parse::<Ethernet>().unwrap();
+
+        let mut copy = ethernet.deep_clone().unwrap();
+        assert_ne!(copy.mbuf(), ethernet.mbuf());
+        copy.swap_addresses();
+        assert_eq!(copy.src(), ethernet.dst());
+        assert_eq!(ethernet.reset().deep_clone().unwrap(), RawPacket::from_bytes(&frame).unwrap());
+    }
+}
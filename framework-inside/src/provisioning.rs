@@ -0,0 +1,350 @@
+//! Keys of the IPsec security associations (SAs), provisioned at run time.
+//!
+//! Keys are not compiled into the enclave. Once sgx-runner has handed it the rings, it relays
+//! the key server, which attests the enclave itself and sends what it has for us over their
+//! session, one sealed message per SA, then says how many it sent:
+//!
+//!    key server:  sealed( key <spi> <encryption key> [<authentication key>] )
+//!    key server:  sealed( sa <spi> <inbound|outbound|both> <cipher> <esn> <bytes> <packets> <reply to> [<src> <dst> [<remote> <local>]] )
+//!    key server:  sealed( end <count> )
+//!
+//! The runner hangs up instead if it has no key server to relay. Build the enclave with
+//! SAFEBRICKS_KEY_SERVER set to the key server's public key, in hex DER, to take keys from that
+//! key server only; the measurement covers it, so the key server can tell. An enclave built
+//! without it takes no keys at all, unless the `unpinned-key-server` feature is on, for tests.
+//!
+//! The keys only ever live in enclave memory, and are zeroed when an SA is replaced or removed.
+//! `utils::ipsec` looks them up by the SPI of each packet. The `sa` message, which describes
+//! the SA to `utils::ipsec::SAD`, is optional; lifetimes of 0 mean none, as does a reply to
+//! SPI 0, and tunnel-mode SAs end in the addresses of their outer header. Outbound tunnel-mode
+//! SAs go in `utils::ipsec::SPD` too, to protect the traffic to the `remote` prefix from the
+//! `local` one, or else all traffic, behind the policies already there. The key server hands
+//! each outbound SA to one enclave only, as every enclave keeps its own sequence numbers: the
+//! others get "both" SAs inbound only, and answer on the outbound SA that replies to it.
+//!
+//! The session stays open after that, for the enclave to ask the key server for the counters
+//! of its checkpoints, see `checkpoint::TrustedCounter`:
+//!
+//!    enclave:     sealed( counter <name> )  or: sealed( increment <name> )
+//!    key server:  sealed( count <name> <value> )
+use attestation::{self, Session};
+use checkpoint::TrustedCounter;
+use common::*;
+use failure::Fail;
+use fnv::FnvHashMap;
+use hex;
+use mbedtls::rng::{Random, Rdrand};
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::ptr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use utils::cidr::Ipv4Cidr;
+use utils::ipsec::{Direction, EspCipher, Lifetime, Policy, PolicyAction, SecurityAssociation, SAD, SPD};
+
+#[derive(Debug, Fail)]
+#[fail(display = "Key provisioning failed: {}", _0)]
+pub struct ProvisioningError(pub String);
+
+/// The key server's public key, in hex DER, if pinned at build time.
+const KEY_SERVER: Option<&str> = option_env!("SAFEBRICKS_KEY_SERVER");
+
+/// The public key of the only key server the enclave takes keys from, if pinned.
+pub fn key_server() -> Result<Option<Vec<u8>>> {
+    match KEY_SERVER.map(hex::decode) {
+        Some(Ok(key)) => Ok(Some(key)),
+        Some(Err(e)) => Err(ProvisioningError(format!("bad SAFEBRICKS_KEY_SERVER: {}", e)).into()),
+        None => Ok(None),
+    }
+}
+
+/// Length of the salt after the key of an AES-GCM SA.
+pub const SALT_LEN: usize = 4;
+
+/// Key material of one SA.
+pub struct SaKeys {
+    pub spi: u32,
+    /// AES key, 128 or 256 bits. AES-GCM SAs have the `SALT_LEN` bytes of salt of their nonces
+    /// appended, as RFC 4106 and `ip xfrm` have it.
+    pub enc_key: Vec<u8>,
+    /// HMAC-SHA256 key; empty for AES-GCM SAs.
+    pub auth_key: Vec<u8>,
+    /// The explicit part of the next AES-GCM nonce.
+    counter: AtomicU64,
+}
+
+impl SaKeys {
+    /// The nonce counter starts at a random value: the same keys may well be provisioned again
+    /// after a restart, and must not repeat the nonces of the last run.
+    pub fn new(spi: u32, enc_key: Vec<u8>, auth_key: Vec<u8>) -> Result<SaKeys> {
+        let mut start = [0u8; 8];
+        Rdrand.random(&mut start)?;
+        Ok(SaKeys {
+            spi,
+            enc_key,
+            auth_key,
+            counter: AtomicU64::new(u64::from_be_bytes(start)),
+        })
+    }
+
+    /// A value the explicit nonce of this SA never had before. RFC 4106 only asks for that,
+    /// so a counter shared by all cores does.
+    #[inline]
+    pub fn next_iv(&self) -> u64 {
+        self.counter.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+impl Drop for SaKeys {
+    fn drop(&mut self) {
+        for key in [&mut self.enc_key, &mut self.auth_key].iter_mut() {
+            for byte in key.iter_mut() {
+                // volatile, so the compiler cannot drop the stores to memory about to be freed.
+                unsafe { ptr::write_volatile(byte, 0) };
+            }
+        }
+    }
+}
+
+// never print the keys themselves.
+impl fmt::Debug for SaKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SA {:#010x} ({}-bit encryption key, {}-bit authentication key)",
+            self.spi,
+            self.enc_key.len() * 8,
+            self.auth_key.len() * 8
+        )
+    }
+}
+
+lazy_static! {
+    static ref SA_KEYS: RwLock<FnvHashMap<u32, Arc<SaKeys>>> = RwLock::new(FnvHashMap::default());
+}
+
+/// Bumped on every change to the SAs, so per-thread cipher contexts know to start over.
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Installs `keys`, replacing the SA with the same SPI if there is one.
+pub fn install(keys: SaKeys) {
+    let spi = keys.spi;
+    if SA_KEYS.write().unwrap().insert(spi, Arc::new(keys)).is_some() {
+        info!("replaced the keys of SA {:#010x}", spi);
+    }
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Forgets the SA `spi`. Returns whether there was one.
+pub fn remove(spi: u32) -> bool {
+    let removed = SA_KEYS.write().unwrap().remove(&spi).is_some();
+    GENERATION.fetch_add(1, Ordering::Release);
+    removed
+}
+
+/// The keys of SA `spi`, if provisioned.
+pub fn lookup(spi: u32) -> Option<Arc<SaKeys>> {
+    SA_KEYS.read().unwrap().get(&spi).cloned()
+}
+
+/// Changes whenever an SA is installed or removed.
+#[inline]
+pub fn generation() -> usize {
+    GENERATION.load(Ordering::Acquire)
+}
+
+fn field(fields: &[String], index: usize, name: &str) -> Result<Vec<u8>> {
+    match fields.get(index).map(|field| hex::decode(field)) {
+        Some(Ok(bytes)) => Ok(bytes),
+        _ => Err(ProvisioningError(format!("missing or malformed {}", name)).into()),
+    }
+}
+
+fn number<T: ::std::str::FromStr>(fields: &[String], index: usize, name: &str) -> Result<T> {
+    match fields.get(index).map(|field| field.parse::<T>()) {
+        Some(Ok(number)) => Ok(number),
+        _ => Err(ProvisioningError(format!("missing or malformed {}", name)).into()),
+    }
+}
+
+/// Adds the SA an `sa` message describes to the SAD, both ways if it says so.
+fn install_sa(fields: &[String]) -> Result<()> {
+    let spi = number::<u32>(fields, 1, "SPI")?;
+    let directions: &[Direction] = match fields.get(2).map(|field| field.as_str()) {
+        Some("inbound") => &[Direction::Inbound],
+        Some("outbound") => &[Direction::Outbound],
+        Some("both") => &[Direction::Inbound, Direction::Outbound],
+        other => return Err(ProvisioningError(format!("unknown direction {:?}", other)).into()),
+    };
+    let cipher = match fields.get(3).and_then(|field| EspCipher::from_name(field)) {
+        Some(cipher) => cipher,
+        None => return Err(ProvisioningError(format!("unknown cipher {:?}", fields.get(3))).into()),
+    };
+    // the keys come first.
+    if let Some(keys) = lookup(spi) {
+        if !cipher.suite().fits(&keys.enc_key, &keys.auth_key) {
+            return Err(ProvisioningError(format!("{:?} does not fit {}", keys, cipher.suite().name())).into());
+        }
+    }
+    let esn = number::<u8>(fields, 4, "ESN flag")? != 0;
+    let limit = |index, name| number::<u64>(fields, index, name).map(|limit| if limit == 0 { None } else { Some(limit) });
+    let lifetime = Lifetime {
+        bytes: limit(5, "byte lifetime")?,
+        packets: limit(6, "packet lifetime")?,
+    };
+    let reply_to = number::<u32>(fields, 7, "reply SPI")?;
+    let tunnel = if fields.len() > 8 {
+        Some((number::<IpAddr>(fields, 8, "tunnel source")?, number::<IpAddr>(fields, 9, "tunnel destination")?))
+    } else {
+        None
+    };
+    let selectors = if fields.len() > 10 {
+        Some((number::<Ipv4Cidr>(fields, 10, "remote prefix")?, number::<Ipv4Cidr>(fields, 11, "local prefix")?))
+    } else {
+        None
+    };
+    for &direction in directions {
+        let mut sa = SecurityAssociation::new(spi, direction, cipher)
+            .with_esn(esn)
+            .with_lifetime(lifetime);
+        if direction == Direction::Outbound && reply_to != 0 {
+            sa = sa.with_reply_to(reply_to);
+        }
+        SAD.install(match tunnel {
+            Some((src, dst)) => sa.with_tunnel(src, dst),
+            None => sa,
+        });
+        if direction == Direction::Outbound && tunnel.is_some() {
+            SPD.remove_sa(spi);
+            SPD.add(match selectors {
+                Some((ref remote, ref local)) => Policy {
+                    src: Some(local.clone()),
+                    dst: Some(remote.clone()),
+                    ..Policy::new(PolicyAction::Protect(spi))
+                },
+                None => Policy::new(PolicyAction::Protect(spi)),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Answers the key server sgx-runner relays after the rings, signed by `key_server` if set, and
+/// installs the SAs it sends. Returns how many, and the session for the key server's counters:
+/// none if the runner hangs up instead.
+pub fn receive<S: Read + Write>(
+    mut stream: BufReader<S>,
+    key_server: Option<&[u8]>,
+) -> Result<(usize, Option<CounterSession<S>>)> {
+    if stream.fill_buf()?.is_empty() {
+        return Ok((0, None));
+    }
+    let mut session = attestation::respond_to_key_server(&mut stream, key_server)?;
+    let received = receive_sealed(&mut session, &mut stream)?;
+    Ok((received, Some(CounterSession { session, stream })))
+}
+
+/// The checkpoint counters of the key server, over the session the enclave got its keys on.
+pub struct CounterSession<S> {
+    session: Session,
+    stream: BufReader<S>,
+}
+
+impl<S: Read + Write> CounterSession<S> {
+    fn request(&mut self, verb: &str, name: &str) -> Result<u64> {
+        self.session.seal_message(self.stream.get_mut(), verb, &[name.to_string()])?;
+        if self.stream.fill_buf()?.is_empty() {
+            return Err(ProvisioningError("the key server keeps no counters, or hung up".to_string()).into());
+        }
+        let fields = self.session.open_message(&mut self.stream)?;
+        match (fields.first().map(|verb| verb.as_str()), fields.get(1)) {
+            (Some("count"), Some(counted)) if counted == name => number(&fields, 2, "count"),
+            _ => Err(ProvisioningError(format!("unexpected answer {:?} to a counter request", fields.first())).into()),
+        }
+    }
+}
+
+impl<S: Read + Write> TrustedCounter for CounterSession<S> {
+    fn counter(&mut self, name: &str) -> Result<u64> {
+        self.request("counter", name)
+    }
+
+    fn increment(&mut self, name: &str) -> Result<u64> {
+        self.request("increment", name)
+    }
+}
+
+lazy_static! {
+    static ref COUNTER_SESSION: Mutex<Option<CounterSession<TcpStream>>> = Mutex::new(None);
+}
+
+/// Has `trusted_counter` go through `session`, in place of the one before.
+pub fn keep_counter_session(session: CounterSession<TcpStream>) {
+    *COUNTER_SESSION.lock().unwrap() = Some(session);
+}
+
+/// The key server's counters, for the threads of the enclave to share, if it has a session
+/// with the key server.
+pub fn trusted_counter() -> Option<KeyServerCounter> {
+    if COUNTER_SESSION.lock().unwrap().is_some() {
+        Some(KeyServerCounter)
+    } else {
+        None
+    }
+}
+
+/// The counters of the session `keep_counter_session` kept.
+pub struct KeyServerCounter;
+
+impl KeyServerCounter {
+    fn with<T, F: FnOnce(&mut CounterSession<TcpStream>) -> Result<T>>(&self, f: F) -> Result<T> {
+        match *COUNTER_SESSION.lock().unwrap() {
+            Some(ref mut session) => f(session),
+            None => Err(ProvisioningError("no session with the key server".to_string()).into()),
+        }
+    }
+}
+
+impl TrustedCounter for KeyServerCounter {
+    fn counter(&mut self, name: &str) -> Result<u64> {
+        self.with(|session| session.counter(name))
+    }
+
+    fn increment(&mut self, name: &str) -> Result<u64> {
+        self.with(|session| session.increment(name))
+    }
+}
+
+fn receive_sealed<S: Read>(session: &mut Session, stream: &mut BufReader<S>) -> Result<usize> {
+    let mut received = 0;
+    loop {
+        let fields = session.open_message(stream)?;
+        match fields.first().map(|verb| verb.as_str()) {
+            Some("key") => {}
+            Some("sa") => {
+                install_sa(&fields)?;
+                continue;
+            }
+            Some("end") if fields.get(1) == Some(&received.to_string()) => return Ok(received),
+            Some("end") => {
+                return Err(ProvisioningError(format!("key server sent {:?} SAs, got {}", fields.get(1), received)).into());
+            }
+            _ => return Err(ProvisioningError(format!("unexpected sealed message {:?}", fields.first())).into()),
+        }
+        let keys = SaKeys::new(
+            number(&fields, 1, "SPI")?,
+            field(&fields, 2, "encryption key")?,
+            // AES-GCM SAs have none.
+            if fields.len() > 3 { field(&fields, 3, "authentication key")? } else { Vec::new() },
+        )?;
+        // AES-128 or AES-256, the AES-GCM ones with their salt.
+        match keys.enc_key.len() {
+            16 | 32 => {}
+            len if len == 16 + SALT_LEN || len == 32 + SALT_LEN => {}
+            _ => return Err(ProvisioningError(format!("{:?} has keys of the wrong size", keys)).into()),
+        }
+        install(keys);
+        received += 1;
+    }
+}
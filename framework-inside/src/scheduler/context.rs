@@ -1,16 +1,21 @@
 use allocators::CacheAligned;
 use common::*;
-use config::NetBricksConfiguration;
+use config::{NetBricksConfiguration, SchedulerConfiguration};
 use failure::Fail;
+use heap_ring::poll::PollConfig;
 // use interface::dpdk::{init_system, init_thread};
 // use interface::{PmdPort, PortQueue, VirtualPort, VirtualQueue};
-use interface::{SimulatePort, SimulateQueue};
+use interface::{copy_drops, SimulatePort, SimulateQueue};
+use native::mbuf_pool;
+use operators::set_batch_size;
 use scheduler::*;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::mpsc::{sync_channel, SyncSender};
 use std::sync::Arc;
 use std::thread::{self, JoinHandle, Thread};
+use std::time::Duration;
+use utils::ipsec::icv_mismatches;
 
 // type AlignedPortQueue = CacheAligned<PortQueue>;
 // type AlignedVirtualQueue = CacheAligned<VirtualQueue>;
@@ -45,22 +50,63 @@ pub struct NetBricksContext {
     pub ports: Vec<Arc<SimulatePort>>,
     pub rx_queues: Vec<CacheAligned<SimulateQueue>>,
     pub active_cores: Vec<i32>,
+    /// How `run` schedules the tasks it installs.
+    pub scheduler: SchedulerConfiguration,
+    /// How long `run` runs them for, if not until it is stopped otherwise.
+    pub duration: Option<Duration>,
+    /// How `run` backs off when the queues are idle.
+    pub polling: PollConfig,
 }
 
 impl NetBricksContext {
 
-    /// Run a function (which installs a pipeline) on the first core in the system, blocking. 
-    pub fn run<T>(&mut self, run: Arc<T>, npkts: u64)
+    /// Run a function (which installs a pipeline) on the first core in the system, blocking
+    /// until `npkts` packets (0 for no limit) have been processed, `duration` is up, the host
+    /// stops the rings or the pipeline sends the scheduler a `Shutdown`. Whichever it is, the
+    /// queues acknowledge the stop, so the host knows the NF is gone.
+    pub fn run<T>(&mut self, run: Arc<T>, npkts: u64) -> SchedulerSummary
     where
         T: Fn(Vec<AlignedSimulateQueue>, &mut StandaloneScheduler) + Send + Sync + 'static,
     {
-        let mut sched = StandaloneScheduler::new(npkts);
+        let mut sched = StandaloneScheduler::with_config(npkts, &self.scheduler);
+        if let Some(duration) = self.duration {
+            sched.stop_after(duration);
+        }
+        for queue in &self.rx_queues {
+            let queue = queue.clone();
+            sched.stop_when(move || queue.stop_requested());
+        }
+        if self.polling.adaptive {
+            let queues = self.rx_queues.clone();
+            sched.back_off_with(self.polling, move |timeout| {
+                // splits the wait, a doorbell is only watched while waiting on its queue.
+                let share = timeout / queues.len().max(1) as u32;
+                queues.iter().any(|queue| queue.wait_for_packets(share))
+            });
+        }
         let boxed_run = run.clone();
         let ports = self.rx_queues.clone();
         sched.run(Arc::new(move |s| {
             boxed_run(ports.clone(), s)
         }));
-        sched.execute_loop();
+        let summary = sched.execute_loop();
+        for queue in &self.rx_queues {
+            queue.acknowledge_stop();
+        }
+        println!("{}", summary);
+        if let Some(stats) = sched.backoff_stats() {
+            println!("backoff: {}", stats);
+        }
+        if copy_drops() > 0 {
+            println!("{} packets dropped copying them in or out of the enclave", copy_drops());
+        }
+        if icv_mismatches() > 0 {
+            println!("{} ESP packets dropped for a bad ICV", icv_mismatches());
+        }
+        if mbuf_pool::leaked() > 0 {
+            println!("{} dropped mbufs never made it back to dpdkIO", mbuf_pool::leaked());
+        }
+        summary
     }
 }
 
@@ -70,7 +116,13 @@ impl NetBricksContext {
 /// Return a Context to Execute.
 pub fn initialize_system(configuration: &NetBricksConfiguration) -> Result<NetBricksContext> {
     // init_system(configuration);
+    set_batch_size(configuration.batch_size);
     let mut ctx: NetBricksContext = Default::default();
+    ctx.scheduler = configuration.scheduler.clone();
+    ctx.polling = PollConfig::from(&configuration.polling);
+    if configuration.duration > 0 {
+        ctx.duration = Some(Duration::from_secs(configuration.duration));
+    }
     let mut cores: HashSet<_> = configuration.cores.iter().cloned().collect();
     for port in &configuration.ports {
         match SimulatePort::new(port) {
@@ -28,6 +28,43 @@ pub enum SchedulerError {
 pub trait Executable {
     fn execute(&mut self) -> usize;
     fn dependencies(&mut self) -> Vec<usize>;
+
+    /// Share of the core the task gets relative to the others under a weighted policy of the
+    /// `StandaloneScheduler`. Wrap a task in `Weighted` to change it.
+    fn weight(&self) -> u32 {
+        1
+    }
+}
+
+/// A task with a weight of its own, for `Scheduler::add_task`; see also
+/// `Scheduler::add_task_weighted`.
+pub struct Weighted<T: Executable> {
+    task: T,
+    weight: u32,
+}
+
+impl<T: Executable> Weighted<T> {
+    /// `task` with `weight` times the share of a task of weight 1; a weight of 0 counts as 1.
+    pub fn new(task: T, weight: u32) -> Weighted<T> {
+        Weighted {
+            task,
+            weight: weight.max(1),
+        }
+    }
+}
+
+impl<T: Executable> Executable for Weighted<T> {
+    fn execute(&mut self) -> usize {
+        self.task.execute()
+    }
+
+    fn dependencies(&mut self) -> Vec<usize> {
+        self.task.dependencies()
+    }
+
+    fn weight(&self) -> u32 {
+        self.weight
+    }
 }
 
 impl<F> Executable for F
@@ -44,7 +81,16 @@ where
 }
 
 pub trait Scheduler {
+    /// Adds `task` with the weight it has, 1 unless it is wrapped in `Weighted`.
     fn add_task<T: Executable + 'static>(&mut self, task: T) -> Result<usize>
     where
         Self: Sized;
+
+    /// Adds `task` with `weight` times the share of a task of weight 1.
+    fn add_task_weighted<T: Executable + 'static>(&mut self, task: T, weight: u32) -> Result<usize>
+    where
+        Self: Sized,
+    {
+        self.add_task(Weighted::new(task, weight))
+    }
 }
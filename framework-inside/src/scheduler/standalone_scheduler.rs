@@ -1,49 +1,181 @@
 use super::{Executable, Scheduler};
 use common::*;
+use config::{SchedulerConfiguration, SchedulingCost, SchedulingPolicy};
+use heap_ring::poll::{AdaptivePoller, PollConfig, PollStats};
 use std::default::Default;
+use std::fmt;
 use std::sync::mpsc::{sync_channel, Receiver, RecvError, SyncSender};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
 use utils;
 use std::io::stdout;
 use std::io::Write;
 
-/// Used to keep stats about each pipeline and eventually grant tokens, etc.
+/// Virtual time under WFQ is kept in units of 1/WEIGHT_SCALE of the cost, so that a cost of
+/// 1 packet still counts for tasks of a weight above 1.
+const WEIGHT_SCALE: u64 = 1 << 10;
+
+/// Rounds between two looks at the clock for `stop_after`, which costs an exit from the enclave.
+const DEADLINE_CHECK_ROUNDS: u64 = 1024;
+
+/// Used to keep stats about each pipeline and to share the core among them.
 struct Runnable {
+    /// What `add_task` returned for it.
+    pub id: usize,
     pub task: Box<Executable>,
     pub cycles: u64,
     pub last_run: u64,
+    /// Packets handled so far.
+    pub packets: u64,
+    /// Credit left in the current round under DRR, in units of the cost.
+    pub deficit: i64,
+    /// Cost over weight so far under WFQ, scaled by WEIGHT_SCALE.
+    pub vtime: u64,
+    /// Runs in a row without packets.
+    pub empty_runs: u32,
 }
 
 impl Runnable {
     pub fn from_task<T: Executable + 'static>(task: T) -> Runnable {
-        Runnable {
-            task: box task,
-            cycles: 0,
-            last_run: utils::rdtsc_unsafe(),
-        }
+        Runnable::from_boxed_task(box task)
     }
     pub fn from_boxed_task(task: Box<Executable>) -> Runnable {
         Runnable {
+            id: 0,
             task,
             cycles: 0,
             last_run: utils::rdtsc_unsafe(),
+            packets: 0,
+            deficit: 0,
+            vtime: 0,
+            empty_runs: 0,
+        }
+    }
+
+    /// Runs the task once. Returns the packets it handled and the cycles it took.
+    #[inline]
+    fn run(&mut self) -> (u64, u64) {
+        let begin = utils::rdtsc_unsafe();
+        let packets = self.task.execute() as u64;
+        let end = utils::rdtsc_unsafe();
+        self.cycles += end - begin;
+        self.last_run = end;
+        self.packets += packets;
+        if packets == 0 {
+            self.empty_runs = self.empty_runs.saturating_add(1);
+        } else {
+            self.empty_runs = 0;
+        }
+        (packets, end - begin)
+    }
+
+    fn summary(&self) -> TaskSummary {
+        TaskSummary {
+            id: self.id,
+            weight: self.task.weight(),
+            packets: self.packets,
+            cycles: self.cycles,
         }
     }
 }
 
-/// A very simple round-robin scheduler. This should really be more of a DRR scheduler.
+/// Why `StandaloneScheduler::execute_loop` returned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    /// It had no tasks to run.
+    NoTasks,
+    /// The tasks handled the number of packets it was created with.
+    Packets,
+    /// It ran for as long as `stop_after` said.
+    Duration,
+    /// A signal of `stop_when` went off, e.g. the host stopped the rings.
+    Signal,
+    /// It got a `SchedulerCommand::Shutdown`.
+    Shutdown,
+}
+
+/// What a task did while the scheduler ran.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskSummary {
+    pub id: usize,
+    pub weight: u32,
+    pub packets: u64,
+    pub cycles: u64,
+}
+
+/// What the tasks of a `StandaloneScheduler` did until it stopped, in the order they were
+/// added.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SchedulerSummary {
+    pub reason: StopReason,
+    pub packets: u64,
+    pub rounds: u64,
+    pub elapsed: Duration,
+    pub tasks: Vec<TaskSummary>,
+}
+
+impl fmt::Display for SchedulerSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "scheduler stopped ({:?}) after {:?}, {} rounds, {} packets",
+            self.reason, self.elapsed, self.rounds, self.packets,
+        )?;
+        for task in &self.tasks {
+            write!(
+                f,
+                "\n\ttask {}: weight {}, {} packets, {} cycles",
+                task.id, task.weight, task.packets, task.cycles,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A scheduler of the tasks of a core, round robin, deficit round robin or weighted fair
+/// queueing as its `SchedulerConfiguration` says
+///
+/// Under DRR and WFQ, a task gets a share of the core in proportion to its `weight`, counting
+/// what its runs cost in cycles or packets. Tasks that keep finding no packets move to an idle
+/// queue, which is only run once every few rounds of the run queue, until they find some.
 pub struct StandaloneScheduler {
-    /// The set of runnable items. Note we currently don't have a blocked queue.
+    /// The set of runnable items.
     run_q: Vec<Runnable>,
-    /// Next task to run.
+    /// Tasks that found no packets in their last `idle_runs` runs.
+    idle_q: Vec<Runnable>,
+    /// Next task to run, under round robin and DRR.
     next_task: usize,
+    /// Whether the next task has had its credit for the current round, under DRR.
+    in_turn: bool,
+    /// Runs in the current round, under WFQ.
+    round_runs: usize,
+    /// Rounds of the run queue so far.
+    rounds: u64,
+    /// Virtual time of the last task run under WFQ, which tasks joining the run queue start at.
+    vtime: u64,
+    config: SchedulerConfiguration,
+    /// Tasks added so far.
+    tasks: usize,
     /// Signal scheduler should continue executing tasks.
     execute_loop: bool,
+    /// Why it stopped executing them.
+    stop_reason: Option<StopReason>,
     /// Number of packet processed so far
-    npkts: u64, 
-    /// Number of packet that will process
+    npkts: u64,
+    /// Number of packet that will process, 0 for no limit.
     tol_pkts: u64,
+    /// How long `execute_loop` runs for, if not until something else stops it.
+    duration: Option<Duration>,
+    deadline: Option<Instant>,
+    /// Stop once any of these returns true.
+    stop_signals: Vec<Box<Fn() -> bool>>,
+    /// Commands sent through `command_channel`.
+    sched_channel: Option<Receiver<SchedulerCommand>>,
+    /// Packets handled in the current round.
+    round_packets: u64,
+    /// Decides when to wait for packets, and how, see `back_off_with`.
+    backoff: Option<(AdaptivePoller, Box<Fn(Duration) -> bool>)>,
 }
 
 /// Messages that can be sent on the scheduler channel to add or remove tasks.
@@ -64,60 +196,415 @@ impl Default for StandaloneScheduler {
 }
 
 impl Scheduler for StandaloneScheduler {
-    /// Add a task to the current scheduler.
+    /// Add a task to the current scheduler. Its `weight` is its share of the core under DRR
+    /// and WFQ.
     fn add_task<T: Executable + 'static>(&mut self, task: T) -> Result<usize> {
-		self.run_q.push(Runnable::from_task(task));
-        Ok(self.run_q.len())
+        Ok(self.push_task(Runnable::from_task(task)))
     }
 }
 
 impl StandaloneScheduler {
+    /// A round-robin scheduler.
     pub fn new(tol_pkts: u64) -> StandaloneScheduler {
+        StandaloneScheduler::with_config(tol_pkts, &SchedulerConfiguration::default())
+    }
+
+    pub fn with_config(tol_pkts: u64, config: &SchedulerConfiguration) -> StandaloneScheduler {
         StandaloneScheduler {
             run_q: Vec::with_capacity(DEFAULT_Q_SIZE),
+            idle_q: Vec::new(),
             next_task: 0,
+            in_turn: false,
+            round_runs: 0,
+            rounds: 0,
+            vtime: 0,
+            config: config.clone(),
+            tasks: 0,
             execute_loop: false,
+            stop_reason: None,
             npkts: 0,
-            tol_pkts, 
+            tol_pkts,
+            duration: None,
+            deadline: None,
+            stop_signals: Vec::new(),
+            sched_channel: None,
+            round_packets: 0,
+            backoff: None,
+        }
+    }
+
+    /// Backs off as `config` says once whole rounds find no packets, with `wait` to sleep until
+    /// packets may have come in or the timeout it is given passes; it returns whether they may
+    /// have. Tasks themselves should never sleep, that would hold up all the others.
+    pub fn back_off_with<F: Fn(Duration) -> bool + 'static>(&mut self, config: PollConfig, wait: F) {
+        self.backoff = Some((AdaptivePoller::new(config), box wait));
+    }
+
+    /// Poller statistics of the rounds so far, if it backs off.
+    pub fn backoff_stats(&self) -> Option<PollStats> {
+        self.backoff.as_ref().map(|&(ref poller, _)| *poller.stats())
+    }
+
+    /// Stops `execute_loop` once it has run for `duration`.
+    pub fn stop_after(&mut self, duration: Duration) {
+        self.duration = Some(duration);
+    }
+
+    /// Stops `execute_loop` once `signal` returns true. Signals are checked between rounds of
+    /// the run queue, so they should be cheap.
+    pub fn stop_when<F: Fn() -> bool + 'static>(&mut self, signal: F) {
+        self.stop_signals.push(box signal);
+    }
+
+    /// A channel to send the scheduler commands on while it runs, e.g. `Shutdown` from another
+    /// thread. They are handled between rounds of the run queue.
+    pub fn command_channel(&mut self) -> SyncSender<SchedulerCommand> {
+        let (sender, receiver) = sync_channel(DEFAULT_Q_SIZE);
+        self.sched_channel = Some(receiver);
+        sender
+    }
+
+    fn push_task(&mut self, mut runnable: Runnable) -> usize {
+        self.tasks += 1;
+        runnable.id = self.tasks;
+        runnable.vtime = self.vtime;
+        self.run_q.push(runnable);
+        self.tasks
+    }
+
+    fn handle_request(&mut self, request: SchedulerCommand) {
+        match request {
+            SchedulerCommand::Add(ex) => {
+                self.push_task(Runnable::from_boxed_task(ex));
+            }
+            SchedulerCommand::Run(f) => f(self),
+            // already executing.
+            SchedulerCommand::Execute => {}
+            SchedulerCommand::Shutdown => self.stop(StopReason::Shutdown),
+            SchedulerCommand::Handshake(chan) => {
+                chan.send(true).unwrap(); // Inform context about reaching barrier.
+                thread::park();
+            }
         }
     }
 
+    fn stop(&mut self, reason: StopReason) {
+        self.execute_loop = false;
+        self.stop_reason = Some(reason);
+    }
+
     pub fn run(&mut self, f: Arc<Fn(&mut StandaloneScheduler) + Send + Sync>) {
         f(self);
     }
 
-    /// Run the scheduling loop.
-    pub fn execute_loop(&mut self) {
+    /// Run the scheduling loop, until the packets it was created with have been handled, it
+    /// has run for `stop_after`, a signal of `stop_when` goes off or it gets a `Shutdown`.
+    pub fn execute_loop(&mut self) -> SchedulerSummary {
+        let start = Instant::now();
+        self.deadline = self.duration.map(|duration| start + duration);
+        self.stop_reason = None;
         self.execute_loop = true;
-        let mut begin_time = utils::rdtsc_unsafe();
-        if !self.run_q.is_empty() {
-            while self.execute_loop {
-                begin_time = self.execute_internal(begin_time)
-            }
+        if self.run_q.is_empty() && self.idle_q.is_empty() {
+            self.stop(StopReason::NoTasks);
+        }
+        while self.execute_loop {
+            self.execute_internal()
+        }
+        self.summary(start.elapsed())
+    }
+
+    /// What the tasks did so far.
+    fn summary(&self, elapsed: Duration) -> SchedulerSummary {
+        let mut tasks = self
+            .run_q
+            .iter()
+            .chain(self.idle_q.iter())
+            .map(Runnable::summary)
+            .collect::<Vec<_>>();
+        tasks.sort_by_key(|task| task.id);
+        SchedulerSummary {
+            reason: self.stop_reason.unwrap_or(StopReason::Shutdown),
+            packets: self.npkts,
+            rounds: self.rounds,
+            elapsed,
+            tasks,
         }
     }
 
     #[inline]
-    fn execute_internal(&mut self, begin: u64) -> u64 {
-		let time = {
-            let task = &mut (&mut self.run_q[self.next_task]);
-            self.npkts += task.task.execute() as u64;
-            let end = utils::rdtsc_unsafe();
-            task.cycles += end - begin;
-            task.last_run = end;
-            end
+    fn execute_internal(&mut self) {
+        if self.run_q.is_empty() {
+            // only idle tasks left, so there are no rounds to wait for.
+            self.run_idle();
+            self.rounds += 1;
+            self.check_stop();
+            self.back_off();
+            return;
+        }
+        let current = match self.config.policy {
+            SchedulingPolicy::Wfq => self.least_served(),
+            _ => self.next_task,
         };
-        let len = self.run_q.len();
-        let next = self.next_task + 1;
-        if next == len {
-            self.next_task = 0;
-            // if self.npkts >= self.tol_pkts {
-            //     self.execute_loop = false;
-            // }
-        } else {
-            self.next_task = next;
+        let quantum = self.config.quantum;
+        let (packets, idle, turn_over) = {
+            let task = &mut self.run_q[current];
+            if self.config.policy == SchedulingPolicy::Drr && !self.in_turn {
+                task.deficit += (quantum * u64::from(task.task.weight())) as i64;
+                self.in_turn = true;
+            }
+            let (packets, cycles) = task.run();
+            let cost = match self.config.cost {
+                SchedulingCost::Cycles => cycles,
+                SchedulingCost::Packets => packets.max(1),
+            };
+            let turn_over = match self.config.policy {
+                SchedulingPolicy::RoundRobin => true,
+                SchedulingPolicy::Drr => {
+                    // credit is for packets waiting, not to be saved up while there are none.
+                    task.deficit = if packets == 0 { 0 } else { task.deficit - cost as i64 };
+                    task.deficit <= 0
+                }
+                SchedulingPolicy::Wfq => {
+                    self.vtime = task.vtime;
+                    task.vtime += cost * WEIGHT_SCALE / u64::from(task.task.weight());
+                    true
+                }
+            };
+            let idle = self.config.idle_runs > 0 && task.empty_runs >= self.config.idle_runs;
+            (packets, idle, turn_over)
+        };
+        self.npkts += packets;
+        self.round_packets += packets;
+
+        if idle {
+            let mut task = self.run_q.remove(current);
+            task.deficit = 0;
+            self.idle_q.push(task);
+        }
+        match self.config.policy {
+            SchedulingPolicy::Wfq => {
+                self.round_runs += 1;
+                if self.round_runs >= self.run_q.len() {
+                    self.round_runs = 0;
+                    self.end_round();
+                }
+            }
+            _ => {
+                if idle || turn_over {
+                    self.in_turn = false;
+                    // the task after an idled one has moved into its place.
+                    let next = if idle { current } else { current + 1 };
+                    if next >= self.run_q.len() {
+                        self.next_task = 0;
+                        self.end_round();
+                    } else {
+                        self.next_task = next;
+                    }
+                }
+            }
+        }
+    }
+
+    /// The task on the run queue that has had the least of the core for its weight.
+    #[inline]
+    fn least_served(&self) -> usize {
+        let mut least = 0;
+        for (i, task) in self.run_q.iter().enumerate().skip(1) {
+            if task.vtime < self.run_q[least].vtime {
+                least = i;
+            }
+        }
+        least
+    }
+
+    fn end_round(&mut self) {
+        self.rounds += 1;
+        self.check_stop();
+        self.back_off();
+        if !self.idle_q.is_empty() && self.rounds % u64::from(self.config.idle_rounds.max(1)) == 0 {
+            self.run_idle();
+        }
+    }
+
+    /// Feeds the packets of the round to the poller, and waits if it has been idle long enough.
+    fn back_off(&mut self) {
+        let packets = self.round_packets as usize;
+        self.round_packets = 0;
+        if let Some((ref mut poller, ref wait)) = self.backoff {
+            if let Some(timeout) = poller.poll(packets) {
+                let woken = wait(timeout);
+                poller.slept(woken);
+            }
+        }
+    }
+
+    /// Stops the loop if it is time to, and handles the commands sent in the meantime.
+    fn check_stop(&mut self) {
+        if self.tol_pkts > 0 && self.npkts >= self.tol_pkts {
+            self.stop(StopReason::Packets);
+        }
+        if self.stop_signals.iter().any(|signal| signal()) {
+            self.stop(StopReason::Signal);
+        }
+        if let Some(deadline) = self.deadline {
+            if self.rounds % DEADLINE_CHECK_ROUNDS == 0 && Instant::now() >= deadline {
+                self.stop(StopReason::Duration);
+            }
+        }
+        let request = match self.sched_channel {
+            Some(ref channel) => channel.try_recv().ok(),
+            None => None,
         };
-        time
+        if let Some(request) = request {
+            self.handle_request(request);
+        }
+    }
+
+    /// Runs every idle task once, and moves those that found packets back to the run queue.
+    fn run_idle(&mut self) {
+        let mut i = 0;
+        while i < self.idle_q.len() {
+            let (packets, _) = self.idle_q[i].run();
+            self.npkts += packets;
+            self.round_packets += packets;
+            if packets > 0 {
+                let mut task = self.idle_q.swap_remove(i);
+                task.vtime = task.vtime.max(self.vtime);
+                self.run_q.push(task);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use scheduler::Weighted;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// A task that counts its runs, and handles `packets` packets every time.
+    fn counted(packets: Rc<Cell<usize>>) -> (Rc<Cell<usize>>, impl FnMut() -> usize) {
+        let runs = Rc::new(Cell::new(0));
+        let counter = runs.clone();
+        (runs, move || {
+            counter.set(counter.get() + 1);
+            packets.get()
+        })
+    }
+
+    fn scheduler(policy: SchedulingPolicy) -> StandaloneScheduler {
+        StandaloneScheduler::with_config(
+            0,
+            &SchedulerConfiguration {
+                policy,
+                cost: SchedulingCost::Packets,
+                quantum: 4,
+                idle_runs: 4,
+                idle_rounds: 8,
+            },
+        )
+    }
+
+    #[test]
+    fn weighted_policies_share_the_core_by_weight() {
+        for &policy in [SchedulingPolicy::Drr, SchedulingPolicy::Wfq].iter() {
+            let mut sched = scheduler(policy);
+            let (heavy, task) = counted(Rc::new(Cell::new(1)));
+            sched.add_task(Weighted::new(task, 3)).unwrap();
+            let (light, task) = counted(Rc::new(Cell::new(1)));
+            sched.add_task(task).unwrap();
+            for _ in 0..1600 {
+                sched.execute_internal();
+            }
+            assert_eq!((heavy.get(), light.get()), (1200, 400), "{:?}", policy);
+            assert_eq!(sched.npkts, 1600);
+        }
     }
 
+    #[test]
+    fn empty_tasks_wait_on_the_idle_queue() {
+        let mut sched = scheduler(SchedulingPolicy::RoundRobin);
+        let (busy, task) = counted(Rc::new(Cell::new(1)));
+        sched.add_task(task).unwrap();
+        let arrivals = Rc::new(Cell::new(0));
+        let (empty, task) = counted(arrivals.clone());
+        sched.add_task(task).unwrap();
+
+        for _ in 0..808 {
+            sched.execute_internal();
+        }
+        // 4 runs to go idle, then one every 8 rounds of the busy task alone.
+        assert_eq!(sched.idle_q.len(), 1);
+        assert_eq!(busy.get(), 804);
+        assert_eq!(empty.get(), 4 + 100);
+
+        arrivals.set(2);
+        for _ in 0..16 {
+            sched.execute_internal();
+        }
+        assert!(sched.idle_q.is_empty());
+        assert_eq!(sched.run_q.len(), 2);
+        assert!(empty.get() > 105);
+    }
+
+    #[test]
+    fn loop_stops_and_sums_up() {
+        let mut sched = StandaloneScheduler::with_config(1000, &SchedulerConfiguration::default());
+        assert_eq!(sched.execute_loop().reason, StopReason::NoTasks);
+        let (_, task) = counted(Rc::new(Cell::new(3)));
+        let first = sched.add_task(task).unwrap();
+        let (_, task) = counted(Rc::new(Cell::new(1)));
+        let second = sched.add_task_weighted(task, 2).unwrap();
+        let summary = sched.execute_loop();
+        assert_eq!(summary.reason, StopReason::Packets);
+        assert_eq!((summary.packets, summary.rounds), (1000, 250));
+        let tasks = summary.tasks.iter().map(|t| (t.id, t.weight, t.packets)).collect::<Vec<_>>();
+        assert_eq!(tasks, vec![(first, 1, 750), (second, 2, 250)]);
+
+        let mut sched = StandaloneScheduler::with_config(0, &SchedulerConfiguration::default());
+        let stop = Rc::new(Cell::new(false));
+        let signal = stop.clone();
+        sched.stop_when(move || signal.get());
+        let (runs, task) = counted(Rc::new(Cell::new(1)));
+        sched.add_task(task).unwrap();
+        sched.add_task(move || {
+            stop.set(runs.get() == 10);
+            0
+        }).unwrap();
+        assert_eq!(sched.execute_loop().reason, StopReason::Signal);
+
+        let mut sched = StandaloneScheduler::with_config(0, &SchedulerConfiguration::default());
+        let commands = sched.command_channel();
+        sched.add_task(move || {
+            commands.send(SchedulerCommand::Shutdown).unwrap();
+            1
+        }).unwrap();
+        let summary = sched.execute_loop();
+        assert_eq!((summary.reason, summary.rounds), (StopReason::Shutdown, 1));
+
+        let mut sched = StandaloneScheduler::with_config(0, &SchedulerConfiguration::default());
+        sched.add_task(|| 0).unwrap();
+        sched.stop_after(Duration::from_millis(10));
+        let waits = Rc::new(Cell::new(0));
+        let counter = waits.clone();
+        let config = PollConfig {
+            adaptive: true,
+            spin_polls: 2,
+            idle: Duration::from_millis(0),
+            max_sleep: Duration::from_millis(1),
+        };
+        // idle rounds end in waits on the queues rather than in busy polls.
+        sched.back_off_with(config, move |_| {
+            counter.set(counter.get() + 1);
+            false
+        });
+        let summary = sched.execute_loop();
+        assert_eq!(summary.reason, StopReason::Duration);
+        assert!(summary.elapsed >= Duration::from_millis(10));
+        assert!(waits.get() > 0);
+        assert_eq!(sched.backoff_stats().unwrap().sleeps, waits.get());
+    }
 }
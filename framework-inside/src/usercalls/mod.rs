@@ -0,0 +1,107 @@
+//! Clients of the usercall services of sgx-runner: the time of the host, a log, checkpoint
+//! storage, metrics and configuration.
+//!
+//! Connecting to `SERVICE_PREFIX` followed by the name of a service gets the enclave a stream
+//! to that service of the runner rather than a socket; the crate `protocol` has the frames and
+//! messages of each. A `Client` sends the requests of its service and reads back a `Result` of
+//! the response or of what went wrong on the runner's side, refusing frames longer than the
+//! service ever sends. Everything the runner answers is the host's word: nothing of it is
+//! confidential, and only what the enclave sealed itself can be trusted.
+use bincode;
+use common::*;
+use failure::Fail;
+use protocol::usercalls::{read_frame, write_frame};
+use std::io::{Read, Write};
+use std::marker::PhantomData;
+use std::net::TcpStream;
+
+pub use self::services::*;
+pub use protocol::usercalls::{Service, MAX_FRAME_LEN, SERVICE_PREFIX};
+
+mod services;
+
+#[derive(Debug, Fail)]
+pub enum UsercallError {
+    /// The runner could not carry the request out.
+    #[fail(display = "Usercall service {} failed: {}", _0, _1)]
+    Service(&'static str, String),
+    #[fail(display = "Usercall service {} hung up", _0)]
+    HungUp(&'static str),
+    /// A response of another kind than the request calls for.
+    #[fail(display = "Unexpected response from usercall service {}", _0)]
+    UnexpectedResponse(&'static str),
+}
+
+/// A connection to the usercall service `T`.
+pub struct Client<T: Service, S: Read + Write = TcpStream> {
+    stream: S,
+    phantom: PhantomData<T>,
+}
+
+impl<T: Service> Client<T, TcpStream> {
+    /// Connects to the service. Outside an enclave there is no runner to intercept the
+    /// connection, and it fails.
+    pub fn connect() -> Result<Client<T, TcpStream>> {
+        Ok(Client::new(TcpStream::connect(format!("{}{}", SERVICE_PREFIX, T::NAME))?))
+    }
+}
+
+impl<T: Service, S: Read + Write> Client<T, S> {
+    pub fn new(stream: S) -> Client<T, S> {
+        Client {
+            stream,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Sends `request` and waits for the response.
+    pub fn call(&mut self, request: &T::Request) -> Result<T::Response> {
+        write_frame(&mut self.stream, &bincode::serialize(request)?, T::MAX_REQUEST_LEN)?;
+        let frame = read_frame(&mut self.stream, T::MAX_RESPONSE_LEN)?.ok_or(UsercallError::HungUp(T::NAME))?;
+        match bincode::deserialize::<::std::result::Result<T::Response, String>>(&frame)? {
+            Ok(response) => Ok(response),
+            Err(e) => Err(UsercallError::Service(T::NAME, e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    #[test]
+    fn responses_are_checked() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let runner = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_frame(&mut stream, MAX_FRAME_LEN).unwrap().unwrap();
+            match bincode::deserialize::<ConfigRequest>(&request).unwrap() {
+                ConfigRequest::Fetch(ref name) if name == "nf" => {}
+                request => panic!("unexpected {:?}", request),
+            }
+            let response: ::std::result::Result<ConfigResponse, String> = Ok(ConfigResponse::Document(None));
+            write_frame(&mut stream, &bincode::serialize(&response).unwrap(), MAX_FRAME_LEN).unwrap();
+            read_frame(&mut stream, MAX_FRAME_LEN).unwrap().unwrap();
+            let response: ::std::result::Result<ConfigResponse, String> = Err("no such document".to_string());
+            write_frame(&mut stream, &bincode::serialize(&response).unwrap(), MAX_FRAME_LEN).unwrap();
+            read_frame(&mut stream, MAX_FRAME_LEN).unwrap().unwrap();
+            let response: ::std::result::Result<ConfigResponse, String> = Ok(ConfigResponse::Document(None));
+            write_frame(&mut stream, &bincode::serialize(&response).unwrap(), MAX_FRAME_LEN).unwrap();
+            read_frame(&mut stream, MAX_FRAME_LEN).unwrap().unwrap();
+            stream.write_all(&(MAX_FRAME_LEN as u32).to_be_bytes()).unwrap();
+        });
+
+        let mut config = Client::<ConfigService, _>::new(TcpStream::connect(addr).unwrap());
+        assert_eq!(config.fetch("nf").unwrap(), None);
+        let e = config.fetch("nf").unwrap_err();
+        assert_eq!(e.to_string(), "Usercall service config failed: no such document");
+        // a response to another request.
+        assert!(config.reject("nf", "too short").is_err());
+        // a frame longer than any configuration document.
+        assert!(config.fetch("nf").is_err());
+        runner.join().unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+//! What the enclave makes of the services of sgx-runner, whose messages are in the crate
+//! `protocol`.
+use super::{Client, Service, UsercallError};
+use common::*;
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use mbedtls::hash::{Md, Type};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+
+pub use protocol::usercalls::{
+    ConfigRequest, ConfigResponse, ConfigService, LogRecord, LogService, MetricsService, StorageRequest,
+    StorageResponse, StorageService, TimeRequest, TimeService, Timestamp,
+};
+
+impl<S: Read + Write> Client<TimeService, S> {
+    /// The time of the host since the UNIX epoch.
+    pub fn now(&mut self) -> Result<Duration> {
+        let now = self.call(&TimeRequest::Now)?;
+        Ok(Duration::new(now.secs, now.nanos))
+    }
+}
+
+impl<S: Read + Write> Client<MetricsService, S> {
+    pub fn report(&mut self, values: Vec<(String, u64)>) -> Result<()> {
+        self.call(&values)
+    }
+}
+
+impl<S: Read + Write> Client<ConfigService, S> {
+    /// The document `name`, if the runner has one.
+    pub fn fetch(&mut self, name: &str) -> Result<Option<String>> {
+        match self.call(&ConfigRequest::Fetch(name.to_string()))? {
+            ConfigResponse::Document(document) => Ok(document),
+            _ => Err(UsercallError::UnexpectedResponse(ConfigService::NAME).into()),
+        }
+    }
+
+    /// Tells the runner why the enclave cannot run with document `name`.
+    pub fn reject(&mut self, name: &str, reason: &str) -> Result<()> {
+        match self.call(&ConfigRequest::Reject(name.to_string(), reason.to_string()))? {
+            ConfigResponse::Noted => Ok(()),
+            _ => Err(UsercallError::UnexpectedResponse(ConfigService::NAME).into()),
+        }
+    }
+
+    /// The document `name` and its version, if the runner has a version newer than `version`.
+    pub fn poll(&mut self, name: &str, version: u64) -> Result<Option<(u64, String)>> {
+        match self.call(&ConfigRequest::Poll(name.to_string(), version))? {
+            ConfigResponse::Update(update) => Ok(update),
+            _ => Err(UsercallError::UnexpectedResponse(ConfigService::NAME).into()),
+        }
+    }
+}
+
+/// The MAC of log record `seq`: HMAC-SHA256 under `key` of the MAC of the record before, all
+/// zeroes for the first, and of the fields of the record. A record that is dropped, reordered
+/// or changed breaks the chain from there on.
+pub fn record_mac(key: &[u8], prev: &[u8; 32], seq: u64, level: &str, target: &str, message: &str) -> Result<[u8; 32]> {
+    let mut input = prev.to_vec();
+    input.extend_from_slice(&seq.to_be_bytes());
+    for field in [level, target, message].iter() {
+        input.extend_from_slice(&(field.len() as u32).to_be_bytes());
+        input.extend_from_slice(field.as_bytes());
+    }
+    let mut mac = [0u8; 32];
+    Md::hmac(Type::Sha256, key, &input, &mut mac)?;
+    Ok(mac)
+}
+
+struct LogChain<S: Read + Write> {
+    client: Client<LogService, S>,
+    key: Vec<u8>,
+    seq: u64,
+    mac: [u8; 32],
+}
+
+impl<S: Read + Write> LogChain<S> {
+    fn send(&mut self, level: Level, target: &str, message: String) -> Result<()> {
+        let level = level.to_string();
+        let mac = record_mac(&self.key, &self.mac, self.seq, &level, target, &message)?;
+        self.client.call(&LogRecord {
+            seq: self.seq,
+            level,
+            target: target.to_string(),
+            message,
+            mac: mac.to_vec(),
+        })?;
+        self.seq += 1;
+        self.mac = mac;
+        Ok(())
+    }
+}
+
+/// Logs through the log service of the runner. The host sees the records, so nothing secret
+/// belongs in them, but it cannot drop or change any unnoticed by whoever has `key`.
+pub struct UsercallLogger<S: Read + Write + Send = TcpStream> {
+    level: LevelFilter,
+    chain: Mutex<LogChain<S>>,
+}
+
+impl UsercallLogger<TcpStream> {
+    /// Connects to the log service, and makes the logger the one of the `log` crate.
+    pub fn init(key: &[u8], level: LevelFilter) -> Result<()> {
+        let logger = UsercallLogger::new(Client::connect()?, key, level);
+        log::set_boxed_logger(Box::new(logger))?;
+        log::set_max_level(level);
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Send> UsercallLogger<S> {
+    pub fn new(client: Client<LogService, S>, key: &[u8], level: LevelFilter) -> UsercallLogger<S> {
+        UsercallLogger {
+            level,
+            chain: Mutex::new(LogChain {
+                client,
+                key: key.to_vec(),
+                seq: 0,
+                mac: [0; 32],
+            }),
+        }
+    }
+}
+
+impl<S: Read + Write + Send> Log for UsercallLogger<S> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let mut chain = self.chain.lock().unwrap();
+            if let Err(e) = chain.send(record.level(), record.target(), record.args().to_string()) {
+                eprintln!("{}: {}", record.target(), record.args());
+                eprintln!("(not logged: {})", e);
+            }
+        }
+    }
+
+    fn flush(&self) {}
+}
@@ -0,0 +1,157 @@
+//! ESP over whole batches of packets.
+//!
+//! mbedtls has no multi-buffer AES-GCM or HMAC to hand several packets to at once, so what a
+//! batch saves is the work around the cipher: a run of packets of one SA looks the SA up,
+//! locks it and borrows its cipher context once, instead of once per packet.
+use super::*;
+use std::mem;
+
+/// A packet of a batch, and room for what becomes of it.
+pub struct EspJob<'a> {
+    /// The outbound SA of a packet to encrypt. Packets to decrypt name their SA themselves.
+    pub spi: u32,
+    pub input: &'a [u8],
+    pub output: &'a mut [u8],
+    /// What `SecurityAssociationDatabase::encrypt` or `decrypt` would have returned.
+    pub result: Result<usize, CryptoError>,
+    /// The full sequence number of the packet, once known.
+    seq: u64,
+}
+
+impl<'a> EspJob<'a> {
+    pub fn new(spi: u32, input: &'a [u8], output: &'a mut [u8]) -> EspJob<'a> {
+        EspJob {
+            spi,
+            input,
+            output,
+            result: Ok(0),
+            seq: 0,
+        }
+    }
+
+    /// The ESP header of the packet to encrypt.
+    pub(crate) fn esp_header(&self) -> [u8; ESP_HEADER_LENGTH] {
+        let mut esphdr = [0u8; ESP_HEADER_LENGTH];
+        esphdr[..4].copy_from_slice(&self.spi.to_be_bytes());
+        esphdr[4..].copy_from_slice(&(self.seq as u32).to_be_bytes());
+        esphdr
+    }
+
+    /// The high half of the sequence number, which the ICV covers if the SA uses ESN.
+    pub(crate) fn seq_hi(&self, esn: bool) -> Option<u32> {
+        if esn {
+            Some((self.seq >> 32) as u32)
+        } else {
+            None
+        }
+    }
+
+    fn fail_with(&mut self, error: CryptoError) {
+        if self.result.is_ok() {
+            self.result = Err(error);
+        }
+    }
+}
+
+/// Calls `f` on each run of consecutive jobs of the same SPI.
+fn for_each_run<F: FnMut(u32, &mut [EspJob])>(jobs: &mut [EspJob], mut f: F) {
+    let mut at = 0;
+    while at < jobs.len() {
+        let spi = jobs[at].spi;
+        let len = jobs[at..].iter().take_while(|job| job.spi == spi).count();
+        f(spi, &mut jobs[at..(at + len)]);
+        at += len;
+    }
+}
+
+impl SecurityAssociationDatabase {
+    /// `encrypt` for each of `jobs`: protects its input with outbound SA `spi` into its output.
+    /// Returns the number of packets encrypted.
+    pub fn encrypt_batch(&self, jobs: &mut [EspJob]) -> usize {
+        for_each_run(jobs, |spi, run| self.encrypt_run(spi, run));
+        jobs.iter().filter(|job| job.result.is_ok()).count()
+    }
+
+    /// `decrypt` for each of `jobs`: checks and decrypts its input, an ESP packet, into its
+    /// output. Returns the number of packets decrypted.
+    pub fn decrypt_batch(&self, jobs: &mut [EspJob]) -> usize {
+        for job in jobs.iter_mut() {
+            if job.input.len() < ESP_HEADER_LENGTH {
+                job.fail_with(CryptoError::PktlenError);
+            } else {
+                job.spi = esp_spi(job.input);
+            }
+        }
+        for_each_run(jobs, |spi, run| self.decrypt_run(spi, run));
+        jobs.iter().filter(|job| job.result.is_ok()).count()
+    }
+
+    fn encrypt_run(&self, spi: u32, jobs: &mut [EspJob]) {
+        let sa = match self.get(spi, Direction::Outbound) {
+            Some(sa) => sa,
+            None => return jobs.iter_mut().for_each(|job| job.fail_with(CryptoError::UnknownSa(spi))),
+        };
+        let (cipher, esn) = {
+            let mut sa = sa.lock().unwrap();
+            for job in jobs.iter_mut().filter(|job| job.result.is_ok()) {
+                let len = job.input.len() + sa.cipher.overhead();
+                if job.output.len() < len {
+                    job.fail_with(CryptoError::PktlenError);
+                    continue;
+                }
+                match sa.account(job.input.len()).and_then(|()| sa.next_seq()) {
+                    Ok(seq) => job.seq = seq,
+                    Err(e) => job.fail_with(e),
+                }
+                // the suites fill their output to the end.
+                let output = mem::replace(&mut job.output, &mut []);
+                job.output = &mut output[..len];
+            }
+            (sa.cipher, sa.esn)
+        };
+        cipher.suite().encrypt_batch(jobs, esn);
+    }
+
+    fn decrypt_run(&self, spi: u32, jobs: &mut [EspJob]) {
+        let sa = match self.get(spi, Direction::Inbound) {
+            Some(sa) => sa,
+            None => return jobs.iter_mut().for_each(|job| job.fail_with(CryptoError::UnknownSa(spi))),
+        };
+        let (cipher, esn) = {
+            let sa = sa.lock().unwrap();
+            for job in jobs.iter_mut().filter(|job| job.result.is_ok()) {
+                if job.input.len() < sa.cipher.overhead()
+                    || job.output.len() < job.input.len() - sa.cipher.overhead() + sa.cipher.suite().decrypt_slack()
+                {
+                    job.fail_with(CryptoError::PktlenError);
+                    continue;
+                }
+                match sa.check_replay(seq_lo(job.input)) {
+                    Ok(seq) => job.seq = seq,
+                    Err(e) => job.fail_with(e),
+                }
+            }
+            (sa.cipher, sa.esn)
+        };
+        // the SA is not held while decrypting, so other cores can use it meanwhile.
+        cipher.suite().decrypt_batch(jobs, esn);
+        let mut sa = sa.lock().unwrap();
+        for job in jobs.iter_mut().filter(|job| job.result.is_ok()) {
+            // another core, or an earlier packet of the batch, may have taken the same sequence
+            // number in the meantime.
+            let accepted = sa
+                .check_replay(seq_lo(job.input))
+                .and_then(|seq| if seq == job.seq { Ok(()) } else { Err(CryptoError::Replay(spi, seq)) })
+                .and_then(|()| sa.account(job.input.len()));
+            match accepted {
+                Ok(()) => sa.accept(job.seq),
+                Err(e) => job.fail_with(e),
+            }
+        }
+    }
+}
+
+/// The low half of the sequence number in the ESP header `esphdr` starts with.
+fn seq_lo(esphdr: &[u8]) -> u32 {
+    u32::from_be_bytes([esphdr[4], esphdr[5], esphdr[6], esphdr[7]])
+}
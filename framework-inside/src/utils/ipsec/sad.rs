@@ -0,0 +1,529 @@
+//! Security Association Database (RFC 4301): per-SA state of the ESP code, with RFC 4303
+//! sequence numbers, extended sequence numbers (ESN), the anti-replay window and lifetimes.
+//!
+//! The keys of an SA live in `provisioning`; this is everything else about it. `esp_encrypt`
+//! and `esp_decrypt` put the two together for NFs that terminate ESP, and the `esp_decap` and
+//! `esp_encap` operators for tunnel-mode SAs.
+use super::*;
+use fnv::FnvHashMap;
+use std::net::IpAddr;
+use std::slice;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Packets the anti-replay window covers, one bit each.
+pub const REPLAY_WINDOW: u64 = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// How the packets of an SA are protected: the cipher suite negotiated for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EspCipher {
+    AesCbcSha256,
+    AesGcm128,
+    AesGcm256,
+    ChaCha20Poly1305,
+}
+
+impl EspCipher {
+    pub const ALL: [EspCipher; 4] = [
+        EspCipher::AesCbcSha256,
+        EspCipher::AesGcm128,
+        EspCipher::AesGcm256,
+        EspCipher::ChaCha20Poly1305,
+    ];
+
+    pub fn suite(self) -> &'static CipherSuite {
+        match self {
+            EspCipher::AesCbcSha256 => &AES_CBC_SHA256,
+            EspCipher::AesGcm128 => &AES_GCM128,
+            EspCipher::AesGcm256 => &AES_GCM256,
+            EspCipher::ChaCha20Poly1305 => &CHACHA20_POLY1305,
+        }
+    }
+
+    /// The suite `CipherSuite::name` calls `name`.
+    pub fn from_name(name: &str) -> Option<EspCipher> {
+        EspCipher::ALL.iter().cloned().find(|cipher| cipher.suite().name() == name)
+    }
+
+    /// ESP header and IV, in front of the payload.
+    pub fn header_len(self) -> usize {
+        ESP_HEADER_LENGTH + self.suite().iv_len()
+    }
+
+    /// ESP header, IV and ICV around the payload.
+    pub fn overhead(self) -> usize {
+        self.header_len() + self.icv_len()
+    }
+
+    pub fn icv_len(self) -> usize {
+        self.suite().icv_len()
+    }
+
+    /// Padding, pad length and next header after `payload_len` bytes of payload, so the
+    /// ciphertext is whole blocks of the cipher (RFC 4303 section 2.4).
+    pub fn trailer_len(self, payload_len: usize) -> usize {
+        let block = self.suite().block_len();
+        let unpadded = payload_len + ESP_TRAILER_LENGTH;
+        ESP_TRAILER_LENGTH + (block - unpadded % block) % block
+    }
+}
+
+/// Hard limits on the traffic an SA may protect. Past 90% of either the SA is soft-expired,
+/// the hint to rekey.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Lifetime {
+    pub bytes: Option<u64>,
+    pub packets: Option<u64>,
+}
+
+impl Lifetime {
+    fn reached(limit: Option<u64>, count: u64, percent: u64) -> bool {
+        limit.map_or(false, |limit| u128::from(count) * 100 >= u128::from(limit) * u128::from(percent))
+    }
+}
+
+/// The sliding window of RFC 4303 section 3.4.3, over 64-bit sequence numbers.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayWindow {
+    /// Highest sequence number accepted so far.
+    top: u64,
+    /// Bit i is set when `top - i` was accepted.
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// The full sequence number of a packet carrying `seq_lo`, if it is new and within the
+    /// window. With `esn` the high half is inferred as in RFC 4303 appendix A2.1.
+    pub fn check(&self, seq_lo: u32, esn: bool) -> Option<u64> {
+        let seq = if esn { self.infer(seq_lo)? } else { u64::from(seq_lo) };
+        // nobody sends sequence number 0.
+        if seq == 0 {
+            return None;
+        }
+        if seq > self.top {
+            return Some(seq);
+        }
+        let age = self.top - seq;
+        if age >= REPLAY_WINDOW || self.bitmap & (1 << age) != 0 {
+            return None;
+        }
+        Some(seq)
+    }
+
+    fn infer(&self, seq_lo: u32) -> Option<u64> {
+        let top_lo = self.top as u32;
+        let top_hi = (self.top >> 32) as u32;
+        let bottom_lo = top_lo.wrapping_sub(REPLAY_WINDOW as u32 - 1);
+        let seq_hi = if top_lo >= REPLAY_WINDOW as u32 - 1 {
+            // the window sits within one epoch: anything below it is from the next.
+            if seq_lo >= bottom_lo {
+                top_hi
+            } else {
+                top_hi.checked_add(1)?
+            }
+        } else if seq_lo >= bottom_lo {
+            // the window straddles two epochs and this is from the end of the older one.
+            top_hi.checked_sub(1)?
+        } else {
+            top_hi
+        };
+        Some(u64::from(seq_hi) << 32 | u64::from(seq_lo))
+    }
+
+    /// Records `seq`, which `check` let through and whose ICV verified.
+    pub fn accept(&mut self, seq: u64) {
+        if seq > self.top {
+            let shift = seq - self.top;
+            self.bitmap = if shift >= REPLAY_WINDOW { 0 } else { self.bitmap << shift };
+            self.bitmap |= 1;
+            self.top = seq;
+        } else {
+            self.bitmap |= 1 << (self.top - seq);
+        }
+    }
+}
+
+/// One SA: what protects its packets and how far along it is.
+#[derive(Clone, Debug)]
+pub struct SecurityAssociation {
+    pub spi: u32,
+    pub direction: Direction,
+    pub cipher: EspCipher,
+    /// 64-bit sequence numbers, of which the packets carry the low half.
+    pub esn: bool,
+    pub lifetime: Lifetime,
+    /// Source and destination of the outer header of tunnel-mode packets.
+    pub tunnel: Option<(IpAddr, IpAddr)>,
+    /// The inbound SA whose traffic an outbound SA answers, see
+    /// `SecurityAssociationDatabase::reply_spi`.
+    pub reply_to: Option<u32>,
+    /// Last sequence number sent, outbound.
+    seq: u64,
+    /// Sequence numbers received, inbound.
+    window: ReplayWindow,
+    bytes: u64,
+    packets: u64,
+}
+
+impl SecurityAssociation {
+    pub fn new(spi: u32, direction: Direction, cipher: EspCipher) -> SecurityAssociation {
+        SecurityAssociation {
+            spi,
+            direction,
+            cipher,
+            esn: false,
+            lifetime: Lifetime::default(),
+            tunnel: None,
+            reply_to: None,
+            seq: 0,
+            window: ReplayWindow::default(),
+            bytes: 0,
+            packets: 0,
+        }
+    }
+
+    pub fn with_esn(mut self, esn: bool) -> SecurityAssociation {
+        self.esn = esn;
+        self
+    }
+
+    pub fn with_lifetime(mut self, lifetime: Lifetime) -> SecurityAssociation {
+        self.lifetime = lifetime;
+        self
+    }
+
+    pub fn with_tunnel(mut self, src: IpAddr, dst: IpAddr) -> SecurityAssociation {
+        self.tunnel = Some((src, dst));
+        self
+    }
+
+    pub fn with_reply_to(mut self, inbound_spi: u32) -> SecurityAssociation {
+        self.reply_to = Some(inbound_spi);
+        self
+    }
+
+    /// Bytes and packets protected so far.
+    pub fn usage(&self) -> (u64, u64) {
+        (self.bytes, self.packets)
+    }
+
+    pub fn soft_expired(&self) -> bool {
+        Lifetime::reached(self.lifetime.bytes, self.bytes, 90)
+            || Lifetime::reached(self.lifetime.packets, self.packets, 90)
+    }
+
+    pub fn hard_expired(&self) -> bool {
+        Lifetime::reached(self.lifetime.bytes, self.bytes, 100)
+            || Lifetime::reached(self.lifetime.packets, self.packets, 100)
+    }
+
+    /// Takes the sequence number of the next outbound packet. Without ESN the SA is used up
+    /// once the 32-bit counter would wrap.
+    pub fn next_seq(&mut self) -> Result<u64, CryptoError> {
+        let limit = if self.esn { u64::max_value() } else { u64::from(u32::max_value()) };
+        if self.seq == limit {
+            return Err(CryptoError::SequenceOverflow(self.spi));
+        }
+        self.seq += 1;
+        Ok(self.seq)
+    }
+
+    /// The full sequence number of an inbound packet carrying `seq_lo`, unless it is a replay.
+    pub fn check_replay(&self, seq_lo: u32) -> Result<u64, CryptoError> {
+        self.window
+            .check(seq_lo, self.esn)
+            .ok_or_else(|| CryptoError::Replay(self.spi, u64::from(seq_lo)))
+    }
+
+    /// Records an inbound packet that passed its ICV check.
+    pub fn accept(&mut self, seq: u64) {
+        self.window.accept(seq);
+    }
+
+    /// Fails once the SA hit its hard lifetime, otherwise counts a packet of `bytes`.
+    pub fn account(&mut self, bytes: usize) -> Result<(), CryptoError> {
+        if self.hard_expired() {
+            return Err(CryptoError::LifetimeExpired(self.spi));
+        }
+        self.bytes += bytes as u64;
+        self.packets += 1;
+        Ok(())
+    }
+}
+
+type SaTable = RwLock<FnvHashMap<u32, Arc<Mutex<SecurityAssociation>>>>;
+
+/// The SAs of this enclave, inbound ones by the SPI their packets carry and outbound ones by
+/// the SPI their packets get.
+#[derive(Default)]
+pub struct SecurityAssociationDatabase {
+    inbound: SaTable,
+    outbound: SaTable,
+    /// Outbound SPI by the inbound SPI it answers.
+    replies: RwLock<FnvHashMap<u32, u32>>,
+}
+
+impl SecurityAssociationDatabase {
+    pub fn new() -> SecurityAssociationDatabase {
+        SecurityAssociationDatabase::default()
+    }
+
+    fn table(&self, direction: Direction) -> &SaTable {
+        match direction {
+            Direction::Inbound => &self.inbound,
+            Direction::Outbound => &self.outbound,
+        }
+    }
+
+    /// Adds `sa`, replacing the one with the same SPI and direction, whose state is lost.
+    pub fn install(&self, sa: SecurityAssociation) {
+        let spi = sa.spi;
+        let table = self.table(sa.direction);
+        if let (Direction::Outbound, Some(inbound_spi)) = (sa.direction, sa.reply_to) {
+            self.replies.write().unwrap().insert(inbound_spi, spi);
+        }
+        if table.write().unwrap().insert(spi, Arc::new(Mutex::new(sa))).is_some() {
+            info!("replaced SA {:#010x}", spi);
+        }
+    }
+
+    pub fn remove(&self, spi: u32, direction: Direction) -> bool {
+        if direction == Direction::Outbound {
+            self.replies.write().unwrap().retain(|_, outbound_spi| *outbound_spi != spi);
+        }
+        self.table(direction).write().unwrap().remove(&spi).is_some()
+    }
+
+    pub fn get(&self, spi: u32, direction: Direction) -> Option<Arc<Mutex<SecurityAssociation>>> {
+        self.table(direction).read().unwrap().get(&spi).cloned()
+    }
+
+    /// The outbound SA to answer the traffic of inbound SA `inbound_spi` with: the one
+    /// provisioned as its reply, else the outbound SA of the same SPI. Enclaves do not share
+    /// outbound SAs, whose sequence numbers each keeps on its own, so this differs between them.
+    pub fn reply_spi(&self, inbound_spi: u32) -> Result<u32, CryptoError> {
+        if let Some(&spi) = self.replies.read().unwrap().get(&inbound_spi) {
+            return Ok(spi);
+        }
+        if self.outbound.read().unwrap().contains_key(&inbound_spi) {
+            return Ok(inbound_spi);
+        }
+        Err(CryptoError::UnknownSa(inbound_spi))
+    }
+
+    /// Protects `plain` with outbound SA `spi` into `output`, which gets the ESP header with
+    /// the next sequence number, the IV, the ciphertext and the ICV, and has to be
+    /// `EspCipher::overhead` longer than `plain`. Returns the length of the ESP packet.
+    pub fn encrypt(&self, spi: u32, plain: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+        let mut job = EspJob::new(spi, plain, output);
+        self.encrypt_batch(slice::from_mut(&mut job));
+        job.result
+    }
+
+    /// Checks and decrypts `pkt`, from its ESP header on, with the inbound SA its SPI names
+    /// and writes the payload to `output`. Replays, packets left of the anti-replay window and
+    /// packets whose ICV does not verify are refused. Returns the length of the payload.
+    pub fn decrypt(&self, pkt: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+        let mut job = EspJob::new(0, pkt, output);
+        self.decrypt_batch(slice::from_mut(&mut job));
+        job.result
+    }
+}
+
+lazy_static! {
+    /// The SAD `esp_encrypt` and `esp_decrypt` work with, filled by `provisioning`.
+    pub static ref SAD: SecurityAssociationDatabase = SecurityAssociationDatabase::new();
+}
+
+/// Protects `plain`, an IP packet, with outbound SA `spi` of `SAD`. See
+/// `SecurityAssociationDatabase::encrypt`.
+pub fn esp_encrypt(spi: u32, plain: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+    SAD.encrypt(spi, plain, output)
+}
+
+/// The outbound SA of `SAD` that answers inbound SA `inbound_spi`. See
+/// `SecurityAssociationDatabase::reply_spi`.
+pub fn esp_reply_spi(inbound_spi: u32) -> Result<u32, CryptoError> {
+    SAD.reply_spi(inbound_spi)
+}
+
+/// Checks and decrypts `pkt` with the inbound SA of `SAD` its SPI names. See
+/// `SecurityAssociationDatabase::decrypt`.
+pub fn esp_decrypt(pkt: &[u8], output: &mut [u8]) -> Result<usize, CryptoError> {
+    SAD.decrypt(pkt, output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::default();
+        assert_eq!(window.check(0, false), None);
+        for seq in (1..=70).filter(|seq| seq % 10 != 0) {
+            assert_eq!(window.check(seq, false), Some(u64::from(seq)));
+            window.accept(u64::from(seq));
+        }
+        assert_eq!(window.check(69, false), None);
+        // late, but within the window.
+        assert_eq!(window.check(60, false), Some(60));
+        // left of it.
+        assert_eq!(window.check(6, false), None);
+        assert_eq!(window.check(200, false), Some(200));
+        window.accept(200);
+        assert_eq!(window.check(70, false), None);
+    }
+
+    #[test]
+    fn esn_inference() {
+        let mut window = ReplayWindow::default();
+        window.accept((1 << 32) - 10);
+        // the low half wrapped: the next epoch.
+        assert_eq!(window.check(5, true), Some((1 << 32) + 5));
+        window.accept((1 << 32) + 5);
+        // the end of the previous epoch is still in the window.
+        assert_eq!(window.check(0xffff_fff8, true), Some(0xffff_fff8));
+        assert_eq!(window.check(0xffff_fff6, true), None);
+        // without ESN the same packet is just old.
+        assert_eq!(window.check(6, false), None);
+    }
+
+    #[test]
+    fn sequence_numbers_run_out() {
+        let mut sa = SecurityAssociation::new(1, Direction::Outbound, EspCipher::AesGcm128);
+        assert_eq!(sa.next_seq().unwrap(), 1);
+        sa.seq = u64::from(u32::max_value()) - 1;
+        assert_eq!(sa.next_seq().unwrap(), u64::from(u32::max_value()));
+        assert!(sa.next_seq().is_err());
+        sa.esn = true;
+        assert_eq!(sa.next_seq().unwrap(), 1 << 32);
+    }
+
+    fn provision(spi: u32, cipher: EspCipher, esn: bool, lifetime: Lifetime) {
+        let (enc_key, auth_key) = match cipher {
+            EspCipher::AesCbcSha256 => (vec![0x11; 16], vec![0x22; 32]),
+            // with their salt.
+            EspCipher::AesGcm128 => (vec![0x11; 20], Vec::new()),
+            EspCipher::AesGcm256 | EspCipher::ChaCha20Poly1305 => (vec![0x11; 36], Vec::new()),
+        };
+        provisioning::install(SaKeys::new(spi, enc_key, auth_key).unwrap());
+        for &direction in &[Direction::Inbound, Direction::Outbound] {
+            SAD.install(SecurityAssociation::new(spi, direction, cipher).with_esn(esn).with_lifetime(lifetime));
+        }
+    }
+
+    fn round_trip(spi: u32, cipher: EspCipher, esn: bool) {
+        provision(spi, cipher, esn, Lifetime::default());
+        let plain = [0x45u8; 48];
+        let mut sealed = vec![vec![0u8; plain.len() + cipher.overhead()]; 3];
+        for pkt in sealed.iter_mut() {
+            assert_eq!(esp_encrypt(spi, &plain, pkt).unwrap(), pkt.len());
+        }
+        assert_eq!(&sealed[2][4..8], &[0, 0, 0, 3]);
+
+        let mut opened = [0u8; 48 + 16];
+        for &i in &[0, 2, 1] {
+            assert_eq!(esp_decrypt(&sealed[i], &mut opened).unwrap(), plain.len());
+            assert_eq!(&opened[..plain.len()], &plain[..]);
+        }
+        match esp_decrypt(&sealed[1], &mut opened) {
+            Err(CryptoError::Replay(s, 2)) if s == spi => {}
+            other => panic!("replay let through: {:?}", other),
+        }
+        let (bytes, packets) = SAD.get(spi, Direction::Inbound).unwrap().lock().unwrap().usage();
+        assert_eq!((bytes, packets), (3 * sealed[0].len() as u64, 3));
+    }
+
+    #[test]
+    fn esp_round_trip_gcm() {
+        round_trip(0x3001, EspCipher::AesGcm128, false);
+    }
+
+    #[test]
+    fn esp_round_trip_cbc_esn() {
+        round_trip(0x3002, EspCipher::AesCbcSha256, true);
+    }
+
+    #[test]
+    fn esp_round_trip_gcm256() {
+        round_trip(0x3005, EspCipher::AesGcm256, false);
+    }
+
+    #[test]
+    fn esp_round_trip_chachapoly_esn() {
+        round_trip(0x3006, EspCipher::ChaCha20Poly1305, true);
+    }
+
+    #[test]
+    fn tampered_packets_are_not_accepted() {
+        provision(0x3003, EspCipher::AesGcm128, false, Lifetime::default());
+        let plain = [0x45u8; 48];
+        let mut sealed = [0u8; 48 + 32];
+        esp_encrypt(0x3003, &plain, &mut sealed).unwrap();
+        let mut opened = [0u8; 48 + 16];
+        sealed[30] ^= 1;
+        assert!(esp_decrypt(&sealed, &mut opened).is_err());
+        // nor does the forgery use up its sequence number.
+        sealed[30] ^= 1;
+        assert!(esp_decrypt(&sealed, &mut opened).is_ok());
+    }
+
+    #[test]
+    fn short_output_is_refused() {
+        for (&cipher, spi) in EspCipher::ALL.iter().zip(0x3010..) {
+            provision(spi, cipher, false, Lifetime::default());
+            let plain = [0x45u8; 48];
+            let mut sealed = vec![0u8; plain.len() + cipher.overhead()];
+            esp_encrypt(spi, &plain, &mut sealed).unwrap();
+            let mut opened = [0u8; 47];
+            match esp_decrypt(&sealed, &mut opened) {
+                Err(CryptoError::PktlenError) => {}
+                other => panic!("{:?} decrypted into too little room: {:?}", cipher, other),
+            }
+            // nor does that use up the sequence number.
+            let mut opened = [0u8; 48 + 16];
+            assert_eq!(esp_decrypt(&sealed, &mut opened).unwrap(), plain.len());
+        }
+    }
+
+    #[test]
+    fn replies_go_out_on_their_own_sa() {
+        let sad = SecurityAssociationDatabase::new();
+        sad.install(SecurityAssociation::new(0x10, Direction::Inbound, EspCipher::AesGcm128));
+        sad.install(SecurityAssociation::new(0x20, Direction::Inbound, EspCipher::AesGcm128));
+        sad.install(SecurityAssociation::new(0x20, Direction::Outbound, EspCipher::AesGcm128));
+        assert!(sad.reply_spi(0x10).is_err());
+        assert_eq!(sad.reply_spi(0x20).unwrap(), 0x20);
+        sad.install(SecurityAssociation::new(0x11, Direction::Outbound, EspCipher::AesGcm128).with_reply_to(0x10));
+        assert_eq!(sad.reply_spi(0x10).unwrap(), 0x11);
+        assert!(sad.remove(0x11, Direction::Outbound));
+        assert!(sad.reply_spi(0x10).is_err());
+    }
+
+    #[test]
+    fn lifetime_expires() {
+        let lifetime = Lifetime {
+            bytes: None,
+            packets: Some(10),
+        };
+        provision(0x3004, EspCipher::AesGcm128, false, lifetime);
+        let plain = [0x45u8; 48];
+        let mut sealed = [0u8; 48 + 32];
+        for _ in 0..9 {
+            esp_encrypt(0x3004, &plain, &mut sealed).unwrap();
+        }
+        assert!(SAD.get(0x3004, Direction::Outbound).unwrap().lock().unwrap().soft_expired());
+        esp_encrypt(0x3004, &plain, &mut sealed).unwrap();
+        match esp_encrypt(0x3004, &plain, &mut sealed) {
+            Err(CryptoError::LifetimeExpired(0x3004)) => {}
+            other => panic!("SA outlived its lifetime: {:?}", other),
+        }
+    }
+}
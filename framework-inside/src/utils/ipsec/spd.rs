@@ -0,0 +1,197 @@
+//! Security Policy Database (RFC 4301): which outbound traffic is protected, and with which SA.
+use packets::ip::{Flow, ProtocolNumber};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::RwLock;
+use utils::cidr::{Cidr, Ipv4Cidr};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyAction {
+    /// Protect with the outbound SA of this SPI.
+    Protect(u32),
+    /// Send in the clear.
+    Bypass,
+    Discard,
+}
+
+/// Traffic selectors and what to do with the traffic they match. A selector left `None`
+/// matches anything.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub src: Option<Ipv4Cidr>,
+    pub dst: Option<Ipv4Cidr>,
+    pub protocol: Option<ProtocolNumber>,
+    /// Inclusive port ranges, which packets without ports never match.
+    pub src_ports: Option<(u16, u16)>,
+    pub dst_ports: Option<(u16, u16)>,
+    pub action: PolicyAction,
+}
+
+impl Policy {
+    pub fn new(action: PolicyAction) -> Policy {
+        Policy {
+            src: None,
+            dst: None,
+            protocol: None,
+            src_ports: None,
+            dst_ports: None,
+            action,
+        }
+    }
+
+    pub fn matches(&self, flow: &Flow) -> bool {
+        self.selects_ports(flow.src_ip(), flow.dst_ip(), flow.protocol(), Some((flow.src_port(), flow.dst_port())))
+    }
+
+    /// Whether the selectors match a packet from `src` to `dst` carrying `protocol`.
+    pub fn selects(&self, src: IpAddr, dst: IpAddr, protocol: ProtocolNumber) -> bool {
+        self.selects_ports(src, dst, protocol, None)
+    }
+
+    /// `selects`, for a packet with source and destination port `ports`, if it has ports.
+    pub fn selects_ports(&self, src: IpAddr, dst: IpAddr, protocol: ProtocolNumber, ports: Option<(u16, u16)>) -> bool {
+        fn contains(cidr: &Option<Ipv4Cidr>, ip: IpAddr) -> bool {
+            cidr.as_ref().map_or(true, |cidr| cidr.contains_ip(ip))
+        }
+        fn within(range: Option<(u16, u16)>, port: Option<u16>) -> bool {
+            match (range, port) {
+                (None, _) => true,
+                (Some((start, end)), Some(port)) => start <= port && port <= end,
+                (Some(_), None) => false,
+            }
+        }
+        contains(&self.src, src)
+            && contains(&self.dst, dst)
+            && self.protocol.map_or(true, |selected| selected == protocol)
+            && within(self.src_ports, ports.map(|ports| ports.0))
+            && within(self.dst_ports, ports.map(|ports| ports.1))
+    }
+}
+
+/// The fewest prefixes that cover `start` to `end`, inclusive; `None` for all of IPv4.
+pub fn range_cidrs(start: Ipv4Addr, end: Ipv4Addr) -> Vec<Option<Ipv4Cidr>> {
+    let (mut start, end) = (u64::from(u32::from(start)), u64::from(u32::from(end)));
+    if start == 0 && end == u64::from(u32::max_value()) {
+        return vec![None];
+    }
+    let mut cidrs = Vec::new();
+    while start <= end {
+        // the biggest block aligned at start that does not run past end.
+        let mut size = if start == 0 { 1 << 32 } else { start & start.wrapping_neg() };
+        while start + size - 1 > end {
+            size >>= 1;
+        }
+        let length = 32 - size.trailing_zeros() as usize;
+        cidrs.push(Ipv4Cidr::new(Ipv4Addr::from(start as u32), length).ok());
+        start += size;
+    }
+    cidrs
+}
+
+/// An ordered list of policies; the first one that matches a flow decides. Data path cores
+/// look policies up while IKE adds and removes them.
+#[derive(Debug, Default)]
+pub struct SecurityPolicyDatabase {
+    policies: RwLock<Vec<Policy>>,
+}
+
+impl Clone for SecurityPolicyDatabase {
+    fn clone(&self) -> SecurityPolicyDatabase {
+        SecurityPolicyDatabase {
+            policies: RwLock::new(self.policies.read().unwrap().clone()),
+        }
+    }
+}
+
+impl SecurityPolicyDatabase {
+    pub fn new() -> SecurityPolicyDatabase {
+        SecurityPolicyDatabase::default()
+    }
+
+    /// Adds `policy` after the ones already there.
+    pub fn add(&self, policy: Policy) {
+        self.policies.write().unwrap().push(policy);
+    }
+
+    /// Adds `policies` ahead of the ones already there, so they take over the traffic they
+    /// share with older policies.
+    pub fn add_first(&self, policies: Vec<Policy>) {
+        let mut existing = self.policies.write().unwrap();
+        let older = existing.split_off(0);
+        existing.extend(policies);
+        existing.extend(older);
+    }
+
+    /// Removes the policies that protect with the SA of `spi`. Returns how many there were.
+    pub fn remove_sa(&self, spi: u32) -> usize {
+        let mut policies = self.policies.write().unwrap();
+        let before = policies.len();
+        policies.retain(|policy| policy.action != PolicyAction::Protect(spi));
+        before - policies.len()
+    }
+
+    /// What to do with `flow`. Traffic no policy covers is discarded, as RFC 4301 wants.
+    pub fn lookup(&self, flow: &Flow) -> PolicyAction {
+        self.select_ports(flow.src_ip(), flow.dst_ip(), flow.protocol(), Some((flow.src_port(), flow.dst_port())))
+    }
+
+    /// What to do with a packet from `src` to `dst` carrying `protocol`, for packets that
+    /// have no ports.
+    pub fn select(&self, src: IpAddr, dst: IpAddr, protocol: ProtocolNumber) -> PolicyAction {
+        self.select_ports(src, dst, protocol, None)
+    }
+
+    /// `select`, for a packet with source and destination port `ports`, if it has ports.
+    pub fn select_ports(&self, src: IpAddr, dst: IpAddr, protocol: ProtocolNumber, ports: Option<(u16, u16)>) -> PolicyAction {
+        self.policies
+            .read()
+            .unwrap()
+            .iter()
+            .find(|policy| policy.selects_ports(src, dst, protocol, ports))
+            .map_or(PolicyAction::Discard, |policy| policy.action)
+    }
+}
+
+lazy_static! {
+    /// The SPD of `esp_encap`: IKE installs the policies of its child SAs in it, and
+    /// `provisioning` those of the outbound tunnel-mode SAs of the key server.
+    pub static ref SPD: SecurityPolicyDatabase = SecurityPolicyDatabase::new();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use packets::ip::ProtocolNumbers;
+
+    fn cidrs(start: &str, end: &str) -> Vec<String> {
+        range_cidrs(start.parse().unwrap(), end.parse().unwrap())
+            .iter()
+            .map(|cidr| cidr.as_ref().map_or("any".to_string(), |cidr| format!("{}/{}", cidr.address(), cidr.length())))
+            .collect()
+    }
+
+    #[test]
+    fn ranges_to_prefixes() {
+        assert_eq!(cidrs("10.0.1.0", "10.0.1.255"), ["10.0.1.0/24"]);
+        assert_eq!(cidrs("10.0.0.255", "10.0.2.0"), ["10.0.0.255/32", "10.0.1.0/24", "10.0.2.0/32"]);
+        assert_eq!(cidrs("0.0.0.0", "255.255.255.255"), ["any"]);
+        assert_eq!(cidrs("0.0.0.0", "127.255.255.255"), ["0.0.0.0/1"]);
+        assert_eq!(cidrs("255.255.255.255", "255.255.255.255"), ["255.255.255.255/32"]);
+    }
+
+    #[test]
+    fn first_match_decides() {
+        let spd = SecurityPolicyDatabase::new();
+        spd.add(Policy::new(PolicyAction::Bypass));
+        spd.add_first(vec![Policy {
+            dst: Some("10.0.2.0/24".parse().unwrap()),
+            dst_ports: Some((500, 500)),
+            ..Policy::new(PolicyAction::Protect(7))
+        }]);
+        let (src, dst) = ("10.0.1.1".parse().unwrap(), "10.0.2.1".parse().unwrap());
+        assert_eq!(spd.select_ports(src, dst, ProtocolNumbers::Udp, Some((4500, 500))), PolicyAction::Protect(7));
+        assert_eq!(spd.select_ports(src, dst, ProtocolNumbers::Udp, Some((4500, 501))), PolicyAction::Bypass);
+        assert_eq!(spd.select(src, dst, ProtocolNumbers::Icmpv4), PolicyAction::Bypass);
+        assert_eq!(spd.remove_sa(7), 1);
+        assert_eq!(spd.select_ports(src, dst, ProtocolNumbers::Udp, Some((4500, 500))), PolicyAction::Bypass);
+    }
+}
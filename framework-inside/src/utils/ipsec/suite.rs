@@ -0,0 +1,210 @@
+//! ESP cipher suites. Each SA is protected with the suite it was provisioned with, which
+//! `EspCipher` names.
+use super::*;
+
+/// How the packets of an SA are encrypted and authenticated.
+///
+/// Suites hold no keys: those, and the cipher contexts keyed with them, are looked up by the
+/// SPI in the ESP header. Another suite implements this and gets a variant of `EspCipher`.
+pub trait CipherSuite: Sync {
+    /// The name provisioning and the runner's configuration know the suite by.
+    fn name(&self) -> &'static str;
+
+    /// Bytes of IV between the ESP header and the ciphertext.
+    fn iv_len(&self) -> usize;
+
+    fn icv_len(&self) -> usize;
+
+    /// The plaintext, with its ESP trailer, is padded to a multiple of this.
+    fn block_len(&self) -> usize;
+
+    /// Room `decrypt` needs in `output` past the payload.
+    fn decrypt_slack(&self) -> usize {
+        0
+    }
+
+    /// Whether the provisioned keys are of the lengths the suite takes. AEAD keys end in the
+    /// `SALT_LEN` bytes of salt of their nonces.
+    fn fits(&self, enc_key: &[u8], auth_key: &[u8]) -> bool;
+
+    /// Encrypts `plain` behind ESP header `esphdr` into `output`, followed by the ICV, which
+    /// also covers `seq_hi`, the high half of an extended sequence number. Returns the length
+    /// of the ESP packet.
+    fn encrypt(&self, plain: &[u8], esphdr: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError>;
+
+    /// Checks the ICV of ESP packet `pkt` and decrypts its payload into `output`. Returns the
+    /// length of the payload.
+    fn decrypt(&self, pkt: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError>;
+
+    /// `encrypt` for each of `jobs`, packets of one SA whose sequence numbers are taken. Jobs
+    /// that failed already are skipped. Suites that can look the keys up once for all of them.
+    fn encrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        for job in jobs.iter_mut().filter(|job| job.result.is_ok()) {
+            job.result = self.encrypt(job.input, &job.esp_header(), job.seq_hi(esn), job.output);
+        }
+    }
+
+    /// `decrypt` for each of `jobs`, as `encrypt_batch`.
+    fn decrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        for job in jobs.iter_mut().filter(|job| job.result.is_ok()) {
+            job.result = self.decrypt(job.input, job.seq_hi(esn), job.output);
+        }
+    }
+}
+
+/// AES-CBC with HMAC-SHA256 truncated to 128 bits (RFC 3602 and 4868).
+pub struct AesCbcSha256Suite;
+
+impl CipherSuite for AesCbcSha256Suite {
+    fn name(&self) -> &'static str {
+        "aes-cbc-sha256"
+    }
+
+    fn iv_len(&self) -> usize {
+        AES_CBC_IV_LENGTH
+    }
+
+    fn icv_len(&self) -> usize {
+        ICV_LEN_SHA256
+    }
+
+    fn block_len(&self) -> usize {
+        16
+    }
+
+    /// mbedtls wants a block of room past the plaintext of CBC.
+    fn decrypt_slack(&self) -> usize {
+        16
+    }
+
+    fn fits(&self, enc_key: &[u8], auth_key: &[u8]) -> bool {
+        (enc_key.len() == 16 || enc_key.len() == 32) && auth_key.len() == 32
+    }
+
+    fn encrypt(&self, plain: &[u8], esphdr: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        cbc_sha256_encrypt(plain, esphdr, seq_hi, output)
+    }
+
+    fn decrypt(&self, pkt: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        cbc_sha256_decrypt(pkt, seq_hi, output).map(|len| len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)
+    }
+
+    fn encrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_ENCRY_CBC_SHA, jobs, |keys, cipher, job| {
+            let mut iv = [0u8; AES_CBC_IV_LENGTH];
+            Rdrand.random(&mut iv).map_err(|_| CryptoError::AESEncryptError)?;
+            cbc_sha256_seal(keys, cipher, job.input, &job.esp_header(), &iv, job.seq_hi(esn), job.output)
+        })
+    }
+
+    fn decrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_DECRY_CBC_SHA, jobs, |keys, cipher, job| {
+            cbc_sha256_open(keys, cipher, job.input, job.seq_hi(esn), job.output)
+                .map(|len| len - ESP_HEADER_LENGTH - AES_CBC_IV_LENGTH)
+        })
+    }
+}
+
+/// AES-GCM with a 16-byte ICV (RFC 4106), with a key of `key_len` bytes.
+pub struct AesGcmSuite {
+    key_len: usize,
+}
+
+impl CipherSuite for AesGcmSuite {
+    fn name(&self) -> &'static str {
+        if self.key_len == 32 {
+            "aes-gcm256"
+        } else {
+            "aes-gcm128"
+        }
+    }
+
+    fn iv_len(&self) -> usize {
+        AES_GCM_IV_LENGTH
+    }
+
+    fn icv_len(&self) -> usize {
+        ICV_LEN_GCM128
+    }
+
+    // a stream cipher: only the 4-byte alignment ESP asks for.
+    fn block_len(&self) -> usize {
+        4
+    }
+
+    fn fits(&self, enc_key: &[u8], auth_key: &[u8]) -> bool {
+        enc_key.len() == self.key_len + SALT_LEN && auth_key.is_empty()
+    }
+
+    fn encrypt(&self, plain: &[u8], esphdr: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        gcm128_encrypt(plain, esphdr, seq_hi, output)
+    }
+
+    fn decrypt(&self, pkt: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        gcm128_decrypt(pkt, seq_hi, output).map(|len| len - ESP_HEADER_LENGTH - AES_GCM_IV_LENGTH)
+    }
+
+    fn encrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_ENCRY_GCM, jobs, |keys, cipher, job| {
+            aead_seal(keys, cipher, job.input, &job.esp_header(), None, job.seq_hi(esn), job.output)
+        })
+    }
+
+    fn decrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_DECRY_GCM, jobs, |keys, cipher, job| {
+            aead_open(keys, cipher, job.input, job.seq_hi(esn), job.output)
+                .map(|len| len - ESP_HEADER_LENGTH - AES_GCM_IV_LENGTH)
+        })
+    }
+}
+
+/// ChaCha20-Poly1305 (RFC 7634), for hosts whose enclaves have no AES-NI to make AES fast.
+pub struct ChaCha20Poly1305Suite;
+
+impl CipherSuite for ChaCha20Poly1305Suite {
+    fn name(&self) -> &'static str {
+        "chacha20-poly1305"
+    }
+
+    fn iv_len(&self) -> usize {
+        CHACHAPOLY_IV_LENGTH
+    }
+
+    fn icv_len(&self) -> usize {
+        ICV_LEN_CHACHAPOLY
+    }
+
+    fn block_len(&self) -> usize {
+        4
+    }
+
+    fn fits(&self, enc_key: &[u8], auth_key: &[u8]) -> bool {
+        enc_key.len() == 32 + SALT_LEN && auth_key.is_empty()
+    }
+
+    fn encrypt(&self, plain: &[u8], esphdr: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        chachapoly_encrypt(plain, esphdr, seq_hi, output)
+    }
+
+    fn decrypt(&self, pkt: &[u8], seq_hi: Option<u32>, output: &mut [u8]) -> Result<usize, CryptoError> {
+        chachapoly_decrypt(pkt, seq_hi, output).map(|len| len - ESP_HEADER_LENGTH - CHACHAPOLY_IV_LENGTH)
+    }
+
+    fn encrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_ENCRY_CHACHAPOLY, jobs, |keys, cipher, job| {
+            aead_seal(keys, cipher, job.input, &job.esp_header(), None, job.seq_hi(esn), job.output)
+        })
+    }
+
+    fn decrypt_batch(&self, jobs: &mut [EspJob], esn: bool) {
+        with_sa_cipher(&CIPHER_DECRY_CHACHAPOLY, jobs, |keys, cipher, job| {
+            aead_open(keys, cipher, job.input, job.seq_hi(esn), job.output)
+                .map(|len| len - ESP_HEADER_LENGTH - CHACHAPOLY_IV_LENGTH)
+        })
+    }
+}
+
+pub static AES_CBC_SHA256: AesCbcSha256Suite = AesCbcSha256Suite;
+pub static AES_GCM128: AesGcmSuite = AesGcmSuite { key_len: 16 };
+pub static AES_GCM256: AesGcmSuite = AesGcmSuite { key_len: 32 };
+pub static CHACHA20_POLY1305: ChaCha20Poly1305Suite = ChaCha20Poly1305Suite;
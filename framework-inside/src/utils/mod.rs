@@ -3,10 +3,13 @@ pub use self::atom::*;
 pub use self::ipsec::*;
 pub use self::dpirules::*;
 pub use self::dpihsrules::*;
+pub use self::table::*;
 mod asm;
 mod atom;
+mod table;
 pub mod ipsec;
 pub mod cidr;
+pub mod pcap;
 pub mod dpirules;
 pub mod dpihsrules;
 
@@ -0,0 +1,136 @@
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use utils::Atom;
+
+/// A version of the table of a `VersionedTable`.
+pub struct Versioned<T> {
+    pub version: u64,
+    pub table: T,
+}
+
+/// A table, such as the rules of a firewall, that the control plane replaces while pipelines
+/// read it
+///
+/// The control plane builds a new table wherever it likes, off the data path, and `publish`es
+/// it in one swap. Pipelines read it through a `TableReader` of their own, which holds on to
+/// the version it has until it is refreshed, once per batch, so a table is freed once the last
+/// pipeline has moved on from it, RCU-style.
+pub struct VersionedTable<T: 'static> {
+    current: Atom<Versioned<T>>,
+    version: AtomicU64,
+    publishing: Mutex<()>,
+}
+
+impl<T> VersionedTable<T> {
+    /// A table at version 1.
+    pub fn new(table: T) -> VersionedTable<T> {
+        VersionedTable {
+            current: Atom::new(Versioned { version: 1, table }),
+            version: AtomicU64::new(1),
+            publishing: Mutex::new(()),
+        }
+    }
+
+    /// Replaces the table. Returns the version it is now at.
+    pub fn publish(&self, table: T) -> u64 {
+        let _publishing = self.publishing.lock().unwrap();
+        let version = self.version.load(Ordering::Relaxed) + 1;
+        self.current.set(Versioned { version, table });
+        // readers only go for the table once they see the new version.
+        self.version.store(version, Ordering::Release);
+        version
+    }
+
+    /// The latest version.
+    #[inline]
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+
+    /// The latest version of the table.
+    pub fn current(&self) -> Arc<Versioned<T>> {
+        self.current.get()
+    }
+}
+
+/// A reader of a `VersionedTable`, for a pipeline to keep. It reads the same version until it
+/// is refreshed, which a pipeline does before every batch, see `Batch::on_batch`, and which
+/// costs an atomic load to look for a newer version. Clones share the version, so the
+/// closures of one pipeline can each have one; `new` makes a reader of its own.
+pub struct TableReader<T: 'static> {
+    table: Arc<VersionedTable<T>>,
+    current: Rc<RefCell<Arc<Versioned<T>>>>,
+}
+
+impl<T> TableReader<T> {
+    pub fn new(table: Arc<VersionedTable<T>>) -> TableReader<T> {
+        let current = Rc::new(RefCell::new(table.current()));
+        TableReader { table, current }
+    }
+
+    /// Moves on to the latest version of the table, if there is a newer one than that of the
+    /// reader. Returns whether there was.
+    #[inline]
+    pub fn refresh(&self) -> bool {
+        if self.table.version() != self.version() {
+            *self.current.borrow_mut() = self.table.current();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// The version of the table the reader has.
+    #[inline]
+    pub fn get(&self) -> Ref<T> {
+        Ref::map(self.current.borrow(), |current| &current.table)
+    }
+
+    /// The version number of the table the reader has.
+    pub fn version(&self) -> u64 {
+        self.current.borrow().version
+    }
+}
+
+impl<T> Clone for TableReader<T> {
+    fn clone(&self) -> TableReader<T> {
+        TableReader {
+            table: self.table.clone(),
+            current: self.current.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn readers_move_on_to_published_tables() {
+        let table = Arc::new(VersionedTable::new(vec![1, 2, 3]));
+        let reader = TableReader::new(table.clone());
+        let pipeline = reader.clone();
+        assert_eq!(*reader.get(), vec![1, 2, 3]);
+        assert!(!reader.refresh());
+
+        let old = table.current();
+        let publisher = {
+            let table = table.clone();
+            thread::spawn(move || table.publish(vec![4, 5]))
+        };
+        assert_eq!(publisher.join().unwrap(), 2);
+        // whoever still holds the old version keeps it as it was.
+        assert_eq!(old.table, vec![1, 2, 3]);
+        // the reader keeps its version until it is refreshed, and so do its clones.
+        assert_eq!(reader.version(), 1);
+        assert_eq!(*reader.get(), vec![1, 2, 3]);
+        assert!(reader.refresh());
+        assert_eq!(*reader.get(), vec![4, 5]);
+        assert_eq!(pipeline.version(), 2);
+        assert!(!pipeline.refresh());
+        assert_eq!(*TableReader::new(table).get(), vec![4, 5]);
+    }
+}
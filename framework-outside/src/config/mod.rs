@@ -39,6 +39,96 @@ pub struct NetBricksConfiguration {
     pub cache_size: u32,
     /// Custom DPDK arguments.
     pub dpdk_args: Option<String>,
+    /// Number of enclave rings dpdkIO spreads packets over. Defaults to one ring
+    /// per rx queue.
+    pub enclaves: Option<usize>,
+    /// How dpdkIO picks the enclave ring a packet goes to.
+    #[serde(default)]
+    pub steering: SteeringHash,
+    /// Backoff of the hostio loop when neither the NIC nor the enclaves have packets.
+    #[serde(default)]
+    pub polling: PollingConfiguration,
+    /// The part of sgx-runner's `[enclave]` table the rings depend on.
+    #[serde(default)]
+    pub enclave: EnclaveRings,
+}
+
+/// How many rings each enclave gets, read from the same `[enclave]` table as sgx-runner.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct EnclaveRings {
+    /// Queues of each enclave, each on a set of rings of its own.
+    pub queues: usize,
+    /// The queue of each enclave its IKE responder runs on, if it runs one.
+    pub ike: Option<IkeQueue>,
+}
+
+impl Default for EnclaveRings {
+    fn default() -> EnclaveRings {
+        EnclaveRings { queues: 1, ike: None }
+    }
+}
+
+/// The queue of the `[enclave.ike]` table; dpdkIO steers UDP port 500 to it, and nothing else.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct IkeQueue {
+    pub queue: usize,
+}
+
+/// How the shared-ring pollers back off when there is nothing to do.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct PollingConfiguration {
+    /// Back off when idle, at the cost of latency on the first packets of a burst. Off by
+    /// default: busy polling, which burns a core even at zero load.
+    pub adaptive: bool,
+    /// Empty polls in a row before starting to back off.
+    pub spin_polls: u32,
+    /// How long the rings must stay empty before sleeping on the doorbell, in microseconds.
+    pub idle_us: u64,
+    /// Upper bound on one sleep, in microseconds.
+    pub max_sleep_us: u64,
+}
+
+impl Default for PollingConfiguration {
+    fn default() -> PollingConfiguration {
+        PollingConfiguration {
+            adaptive: false,
+            spin_polls: 256,
+            idle_us: 1000,
+            max_sleep_us: 1000,
+        }
+    }
+}
+
+impl fmt::Display for PollingConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "adaptive: {}, spin polls: {}, idle: {}us, max sleep: {}us",
+            self.adaptive, self.spin_polls, self.idle_us, self.max_sleep_us,
+        )
+    }
+}
+
+/// Hash dpdkIO uses to pick the enclave ring for a packet. The flow hashes are
+/// symmetric, so both directions of a connection land on the same enclave.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SteeringHash {
+    /// Ring i gets whatever the NIC put on rx queue i, i.e. rely on NIC RSS.
+    Queue,
+    /// Toeplitz over the 5-tuple with a symmetric key, like symmetric NIC RSS.
+    SymmetricToeplitz,
+    /// FNV over the 5-tuple with the two endpoints ordered; cheaper than Toeplitz.
+    SortedTuple,
+}
+
+impl Default for SteeringHash {
+    fn default() -> SteeringHash {
+        SteeringHash::Queue
+    }
 }
 
 impl fmt::Display for NetBricksConfiguration {
@@ -52,7 +142,7 @@ impl fmt::Display for NetBricksConfiguration {
 
         write!(
             f,
-            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}",
+            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}\nenclaves: {:?}, {} queue(s) each, IKE queue: {:?}, steering: {:?}\npolling: {}",
             self.name,
             self.secondary,
             self.pool_size,
@@ -62,6 +152,11 @@ impl fmt::Display for NetBricksConfiguration {
             self.strict,
             ports,
             self.dpdk_args,
+            self.enclaves,
+            self.enclave.queues,
+            self.enclave.ike.map(|ike| ike.queue),
+            self.steering,
+            self.polling,
         )
     }
 }
@@ -122,6 +217,10 @@ lazy_static! {
         (@arg dpdk_args: --("dpdk-args") ... +takes_value "custom DPDK arguments")
         (@arg duration: -d --duration +takes_value "test duration")
         (@arg sgxapp: -s --sgxapp +takes_value "sgx app binary")
+        (@arg enclaves: --enclaves +takes_value "number of enclave rings to steer packets to")
+        (@arg steering: --steering +takes_value "queue, symmetric_toeplitz or sorted_tuple")
+        (@arg queues: --queues +takes_value "queues of each enclave")
+        (@arg ring_size: --("ring-size") +takes_value "slots of each shared ring")
     )
     .get_matches();
 }
@@ -175,6 +274,22 @@ impl Source for CommandLine {
             );
         }
 
+        if CLI_ARGS.is_present("enclaves") {
+            let enclaves = value_t!(CLI_ARGS, "enclaves", u32)
+                .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+            map.insert("enclaves".to_string(), Value::new(uri, i64::from(enclaves)));
+        }
+
+        if let Some(steering) = CLI_ARGS.value_of("steering") {
+            map.insert("steering".to_string(), Value::new(uri, steering));
+        }
+
+        if CLI_ARGS.is_present("queues") {
+            let queues = value_t!(CLI_ARGS, "queues", u32)
+                .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+            map.insert("enclave.queues".to_string(), Value::new(uri, i64::from(queues)));
+        }
+
         if let Some(ports) = CLI_ARGS.values_of("ports") {
             let cores = values_t!(CLI_ARGS, "cores", i32)
                 .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
@@ -244,5 +359,32 @@ pub fn load_config() -> Result<NetBricksConfiguration, ConfigError> {
     }
 
     config.merge(CommandLine())?; 
-    config.try_into()
+    let mut configuration: NetBricksConfiguration = config.try_into()?;
+    // the rings of every port, like sgx-runner sizes them.
+    if CLI_ARGS.is_present("ring_size") {
+        let ring_size = value_t!(CLI_ARGS, "ring_size", i32)
+            .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+        for port in &mut configuration.ports {
+            port.rxd = ring_size;
+            port.txd = ring_size;
+        }
+    }
+    if configuration.ports.is_empty() {
+        return Err(ConfigError::Message("no ports".to_string()));
+    }
+    if configuration.enclave.queues == 0 {
+        return Err(ConfigError::Message("enclaves need at least one queue".to_string()));
+    }
+    if let Some(ike) = configuration.enclave.ike {
+        if ike.queue >= configuration.enclave.queues || configuration.enclave.queues < 2 {
+            return Err(ConfigError::Message(format!(
+                "IKE queue {} of {} queue(s): IKE takes a queue of its own besides those of the traffic",
+                ike.queue, configuration.enclave.queues
+            )));
+        }
+        if configuration.steering == SteeringHash::Queue {
+            return Err(ConfigError::Message("IKE needs a flow hash to be steered, not queue steering".to_string()));
+        }
+    }
+    Ok(configuration)
 }
@@ -1,3 +1,4 @@
+pub use self::pcap_port::*;
 pub use self::phy_port::*;
 pub use self::virt_port::*;
 use allocators::*;
@@ -6,6 +7,7 @@ use interface::{PacketRx, PacketTx};
 use native::mbuf::MBuf;
 use std::sync::atomic::AtomicUsize;
 
+mod pcap_port;
 mod virt_port;
 mod phy_port;
 
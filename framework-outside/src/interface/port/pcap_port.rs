@@ -0,0 +1,186 @@
+use super::super::{PacketRx, PacketTx};
+use super::PortStats;
+use allocators::*;
+use common::*;
+use native::mbuf::MBuf;
+use native::zcsi::*;
+use packets::buffer::{self, BufferError};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use utils::pcap::*;
+
+/// A port that replays a pcap or pcapng file into DPDK mbufs and writes what is sent to it to a
+/// pcap file, see `PcapOptions`. Lets a pipeline run without a NIC, e.g. for regression tests.
+#[derive(Clone)]
+pub struct PcapPort {
+    stats_rx: Arc<CacheAligned<PortStats>>,
+    stats_tx: Arc<CacheAligned<PortStats>>,
+    options: Arc<PcapOptions>,
+    replay: Arc<Mutex<PcapReplay>>,
+    capture: Option<Arc<Mutex<BufWriter<File>>>>,
+    /// Set by the first empty poll after the last frame.
+    drained: Arc<AtomicBool>,
+}
+
+impl fmt::Display for PcapPort {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pcap port rx {:?}, tx {:?}", self.options.rx, self.options.tx)
+    }
+}
+
+impl PcapPort {
+    pub fn new(options: &PcapOptions) -> Result<CacheAligned<PcapPort>> {
+        let frames = match options.rx {
+            Some(ref path) => read_pcap(path)?,
+            None => Vec::new(),
+        };
+        let capture = match options.tx {
+            Some(ref path) => {
+                let mut out = BufWriter::new(File::create(path)?);
+                write_pcap_header(&mut out)?;
+                out.flush()?;
+                Some(Arc::new(Mutex::new(out)))
+            }
+            None => None,
+        };
+        Ok(CacheAligned::allocate(PcapPort {
+            stats_rx: Arc::new(PortStats::new()),
+            stats_tx: Arc::new(PortStats::new()),
+            options: Arc::new(options.clone()),
+            replay: Arc::new(Mutex::new(PcapReplay::new(frames, options))),
+            capture,
+            drained: Arc::new(AtomicBool::new(false)),
+        }))
+    }
+
+    /// Whether every frame of the input has been received, as many times as asked for.
+    pub fn replay_done(&self) -> bool {
+        self.replay.lock().unwrap().done()
+    }
+
+    /// Whether the port was asked to `exit` and everything the pipeline made of the replay has
+    /// been sent, so whoever runs it can stop.
+    pub fn finished(&self) -> bool {
+        self.options.exit_when_done && self.drained.load(Ordering::Relaxed)
+    }
+
+    /// Get stats for an RX/TX queue pair.
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.stats_rx.stats.load(Ordering::Relaxed),
+            self.stats_tx.stats.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// Copies `frame` into a fresh mbuf.
+fn mbuf_with(frame: &[u8]) -> Result<*mut MBuf> {
+    let mbuf = unsafe { mbuf_alloc() };
+    if mbuf.is_null() {
+        return Err(BufferError::FailAlloc.into());
+    }
+    let copied = buffer::alloc(mbuf, 0, frame.len()).and_then(|_| buffer::write_slice(mbuf, 0, frame));
+    if let Err(e) = copied {
+        let mut mbuf = mbuf;
+        unsafe { mbuf_free_bulk(&mut mbuf, 1) };
+        return Err(e);
+    }
+    Ok(mbuf)
+}
+
+impl PacketRx for PcapPort {
+    fn recv(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let mut replay = self.replay.lock().unwrap();
+        let allowed = replay.allowance(pkts.len());
+        let mut count = 0;
+        while count < allowed {
+            match replay.next_frame().map(mbuf_with) {
+                Some(Ok(mbuf)) => pkts[count] = mbuf,
+                // the pool is empty, or the frame does not fit an mbuf: the frame is lost.
+                Some(Err(e)) => {
+                    warn_chain!(&e);
+                    break;
+                }
+                None => break,
+            }
+            count += 1;
+        }
+        // an empty poll after the last frame means everything made of it has been sent.
+        if count == 0 && replay.done() && self.options.rx.is_some() {
+            self.drained.store(true, Ordering::Relaxed);
+        }
+        let update = self.stats_rx.stats.load(Ordering::Relaxed) + count;
+        self.stats_rx.stats.store(update, Ordering::Relaxed);
+        Ok(count as u32)
+    }
+}
+
+impl PacketTx for PcapPort {
+    fn send(&self, pkts: &mut [*mut MBuf]) -> Result<u32> {
+        let written = match self.capture {
+            Some(ref capture) => {
+                let mut out = capture.lock().unwrap();
+                let now = SystemTime::now();
+                pkts.iter()
+                    .try_for_each(|&mbuf| {
+                        let frame = buffer::read_slice::<u8>(mbuf, 0, unsafe { (*mbuf).data_len() })?;
+                        write_pcap_record(&mut *out, unsafe { &*frame }, now)
+                    })
+                    // keep the file readable even if the NF never exits cleanly.
+                    .and_then(|_| out.flush().map_err(Into::into))
+            }
+            None => Ok(()),
+        };
+        // the packets are ours either way, a failed write must not leak them.
+        let len = pkts.len();
+        unsafe {
+            mbuf_free_bulk(pkts.as_mut_ptr(), len as i32);
+        }
+        written?;
+        let update = self.stats_tx.stats.load(Ordering::Relaxed) + len;
+        self.stats_tx.stats.store(update, Ordering::Relaxed);
+        Ok(len as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dpdk_test;
+
+    #[test]
+    fn replay_and_capture() {
+        dpdk_test! {
+            let dir = ::std::env::temp_dir();
+            let input = dir.join(format!("pcap_port_in_{}.pcap", ::std::process::id()));
+            let output = dir.join(format!("pcap_port_out_{}.pcap", ::std::process::id()));
+            let frames: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 64 + i as usize]).collect();
+            {
+                let mut file = File::create(&input).unwrap();
+                write_pcap_header(&mut file).unwrap();
+                for frame in &frames {
+                    write_pcap_record(&mut file, frame, SystemTime::now()).unwrap();
+                }
+            }
+
+            let spec = format!("rx={},tx={},exit", input.display(), output.display());
+            let port = PcapPort::new(&PcapOptions::parse(&spec).unwrap()).unwrap();
+            let mut pkts = vec![0 as *mut MBuf; 8];
+            assert_eq!(port.recv(&mut pkts).unwrap(), 3);
+            assert!(port.replay_done());
+            assert_eq!(port.send(&mut pkts[..3]).unwrap(), 3);
+            assert!(!port.finished());
+            assert_eq!(port.recv(&mut pkts).unwrap(), 0);
+            assert!(port.finished());
+            assert_eq!(port.stats(), (3, 3));
+
+            assert_eq!(read_pcap(output.to_str().unwrap()).unwrap(), frames);
+            let _ = ::std::fs::remove_file(&input);
+            let _ = ::std::fs::remove_file(&output);
+        }
+    }
+}
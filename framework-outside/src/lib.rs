@@ -27,6 +27,7 @@ extern crate lazy_static;
 extern crate libc;
 #[macro_use]
 extern crate log;
+extern crate pcapfile;
 extern crate net2;
 #[cfg(unix)]
 extern crate nix;
@@ -2,3 +2,5 @@ pub(crate) mod libnuma;
 pub(crate) mod zcsi;
 // pub(crate) mod mbuf;
 pub mod mbuf;
+/// Raw mempool access, for handing empty mbufs to an enclave (the mbufq) and freeing leftovers.
+pub use self::zcsi::{mbuf_alloc_bulk, mbuf_free_bulk};
@@ -5,6 +5,7 @@ mod asm;
 mod atom;
 pub mod ipsec;
 pub mod cidr;
+pub mod pcap;
 
 pub const PAGE_SIZE: usize = 4096; // Page size in bytes, not using huge pages here.
 
@@ -0,0 +1,2 @@
+//! The pcap reader and writer, shared with the other framework.
+pub use pcapfile::*;
@@ -0,0 +1,387 @@
+//! Just enough of the pcap and pcapng formats to replay Ethernet captures and write new ones.
+//! Both frameworks use it for their pcap ports, as `utils::pcap`.
+#[macro_use]
+extern crate failure;
+
+use failure::Error;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+/// Classic libpcap magic, microsecond timestamps.
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+/// Classic libpcap magic, nanosecond timestamps.
+const PCAP_MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+/// pcapng section header block; the type reads the same in either byte order.
+const PCAPNG_SHB: u32 = 0x0a0d_0d0a;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+const PCAPNG_IDB: u32 = 1;
+const PCAPNG_SPB: u32 = 3;
+const PCAPNG_EPB: u32 = 6;
+const LINKTYPE_ETHERNET: u32 = 1;
+const PCAP_SNAPLEN: u32 = 65535;
+
+type Result<T> = ::std::result::Result<T, Error>;
+
+#[derive(Debug, Fail)]
+#[fail(display = "Bad pcap file {}: {}", _0, _1)]
+pub struct PcapError(pub String, pub String);
+
+/// Reads every frame of an Ethernet pcap or pcapng file.
+pub fn read_pcap(path: &str) -> Result<Vec<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    parse_capture(path, &bytes)
+}
+
+/// Splits a capture held in memory into frames; `name` is only used in errors.
+pub fn parse_capture(name: &str, bytes: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let bad = |why: String| -> Error { PcapError(name.to_string(), why).into() };
+    if bytes.len() < 12 {
+        return Err(bad("truncated header".to_string()));
+    }
+    if u32_at(bytes, 0, false) == PCAPNG_SHB {
+        parse_pcapng(bytes).map_err(bad)
+    } else {
+        parse_classic(bytes).map_err(bad)
+    }
+}
+
+fn parse_classic(bytes: &[u8]) -> ::std::result::Result<Vec<Vec<u8>>, String> {
+    if bytes.len() < 24 {
+        return Err("truncated header".to_string());
+    }
+    let magic = u32_at(bytes, 0, false);
+    let swapped = match magic {
+        PCAP_MAGIC | PCAP_MAGIC_NANOS => false,
+        _ if magic.swap_bytes() == PCAP_MAGIC || magic.swap_bytes() == PCAP_MAGIC_NANOS => true,
+        _ => return Err(format!("bad magic {:#x}", magic)),
+    };
+    let linktype = u32_at(bytes, 20, swapped);
+    if linktype != LINKTYPE_ETHERNET {
+        return Err(format!("link type {} is not Ethernet", linktype));
+    }
+
+    let mut frames = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= bytes.len() {
+        let caplen = u32_at(bytes, offset + 8, swapped) as usize;
+        offset += 16;
+        if offset + caplen > bytes.len() {
+            return Err(format!("truncated record at {}", offset));
+        }
+        frames.push(bytes[offset..offset + caplen].to_vec());
+        offset += caplen;
+    }
+    Ok(frames)
+}
+
+/// Walks the blocks of a pcapng file. Every section may have its own byte order and its own
+/// interfaces; packets on anything but an Ethernet interface are rejected, unknown blocks are
+/// skipped.
+fn parse_pcapng(bytes: &[u8]) -> ::std::result::Result<Vec<Vec<u8>>, String> {
+    let mut frames = Vec::new();
+    let mut swapped = false;
+    let mut linktypes: Vec<u32> = Vec::new();
+    let mut offset = 0;
+    while offset + 12 <= bytes.len() {
+        let block_type = u32_at(bytes, offset, swapped);
+        if block_type == PCAPNG_SHB {
+            let order = u32_at(bytes, offset + 8, false);
+            swapped = match order {
+                PCAPNG_BYTE_ORDER_MAGIC => false,
+                _ if order.swap_bytes() == PCAPNG_BYTE_ORDER_MAGIC => true,
+                _ => return Err(format!("bad byte-order magic {:#x} at {}", order, offset)),
+            };
+            linktypes.clear();
+        }
+        let block_len = u32_at(bytes, offset + 4, swapped) as usize;
+        if block_len < 12 || block_len % 4 != 0 || offset + block_len > bytes.len() {
+            return Err(format!("bad block length {} at {}", block_len, offset));
+        }
+        let block = &bytes[offset..offset + block_len];
+        match block_type {
+            PCAPNG_IDB if block_len >= 20 => {
+                linktypes.push(u32::from(u16_at(block, 8, swapped)));
+            }
+            PCAPNG_EPB if block_len >= 32 => {
+                let interface = u32_at(block, 8, swapped) as usize;
+                let caplen = u32_at(block, 20, swapped) as usize;
+                if 28 + caplen > block_len - 4 {
+                    return Err(format!("truncated packet block at {}", offset));
+                }
+                check_ethernet(&linktypes, interface, offset)?;
+                frames.push(block[28..28 + caplen].to_vec());
+            }
+            PCAPNG_SPB if block_len >= 16 => {
+                // the simple packet block always refers to the first interface and has no
+                // captured length, the frame is cut at the end of the block.
+                let len = u32_at(block, 8, swapped) as usize;
+                let caplen = len.min(block_len - 16);
+                check_ethernet(&linktypes, 0, offset)?;
+                frames.push(block[12..12 + caplen].to_vec());
+            }
+            _ => {}
+        }
+        offset += block_len;
+    }
+    Ok(frames)
+}
+
+fn check_ethernet(linktypes: &[u32], interface: usize, offset: usize) -> ::std::result::Result<(), String> {
+    match linktypes.get(interface) {
+        Some(&LINKTYPE_ETHERNET) => Ok(()),
+        Some(linktype) => Err(format!("link type {} is not Ethernet", linktype)),
+        None => Err(format!("packet at {} on undeclared interface {}", offset, interface)),
+    }
+}
+
+#[inline]
+fn u32_at(bytes: &[u8], offset: usize, swapped: bool) -> u32 {
+    let mut word = [0u8; 4];
+    word.copy_from_slice(&bytes[offset..offset + 4]);
+    let value = u32::from_le_bytes(word);
+    if swapped {
+        value.swap_bytes()
+    } else {
+        value
+    }
+}
+
+#[inline]
+fn u16_at(bytes: &[u8], offset: usize, swapped: bool) -> u16 {
+    let value = u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+    if swapped {
+        value.swap_bytes()
+    } else {
+        value
+    }
+}
+
+/// Writes the global header of a little-endian, microsecond pcap file.
+pub fn write_pcap_header<W: Write>(out: &mut W) -> Result<()> {
+    out.write_all(&PCAP_MAGIC.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&0i32.to_le_bytes())?;
+    out.write_all(&0u32.to_le_bytes())?;
+    out.write_all(&PCAP_SNAPLEN.to_le_bytes())?;
+    out.write_all(&LINKTYPE_ETHERNET.to_le_bytes())?;
+    Ok(())
+}
+
+/// Appends one frame to a pcap file started with `write_pcap_header`.
+pub fn write_pcap_record<W: Write>(out: &mut W, frame: &[u8], timestamp: SystemTime) -> Result<()> {
+    let since_epoch = timestamp.duration_since(UNIX_EPOCH).unwrap_or_default();
+    out.write_all(&(since_epoch.as_secs() as u32).to_le_bytes())?;
+    out.write_all(&since_epoch.subsec_micros().to_le_bytes())?;
+    out.write_all(&(frame.len() as u32).to_le_bytes())?;
+    out.write_all(&(frame.len() as u32).to_le_bytes())?;
+    out.write_all(frame)?;
+    Ok(())
+}
+
+/// How a `PcapPort` replays and captures, parsed from the part of a port name after `pcap:`:
+///
+///    rx=<file>   capture to replay, pcap or pcapng.
+///    tx=<file>   pcap file sent packets are written to.
+///    pps=<n>     replay at most n packets per second (default: as fast as possible).
+///    loop=<n>    replay the input n times, 0 for ever (default: 1).
+///    exit        stop the NF once the replay is done and sent on, so the capture can be checked.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PcapOptions {
+    pub rx: Option<String>,
+    pub tx: Option<String>,
+    pub pps: Option<u64>,
+    pub loops: Option<u32>,
+    pub exit_when_done: bool,
+}
+
+impl PcapOptions {
+    pub fn parse(spec: &str) -> Result<PcapOptions> {
+        let bad = |why: String| -> Error { PcapError(spec.to_string(), why).into() };
+        let mut options = PcapOptions::default();
+        for part in spec.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = match part.find('=') {
+                Some(at) => (&part[..at], Some(&part[at + 1..])),
+                None => (part, None),
+            };
+            match (key, value) {
+                ("rx", Some(path)) => options.rx = Some(path.to_string()),
+                ("tx", Some(path)) => options.tx = Some(path.to_string()),
+                ("pps", Some(rate)) => match rate.parse() {
+                    Ok(rate) if rate > 0 => options.pps = Some(rate),
+                    _ => return Err(bad(format!("bad packet rate {:?}", rate))),
+                },
+                ("loop", Some(count)) => match count.parse() {
+                    Ok(count) => options.loops = Some(count),
+                    Err(_) => return Err(bad(format!("bad loop count {:?}", count))),
+                },
+                ("exit", None) => options.exit_when_done = true,
+                _ => return Err(bad(format!("unknown option {:?}", part))),
+            }
+        }
+        if options.rx.is_none() && options.tx.is_none() {
+            return Err(bad("needs rx= and/or tx=".to_string()));
+        }
+        Ok(options)
+    }
+}
+
+/// Replay state of a `PcapPort`: which frame comes next, how many passes are left, and how
+/// far ahead of the packet rate the replay is.
+pub struct PcapReplay {
+    frames: Vec<Vec<u8>>,
+    next: usize,
+    /// Passes over `frames` still to start after the current one, `None` for ever.
+    passes_left: Option<u32>,
+    pps: Option<u64>,
+    /// Set by the first `allowance` when pacing.
+    started: Option<Instant>,
+    replayed: u64,
+}
+
+impl PcapReplay {
+    pub fn new(frames: Vec<Vec<u8>>, options: &PcapOptions) -> PcapReplay {
+        let passes_left = match options.loops {
+            Some(0) => None,
+            Some(loops) => Some(loops - 1),
+            None => Some(0),
+        };
+        PcapReplay {
+            frames,
+            next: 0,
+            passes_left,
+            pps: options.pps,
+            started: None,
+            replayed: 0,
+        }
+    }
+
+    /// How many of `wanted` packets the rate limit lets through right now.
+    pub fn allowance(&mut self, wanted: usize) -> usize {
+        let pps = match self.pps {
+            Some(pps) => pps,
+            None => return wanted,
+        };
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let elapsed = started.elapsed();
+        let due = elapsed.as_secs() * pps + u64::from(elapsed.subsec_nanos()) * pps / 1_000_000_000;
+        // the first packet goes out right away.
+        (due + 1).saturating_sub(self.replayed).min(wanted as u64) as usize
+    }
+
+    /// Next frame to replay, starting the next pass if this one is done.
+    pub fn next_frame(&mut self) -> Option<&[u8]> {
+        if self.frames.is_empty() {
+            return None;
+        }
+        if self.next == self.frames.len() {
+            match self.passes_left {
+                Some(0) => return None,
+                Some(ref mut left) => *left -= 1,
+                None => {}
+            }
+            self.next = 0;
+        }
+        self.next += 1;
+        self.replayed += 1;
+        Some(&self.frames[self.next - 1])
+    }
+
+    /// Whether every frame has been replayed, as many times as asked for.
+    pub fn done(&self) -> bool {
+        self.frames.is_empty() || (self.next == self.frames.len() && self.passes_left == Some(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(block_type: u32, body: &[u8]) -> Vec<u8> {
+        let len = 12 + (body.len() + 3) / 4 * 4;
+        let mut out = Vec::new();
+        out.extend_from_slice(&block_type.to_le_bytes());
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        out.resize(len - 4, 0);
+        out.extend_from_slice(&(len as u32).to_le_bytes());
+        out
+    }
+
+    fn pcapng(frames: &[Vec<u8>]) -> Vec<u8> {
+        let mut shb = PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes().to_vec();
+        shb.extend_from_slice(&[1, 0, 0, 0]);
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        let mut idb = (LINKTYPE_ETHERNET as u16).to_le_bytes().to_vec();
+        idb.extend_from_slice(&[0, 0]);
+        idb.extend_from_slice(&PCAP_SNAPLEN.to_le_bytes());
+
+        let mut out = block(PCAPNG_SHB, &shb);
+        out.extend(block(PCAPNG_IDB, &idb));
+        // an unknown block (name resolution) is skipped.
+        out.extend(block(4, &[0; 4]));
+        for (i, frame) in frames.iter().enumerate() {
+            if i % 2 == 0 {
+                let mut epb = vec![0u8; 12];
+                epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+                epb.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+                epb.extend_from_slice(frame);
+                out.extend(block(PCAPNG_EPB, &epb));
+            } else {
+                let mut spb = (frame.len() as u32).to_le_bytes().to_vec();
+                spb.extend_from_slice(frame);
+                out.extend(block(PCAPNG_SPB, &spb));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn classic_round_trip() {
+        let frames: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; 60 + i as usize]).collect();
+        let mut out = Vec::new();
+        write_pcap_header(&mut out).unwrap();
+        for frame in &frames {
+            write_pcap_record(&mut out, frame, SystemTime::now()).unwrap();
+        }
+        assert_eq!(parse_capture("mem", &out).unwrap(), frames);
+
+        out[20] = 101;
+        assert!(parse_capture("mem", &out).is_err());
+        out[20] = 1;
+        out.pop();
+        assert!(parse_capture("mem", &out).is_err());
+    }
+
+    #[test]
+    fn pcapng_blocks() {
+        // odd lengths exercise the block padding.
+        let frames: Vec<Vec<u8>> = (0..5u8).map(|i| vec![i; 61 + i as usize]).collect();
+        let bytes = pcapng(&frames);
+        assert_eq!(parse_capture("mem", &bytes).unwrap(), frames);
+
+        // a packet on an interface nobody declared.
+        let mut bytes = pcapng(&frames[..1]);
+        let epb = bytes.len() - (12 + 20 + 64);
+        bytes[epb + 8] = 3;
+        assert!(parse_capture("mem", &bytes).is_err());
+    }
+
+    #[test]
+    fn parse_options() {
+        let options = PcapOptions::parse("rx=in.pcapng,tx=out.pcap,pps=1000,loop=0,exit").unwrap();
+        assert_eq!(options.rx, Some("in.pcapng".to_string()));
+        assert_eq!(options.tx, Some("out.pcap".to_string()));
+        assert_eq!(options.pps, Some(1000));
+        assert_eq!(options.loops, Some(0));
+        assert!(options.exit_when_done);
+
+        assert!(PcapOptions::parse("").is_err());
+        assert!(PcapOptions::parse("in.pcap").is_err());
+        assert!(PcapOptions::parse("rx=in.pcap,pps=0").is_err());
+        assert!(PcapOptions::parse("rx=in.pcap,loop=-1").is_err());
+        assert!(PcapOptions::parse("rx=in.pcap,exit=yes").is_err());
+    }
+}
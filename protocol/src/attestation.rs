@@ -0,0 +1,33 @@
+//! Sizes, offsets and labels of the attestation handshake, see framework-inside's
+//! `attestation` module for the messages.
+
+pub const NONCE_LEN: usize = 32;
+pub const TARGETINFO_LEN: usize = 512;
+pub const REPORT_LEN: usize = 432;
+/// The part of a report its MAC covers, and of a quote its report body.
+pub const REPORT_BODY_LEN: usize = 384;
+pub const MRENCLAVE_OFFSET: usize = 64;
+pub const REPORTDATA_OFFSET: usize = 320;
+pub const TAG_LEN: usize = 32;
+pub const SEAL_TAG_LEN: usize = 16;
+/// Additional data of sealed messages.
+pub const SEAL_AD: &[u8] = b"sealed";
+/// Label the channel key is derived from the session key with.
+pub const CHANNEL_LABEL: &[u8] = b"safebricks channel";
+/// Label the return key, of the enclave's sealed messages, is derived from the session key
+/// with.
+pub const RETURN_LABEL: &[u8] = b"safebricks return channel";
+
+/// Key of the reports an enclave built for a non-SGX target makes up, which sgx-runner's mock
+/// quoting service knows as well. Only good for tests: a real quoting enclave never accepts
+/// such a report.
+pub const MOCK_REPORT_KEY: &[u8] = b"safebricks mock report key";
+
+/// Nonce of the `count`th sealed message.
+pub fn seal_nonce(count: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    for (i, byte) in count.to_be_bytes().iter().enumerate() {
+        nonce[4 + i] = *byte;
+    }
+    nonce
+}
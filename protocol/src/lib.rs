@@ -0,0 +1,8 @@
+// what framework-inside and sgx-runner say to each other, kept in one place so that the two
+// cannot drift apart.
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod attestation;
+pub mod usercalls;
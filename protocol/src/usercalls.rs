@@ -0,0 +1,189 @@
+//! The usercall services of sgx-runner, as both ends see them.
+//!
+//! An enclave that connects to `SERVICE_PREFIX` followed by the name of a service gets a
+//! stream to that service of the runner instead of a socket. Both ways, every message is a
+//! frame: its length as a 32-bit big-endian integer, then the message in bincode. The enclave
+//! sends the `Request` of the service and gets a `Result<Response, String>` back for each,
+//! until it hangs up.
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+
+/// What the enclave connects to, followed by the name of the service.
+pub const SERVICE_PREFIX: &str = "safebricks-usercall:";
+/// Frames of any service are no longer than this: about the largest checkpoint of an NF.
+pub const MAX_FRAME_LEN: usize = 64 << 20;
+/// Frames of the services that carry no checkpoints are no longer than this.
+pub const DEFAULT_FRAME_LEN: usize = 1 << 20;
+
+/// A usercall service: its name and the messages it takes and gives.
+pub trait Service {
+    const NAME: &'static str;
+    type Request: Serialize + DeserializeOwned;
+    type Response: Serialize + DeserializeOwned;
+    /// The longest request frame the runner reads.
+    const MAX_REQUEST_LEN: usize = DEFAULT_FRAME_LEN;
+    /// The longest response frame the enclave reads.
+    const MAX_RESPONSE_LEN: usize = DEFAULT_FRAME_LEN;
+}
+
+/// Reads a frame of at most `max_len` bytes. Returns `None` if the other end hung up before
+/// it. The frame only takes as much memory as arrives of it.
+pub fn read_frame<R: Read>(stream: &mut R, max_len: usize) -> IoResult<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > max_len.min(MAX_FRAME_LEN) {
+        return Err(Error::new(ErrorKind::InvalidData, format!("{}-byte frame", len)));
+    }
+    let mut frame = Vec::new();
+    stream.take(len as u64).read_to_end(&mut frame)?;
+    if frame.len() < len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "truncated frame"));
+    }
+    Ok(Some(frame))
+}
+
+/// Writes `frame`, unless it is longer than `max_len` bytes.
+pub fn write_frame<W: Write>(stream: &mut W, frame: &[u8], max_len: usize) -> IoResult<()> {
+    if frame.len() > max_len.min(MAX_FRAME_LEN) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}-byte frame", frame.len())));
+    }
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)?;
+    stream.flush()
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum TimeRequest {
+    Now,
+}
+
+/// Time since the UNIX epoch.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Timestamp {
+    pub secs: u64,
+    pub nanos: u32,
+}
+
+/// The wall-clock time of the host. The enclave has no clock of its own, and must not trust
+/// this one for anything an attacker gains from moving.
+pub struct TimeService;
+
+impl Service for TimeService {
+    const NAME: &'static str = "time";
+    type Request = TimeRequest;
+    type Response = Timestamp;
+}
+
+/// A log record of an enclave, chained to the one before by its MAC, see framework-inside's
+/// `usercalls::record_mac`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct LogRecord {
+    pub seq: u64,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    pub mac: Vec<u8>,
+}
+
+/// The log of the runner.
+pub struct LogService;
+
+impl Service for LogService {
+    const NAME: &'static str = "log";
+    type Request = LogRecord;
+    type Response = ();
+}
+
+/// The values of metrics, by name, which the runner keeps the latest of. The enclave picks
+/// names that tell it apart from the others.
+pub struct MetricsService;
+
+impl Service for MetricsService {
+    const NAME: &'static str = "metrics";
+    type Request = Vec<(String, u64)>;
+    type Response = ();
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ConfigRequest {
+    /// The document of that name.
+    Fetch(String),
+    /// The enclave cannot run with the document of that name, for the reason given.
+    Reject(String, String),
+    /// The document of that name, if the runner has a version of it newer than the one given.
+    Poll(String, u64),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ConfigResponse {
+    Document(Option<String>),
+    Noted,
+    /// The version of the document and the document.
+    Update(Option<(u64, String)>),
+}
+
+/// Configuration documents of the runner, in TOML, by name. The runner may replace them while
+/// the enclave runs; each starts at version 1, the one `Fetch` gets at the latest.
+pub struct ConfigService;
+
+impl Service for ConfigService {
+    const NAME: &'static str = "config";
+    type Request = ConfigRequest;
+    type Response = ConfigResponse;
+}
+
+/// Requests of the storage service, by checkpoint name.
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StorageRequest {
+    Load(String),
+    Store(String, Vec<u8>),
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum StorageResponse {
+    /// The checkpoint, if there is one.
+    Blob(Option<Vec<u8>>),
+    Stored,
+}
+
+/// Where the runner keeps the sealed checkpoints of the enclaves, the only service whose
+/// frames get large.
+pub struct StorageService;
+
+impl Service for StorageService {
+    const NAME: &'static str = "storage";
+    type Request = StorageRequest;
+    type Response = StorageResponse;
+    const MAX_REQUEST_LEN: usize = MAX_FRAME_LEN;
+    const MAX_RESPONSE_LEN: usize = MAX_FRAME_LEN;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_are_capped() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, b"short", 8).unwrap();
+        assert!(write_frame(&mut stream, &[0u8; 9], 8).is_err());
+        let mut stream = Cursor::new(stream);
+        assert_eq!(read_frame(&mut stream, 8).unwrap(), Some(b"short".to_vec()));
+        assert_eq!(read_frame(&mut stream, 8).unwrap(), None);
+
+        // a length past the cap is refused before anything is allocated for it.
+        let mut stream = Cursor::new(vec![0xff, 0xff, 0xff, 0xff]);
+        assert!(read_frame(&mut stream, DEFAULT_FRAME_LEN).is_err());
+        let mut stream = Cursor::new(vec![0, 0, 0, 9, 1, 2, 3]);
+        assert_eq!(read_frame(&mut stream, 8).unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut stream = Cursor::new(vec![0, 0, 0, 8, 1, 2, 3]);
+        assert_eq!(read_frame(&mut stream, 8).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+}
@@ -0,0 +1,563 @@
+//! The runner and key server sides of the attestation handshake. The message flow is described
+//! with the enclave side, in framework-inside's `attestation` module.
+use aesm_client::{AesmClient, QuoteInfo};
+use attestation::Ias;
+use config::AttestationConfiguration;
+use hex;
+use mbedtls::cipher::raw::{Cipher, CipherId, CipherMode, Operation};
+use mbedtls::hash::{Md, Type};
+use mbedtls::pk::{EcGroupId, Pk};
+use mbedtls::rng::{Random, Rdrand};
+use protocol::attestation::{
+    seal_nonce, CHANNEL_LABEL, MRENCLAVE_OFFSET, REPORTDATA_OFFSET, REPORT_BODY_LEN, RETURN_LABEL, SEAL_AD, SEAL_TAG_LEN,
+    TAG_LEN,
+};
+use std::fmt::Display;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result as IoResult, Write};
+
+pub use protocol::attestation::{MOCK_REPORT_KEY, NONCE_LEN, REPORT_LEN, TARGETINFO_LEN};
+
+/// Where the report body starts in a quote (sgx_quote_t).
+const QUOTE_BODY_OFFSET: usize = 48;
+/// Room for the key server's signature, RSA-4096 at most.
+const SIGNATURE_MAX_LEN: usize = 512;
+/// Key the mock quoting service "signs" its quotes with.
+const MOCK_QUOTE_KEY: &[u8] = b"safebricks mock quote key";
+
+fn invalid<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::InvalidData, why.to_string())
+}
+
+fn other<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::Other, why.to_string())
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> IoResult<[u8; 32]> {
+    let mut mac = [0u8; 32];
+    Md::hmac(Type::Sha256, key, data, &mut mac).map_err(other)?;
+    Ok(mac)
+}
+
+fn equal_ct(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// What a verified quote says about the enclave.
+pub struct QuotedReport {
+    pub mrenclave: [u8; 32],
+    pub reportdata: [u8; 64],
+}
+
+impl QuotedReport {
+    pub fn from_quote(quote: &[u8]) -> IoResult<QuotedReport> {
+        if quote.len() < QUOTE_BODY_OFFSET + REPORT_BODY_LEN {
+            return Err(invalid(format!("quote too short: {} bytes", quote.len())));
+        }
+        let body = &quote[QUOTE_BODY_OFFSET..QUOTE_BODY_OFFSET + REPORT_BODY_LEN];
+        let mut quoted = QuotedReport {
+            mrenclave: [0; 32],
+            reportdata: [0; 64],
+        };
+        quoted.mrenclave.copy_from_slice(&body[MRENCLAVE_OFFSET..MRENCLAVE_OFFSET + 32]);
+        quoted.reportdata.copy_from_slice(&body[REPORTDATA_OFFSET..]);
+        Ok(quoted)
+    }
+}
+
+/// Checks quotes.
+pub trait QuoteVerifier {
+    /// Checks `quote` and returns the report it vouches for.
+    fn verify(&self, quote: &[u8]) -> IoResult<QuotedReport>;
+}
+
+/// Turns enclave reports into quotes.
+pub trait QuotingService: QuoteVerifier {
+    /// Target info of the quoting enclave, which the enclave addresses its report to.
+    fn target_info(&mut self) -> IoResult<Vec<u8>>;
+
+    /// Has `report` quoted.
+    fn quote(&mut self, report: &[u8]) -> IoResult<Vec<u8>>;
+}
+
+impl QuoteVerifier for Ias {
+    fn verify(&self, quote: &[u8]) -> IoResult<QuotedReport> {
+        QuotedReport::from_quote(&self.verify_quote(quote)?)
+    }
+}
+
+/// Quotes through the platform's AESM service and has the attestation service (IAS) check the
+/// EPID signature of the quotes.
+pub struct AesmQuotingService {
+    client: AesmClient,
+    spid: Vec<u8>,
+    session: Option<QuoteInfo>,
+    ias: Ias,
+}
+
+impl AesmQuotingService {
+    pub fn new(spid: Vec<u8>, ias: Ias) -> IoResult<AesmQuotingService> {
+        let client = AesmClient::new();
+        client.try_connect().map_err(other)?;
+        Ok(AesmQuotingService {
+            client,
+            spid,
+            session: None,
+            ias,
+        })
+    }
+}
+
+impl QuotingService for AesmQuotingService {
+    fn target_info(&mut self) -> IoResult<Vec<u8>> {
+        let session = self.client.init_quote().map_err(other)?;
+        let target_info = session.target_info().to_vec();
+        self.session = Some(session);
+        Ok(target_info)
+    }
+
+    fn quote(&mut self, report: &[u8]) -> IoResult<Vec<u8>> {
+        let session = self.session.as_ref().ok_or_else(|| other("no quote session, ask for the target info first"))?;
+        let quote = self
+            .client
+            .get_quote(session, report.to_vec(), self.spid.clone(), Vec::new())
+            .map_err(other)?;
+        Ok(quote.quote().to_vec())
+    }
+}
+
+impl QuoteVerifier for AesmQuotingService {
+    fn verify(&self, quote: &[u8]) -> IoResult<QuotedReport> {
+        self.ias.verify(quote)
+    }
+}
+
+/// Stands in for the quoting enclave in tests and on machines without SGX: accepts the reports
+/// MACed with `MOCK_REPORT_KEY` and HMACs its quotes instead of signing them.
+#[derive(Default)]
+pub struct MockQuotingService;
+
+impl QuotingService for MockQuotingService {
+    fn target_info(&mut self) -> IoResult<Vec<u8>> {
+        Ok(vec![0; TARGETINFO_LEN])
+    }
+
+    fn quote(&mut self, report: &[u8]) -> IoResult<Vec<u8>> {
+        if report.len() != REPORT_LEN {
+            return Err(invalid(format!("report is {} bytes, expected {}", report.len(), REPORT_LEN)));
+        }
+        let mac = hmac(MOCK_REPORT_KEY, &report[..REPORT_BODY_LEN])?;
+        if !equal_ct(&mac[..16], &report[REPORT_LEN - 16..]) {
+            return Err(invalid("report MAC does not verify"));
+        }
+        let mut quote = vec![0u8; QUOTE_BODY_OFFSET];
+        // version 2, like an EPID quote.
+        quote[0] = 2;
+        quote.extend_from_slice(&report[..REPORT_BODY_LEN]);
+        let signature = hmac(MOCK_QUOTE_KEY, &quote)?;
+        quote.extend_from_slice(&signature);
+        Ok(quote)
+    }
+}
+
+impl QuoteVerifier for MockQuotingService {
+    fn verify(&self, quote: &[u8]) -> IoResult<QuotedReport> {
+        let signed = QUOTE_BODY_OFFSET + REPORT_BODY_LEN;
+        if quote.len() != signed + 32 || !equal_ct(&hmac(MOCK_QUOTE_KEY, &quote[..signed])?, &quote[signed..]) {
+            return Err(invalid("quote signature does not verify"));
+        }
+        QuotedReport::from_quote(quote)
+    }
+}
+
+/// Keys shared with an enclave that passed attestation.
+pub struct Session {
+    key: [u8; 32],
+    channel_key: [u8; 32],
+    return_key: [u8; 32],
+    /// Sealed messages sent so far, which numbers the next one.
+    sealed: u64,
+    /// Sealed messages opened so far, which numbers the next one.
+    opened: u64,
+}
+
+impl Session {
+    fn from_key(key: [u8; 32]) -> IoResult<Session> {
+        Ok(Session {
+            key,
+            channel_key: hmac(&key, CHANNEL_LABEL)?,
+            return_key: hmac(&key, RETURN_LABEL)?,
+            sealed: 0,
+            opened: 0,
+        })
+    }
+
+    /// Derives the session key from our ephemeral key, the enclave's and the challenge nonce.
+    fn agree(own: &mut Pk, peer: &Pk, nonce: &[u8]) -> IoResult<Session> {
+        let mut shared = [0u8; 66];
+        let len = own.agree(peer, &mut shared, &mut Rdrand).map_err(other)?;
+        let mut material = shared[..len].to_vec();
+        material.extend_from_slice(nonce);
+        let mut key = [0u8; 32];
+        Md::hash(Type::Sha256, &material, &mut key).map_err(other)?;
+        Session::from_key(key)
+    }
+
+    /// HMAC-SHA256 of `message` under the session key.
+    pub fn tag(&self, message: &[u8]) -> IoResult<[u8; TAG_LEN]> {
+        hmac(&self.key, message)
+    }
+
+    /// Sends `verb` and `fields` on one line, followed by their tag.
+    pub fn write_message<W: Write>(&self, stream: &mut W, verb: &str, fields: &[String]) -> IoResult<()> {
+        let mut line = verb.to_string();
+        for field in fields {
+            line.push(' ');
+            line.push_str(field);
+        }
+        let tag = self.tag(line.as_bytes())?;
+        writeln!(stream, "{} {}", line, hex::encode(&tag))?;
+        stream.flush()
+    }
+
+    /// Sends `verb` and `fields` encrypted under the channel key, for messages that carry
+    /// secrets. Only the enclave can read them.
+    pub fn seal_message<W: Write>(&mut self, stream: &mut W, verb: &str, fields: &[String]) -> IoResult<()> {
+        let mut line = verb.to_string();
+        for field in fields {
+            line.push(' ');
+            line.push_str(field);
+        }
+        let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 256).map_err(other)?;
+        cipher.set_key(Operation::Encrypt, &self.channel_key).map_err(other)?;
+        cipher.set_iv(&seal_nonce(self.sealed)).map_err(other)?;
+        let mut ciphertext = vec![0u8; line.len()];
+        let mut tag = [0u8; SEAL_TAG_LEN];
+        cipher.encrypt_auth(SEAL_AD, line.as_bytes(), &mut ciphertext, &mut tag).map_err(other)?;
+        self.sealed += 1;
+        writeln!(stream, "sealed {} {}", hex::encode(&ciphertext), hex::encode(&tag))?;
+        stream.flush()
+    }
+
+    /// Reads a message the enclave sealed under the return key and returns the fields of the
+    /// line it decrypts to, verb first. They have to be opened in the order they were sent.
+    pub fn open_message<S: Read>(&mut self, stream: &mut BufReader<S>) -> IoResult<Vec<String>> {
+        let sealed = read_message(stream, "sealed")?;
+        let ciphertext = decode_field(&sealed, 0, "sealed message")?;
+        let tag = decode_field(&sealed, 1, "seal")?;
+        if tag.len() != SEAL_TAG_LEN {
+            return Err(invalid(format!("seal is {} bytes, expected {}", tag.len(), SEAL_TAG_LEN)));
+        }
+        let mut cipher = Cipher::setup(CipherId::Aes, CipherMode::GCM, 256).map_err(other)?;
+        cipher.set_key(Operation::Decrypt, &self.return_key).map_err(other)?;
+        cipher.set_iv(&seal_nonce(self.opened)).map_err(other)?;
+        let mut plain = vec![0u8; ciphertext.len()];
+        if cipher.decrypt_auth(SEAL_AD, &ciphertext, &mut plain, &tag).is_err() {
+            return Err(invalid(format!("sealed message {} does not open", self.opened)));
+        }
+        self.opened += 1;
+        match String::from_utf8(plain) {
+            Ok(line) => Ok(line.split_whitespace().map(String::from).collect()),
+            Err(_) => Err(invalid("sealed message is not text")),
+        }
+    }
+}
+
+/// Copies the lines of `from` to `to` until `from` hangs up: what the runner does with the
+/// sealed messages of an enclave and its key server, which it cannot read.
+pub fn forward<R: BufRead, W: Write>(from: &mut R, to: &mut W) -> IoResult<()> {
+    let mut line = String::new();
+    while from.read_line(&mut line)? > 0 {
+        to.write_all(line.as_bytes())?;
+        to.flush()?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Reads one line, which must start with `verb`, and returns the fields after it.
+fn read_message<S: Read>(stream: &mut BufReader<S>, verb: &str) -> IoResult<Vec<String>> {
+    let mut line = String::new();
+    stream.read_line(&mut line)?;
+    let mut fields = line.split_whitespace().map(String::from);
+    match fields.next() {
+        Some(ref v) if v == verb => Ok(fields.collect()),
+        _ => Err(invalid(format!("expected a {} message, got {:?}", verb, line.trim()))),
+    }
+}
+
+fn decode_field(fields: &[String], index: usize, name: &str) -> IoResult<Vec<u8>> {
+    match fields.get(index).map(|field| hex::decode(field)) {
+        Some(Ok(bytes)) => Ok(bytes),
+        _ => Err(invalid(format!("missing or malformed {}", name))),
+    }
+}
+
+/// Checks that `quoted` is bound to the challenge `nonce` and the enclave's `public_key`, and
+/// that it is of the measurement `mrenclave` if set.
+fn check_quoted(quoted: &QuotedReport, nonce: &[u8], public_key: &[u8], mrenclave: Option<&[u8]>) -> IoResult<()> {
+    let mut material = nonce.to_vec();
+    material.extend_from_slice(public_key);
+    let mut binding = [0u8; 32];
+    Md::hash(Type::Sha256, &material, &mut binding).map_err(other)?;
+    if !equal_ct(&quoted.reportdata[..32], &binding) {
+        return Err(invalid("report is not bound to this challenge and key"));
+    }
+    if let Some(expected) = mrenclave {
+        if !equal_ct(expected, &quoted.mrenclave) {
+            return Err(invalid(format!("unexpected enclave measurement {}", hex::encode(&quoted.mrenclave))));
+        }
+    }
+    Ok(())
+}
+
+/// The measurement `config` insists on, `None` if it explicitly accepts any.
+fn measurement(config: &AttestationConfiguration) -> IoResult<Option<Vec<u8>>> {
+    match (config.mrenclave.as_ref(), config.any_mrenclave) {
+        (Some(_), true) => Err(invalid("set either mrenclave or any_mrenclave")),
+        (Some(mrenclave), false) => Ok(Some(hex::decode(mrenclave).map_err(invalid)?)),
+        (None, true) => {
+            println!("attestation: accepting any enclave measurement");
+            Ok(None)
+        }
+        (None, false) => Err(invalid("no mrenclave to insist on, set any_mrenclave to accept any")),
+    }
+}
+
+/// What the key server signs of the handshake with its identity key: SHA-256(nonce || enclave
+/// key || key server key).
+fn accept_digest(nonce: &[u8], enclave_key: &[u8], own_key: &[u8]) -> IoResult<[u8; 32]> {
+    let mut material = nonce.to_vec();
+    material.extend_from_slice(enclave_key);
+    material.extend_from_slice(own_key);
+    let mut digest = [0u8; 32];
+    Md::hash(Type::Sha256, &material, &mut digest).map_err(other)?;
+    Ok(digest)
+}
+
+fn reject<S: Write>(stream: &mut S, why: &Error) -> IoResult<()> {
+    writeln!(stream, "reject {}", why)?;
+    stream.flush()
+}
+
+/// Challenges enclaves and decides whether they get their rings.
+pub struct Verifier {
+    /// Enclaves are not attested without one, and get their rings whatever they run.
+    quoting: Option<Box<QuotingService>>,
+    /// Measurement the enclave must have; any is accepted if unset.
+    mrenclave: Option<Vec<u8>>,
+}
+
+impl Verifier {
+    /// Attests enclaves through `quoting`. `mrenclave` of `None` accepts any measurement, which
+    /// `from_config` only allows if the configuration says so.
+    pub fn new(quoting: Box<QuotingService>, mrenclave: Option<Vec<u8>>) -> Verifier {
+        Verifier {
+            quoting: Some(quoting),
+            mrenclave,
+        }
+    }
+
+    /// Runs the handshake without checking the evidence: the session is not bound to any
+    /// particular enclave.
+    pub fn unattested() -> Verifier {
+        Verifier {
+            quoting: None,
+            mrenclave: None,
+        }
+    }
+
+    pub fn from_config(config: &AttestationConfiguration) -> IoResult<Verifier> {
+        let quoting: Box<QuotingService> = match config.quoting.as_str() {
+            "none" => {
+                if config.mrenclave.is_some() {
+                    return Err(invalid("an mrenclave needs a quoting service to check it"));
+                }
+                println!("attestation: off, enclaves are not verified before they get their rings");
+                return Ok(Verifier::unattested());
+            }
+            "aesm" => {
+                let spid = match config.spid {
+                    Some(ref spid) => hex::decode(spid).map_err(invalid)?,
+                    None => return Err(invalid("aesm quoting needs an spid")),
+                };
+                Box::new(AesmQuotingService::new(spid, Ias::from_config(config)?)?)
+            }
+            "mock" => Box::new(MockQuotingService),
+            other => return Err(invalid(format!("unknown quoting service {:?}", other))),
+        };
+        Ok(Verifier::new(quoting, measurement(config)?))
+    }
+
+    /// Whether the enclaves are attested at all.
+    pub fn attests(&self) -> bool {
+        self.quoting.is_some()
+    }
+
+    /// Checks the evidence the enclave sent for `nonce`.
+    fn check(&mut self, nonce: &[u8], report: &[u8], public_key: &[u8]) -> IoResult<()> {
+        let quoting = match self.quoting {
+            Some(ref mut quoting) => quoting,
+            None => return Ok(()),
+        };
+        let quote = quoting.quote(report)?;
+        let quoted = quoting.verify(&quote)?;
+        check_quoted(&quoted, nonce, public_key, self.mrenclave.as_ref().map(Vec::as_slice))
+    }
+
+    /// Runs the handshake with the enclave at the other end of `stream`. The enclave is told
+    /// why when its evidence is rejected.
+    pub fn attest<S: Read + Write>(&mut self, stream: &mut BufReader<S>) -> IoResult<Session> {
+        let mut nonce = [0u8; NONCE_LEN];
+        Rdrand.random(&mut nonce).map_err(other)?;
+        let target_info = match self.quoting {
+            Some(ref mut quoting) => quoting.target_info()?,
+            None => vec![0; TARGETINFO_LEN],
+        };
+        {
+            let out = stream.get_mut();
+            writeln!(out, "challenge {} {}", hex::encode(&nonce[..]), hex::encode(&target_info))?;
+            out.flush()?;
+        }
+
+        let evidence = read_message(stream, "evidence")?;
+        let report = decode_field(&evidence, 0, "report")?;
+        let public_key = decode_field(&evidence, 1, "enclave key")?;
+        if let Err(e) = self.check(&nonce, &report, &public_key) {
+            reject(stream.get_mut(), &e)?;
+            return Err(e);
+        }
+
+        let peer = Pk::from_public_key(&public_key).map_err(invalid)?;
+        let mut key = Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1).map_err(other)?;
+        let own_public = key.write_public_der_vec().map_err(other)?;
+        {
+            let out = stream.get_mut();
+            writeln!(out, "accept {}", hex::encode(&own_public))?;
+            out.flush()?;
+        }
+        Session::agree(&mut key, &peer, &nonce)
+    }
+
+    /// Relays the handshake between the key server at the other end of `key_server` and the
+    /// enclave at the other end of `enclave`, having the enclave's report quoted on the way,
+    /// up to the key server's answer. What the two send each other after that is sealed, and
+    /// only for `forward` to pass on: the runner only ever sees ciphertext of the keys.
+    pub fn relay<S: Read + Write, T: Read + Write>(
+        &mut self,
+        enclave: &mut BufReader<S>,
+        key_server: &mut BufReader<T>,
+    ) -> IoResult<()> {
+        let quoting = match self.quoting {
+            Some(ref mut quoting) => quoting,
+            None => return Err(other("relaying the key server needs a quoting service")),
+        };
+        let challenge = read_message(key_server, "challenge")?;
+        let nonce = decode_field(&challenge, 0, "nonce")?;
+        let target_info = quoting.target_info()?;
+        {
+            let out = enclave.get_mut();
+            writeln!(out, "challenge {} {}", hex::encode(&nonce), hex::encode(&target_info))?;
+            out.flush()?;
+        }
+
+        let evidence = read_message(enclave, "evidence")?;
+        let report = decode_field(&evidence, 0, "report")?;
+        let public_key = decode_field(&evidence, 1, "enclave key")?;
+        let quote = match quoting.quote(&report) {
+            Ok(quote) => quote,
+            Err(e) => {
+                reject(enclave.get_mut(), &e)?;
+                return Err(e);
+            }
+        };
+        {
+            let out = key_server.get_mut();
+            writeln!(out, "evidence {} {}", hex::encode(&quote), hex::encode(&public_key))?;
+            out.flush()?;
+        }
+
+        // accept or reject.
+        let mut line = String::new();
+        key_server.read_line(&mut line)?;
+        let out = enclave.get_mut();
+        out.write_all(line.as_bytes())?;
+        out.flush()
+    }
+}
+
+/// The key server's side of the handshake, which the runner relays: the key server checks the
+/// quote of the enclave's report itself, and signs its key with its identity key, which the
+/// enclave may have pinned, so that the runner can pose as neither.
+pub struct KeyServerVerifier {
+    quotes: Box<QuoteVerifier>,
+    /// Measurement the enclave must have; any is accepted if unset.
+    mrenclave: Option<Vec<u8>>,
+    identity: Pk,
+}
+
+impl KeyServerVerifier {
+    /// Checks quotes with `quotes` and signs with `identity`. `mrenclave` of `None` accepts any
+    /// measurement, which `from_config` only allows if the configuration says so.
+    pub fn new(quotes: Box<QuoteVerifier>, mrenclave: Option<Vec<u8>>, identity: Pk) -> KeyServerVerifier {
+        KeyServerVerifier {
+            quotes,
+            mrenclave,
+            identity,
+        }
+    }
+
+    /// "aesm" has IAS check the EPID quotes the runners relay, "mock" takes those of the mock
+    /// quoting service.
+    pub fn from_config(config: &AttestationConfiguration, identity: Pk) -> IoResult<KeyServerVerifier> {
+        let quotes: Box<QuoteVerifier> = match config.quoting.as_str() {
+            "aesm" => Box::new(Ias::from_config(config)?),
+            "mock" => Box::new(MockQuotingService),
+            "none" => return Err(invalid("the key server only hands keys to attested enclaves")),
+            other => return Err(invalid(format!("unknown quoting service {:?}", other))),
+        };
+        Ok(KeyServerVerifier::new(quotes, measurement(config)?, identity))
+    }
+
+    /// Runs the handshake with the enclave whose runner is at the other end of `stream`, and
+    /// returns the session with it and what its quote says about it.
+    pub fn attest<S: Read + Write>(&mut self, stream: &mut BufReader<S>) -> IoResult<(Session, QuotedReport)> {
+        let mut nonce = [0u8; NONCE_LEN];
+        Rdrand.random(&mut nonce).map_err(other)?;
+        {
+            let out = stream.get_mut();
+            writeln!(out, "challenge {}", hex::encode(&nonce[..]))?;
+            out.flush()?;
+        }
+
+        let evidence = read_message(stream, "evidence")?;
+        let quote = decode_field(&evidence, 0, "quote")?;
+        let public_key = decode_field(&evidence, 1, "enclave key")?;
+        let quoted = match self
+            .quotes
+            .verify(&quote)
+            .and_then(|quoted| check_quoted(&quoted, &nonce, &public_key, self.mrenclave.as_ref().map(Vec::as_slice)).map(|_| quoted))
+        {
+            Ok(quoted) => quoted,
+            Err(e) => {
+                reject(stream.get_mut(), &e)?;
+                return Err(e);
+            }
+        };
+
+        let peer = Pk::from_public_key(&public_key).map_err(invalid)?;
+        let mut key = Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1).map_err(other)?;
+        let own_public = key.write_public_der_vec().map_err(other)?;
+        let digest = accept_digest(&nonce, &public_key, &own_public)?;
+        let mut signature = vec![0u8; SIGNATURE_MAX_LEN];
+        let len = self
+            .identity
+            .sign(Type::Sha256, &digest, &mut signature, &mut Rdrand)
+            .map_err(other)?;
+        {
+            let out = stream.get_mut();
+            writeln!(out, "accept {} {}", hex::encode(&own_public), hex::encode(&signature[..len]))?;
+            out.flush()?;
+        }
+        Ok((Session::agree(&mut key, &peer, &nonce)?, quoted))
+    }
+}
@@ -0,0 +1,167 @@
+//! Verification of EPID quotes by Intel's attestation service (IAS).
+//!
+//! The quote is posted to the report API; IAS answers with a JSON verification report it signs
+//! with its report signing key, whose certificate chain comes in a header. We only take the
+//! report for it once the certificate chains up to the configured root CA, the signature over
+//! the report holds, the report answers our nonce and the quote body it vouches for is the one
+//! we sent.
+use base64;
+use config::AttestationConfiguration;
+use hex;
+use mbedtls::hash::{Md, Type};
+use mbedtls::rng::{Random, Rdrand};
+use mbedtls::x509::{Certificate, LinkedCertificate};
+use percent_encoding::percent_decode;
+use reqwest;
+use serde_json;
+use std::fmt::Display;
+use std::fs;
+use std::io::{Error, ErrorKind, Read, Result as IoResult};
+
+/// What IAS vouches for of a quote: its header and the report body.
+pub const QUOTE_SIGNED_LEN: usize = 432;
+const SIGNATURE_HEADER: &str = "X-IASReport-Signature";
+const CERTIFICATES_HEADER: &str = "X-IASReport-Signing-Certificate";
+const BEGIN_CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----";
+const END_CERTIFICATE: &str = "-----END CERTIFICATE-----";
+
+fn invalid<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::InvalidData, why.to_string())
+}
+
+fn other<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::Other, why.to_string())
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ReportRequest {
+    isv_enclave_quote: String,
+    nonce: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VerificationReport {
+    isv_enclave_quote_status: String,
+    isv_enclave_quote_body: String,
+    nonce: Option<String>,
+}
+
+/// The DER of every certificate in `pem`, in order.
+pub fn pem_certificates(pem: &str) -> IoResult<Vec<Vec<u8>>> {
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(begin) = rest.find(BEGIN_CERTIFICATE) {
+        rest = &rest[begin + BEGIN_CERTIFICATE.len()..];
+        let end = rest.find(END_CERTIFICATE).ok_or_else(|| invalid("unterminated PEM certificate"))?;
+        let encoded: String = rest[..end].chars().filter(|c| !c.is_whitespace()).collect();
+        certificates.push(base64::decode(&encoded).map_err(invalid)?);
+        rest = &rest[end + END_CERTIFICATE.len()..];
+    }
+    Ok(certificates)
+}
+
+/// Checks a verification report IAS sent in answer to `quote` and `nonce`, and returns the
+/// quote body it vouches for. `certificates` is the URL-encoded PEM chain of the signing
+/// certificate, which must be issued by `root_ca`. Quotes of any status but "OK" and those in
+/// `accept` are refused.
+pub fn check_report(
+    body: &[u8],
+    signature: &str,
+    certificates: &str,
+    root_ca: &[u8],
+    accept: &[String],
+    quote: &[u8],
+    nonce: &str,
+) -> IoResult<Vec<u8>> {
+    let pem = percent_decode(certificates.as_bytes()).decode_utf8().map_err(invalid)?;
+    let chain = pem_certificates(&pem)?;
+    let signing = chain.first().ok_or_else(|| invalid("no report signing certificate"))?;
+    let mut signing = Certificate::from_der(signing).map_err(invalid)?;
+    let mut root_ca = Certificate::from_der(root_ca).map_err(invalid)?;
+    if LinkedCertificate::verify(&mut signing, &mut root_ca, None).is_err() {
+        return Err(invalid("report signing certificate is not issued by the IAS root CA"));
+    }
+    let signature = base64::decode(signature.trim()).map_err(invalid)?;
+    let mut hash = [0u8; 32];
+    Md::hash(Type::Sha256, body, &mut hash).map_err(other)?;
+    if signing.public_key_mut().verify(Type::Sha256, &hash, &signature).is_err() {
+        return Err(invalid("verification report signature does not verify"));
+    }
+
+    let report: VerificationReport = serde_json::from_slice(body).map_err(invalid)?;
+    if report.nonce.as_ref().map(String::as_str) != Some(nonce) {
+        return Err(invalid("verification report is not for this request"));
+    }
+    if report.isv_enclave_quote_status != "OK" && !accept.contains(&report.isv_enclave_quote_status) {
+        return Err(invalid(format!("IAS says the quote is {}", report.isv_enclave_quote_status)));
+    }
+    let quote_body = base64::decode(&report.isv_enclave_quote_body).map_err(invalid)?;
+    if quote.len() < QUOTE_SIGNED_LEN || quote_body != &quote[..QUOTE_SIGNED_LEN] {
+        return Err(invalid("verification report is for another quote"));
+    }
+    Ok(quote_body)
+}
+
+/// A client of the IAS report API.
+pub struct Ias {
+    client: reqwest::Client,
+    url: String,
+    key: String,
+    root_ca: Vec<u8>,
+    accept: Vec<String>,
+}
+
+impl Ias {
+    pub fn from_config(config: &AttestationConfiguration) -> IoResult<Ias> {
+        let key = config.ias_key.clone().ok_or_else(|| invalid("aesm quoting needs an ias_key"))?;
+        let path = config.ias_root_ca.as_ref().ok_or_else(|| invalid("aesm quoting needs an ias_root_ca"))?;
+        let pem = fs::read_to_string(path).map_err(|e| invalid(format!("{}: {}", path, e)))?;
+        let root_ca = pem_certificates(&pem)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| invalid(format!("{}: no certificate", path)))?;
+        Ok(Ias {
+            client: reqwest::Client::new(),
+            url: config.ias_url.clone(),
+            key,
+            root_ca,
+            accept: config.ias_accept.clone(),
+        })
+    }
+
+    /// Has IAS verify `quote` and returns the quote body it vouches for.
+    pub fn verify_quote(&self, quote: &[u8]) -> IoResult<Vec<u8>> {
+        let mut nonce = [0u8; 16];
+        Rdrand.random(&mut nonce).map_err(other)?;
+        let nonce = hex::encode(&nonce);
+        let request = ReportRequest {
+            isv_enclave_quote: base64::encode(quote),
+            nonce: nonce.clone(),
+        };
+        let mut response = self
+            .client
+            .post(&self.url)
+            .header("Ocp-Apim-Subscription-Key", self.key.as_str())
+            .json(&request)
+            .send()
+            .map_err(other)?;
+        if !response.status().is_success() {
+            return Err(invalid(format!("IAS answered {}", response.status())));
+        }
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+                .ok_or_else(|| invalid(format!("IAS sent no {}", name)))
+        };
+        let signature = header(SIGNATURE_HEADER)?;
+        let certificates = header(CERTIFICATES_HEADER)?;
+        let mut body = Vec::new();
+        response.read_to_end(&mut body)?;
+        check_report(&body, &signature, &certificates, &self.root_ca, &self.accept, quote, &nonce)
+    }
+}
@@ -0,0 +1,4 @@
+pub use self::attestation::*;
+pub use self::ias::*;
+pub mod attestation;
+pub mod ias;
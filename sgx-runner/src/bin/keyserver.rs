@@ -0,0 +1,58 @@
+/* Copyright (c) Fortanix, Inc.
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The key server the enclaves get their IPsec keys from, through their runners, see
+//! `mylib::keyserver`. Takes the path of its configuration, like keyserver.toml.
+extern crate hex;
+extern crate mbedtls;
+extern crate mylib;
+
+use mbedtls::pk::Pk;
+use mylib::attestation::KeyServerVerifier;
+use mylib::config::load_key_server_config;
+use mylib::keyserver::{serve, Counters, LocalKeyServer};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::TcpListener;
+use std::process;
+use std::sync::Arc;
+
+fn fail<E: Display>(what: &str, e: E) -> ! {
+    eprintln!("{}: {}", what, e);
+    process::exit(1);
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => fail("usage", "keyserver <configuration file>"),
+    };
+    let configuration = load_key_server_config(&path).unwrap_or_else(|e| fail("Bad configuration", e));
+    let mut identity = fs::read(&configuration.identity)
+        .map_err(|e| e.to_string())
+        .and_then(|der| Pk::from_private_key(&der, None).map_err(|e| e.to_string()))
+        .unwrap_or_else(|e| fail("Could not load the identity key", e));
+    match identity.write_public_der_vec() {
+        Ok(der) => println!("keyserver: enclaves pin this key server with SAFEBRICKS_KEY_SERVER={}", hex::encode(&der)),
+        Err(e) => fail("Bad identity key", e),
+    }
+    let mut verifier = KeyServerVerifier::from_config(&configuration.attestation, identity)
+        .unwrap_or_else(|e| fail("Could not set up attestation", e));
+    let mut key_server = LocalKeyServer::from_config(&configuration.sa).unwrap_or_else(|e| fail("Could not load the SA keys", e));
+    let counters = match configuration.counters {
+        Some(ref dir) => Some(Arc::new(Counters::new(dir).unwrap_or_else(|e| fail("Could not keep counters", e)))),
+        None => {
+            println!("keyserver: no counters directory configured, enclaves cannot checkpoint");
+            None
+        }
+    };
+    let listener = TcpListener::bind(&configuration.listen).unwrap_or_else(|e| fail("Could not listen", e));
+    println!("keyserver: listening on {}", configuration.listen);
+    if let Err(e) = serve(listener, &mut verifier, &mut key_server, counters) {
+        fail("keyserver", e);
+    }
+}
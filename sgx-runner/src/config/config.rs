@@ -2,6 +2,7 @@ use clap::ArgMatches;
 use config_rs::{Config, ConfigError, File, FileFormat, Source, Value};
 use std::collections::HashMap;
 use std::fmt;
+use toml;
 
 pub const DEFAULT_POOL_SIZE: u32 = 2048 - 1;
 pub const DEFAULT_CACHE_SIZE: u32 = 32;
@@ -10,6 +11,10 @@ pub const NUM_TXD: i32 = 128;
 // pub const NUM_RXD: i32 = 512;
 // pub const NUM_TXD: i32 = 512;
 
+/// The name the enclaves fetch `NetBricksConfiguration::enclave_document` by from the
+/// `config` usercall service, see framework-inside's `config::load_config`.
+pub const ENCLAVE_DOCUMENT: &str = "netbricks";
+
 /// NetBricks configuration
 #[derive(Debug, Default, Deserialize, PartialEq)]
 pub struct NetBricksConfiguration {
@@ -39,6 +44,245 @@ pub struct NetBricksConfiguration {
     pub cache_size: u32,
     /// Custom DPDK arguments.
     pub dpdk_args: Option<String>,
+    /// Number of enclaves to launch, one per ring pair dpdkIO created. Defaults
+    /// to one per rx queue.
+    pub enclaves: Option<usize>,
+    /// How enclaves are attested before they get their rings.
+    #[serde(default)]
+    pub attestation: AttestationConfiguration,
+    /// Address of the key server the enclaves get their IPsec keys from, relayed by the runner.
+    /// Enclaves get no keys if unset.
+    pub keyserver: Option<String>,
+    /// Where the enclaves keep their checkpoints. Enclaves cannot checkpoint if unset.
+    pub storage: Option<StorageConfiguration>,
+    /// What the enclaves run with.
+    #[serde(default)]
+    pub enclave: EnclaveConfiguration,
+}
+
+impl NetBricksConfiguration {
+    /// The configuration of the enclaves, in TOML, as framework-inside reads it: one port of
+    /// `queues` queues on the shared rings, which are as large as those of the first port.
+    pub fn enclave_document(&self) -> Result<String, toml::ser::Error> {
+        let queues = (0..self.enclave.queues as i32).collect::<Vec<_>>();
+        let document = EnclaveDocument {
+            batch_size: self.enclave.batch_size,
+            duration: self.enclave.duration,
+            ports: vec![EnclavePort {
+                name: "SimulateQueue",
+                rx_queues: queues.clone(),
+                tx_queues: queues,
+                rxd: self.ports[0].rxd,
+                txd: self.ports[0].txd,
+                loopback: false,
+                tso: false,
+                csum: false,
+                copy: self.enclave.copy,
+            }],
+            scheduler: &self.enclave.scheduler,
+            nf: &self.enclave.nf,
+            ike: self.enclave.ike.as_ref(),
+        };
+        // as a value, whose tables go after its values at every level, which the [ike] table
+        // and those of the NF need.
+        toml::to_string(&toml::Value::try_from(&document)?)
+    }
+}
+
+/// What the enclaves run with, handed to them through the `config` usercall service.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct EnclaveConfiguration {
+    /// Queues of each enclave, each on a set of rings of its own.
+    pub queues: usize,
+    /// Packets the pipelines of an enclave receive and process at a time.
+    pub batch_size: usize,
+    /// Copy packets into enclave memory before the NF sees them.
+    pub copy: bool,
+    /// Seconds the enclaves run their pipelines for; 0 runs them until the rings are stopped.
+    pub duration: u64,
+    /// How the enclaves schedule their pipelines, their `[scheduler]` table.
+    pub scheduler: toml::value::Table,
+    /// Parameters of the NF, its `[nf]` table.
+    pub nf: toml::value::Table,
+    /// The IKE responder of the NF, its `[ike]` table, if it runs one.
+    pub ike: Option<toml::value::Table>,
+}
+
+impl Default for EnclaveConfiguration {
+    fn default() -> EnclaveConfiguration {
+        EnclaveConfiguration {
+            queues: 1,
+            batch_size: 32,
+            copy: false,
+            duration: 0,
+            scheduler: toml::value::Table::new(),
+            nf: toml::value::Table::new(),
+            ike: None,
+        }
+    }
+}
+
+impl fmt::Display for EnclaveConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "queues: {}, batch size: {}, copy: {}, duration: {}s, NF parameters: {}",
+            self.queues,
+            self.batch_size,
+            self.copy,
+            self.duration,
+            self.nf.keys().cloned().collect::<Vec<_>>().join(", "),
+        )
+    }
+}
+
+/// `EnclaveConfiguration` as framework-inside's `NetBricksConfiguration` has it.
+#[derive(Serialize)]
+struct EnclaveDocument<'a> {
+    batch_size: usize,
+    duration: u64,
+    ports: Vec<EnclavePort>,
+    scheduler: &'a toml::value::Table,
+    nf: &'a toml::value::Table,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ike: Option<&'a toml::value::Table>,
+}
+
+#[derive(Serialize)]
+struct EnclavePort {
+    name: &'static str,
+    rx_queues: Vec<i32>,
+    tx_queues: Vec<i32>,
+    rxd: i32,
+    txd: i32,
+    loopback: bool,
+    tso: bool,
+    csum: bool,
+    copy: bool,
+}
+
+/// Checkpoint storage of the enclaves, see `storage::FileStore`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct StorageConfiguration {
+    /// Directory of the checkpoints; created if missing.
+    pub dir: String,
+}
+
+/// Attestation of the enclaves, see `attestation::Verifier`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AttestationConfiguration {
+    /// "none", the default, hands the rings to enclaves without attesting them; "aesm" quotes
+    /// through the platform's AESM service and has IAS verify the quotes; "mock" is for
+    /// enclaves built for a non-SGX target.
+    pub quoting: String,
+    /// Service provider ID for EPID quotes, in hex. Required with "aesm".
+    pub spid: Option<String>,
+    /// Subscription key of the IAS API. Required with "aesm".
+    pub ias_key: Option<String>,
+    /// The IAS report API.
+    pub ias_url: String,
+    /// PEM file of the CA that issues the IAS report signing certificate. Required with "aesm".
+    pub ias_root_ca: Option<String>,
+    /// Quote statuses accepted besides "OK", such as "GROUP_OUT_OF_DATE".
+    pub ias_accept: Vec<String>,
+    /// Expected enclave measurement (MRENCLAVE), in hex. Required to attest, unless
+    /// `any_mrenclave` is set.
+    pub mrenclave: Option<String>,
+    /// Accept enclaves of any measurement.
+    pub any_mrenclave: bool,
+}
+
+impl Default for AttestationConfiguration {
+    fn default() -> AttestationConfiguration {
+        AttestationConfiguration {
+            quoting: "none".to_string(),
+            spid: None,
+            ias_key: None,
+            ias_url: "https://api.trustedservices.intel.com/sgx/dev/attestation/v4/report".to_string(),
+            ias_root_ca: None,
+            ias_accept: Vec::new(),
+            mrenclave: None,
+            any_mrenclave: false,
+        }
+    }
+}
+
+// never print the IAS key.
+impl fmt::Display for AttestationConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "quoting: {}, spid: {:?}, IAS: {} (root CA {:?}, also accepting {:?}), mrenclave: {:?}, any mrenclave: {}",
+            self.quoting, self.spid, self.ias_url, self.ias_root_ca, self.ias_accept, self.mrenclave, self.any_mrenclave,
+        )
+    }
+}
+
+/// Configuration of the key server, which runs apart from the runners, see `keyserver::serve`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct KeyServerConfiguration {
+    /// Address the runners connect to.
+    pub listen: String,
+    /// DER file of the private key the key server signs its handshakes with.
+    pub identity: String,
+    /// How the key server checks the quotes the runners relay: "aesm" has IAS verify them.
+    pub attestation: AttestationConfiguration,
+    /// Directory of the enclaves' checkpoint counters; the key server keeps none if unset, and
+    /// enclaves cannot checkpoint.
+    pub counters: Option<String>,
+    /// IPsec SAs handed to every enclave.
+    #[serde(default)]
+    pub sa: Vec<SaConfiguration>,
+}
+
+/// Keys of one IPsec security association, see `keyserver::LocalKeyServer`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SaConfiguration {
+    /// Security parameter index the ESP packets of the SA carry.
+    pub spi: u32,
+    /// Encryption key, in hex: AES-128 or AES-256, or ChaCha20. The keys of AES-GCM and
+    /// ChaCha20-Poly1305 are followed by 4 bytes of salt, as for `ip xfrm`.
+    pub enc_key: String,
+    /// HMAC-SHA256 key for AES-CBC SAs, in hex.
+    pub auth_key: Option<String>,
+    /// "inbound", "outbound" or "both", the default: an NF that decrypts and re-encrypts
+    /// needs one SA each way. An SA goes out of one enclave only: the others get "both" SAs
+    /// inbound only.
+    #[serde(default = "default_direction")]
+    pub direction: String,
+    /// For outbound SAs, the SPI of the inbound SA whose traffic they answer. Configure one
+    /// per enclave, as each keeps its own sequence numbers.
+    pub reply_to: Option<u32>,
+    /// "aes-cbc-sha256", "aes-gcm128", "aes-gcm256" or "chacha20-poly1305". Defaults to the
+    /// first if there is an auth_key, else to AES-GCM with the key size of enc_key.
+    pub cipher: Option<String>,
+    /// 64-bit extended sequence numbers.
+    #[serde(default)]
+    pub esn: bool,
+    /// Hard lifetime in bytes; none if unset.
+    pub lifetime_bytes: Option<u64>,
+    /// Hard lifetime in packets; none if unset.
+    pub lifetime_packets: Option<u64>,
+    /// Outer source and destination address of tunnel-mode SAs, set both or neither.
+    pub tunnel_src: Option<String>,
+    pub tunnel_dst: Option<String>,
+    /// IPv4 prefixes of the traffic an outbound tunnel-mode SA protects, to `remote` from
+    /// `local`; all of it if unset. The enclave's `esp_encap` picks the SA by them.
+    pub remote: Option<String>,
+    pub local: Option<String>,
+}
+
+fn default_direction() -> String {
+    "both".to_string()
+}
+
+// never print the keys themselves.
+impl fmt::Display for SaConfiguration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "spi: {:#010x} ({}, esn: {})", self.spi, self.direction, self.esn)
+    }
 }
 
 impl fmt::Display for NetBricksConfiguration {
@@ -52,7 +296,7 @@ impl fmt::Display for NetBricksConfiguration {
 
         write!(
             f,
-            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}",
+            "name: {}, secondary: {}, pool size: {}, cache size: {}\nprimary core: {}, cores: {:?}, strict: {}\nports:\n{}\nDPDK args: {:?}\nenclaves: {:?}\nattestation: {}\nkey server: {:?}\nstorage: {:?}\nenclave: {}",
             self.name,
             self.secondary,
             self.pool_size,
@@ -62,6 +306,11 @@ impl fmt::Display for NetBricksConfiguration {
             self.strict,
             ports,
             self.dpdk_args,
+            self.enclaves,
+            self.attestation,
+            self.keyserver,
+            self.storage.as_ref().map(|storage| &storage.dir),
+            self.enclave,
         )
     }
 }
@@ -122,6 +371,11 @@ lazy_static! {
         (@arg dpdk_args: --("dpdk-args") ... +takes_value "custom DPDK arguments")
         (@arg duration: -d --duration +takes_value "test duration")
         (@arg sgxapp: -s --sgxapp +takes_value "sgx app binary")
+        (@arg enclaves: --enclaves +takes_value "number of enclaves to launch")
+        (@arg queues: --queues +takes_value "queues of each enclave")
+        (@arg batch_size: --("batch-size") +takes_value "packets the enclaves process at a time")
+        (@arg ring_size: --("ring-size") +takes_value "slots of each shared ring")
+        (@arg nf: --nf ... +takes_value "NF parameter of the enclaves, as key=value")
     )
     .get_matches();
 }
@@ -175,6 +429,37 @@ impl Source for CommandLine {
             );
         }
 
+        if CLI_ARGS.is_present("enclaves") {
+            let enclaves = value_t!(CLI_ARGS, "enclaves", u32)
+                .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+            map.insert("enclaves".to_string(), Value::new(uri, i64::from(enclaves)));
+        }
+
+        if CLI_ARGS.is_present("queues") {
+            let queues = value_t!(CLI_ARGS, "queues", u32)
+                .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+            map.insert("enclave.queues".to_string(), Value::new(uri, i64::from(queues)));
+        }
+
+        if CLI_ARGS.is_present("batch_size") {
+            let batch_size = value_t!(CLI_ARGS, "batch_size", u32)
+                .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+            map.insert("enclave.batch_size".to_string(), Value::new(uri, i64::from(batch_size)));
+        }
+
+        if let Some(params) = CLI_ARGS.values_of("nf") {
+            for param in params {
+                let mut param = param.splitn(2, '=');
+                match (param.next(), param.next()) {
+                    (Some(key), Some(value)) if !key.is_empty() => {
+                        // a string; the NF converts it to what it expects.
+                        map.insert(format!("enclave.nf.{}", key), Value::new(uri, value));
+                    }
+                    _ => return Err(ConfigError::Message("--nf takes key=value".to_string())),
+                }
+            }
+        }
+
         if let Some(ports) = CLI_ARGS.values_of("ports") {
             let cores = values_t!(CLI_ARGS, "cores", i32)
                 .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
@@ -244,9 +529,46 @@ pub fn load_config() -> Result<NetBricksConfiguration, ConfigError> {
     }
 
     config.merge(CommandLine())?; 
+    let mut configuration: NetBricksConfiguration = config.try_into()?;
+    // the rings of every port, whether they come from the file or the command line.
+    if CLI_ARGS.is_present("ring_size") {
+        let ring_size = value_t!(CLI_ARGS, "ring_size", i32)
+            .map_err(|err| ConfigError::Foreign(Box::new(err)))?;
+        for port in &mut configuration.ports {
+            port.rxd = ring_size;
+            port.txd = ring_size;
+        }
+    }
+    if configuration.ports.is_empty() {
+        return Err(ConfigError::Message("no ports".to_string()));
+    }
+    if configuration.enclave.queues == 0 {
+        return Err(ConfigError::Message("enclaves need at least one queue".to_string()));
+    }
+    if let Some(ref ike) = configuration.enclave.ike {
+        let queue = ike.get("queue").and_then(toml::Value::as_integer).unwrap_or(0);
+        if queue < 0 || queue as usize >= configuration.enclave.queues || configuration.enclave.queues < 2 {
+            return Err(ConfigError::Message(format!(
+                "IKE queue {} of {} queue(s): IKE takes a queue of its own besides those of the traffic",
+                queue, configuration.enclave.queues
+            )));
+        }
+    }
+    Ok(configuration)
+}
+
+/// Loads the key server's configuration from `filename`.
+pub fn load_key_server_config(filename: &str) -> Result<KeyServerConfiguration, ConfigError> {
+    let mut config = Config::new();
+    config.merge(File::with_name(filename))?;
     config.try_into()
 }
 
+/// The configuration file given on the command line, if any.
+pub fn config_file() -> Option<&'static str> {
+    CLI_ARGS.value_of("file")
+}
+
 pub fn get_duration() -> u64 {
     if CLI_ARGS.is_present("duration") {
         println!("duration exists"); // do not know why duration does not exist...
@@ -1,13 +1,16 @@
 use aesm_client::AesmClient;
+use attestation::{forward, Verifier};
+use usercalls::Registry;
 use enclave_runner::usercalls::{SyncListener, SyncStream, UsercallExtension};
 use enclave_runner::EnclaveBuilder;
 use sgxs_loaders::isgx::Device as IsgxDevice;
 use byteorder::{NetworkEndian, ReadBytesExt};
 use std::io;
-use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::io::{BufReader, Error, ErrorKind, Read, Result as IoResult, Write};
 use std::mem::size_of;
 use std::net::Shutdown;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
 use std::thread;
 
 /// This example demonstrates use of usercall extensions for bind call.
@@ -227,9 +230,30 @@ impl SyncListener for ProxyProtocol {
 
 const HAPROXY_ADDRESS: &str = "localhost:6010";
 
+/// Intercepts the enclave's listener for the runner's connection, and its connections to the
+/// usercall services of `registry`.
 #[derive(Debug)]
-struct HaproxyService;
+struct HaproxyService {
+    registry: Arc<Registry>,
+}
+
 impl UsercallExtension for HaproxyService {
+    fn connect_stream(
+        &self,
+        addr: &str,
+        local_addr: Option<&mut String>,
+        peer_addr: Option<&mut String>,
+    ) -> IoResult<Option<Box<dyn SyncStream>>> {
+        let stream = match self.registry.connect(addr)? {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        for name in local_addr.into_iter().chain(peer_addr) {
+            *name = addr.to_string();
+        }
+        Ok(Some(Box::new(stream)))
+    }
+
     fn bind_stream(
         &self,
         addr: &str,
@@ -263,7 +287,8 @@ pub fn parse_args() -> Result<String, ()> {
     }
 }
 
-pub fn run_server(file: String) -> Result<(), ()> {
+/// Runs the enclave in `file`, offering it the usercall services of `registry`.
+pub fn run_server(file: String, registry: Arc<Registry>) -> Result<(), ()> {
     let mut device = IsgxDevice::new()
         .unwrap()
         .einittoken_provider(AesmClient::new())
@@ -271,7 +296,7 @@ pub fn run_server(file: String) -> Result<(), ()> {
 
     let mut enclave_builder = EnclaveBuilder::new(file.as_ref());
     enclave_builder.dummy_signature();
-    enclave_builder.usercall_extension(HaproxyService);
+    enclave_builder.usercall_extension(HaproxyService { registry });
     let enclave = enclave_builder.build(&mut device).unwrap();
 
     enclave.run().map_err(|e| {
@@ -293,19 +318,45 @@ impl SimulateHaProxyConfig {
         stream.shutdown(Shutdown::Write).unwrap();
     }
 
-    fn send_queue_addr(recvq_addr: u64, sendq_addr: u64) {
+    /// Attests the enclave and, if it passes, sends it the addresses of rings number `ring`, and
+    /// relays the key server, which has its SA keys and checkpoint counters.
+    fn send_queue_addr(queue_addrs: &[u64], ring: usize, verifier: &mut Verifier, key_server: Option<&str>) -> IoResult<()> {
         thread::sleep(std::time::Duration::from_secs(2));// wait until server in enclave sets up;
         let header = &[
             0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21, 0x11,
             0x00, 0x0c, 0x7f, 0x00, 0x00, 0x01, 0x7f, 0x00, 0x00, 0x01, 0x97, 0x32, 0x1f, 0x43,
         ];
-        let mut stream = TcpStream::connect(HAPROXY_ADDRESS).unwrap();
-        stream.write_all(header).unwrap();
-        stream
-            .write_all(&format!("{} {}\n", recvq_addr, sendq_addr).as_bytes())
-            .unwrap();
-        stream.shutdown(Shutdown::Write).unwrap();
+        let mut stream = TcpStream::connect(HAPROXY_ADDRESS)?;
+        stream.write_all(header)?;
+        let mut stream = BufReader::new(stream);
+        let session = verifier.attest(&mut stream)?;
+        let mut addrs: Vec<String> = queue_addrs.iter().map(|addr| addr.to_string()).collect();
+        addrs.push(ring.to_string());
+        session.write_message(stream.get_mut(), "rings", &addrs)?;
+        match key_server {
+            Some(address) => {
+                let mut key_server = BufReader::new(TcpStream::connect(address)?);
+                verifier.relay(&mut stream, &mut key_server)?;
+                // the keys, then the enclave's counters, for as long as both stay connected.
+                // The enclave waits for its keys before it sends anything, so nothing of it is
+                // buffered in `stream` yet.
+                let mut from_enclave = BufReader::new(stream.get_ref().try_clone()?);
+                let mut to_key_server = key_server.get_ref().try_clone()?;
+                let mut to_enclave = stream.into_inner();
+                thread::spawn(move || {
+                    let _ = forward(&mut from_enclave, &mut to_key_server);
+                    let _ = to_key_server.shutdown(Shutdown::Write);
+                });
+                thread::spawn(move || {
+                    let _ = forward(&mut key_server, &mut to_enclave);
+                    let _ = to_enclave.shutdown(Shutdown::Write);
+                });
+            }
+            // the enclave takes the hang-up for no keys.
+            None => stream.get_mut().shutdown(Shutdown::Write)?,
+        }
         thread::sleep(std::time::Duration::from_secs(1));// wait until server in enclave sets up;
+        Ok(())
     }
 
     fn ipv4() {
@@ -361,11 +412,13 @@ impl SimulateHaProxyConfig {
     }
 }
 
-pub fn run_client(recvq_addr: u64, sendq_addr: u64) -> Result<(), Error> {
+/// Hands rings number `ring` and `keys` to the enclave listening on HAPROXY_ADDRESS once
+/// `verifier` has attested it.
+pub fn run_client(recvq_addr: u64, sendq_addr: u64, mbufq_addr: u64, freeq_addr: u64, ring: usize, verifier: &mut Verifier, key_server: Option<&str>) -> Result<(), Error> {
     // SimulateHaProxyConfig::ipv4();
     // SimulateHaProxyConfig::ipv6();
     // SimulateHaProxyConfig::local();
-    SimulateHaProxyConfig::send_queue_addr(recvq_addr, sendq_addr);
+    SimulateHaProxyConfig::send_queue_addr(&[recvq_addr, sendq_addr, mbufq_addr, freeq_addr], ring, verifier, key_server)?;
     // fib(30000);
     Ok(())
 }
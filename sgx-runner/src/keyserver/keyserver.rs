@@ -0,0 +1,361 @@
+//! Where the enclaves' IPsec keys come from. The key server runs apart from sgx-runner, which
+//! it does not trust with the keys: once an enclave has its rings, its runner connects to the
+//! key server and relays the handshake between the two, see `Verifier::relay`. The key server
+//! attests the enclave itself, then sends it the keys of its security associations (SAs)
+//! sealed under their session, so that only the enclave can read them. framework-inside's
+//! `provisioning` module describes the messages.
+//!
+//! The key server also keeps the monotonic counters the enclaves check their checkpoints
+//! against, out of reach of the hosts that keep the checkpoints: once provisioned, an enclave
+//! may go on asking for them over the same session.
+use attestation::{KeyServerVerifier, Session};
+use config::SaConfiguration;
+use hex;
+use std::fmt::Display;
+use std::collections::HashSet;
+use std::fs;
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Result as IoResult, Write};
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Length of the salt after the key of an AES-GCM SA (RFC 4106).
+const SALT_LEN: usize = 4;
+
+fn invalid<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::InvalidData, why.to_string())
+}
+
+/// The prefix of all IPv4 traffic.
+const ANY: &str = "0.0.0.0/0";
+
+/// Whether `value` is an IPv4 prefix, such as 10.0.0.0/8.
+fn is_prefix(value: &str) -> bool {
+    let mut parts = value.splitn(2, '/');
+    parts.next().map_or(false, |address| address.parse::<Ipv4Addr>().is_ok())
+        && parts.next().and_then(|length| length.parse::<u8>().ok()).map_or(false, |length| length <= 32)
+}
+
+/// The lengths of encryption key the cipher suites of the enclaves take, with the salt for the
+/// AEADs, and of authentication key.
+fn key_lens(cipher: &str) -> Option<(&'static [usize], usize)> {
+    match cipher {
+        "aes-cbc-sha256" => Some((&[16, 32], 32)),
+        "aes-gcm128" => Some((&[16 + SALT_LEN], 0)),
+        "aes-gcm256" => Some((&[32 + SALT_LEN], 0)),
+        "chacha20-poly1305" => Some((&[32 + SALT_LEN], 0)),
+        _ => None,
+    }
+}
+
+/// Key material of one SA.
+#[derive(Clone)]
+pub struct SaKeys {
+    pub spi: u32,
+    /// AES key, 128 or 256 bits, followed by the salt of the nonces for AES-GCM SAs.
+    pub enc_key: Vec<u8>,
+    /// HMAC-SHA256 key; empty for AES-GCM SAs.
+    pub auth_key: Vec<u8>,
+    /// "inbound", "outbound" or "both".
+    pub direction: String,
+    /// The inbound SA an outbound one answers.
+    pub reply_to: Option<u32>,
+    /// "aes-cbc-sha256", "aes-gcm128", "aes-gcm256" or "chacha20-poly1305".
+    pub cipher: String,
+    pub esn: bool,
+    pub lifetime_bytes: Option<u64>,
+    pub lifetime_packets: Option<u64>,
+    /// Outer source and destination of tunnel-mode SAs.
+    pub tunnel: Option<(IpAddr, IpAddr)>,
+    /// The IPv4 prefixes, remote and local, of the traffic an outbound tunnel-mode SA
+    /// protects.
+    pub selectors: Option<(String, String)>,
+}
+
+impl SaKeys {
+    fn from_config(sa: &SaConfiguration) -> IoResult<SaKeys> {
+        let decode = |name: &str, value: &str| {
+            hex::decode(value).map_err(|e| invalid(format!("SA {:#010x}: bad {}: {}", sa.spi, name, e)))
+        };
+        let enc_key = decode("enc_key", &sa.enc_key)?;
+        let cipher = match sa.cipher {
+            Some(ref cipher) => cipher.clone(),
+            None if sa.auth_key.is_some() => "aes-cbc-sha256".to_string(),
+            None if enc_key.len() == 32 + SALT_LEN => "aes-gcm256".to_string(),
+            None => "aes-gcm128".to_string(),
+        };
+        let address = |name: &str, value: &str| {
+            value
+                .parse::<IpAddr>()
+                .map_err(|e| invalid(format!("SA {:#010x}: bad {}: {}", sa.spi, name, e)))
+        };
+        let tunnel = match (sa.tunnel_src.as_ref(), sa.tunnel_dst.as_ref()) {
+            (Some(src), Some(dst)) => Some((address("tunnel_src", src)?, address("tunnel_dst", dst)?)),
+            (None, None) => None,
+            _ => return Err(invalid(format!("SA {:#010x}: tunnel_src and tunnel_dst go together", sa.spi))),
+        };
+        let prefix = |name: &str, value: &Option<String>| match *value {
+            Some(ref value) if is_prefix(value) => Ok(value.clone()),
+            Some(ref value) => Err(invalid(format!("SA {:#010x}: {} {:?} is not an IPv4 prefix", sa.spi, name, value))),
+            None => Ok(ANY.to_string()),
+        };
+        let selectors = match (sa.remote.is_some() || sa.local.is_some(), tunnel) {
+            (false, _) => None,
+            (true, Some(_)) => Some((prefix("remote", &sa.remote)?, prefix("local", &sa.local)?)),
+            (true, None) => return Err(invalid(format!("SA {:#010x}: only tunnel-mode SAs take remote and local", sa.spi))),
+        };
+        let keys = SaKeys {
+            spi: sa.spi,
+            enc_key,
+            auth_key: decode("auth_key", sa.auth_key.as_ref().map_or("", |key| key.as_str()))?,
+            direction: sa.direction.clone(),
+            reply_to: sa.reply_to,
+            cipher,
+            esn: sa.esn,
+            lifetime_bytes: sa.lifetime_bytes,
+            lifetime_packets: sa.lifetime_packets,
+            tunnel,
+            selectors,
+        };
+        match keys.direction.as_str() {
+            "inbound" | "outbound" | "both" => {}
+            other => return Err(invalid(format!("SA {:#010x}: unknown direction {:?}", sa.spi, other))),
+        }
+        if keys.reply_to.is_some() && keys.direction == "inbound" {
+            return Err(invalid(format!("SA {:#010x}: only outbound SAs reply_to another", sa.spi)));
+        }
+        if keys.selectors.is_some() && keys.direction == "inbound" {
+            return Err(invalid(format!("SA {:#010x}: only outbound SAs protect remote and local", sa.spi)));
+        }
+        let (enc_lens, auth_len) = match key_lens(&keys.cipher) {
+            Some(lens) => lens,
+            None => return Err(invalid(format!("SA {:#010x}: unknown cipher {:?}", sa.spi, keys.cipher))),
+        };
+        match (keys.auth_key.len(), auth_len) {
+            (len, expected) if len == expected => {}
+            (_, 0) => return Err(invalid(format!("SA {:#010x}: {} takes no auth_key", sa.spi, keys.cipher))),
+            (_, expected) => {
+                return Err(invalid(format!("SA {:#010x}: {} needs a {}-byte auth_key", sa.spi, keys.cipher, expected)));
+            }
+        }
+        if !enc_lens.contains(&keys.enc_key.len()) {
+            return Err(invalid(format!(
+                "SA {:#010x}: enc_key is {} bytes, {} takes {:?}",
+                sa.spi,
+                keys.enc_key.len(),
+                keys.cipher,
+                enc_lens
+            )));
+        }
+        if let Some((src, dst)) = keys.tunnel {
+            if src.is_ipv4() != dst.is_ipv4() {
+                return Err(invalid(format!("SA {:#010x}: tunnel_src and tunnel_dst are of different families", sa.spi)));
+            }
+        }
+        Ok(keys)
+    }
+}
+
+/// Hands out SA keys. A key management service implements this to feed the enclaves through
+/// `serve`; `LocalKeyServer` stands in for one.
+pub trait KeyServer {
+    /// The SAs an enclave of measurement `mrenclave` should have.
+    fn keys(&mut self, mrenclave: &[u8]) -> IoResult<Vec<SaKeys>>;
+}
+
+/// Serves the SAs of the key server's configuration. Every enclave gets the inbound ones, but
+/// an outbound SA only goes to one enclave: each enclave counts the sequence numbers of its
+/// outbound SAs itself, and two counting the same SA would have the peer drop the packets of
+/// one as replays. An enclave gets the first outbound SA left of those answering an inbound
+/// SA, and "both" SAs that are taken inbound only.
+pub struct LocalKeyServer {
+    keys: Vec<SaKeys>,
+    /// Outbound SAs some enclave has.
+    taken: HashSet<u32>,
+}
+
+impl LocalKeyServer {
+    pub fn new(keys: Vec<SaKeys>) -> LocalKeyServer {
+        LocalKeyServer {
+            keys,
+            taken: HashSet::new(),
+        }
+    }
+
+    pub fn from_config(sas: &[SaConfiguration]) -> IoResult<LocalKeyServer> {
+        if sas.is_empty() {
+            println!("keyserver: no SAs configured, enclaves get no IPsec keys");
+        }
+        let keys = sas.iter().map(SaKeys::from_config).collect::<IoResult<Vec<_>>>()?;
+        Ok(LocalKeyServer::new(keys))
+    }
+}
+
+impl KeyServer for LocalKeyServer {
+    fn keys(&mut self, _mrenclave: &[u8]) -> IoResult<Vec<SaKeys>> {
+        let mut keys = Vec::new();
+        // inbound SAs the enclave has an outbound SA to answer already.
+        let mut answered = HashSet::new();
+        for sa in &self.keys {
+            let answers = sa.reply_to.unwrap_or(sa.spi);
+            if sa.direction != "inbound" && !answered.contains(&answers) && self.taken.insert(sa.spi) {
+                answered.insert(answers);
+                keys.push(sa.clone());
+            } else if sa.direction != "outbound" {
+                keys.push(SaKeys {
+                    direction: "inbound".to_string(),
+                    reply_to: None,
+                    selectors: None,
+                    ..sa.clone()
+                });
+            }
+        }
+        for sa in self.keys.iter().filter(|sa| sa.direction == "both" && !answered.contains(&sa.spi)) {
+            println!("keyserver: no outbound SA left to answer SA {:#010x}, configure one per enclave", sa.spi);
+        }
+        Ok(keys)
+    }
+}
+
+/// The checkpoint counters of the enclaves, as files `<name>.counter` of a directory.
+#[derive(Debug)]
+pub struct Counters {
+    dir: PathBuf,
+    /// Serializes the increments of the enclaves.
+    lock: Mutex<()>,
+}
+
+impl Counters {
+    pub fn new<P: AsRef<Path>>(dir: P) -> IoResult<Counters> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Counters {
+            dir: dir.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        })
+    }
+
+    /// The file of counter `name`. Names are what the enclave sends, so they must not lead out
+    /// of the directory.
+    fn path(&self, name: &str) -> IoResult<PathBuf> {
+        let fits = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(fits) {
+            return Err(invalid(format!("bad counter name {:?}", name)));
+        }
+        Ok(self.dir.join(format!("{}.counter", name)))
+    }
+
+    /// The value of counter `name`, 0 until first incremented.
+    pub fn get(&self, name: &str) -> IoResult<u64> {
+        match fs::read_to_string(self.path(name)?) {
+            Ok(counter) => counter.trim().parse::<u64>().map_err(|e| invalid(format!("counter {}: {}", name, e))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Increments counter `name` and returns its new value, which is on disk by then.
+    pub fn increment(&self, name: &str) -> IoResult<u64> {
+        let _lock = self.lock.lock().unwrap();
+        let counter = self.get(name)? + 1;
+        let path = self.path(name)?;
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(counter.to_string().as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &path)?;
+        Ok(counter)
+    }
+}
+
+/// Attests the enclave of every runner that connects to `listener` and provisions it with what
+/// `key_server` has for it. An enclave that fails attestation gets nothing, and the next
+/// runner is served. With `counters`, the session of each enclave provisioned then stays open
+/// for its counter requests, see `count`.
+pub fn serve<K: KeyServer>(
+    listener: TcpListener,
+    verifier: &mut KeyServerVerifier,
+    key_server: &mut K,
+    counters: Option<Arc<Counters>>,
+) -> IoResult<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        let mut stream = BufReader::new(stream);
+        let served = verifier.attest(&mut stream).and_then(|(mut session, quoted)| {
+            let keys = key_server.keys(&quoted.mrenclave)?;
+            provision(&mut session, stream.get_mut(), &keys).map(|_| (session, keys.len()))
+        });
+        match served {
+            Ok((session, sas)) => {
+                println!("keyserver: {} SAs to the enclave behind {}", sas, peer);
+                if let Some(ref counters) = counters {
+                    let counters = counters.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = count(session, stream, &counters) {
+                            eprintln!("keyserver: stopped counting for the enclave behind {}: {}", peer, e);
+                        }
+                    });
+                }
+            }
+            Err(e) => eprintln!("keyserver: no keys for the enclave behind {}: {}", peer, e),
+        }
+    }
+    Ok(())
+}
+
+/// Answers the counter requests the enclave at the other end of `session` seals, until it
+/// hangs up:
+///
+///    enclave:    sealed( counter <name> )          or: sealed( increment <name> )
+///    key server: sealed( count <name> <value> )
+pub fn count<S: Read + Write>(mut session: Session, mut stream: BufReader<S>, counters: &Counters) -> IoResult<()> {
+    while !stream.fill_buf()?.is_empty() {
+        let fields = session.open_message(&mut stream)?;
+        let (verb, name) = match (fields.get(0), fields.get(1)) {
+            (Some(verb), Some(name)) => (verb, name),
+            _ => return Err(invalid(format!("malformed counter request {:?}", fields.first()))),
+        };
+        let value = match verb.as_str() {
+            "counter" => counters.get(name)?,
+            "increment" => counters.increment(name)?,
+            _ => return Err(invalid(format!("unexpected sealed message {:?}", verb))),
+        };
+        session.seal_message(stream.get_mut(), "count", &[name.clone(), value.to_string()])?;
+    }
+    Ok(())
+}
+
+/// Seals `keys` to the enclave at the other end of `session`, one message per SA, then tells it
+/// how many there were.
+pub fn provision<W: Write>(session: &mut Session, stream: &mut W, keys: &[SaKeys]) -> IoResult<()> {
+    for sa in keys {
+        let mut fields = vec![sa.spi.to_string(), hex::encode(&sa.enc_key)];
+        // an empty field would vanish from the line: GCM SAs just have no authentication key.
+        if !sa.auth_key.is_empty() {
+            fields.push(hex::encode(&sa.auth_key));
+        }
+        session.seal_message(stream, "key", &fields)?;
+        let mut params = vec![
+            sa.spi.to_string(),
+            sa.direction.clone(),
+            sa.cipher.clone(),
+            (sa.esn as u8).to_string(),
+            sa.lifetime_bytes.unwrap_or(0).to_string(),
+            sa.lifetime_packets.unwrap_or(0).to_string(),
+            sa.reply_to.unwrap_or(0).to_string(),
+        ];
+        if let Some((src, dst)) = sa.tunnel {
+            params.push(src.to_string());
+            params.push(dst.to_string());
+        }
+        if let Some((ref remote, ref local)) = sa.selectors {
+            params.push(remote.clone());
+            params.push(local.clone());
+        }
+        session.seal_message(stream, "sa", &params)?;
+    }
+    session.seal_message(stream, "end", &[keys.len().to_string()])
+}
@@ -0,0 +1,2 @@
+pub use self::keyserver::*;
+pub mod keyserver;
\ No newline at end of file
@@ -16,8 +16,21 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate sharedring;
+extern crate protocol;
 extern crate cc;
 extern crate tokio;
+extern crate hex;
+extern crate mbedtls;
+extern crate bincode;
+extern crate toml;
+extern crate reqwest;
+extern crate serde_json;
+extern crate base64;
+extern crate percent_encoding;
 
+pub mod attestation;
 pub mod config;
-pub mod haproxy;
\ No newline at end of file
+pub mod haproxy;
+pub mod keyserver;
+pub mod storage;
+pub mod usercalls;
\ No newline at end of file
@@ -15,17 +15,20 @@ extern crate clap;
 #[link(name="mapping", kind="static")]
 extern { fn mapping(); }
 
+use mylib::attestation::Verifier;
+use mylib::storage::FileStore;
+use mylib::usercalls::{ConfigService, LogService, MetricsService, Registry, TimeService};
 use mylib::haproxy::{run_client, run_server, parse_args};
-use mylib::config::{load_config, NUM_RXD, NUM_TXD, NetBricksConfiguration, get_duration};
+use mylib::config::{load_config, config_file, NetBricksConfiguration, get_duration, ENCLAVE_DOCUMENT};
 use sharedring::ring_buffer::*;
 
 use std::time::{Duration, Instant};
 use std::thread;
 use std::sync::{Arc, Mutex};
 use std::fmt::Display;
+use std::fs;
 use std::io::{BufRead, BufReader};
 use std::net::TcpListener;
-use std::sync::atomic::{Ordering, compiler_fence};
 use std::process;
 
 use tokio::timer::{Delay, Interval};
@@ -33,6 +36,10 @@ type TokioRuntime = tokio::runtime::current_thread::Runtime;
 
 const PKT_NUM: u64 = (8 * 1024 * 1024);
 const PRINT_INTER: u64 = (1024 * 1024);
+/// How long each enclave gets to drain or acknowledge a stop on ctrl-c.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+/// How often the configuration file is checked for changes to hand to the enclaves.
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 // pull_count;
 lazy_static!{
@@ -50,16 +57,51 @@ lazy_static!{
     };
 }
 
+/// Republishes the enclave configuration whenever the configuration file changes, for the
+/// enclaves to pick up the new NF parameters of, see framework-inside's `NfConfigWatcher`.
+/// Everything else they only read at startup.
+fn watch_config(path: &'static str, enclave_config: ConfigService) {
+    let modified = |path: &str| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let mut last = modified(path);
+    loop {
+        thread::sleep(CONFIG_POLL_INTERVAL);
+        let now = modified(path);
+        if now == last {
+            continue;
+        }
+        last = now;
+        match load_config().map_err(|e| e.to_string()).and_then(|configuration| {
+            configuration.enclave_document().map_err(|e| e.to_string())
+        }) {
+            Ok(document) => {
+                let version = enclave_config.publish(ENCLAVE_DOCUMENT, document);
+                println!("{} changed, the enclave configuration is at version {}", path, version);
+            }
+            Err(e) => eprintln!("{} changed, but the enclaves keep their configuration: {}", path, e),
+        }
+    }
+}
+
 // TODO: extract the config and ring_buffer from the pkupuller (separated from dpdk logic)
 // Reason: system will crash if dpdk crate co-exists with second enclave creation. 
 fn main() {
     unsafe { mapping(); };
 
-    let configuration = load_config().unwrap();
+    let configuration = match load_config() {
+        Ok(configuration) => configuration,
+        Err(e) => {
+            eprintln!("Bad configuration: {}", e);
+            process::exit(1);
+        }
+    };
     println!("{}", configuration);
 
-    let port_num = configuration.ports[0].rx_queues.len();
-    println!("ports number: {}", port_num);
+    // dpdkIO creates a set of rings per queue of each enclave, which need not match its
+    // own queue count.
+    let port_num = configuration.enclaves.unwrap_or(configuration.ports[0].rx_queues.len());
+    let queues = configuration.enclave.queues;
+    println!("enclaves number: {}, {} queue(s) each", port_num, queues);
+    let (rxd, txd) = (configuration.ports[0].rxd as usize, configuration.ports[0].txd as usize);
 
     let core_ids = core_affinity::get_core_ids().unwrap();
     println!("core_affinity detect: # available cores: {}", core_ids.len());
@@ -69,41 +111,135 @@ fn main() {
     let mut server_count: u64 = 0;
     let mut client_count: u64 = 0;
     let file = parse_args().unwrap();
+    let mut verifier = match Verifier::from_config(&configuration.attestation) {
+        Ok(verifier) => verifier,
+        Err(e) => {
+            eprintln!("Could not set up attestation: {}", e);
+            process::exit(1);
+        }
+    };
+    if !verifier.attests() && configuration.keyserver.is_some() {
+        eprintln!("The key server only hands keys to attested enclaves, set [attestation] quoting");
+        process::exit(1);
+    }
+
+    // what the enclaves reach through usercalls.
+    let document = match configuration.enclave_document() {
+        Ok(document) => document,
+        Err(e) => {
+            eprintln!("Could not write the enclave configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let enclave_config = ConfigService::new().with_document(ENCLAVE_DOCUMENT, document);
+    let metrics = MetricsService::new();
+    let mut registry = Registry::new()
+        .with_service(TimeService)
+        .with_service(LogService::stderr())
+        .with_service(metrics.clone())
+        .with_service(enclave_config.clone());
+    match configuration.storage.as_ref().map(FileStore::from_config) {
+        Some(Ok(store)) => registry = registry.with_service(store),
+        Some(Err(e)) => {
+            eprintln!("Could not open the checkpoint storage: {}", e);
+            process::exit(1);
+        }
+        None => {}
+    }
+    println!("usercall services: {}", registry.names().join(", "));
+    if let Some(path) = config_file() {
+        let enclave_config = enclave_config.clone();
+        thread::spawn(move || watch_config(path, enclave_config));
+    }
+    let registry = Arc::new(registry);
 
     let mut recvq_ring: Vec<RingBuffer> = Vec::new();
     let mut sendq_ring: Vec<RingBuffer> = Vec::new();
+    let mut mbufq_ring: Vec<RingBuffer> = Vec::new();
+    let mut freeq_ring: Vec<RingBuffer> = Vec::new();
 
     for i in 0..port_num {
-        // Create two shared queue: recvq and sendq; 
-        recvq_ring.push(unsafe{RingBuffer::new_in_heap((NUM_RXD) as usize, &format!("{}_{}", RECVQ_PREFIX, i), false)}.unwrap());
-        sendq_ring.push(unsafe{RingBuffer::new_in_heap((NUM_TXD) as usize, &format!("{}_{}", SENDQ_PREFIX, i), false)}.unwrap());
-
         let core_ids_sgx = core_ids[i + 1].clone();
         let file_core = file.clone();
+        let registry_core = registry.clone();
         let server = thread::spawn(move || {
             core_affinity::set_for_current(core_ids_sgx);
-            run_server(file_core).unwrap();
+            run_server(file_core, registry_core).unwrap();
             // server_count += run_server_thread().unwrap();
         });
 
-        let recvq_addr_u64: u64 = recvq_ring[i].head.my_usize as u64; // *mut usize
-        let sendq_addr_u64: u64 = sendq_ring[i].head.my_usize as u64;
+        // the enclave sets up its queues one after the other, each on rings of its own.
+        for r in (i * queues)..((i + 1) * queues) {
+            // Attach the shared queues dpdkIO created: recvq, sendq, mbufq and freeq;
+            // attaching validates the header dpdkIO wrote; refuse to start the enclave on a mismatch.
+            let recvq = unsafe{RingBuffer::new_in_heap(rxd, &format!("{}_{}", RECVQ_PREFIX, r), false)};
+            let sendq = unsafe{RingBuffer::new_in_heap(txd, &format!("{}_{}", SENDQ_PREFIX, r), false)};
+            let mbufq = unsafe{RingBuffer::new_in_heap(rxd, &format!("{}_{}", MBUFQ_PREFIX, r), false)};
+            let freeq = unsafe{RingBuffer::new_in_heap(txd, &format!("{}_{}", FREEQ_PREFIX, r), false)};
+            match (recvq, sendq, mbufq, freeq) {
+                (Ok(recvq), Ok(sendq), Ok(mbufq), Ok(freeq)) => {
+                    recvq_ring.push(recvq);
+                    sendq_ring.push(sendq);
+                    mbufq_ring.push(mbufq);
+                    freeq_ring.push(freeq);
+                }
+                (Err(e), _, _, _) | (_, Err(e), _, _) | (_, _, Err(e), _) | (_, _, _, Err(e)) => {
+                    eprintln!("Could not attach to ring {}: {}", r, e);
+                    process::exit(1);
+                }
+            }
+            // the enclave threads live in this process.
+            recvq_ring[r].set_consumer_pid(process::id());
+            sendq_ring[r].set_producer_pid(process::id());
+            mbufq_ring[r].set_consumer_pid(process::id());
+            freeq_ring[r].set_producer_pid(process::id());
 
-        println!("recvq_addr {}, sendq_addr {}", recvq_addr_u64, sendq_addr_u64);
-        // send recvq_addr and sendq_addr to the enclave through TCP tunnel. 
-        run_client(recvq_addr_u64, sendq_addr_u64).unwrap(); // recvq_addr, sendq_addr
+            let recvq_addr_u64: u64 = recvq_ring[r].mem as u64; // start of the shared region
+            let sendq_addr_u64: u64 = sendq_ring[r].mem as u64;
+            let mbufq_addr_u64: u64 = mbufq_ring[r].mem as u64;
+            let freeq_addr_u64: u64 = freeq_ring[r].mem as u64;
 
-        println!("  recvq: head {} vs. tail {}", recvq_ring[i].head(), recvq_ring[i].tail());
-        println!("  sendq: head {} vs. tail {}", sendq_ring[i].head(), sendq_ring[i].tail());
+            println!("recvq_addr {}, sendq_addr {}, mbufq_addr {}, freeq_addr {}", recvq_addr_u64, sendq_addr_u64, mbufq_addr_u64, freeq_addr_u64);
+            // attest the enclave, send it the ring addresses through TCP tunnel, then relay it to the key server.
+            if let Err(e) = run_client(recvq_addr_u64, sendq_addr_u64, mbufq_addr_u64, freeq_addr_u64, r, &mut verifier, configuration.keyserver.as_ref().map(String::as_str)) {
+                eprintln!("Enclave {} failed attestation, not handing it the rings: {}", i, e);
+                for (name, reason) in enclave_config.rejections() {
+                    eprintln!("  it rejected its configuration {}: {}", name, reason);
+                }
+                process::exit(1);
+            }
+
+            println!("  recvq: head {} vs. tail {}", recvq_ring[r].head(), recvq_ring[r].tail());
+            println!("  sendq: head {} vs. tail {}", sendq_ring[r].head(), sendq_ring[r].tail());
+        }
     }
 
     let recvq_ring_r = recvq_ring.clone();
     ctrlc::set_handler(move || {
-        for i in 0..port_num {
-            compiler_fence(Ordering::Release);
-            recvq_ring_r[i as usize].set_size(STOP_MARK as usize);        
+        // let the enclaves flush what they hold to sendq before stopping them; the ones that
+        // have stopped on their own already have.
+        let running: Vec<_> = recvq_ring_r
+            .iter()
+            .enumerate()
+            .filter(|(_, ring)| ring.acked_state() != RingState::Stopped)
+            .collect();
+        for (_, ring) in running.iter() {
+            ring.request_state(RingState::Draining);
+        }
+        for (i, ring) in running.iter() {
+            if !ring.wait_for_ack(RingState::Draining, DRAIN_TIMEOUT) {
+                println!("Ring {}: enclave did not finish draining", i);
+            }
+            ring.request_state(RingState::Stopped);
+        }
+        for (i, ring) in recvq_ring_r.iter().enumerate() {
+            if !ring.wait_for_ack(RingState::Stopped, DRAIN_TIMEOUT) {
+                println!("Ring {}: enclave did not acknowledge stop", i);
+            }
+        }
+        for (name, value) in metrics.snapshot() {
+            println!("{}: {}", name, value);
         }
-        thread::sleep(std::time::Duration::from_secs(1));// wait until server in enclave sets up;
         process::exit(1);
     }).expect("Error setting Ctrl-C handler");
 
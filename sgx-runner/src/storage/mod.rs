@@ -0,0 +1,2 @@
+pub use self::storage::*;
+pub mod storage;
\ No newline at end of file
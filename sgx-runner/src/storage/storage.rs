@@ -0,0 +1,80 @@
+//! Where the enclaves' checkpoints live. framework-inside's `checkpoint` module seals the state
+//! of the NFs and hands it to the `storage` usercall service, which `FileStore` is: it keeps
+//! each checkpoint in a file of its directory. The checkpoints are sealed: the runner only ever
+//! sees ciphertext. The monotonic counters the enclave checks their freshness against are not
+//! the runner's to keep, see `keyserver::Counters`.
+use config::StorageConfiguration;
+use std::fmt::Display;
+use std::fs;
+use std::io::{Error, ErrorKind, Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use usercalls::Service;
+
+pub use protocol::usercalls::{StorageRequest, StorageResponse};
+
+fn invalid<E: Display>(why: E) -> Error {
+    Error::new(ErrorKind::InvalidData, why.to_string())
+}
+
+/// Checkpoints, as files `<name>.checkpoint` of a directory.
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> IoResult<FileStore> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(FileStore {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn from_config(storage: &StorageConfiguration) -> IoResult<FileStore> {
+        FileStore::new(&storage.dir)
+    }
+
+    /// The file of `name` with `extension`. Names are what the enclave sends, so they must not
+    /// lead out of the directory.
+    fn path(&self, name: &str, extension: &str) -> IoResult<PathBuf> {
+        let fits = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(fits) {
+            return Err(invalid(format!("bad checkpoint name {:?}", name)));
+        }
+        Ok(self.dir.join(format!("{}.{}", name, extension)))
+    }
+
+    /// Replaces the file at `path` with `contents` all at once, so a crash leaves either.
+    fn replace(path: &Path, contents: &[u8]) -> IoResult<()> {
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(contents)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)
+    }
+
+    pub fn load(&self, name: &str) -> IoResult<Option<Vec<u8>>> {
+        match fs::read(self.path(name, "checkpoint")?) {
+            Ok(blob) => Ok(Some(blob)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn store(&self, name: &str, blob: &[u8]) -> IoResult<()> {
+        FileStore::replace(&self.path(name, "checkpoint")?, blob)
+    }
+}
+
+impl Service for FileStore {
+    type Protocol = protocol::usercalls::StorageService;
+
+    fn handle(&self, request: StorageRequest) -> IoResult<StorageResponse> {
+        match request {
+            StorageRequest::Load(name) => self.load(&name).map(StorageResponse::Blob),
+            StorageRequest::Store(name, blob) => self.store(&name, &blob).map(|()| StorageResponse::Stored),
+        }
+    }
+}
@@ -0,0 +1,4 @@
+pub use self::registry::*;
+pub use self::services::*;
+pub mod registry;
+pub mod services;
\ No newline at end of file
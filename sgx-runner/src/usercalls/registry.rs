@@ -0,0 +1,98 @@
+//! Named services the enclaves reach through usercalls. An enclave that connects to
+//! `SERVICE_PREFIX` followed by the name of a service gets a stream to it instead of a socket;
+//! framework-inside's `usercalls` module has the clients, and the crate `protocol` the frames
+//! and messages of each service.
+use bincode;
+use protocol::usercalls::{read_frame, write_frame, Service as Protocol, SERVICE_PREFIX};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result as IoResult};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::thread;
+
+/// A usercall service.
+pub trait Service: fmt::Debug + Send + Sync + 'static {
+    /// What the enclaves know the service as, and the messages it takes and gives.
+    type Protocol: Protocol;
+
+    /// Answers a request of an enclave. The error goes back to the enclave.
+    fn handle(&self, request: <Self::Protocol as Protocol>::Request) -> IoResult<<Self::Protocol as Protocol>::Response>;
+}
+
+/// A service with the types of its messages erased.
+trait Handler: fmt::Debug + Send + Sync {
+    /// The longest frames the service takes and gives.
+    fn max_lens(&self) -> (usize, usize);
+    fn handle_frame(&self, frame: &[u8]) -> IoResult<Vec<u8>>;
+}
+
+impl<S: Service> Handler for S {
+    fn max_lens(&self) -> (usize, usize) {
+        (S::Protocol::MAX_REQUEST_LEN, S::Protocol::MAX_RESPONSE_LEN)
+    }
+
+    fn handle_frame(&self, frame: &[u8]) -> IoResult<Vec<u8>> {
+        let response = match bincode::deserialize(frame) {
+            Ok(request) => self.handle(request).map_err(|e| e.to_string()),
+            Err(e) => Err(format!("malformed {} request: {}", S::Protocol::NAME, e)),
+        };
+        bincode::serialize(&response).map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))
+    }
+}
+
+/// The services the runner offers its enclaves, by name.
+#[derive(Debug, Default)]
+pub struct Registry {
+    services: HashMap<&'static str, Arc<Handler>>,
+}
+
+impl Registry {
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+
+    /// Offers `service`, instead of any other of the same name.
+    pub fn with_service<S: Service>(mut self, service: S) -> Registry {
+        self.services.insert(S::Protocol::NAME, Arc::new(service));
+        self
+    }
+
+    /// The names of the services offered.
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<_> = self.services.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// A stream to hand the enclave for its connection to `addr`, with a thread serving the
+    /// other end. `None` if `addr` is not one of ours.
+    pub fn connect(&self, addr: &str) -> IoResult<Option<UnixStream>> {
+        if !addr.starts_with(SERVICE_PREFIX) {
+            return Ok(None);
+        }
+        let name = &addr[SERVICE_PREFIX.len()..];
+        let handler = match self.services.get(name) {
+            Some(handler) => handler.clone(),
+            None => return Err(Error::new(ErrorKind::ConnectionRefused, format!("no usercall service {:?}", name))),
+        };
+        let (enclave, runner) = UnixStream::pair()?;
+        let name = name.to_string();
+        thread::spawn(move || {
+            if let Err(e) = serve(&*handler, runner) {
+                eprintln!("usercalls: {} connection failed: {}", name, e);
+            }
+        });
+        Ok(Some(enclave))
+    }
+}
+
+/// Answers the requests on `stream` until the enclave hangs up.
+fn serve(handler: &Handler, mut stream: UnixStream) -> IoResult<()> {
+    let (max_request_len, max_response_len) = handler.max_lens();
+    while let Some(request) = read_frame(&mut stream, max_request_len)? {
+        let response = handler.handle_frame(&request)?;
+        write_frame(&mut stream, &response, max_response_len)?;
+    }
+    Ok(())
+}
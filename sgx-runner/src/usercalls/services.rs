@@ -0,0 +1,154 @@
+//! The usercall services of the runner besides checkpoint storage, which is `storage`'s.
+//! Their requests and responses are the crate `protocol`'s.
+use super::Service;
+use hex;
+use protocol::usercalls as messages;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Error, ErrorKind, Result as IoResult, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub use protocol::usercalls::{ConfigRequest, ConfigResponse, LogRecord, TimeRequest, Timestamp};
+
+/// The wall-clock time of the host. The enclave has no clock of its own, and must not trust
+/// this one for anything that matters.
+#[derive(Debug)]
+pub struct TimeService;
+
+impl Service for TimeService {
+    type Protocol = messages::TimeService;
+
+    fn handle(&self, _request: TimeRequest) -> IoResult<Timestamp> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| Error::new(ErrorKind::Other, e.to_string()))?;
+        Ok(Timestamp {
+            secs: now.as_secs(),
+            nanos: now.subsec_nanos(),
+        })
+    }
+}
+
+/// Writes the log records of the enclaves out, one line each, with their MACs: whoever has the
+/// key of an enclave can tell whether records of it went missing or were changed.
+pub struct LogService {
+    out: Mutex<Box<Write + Send>>,
+}
+
+impl LogService {
+    pub fn new(out: Box<Write + Send>) -> LogService {
+        LogService { out: Mutex::new(out) }
+    }
+
+    pub fn stderr() -> LogService {
+        LogService::new(Box::new(io::stderr()))
+    }
+}
+
+impl fmt::Debug for LogService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LogService")
+    }
+}
+
+impl Service for LogService {
+    type Protocol = messages::LogService;
+
+    fn handle(&self, record: LogRecord) -> IoResult<()> {
+        let mut out = self.out.lock().unwrap();
+        writeln!(
+            out,
+            "enclave {} {} {}: {} [{}]",
+            record.seq,
+            record.level,
+            record.target,
+            record.message,
+            hex::encode(&record.mac)
+        )
+    }
+}
+
+/// The latest values of the metrics the enclaves report, by name. Clones share them, so the
+/// runner can keep one to read.
+#[derive(Clone, Debug, Default)]
+pub struct MetricsService {
+    values: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl MetricsService {
+    pub fn new() -> MetricsService {
+        MetricsService::default()
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, u64> {
+        self.values.lock().unwrap().clone()
+    }
+}
+
+impl Service for MetricsService {
+    type Protocol = messages::MetricsService;
+
+    fn handle(&self, report: Vec<(String, u64)>) -> IoResult<()> {
+        self.values.lock().unwrap().extend(report);
+        Ok(())
+    }
+}
+
+/// Configuration documents the enclaves fetch by name, in TOML, and what they rejected of
+/// them. Documents can be replaced with `publish` while the enclaves run, which poll for new
+/// versions of them. Clones share both, so the runner can keep one to publish with and to tell
+/// why an enclave quit.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigService {
+    /// The version of each document, from 1, and the document.
+    documents: Arc<Mutex<HashMap<String, (u64, String)>>>,
+    rejections: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl ConfigService {
+    pub fn new() -> ConfigService {
+        ConfigService::default()
+    }
+
+    pub fn with_document(self, name: &str, document: String) -> ConfigService {
+        self.publish(name, document);
+        self
+    }
+
+    /// Replaces document `name`, unless it is the same. Returns its version.
+    pub fn publish(&self, name: &str, document: String) -> u64 {
+        let mut documents = self.documents.lock().unwrap();
+        let entry = documents.entry(name.to_string()).or_insert((0, String::new()));
+        if entry.0 == 0 || entry.1 != document {
+            *entry = (entry.0 + 1, document);
+        }
+        entry.0
+    }
+
+    /// The documents rejected so far, and why.
+    pub fn rejections(&self) -> Vec<(String, String)> {
+        self.rejections.lock().unwrap().clone()
+    }
+}
+
+impl Service for ConfigService {
+    type Protocol = messages::ConfigService;
+
+    fn handle(&self, request: ConfigRequest) -> IoResult<ConfigResponse> {
+        let documents = self.documents.lock().unwrap();
+        match request {
+            ConfigRequest::Fetch(name) => {
+                Ok(ConfigResponse::Document(documents.get(&name).map(|(_, document)| document.clone())))
+            }
+            ConfigRequest::Reject(name, reason) => {
+                eprintln!("An enclave rejected configuration {}: {}", name, reason);
+                self.rejections.lock().unwrap().push((name, reason));
+                Ok(ConfigResponse::Noted)
+            }
+            ConfigRequest::Poll(name, version) => Ok(ConfigResponse::Update(
+                documents.get(&name).filter(|&&(latest, _)| latest > version).cloned(),
+            )),
+        }
+    }
+}
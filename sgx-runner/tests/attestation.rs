@@ -0,0 +1,257 @@
+extern crate hex;
+extern crate mbedtls;
+extern crate mylib;
+extern crate netbricks;
+
+use mbedtls::pk::{EcGroupId, Pk};
+use mbedtls::rng::Rdrand;
+use mylib::attestation::{forward, KeyServerVerifier, MockQuotingService, Verifier};
+use mylib::config::AttestationConfiguration;
+use mylib::keyserver::{serve, KeyServer, LocalKeyServer, SaKeys};
+use netbricks::packets::ip::ProtocolNumbers;
+use netbricks::utils::ipsec::{Direction, PolicyAction, SAD, SPD};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc;
+use std::thread;
+
+fn mock(mrenclave: Option<Vec<u8>>) -> Verifier {
+    Verifier::new(Box::new(MockQuotingService), mrenclave)
+}
+
+struct Keys(Vec<SaKeys>);
+
+impl KeyServer for Keys {
+    fn keys(&mut self, _mrenclave: &[u8]) -> io::Result<Vec<SaKeys>> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Starts a key server that hands `keys` to enclaves of measurement `mrenclave`, and returns
+/// its address and public key.
+fn key_server(mrenclave: Option<Vec<u8>>, keys: Vec<SaKeys>) -> (String, Vec<u8>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let (public, identity) = mpsc::channel();
+    thread::spawn(move || {
+        let mut identity = Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1).unwrap();
+        public.send(identity.write_public_der_vec().unwrap()).unwrap();
+        let mut verifier = KeyServerVerifier::new(Box::new(MockQuotingService), mrenclave, identity);
+        serve(listener, &mut verifier, &mut Keys(keys), None).unwrap();
+    });
+    (addr, identity.recv().unwrap())
+}
+
+/// Records what the runner reads off a stream.
+struct Tap<S> {
+    inner: S,
+    seen: Vec<u8>,
+}
+
+impl<S: Read> Read for Tap<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.seen.extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+impl<S: Write> Write for Tap<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+type Outcome = (Result<(Vec<String>, usize), String>, Result<Vec<u8>, String>);
+
+/// Runs `verifier` against the enclave side of the handshake, then has it relay the key
+/// server at `key_server`, if any, whose key the enclave pins as `pinned`. Returns what each
+/// ended with: the ring message the enclave read and how many SAs it got, and what the runner
+/// read off the key server.
+fn handshake(mut verifier: Verifier, key_server: Option<&str>, pinned: Option<Vec<u8>>) -> Outcome {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let enclave = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut stream = BufReader::new(stream);
+        let session = netbricks::attestation::respond(&mut stream).map_err(|e| e.to_string())?;
+        let rings = session.read_message(&mut stream, "rings").map_err(|e| e.to_string())?;
+        let (sas, _) = netbricks::provisioning::receive(stream, pinned.as_ref().map(Vec::as_slice))
+            .map_err(|e| e.to_string())?;
+        Ok((rings, sas))
+    });
+
+    let mut stream = BufReader::new(TcpStream::connect(addr).unwrap());
+    let runner = verifier
+        .attest(&mut stream)
+        .and_then(|session| {
+            session.write_message(stream.get_mut(), "rings", &["4096".to_string(), "8192".to_string()])?;
+            let mut seen = Vec::new();
+            if let Some(address) = key_server {
+                let mut key_server = BufReader::new(Tap {
+                    inner: TcpStream::connect(address)?,
+                    seen: Vec::new(),
+                });
+                verifier.relay(&mut stream, &mut key_server)?;
+                let mut from_enclave = BufReader::new(stream.get_ref().try_clone()?);
+                let mut to_key_server = key_server.get_ref().inner.try_clone()?;
+                thread::spawn(move || {
+                    let _ = forward(&mut from_enclave, &mut to_key_server);
+                    let _ = to_key_server.shutdown(Shutdown::Write);
+                });
+                forward(&mut key_server, stream.get_mut())?;
+                seen = key_server.into_inner().seen;
+            }
+            stream.get_mut().shutdown(Shutdown::Write)?;
+            Ok(seen)
+        })
+        .map_err(|e| e.to_string());
+    (enclave.join().unwrap(), runner)
+}
+
+fn rings() -> Vec<String> {
+    vec!["4096".to_string(), "8192".to_string()]
+}
+
+fn hex_zeros() -> String {
+    "00".repeat(32)
+}
+
+#[test]
+fn mock_enclave_gets_its_rings() {
+    // reports made outside an enclave carry a zero measurement.
+    let (enclave, runner) = handshake(mock(Some(vec![0; 32])), None, None);
+    assert!(runner.is_ok());
+    // no key server, no keys.
+    assert_eq!(enclave, Ok((rings(), 0)));
+}
+
+#[test]
+fn unexpected_measurement_is_rejected() {
+    let (enclave, runner) = handshake(mock(Some(vec![0x11; 32])), None, None);
+    assert!(runner.unwrap_err().contains("unexpected enclave measurement"));
+    assert!(enclave.unwrap_err().contains("rejected by the runner"));
+}
+
+#[test]
+fn enclaves_are_not_attested_by_default() {
+    let verifier = Verifier::from_config(&AttestationConfiguration::default()).unwrap();
+    assert!(!verifier.attests());
+    let (enclave, runner) = handshake(verifier, None, None);
+    assert!(runner.is_ok());
+    assert_eq!(enclave, Ok((rings(), 0)));
+
+    let config = AttestationConfiguration {
+        mrenclave: Some(hex_zeros()),
+        ..AttestationConfiguration::default()
+    };
+    assert!(Verifier::from_config(&config).is_err());
+}
+
+#[test]
+fn any_measurement_must_be_asked_for() {
+    let mut config = AttestationConfiguration {
+        quoting: "mock".to_string(),
+        ..AttestationConfiguration::default()
+    };
+    assert!(Verifier::from_config(&config).is_err());
+    config.any_mrenclave = true;
+    assert!(Verifier::from_config(&config).unwrap().attests());
+    config.mrenclave = Some(hex_zeros());
+    assert!(Verifier::from_config(&config).is_err());
+    config.any_mrenclave = false;
+    assert!(Verifier::from_config(&config).is_ok());
+}
+
+#[test]
+fn keys_reach_only_the_enclave() {
+    let keys = SaKeys {
+        spi: 0x5a5a,
+        enc_key: vec![0x42; 16],
+        auth_key: vec![0x24; 32],
+        direction: "both".to_string(),
+        cipher: "aes-cbc-sha256".to_string(),
+        esn: true,
+        lifetime_bytes: None,
+        lifetime_packets: Some(1000),
+        reply_to: None,
+        tunnel: Some(("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap())),
+        selectors: Some(("10.0.2.0/24".to_string(), "10.0.1.0/24".to_string())),
+    };
+    let (address, identity) = key_server(None, vec![keys]);
+    let (enclave, runner) = handshake(mock(Some(vec![0; 32])), Some(&address), Some(identity.clone()));
+    assert_eq!(enclave, Ok((rings(), 1)));
+    // all the runner got to see of the keys is ciphertext.
+    let seen = String::from_utf8(runner.unwrap()).unwrap();
+    assert!(seen.contains("sealed"));
+    assert!(!seen.contains(&hex::encode(&[0x42; 16])) && !seen.contains(&hex::encode(&[0x24; 32])));
+    let installed = netbricks::provisioning::lookup(0x5a5a).unwrap();
+    assert_eq!(installed.enc_key, vec![0x42; 16]);
+    assert_eq!(installed.auth_key, vec![0x24; 32]);
+    let sa = SAD.get(0x5a5a, Direction::Outbound).unwrap();
+    let sa = sa.lock().unwrap();
+    assert!(sa.esn);
+    assert_eq!(sa.lifetime.packets, Some(1000));
+    assert_eq!(sa.tunnel, Some(("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap())));
+    assert!(SAD.get(0x5a5a, Direction::Inbound).is_some());
+    let select = |src: [u8; 4], dst: [u8; 4]| SPD.select(IpAddr::from(src), IpAddr::from(dst), ProtocolNumbers::Udp);
+    assert_eq!(select([10, 0, 1, 7], [10, 0, 2, 7]), PolicyAction::Protect(0x5a5a));
+    assert_eq!(select([10, 0, 2, 7], [10, 0, 1, 7]), PolicyAction::Discard);
+
+    // a measurement mismatch means no keys either.
+    let keys = SaKeys {
+        spi: 0xa5a5,
+        enc_key: vec![0x42; 36],
+        auth_key: Vec::new(),
+        direction: "inbound".to_string(),
+        cipher: "chacha20-poly1305".to_string(),
+        esn: false,
+        lifetime_bytes: None,
+        lifetime_packets: None,
+        reply_to: None,
+        tunnel: None,
+        selectors: None,
+    };
+    // the key server checks the measurement itself, whatever the runner accepts.
+    let (address, identity) = key_server(Some(vec![0x11; 32]), vec![keys.clone()]);
+    let (enclave, _) = handshake(mock(None), Some(&address), Some(identity));
+    assert!(enclave.unwrap_err().contains("unexpected enclave measurement"));
+    assert!(netbricks::provisioning::lookup(0xa5a5).is_none());
+
+    // nor does any but the key server the enclave pinned get to hand it keys.
+    let (address, _) = key_server(None, vec![keys]);
+    let (_, pinned) = key_server(None, Vec::new());
+    let (enclave, _) = handshake(mock(None), Some(&address), Some(pinned));
+    assert!(enclave.unwrap_err().contains("not signed by the key server"));
+    assert!(netbricks::provisioning::lookup(0xa5a5).is_none());
+}
+
+#[test]
+fn outbound_sas_go_to_one_enclave() {
+    let sa = |spi: u32, direction: &str, reply_to: Option<u32>| SaKeys {
+        spi,
+        enc_key: vec![0x42; 20],
+        auth_key: Vec::new(),
+        direction: direction.to_string(),
+        cipher: "aes-gcm128".to_string(),
+        esn: false,
+        lifetime_bytes: None,
+        lifetime_packets: None,
+        reply_to,
+        tunnel: None,
+        selectors: None,
+    };
+    let mut key_server = LocalKeyServer::new(vec![sa(1, "both", None), sa(2, "outbound", Some(1)), sa(3, "inbound", None)]);
+    let sas = |keys: Vec<SaKeys>| keys.iter().map(|sa| (sa.spi, sa.direction.clone())).collect::<Vec<_>>();
+    let first = sas(key_server.keys(&[]).unwrap());
+    assert_eq!(first, vec![(1, "both".to_string()), (3, "inbound".to_string())]);
+    let second = sas(key_server.keys(&[]).unwrap());
+    assert_eq!(second, vec![(1, "inbound".to_string()), (2, "outbound".to_string()), (3, "inbound".to_string())]);
+    // none left to answer with.
+    let third = sas(key_server.keys(&[]).unwrap());
+    assert_eq!(third, vec![(1, "inbound".to_string()), (3, "inbound".to_string())]);
+}
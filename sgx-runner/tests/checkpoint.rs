@@ -0,0 +1,109 @@
+extern crate mbedtls;
+extern crate mylib;
+extern crate netbricks;
+
+use mbedtls::pk::{EcGroupId, Pk};
+use mbedtls::rng::Rdrand;
+use mylib::attestation::{forward, KeyServerVerifier, MockQuotingService, Verifier};
+use mylib::keyserver::{serve, Counters, LocalKeyServer};
+use mylib::storage::FileStore;
+use mylib::usercalls::Registry;
+use netbricks::checkpoint::{self, TrustedCounter};
+use netbricks::provisioning::{self, CounterSession};
+use netbricks::usercalls::{Client, StorageService};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::path::Path;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+thread_local! {
+    static PORT_MAP: RefCell<HashMap<u16, u16>> = RefCell::new(HashMap::new());
+}
+
+/// Starts a key server that keeps its counters in `dir`, and returns its address.
+fn key_server(dir: &Path) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let counters = Arc::new(Counters::new(dir).unwrap());
+    thread::spawn(move || {
+        let identity = Pk::generate_ec(&mut Rdrand, EcGroupId::SecP256R1).unwrap();
+        let mut verifier = KeyServerVerifier::new(Box::new(MockQuotingService), None, identity);
+        serve(listener, &mut verifier, &mut LocalKeyServer::new(Vec::new()), Some(counters)).unwrap();
+    });
+    addr
+}
+
+/// Has a runner relay the key server at `address` to a new enclave, and returns the enclave's
+/// session for the counters.
+fn counters(address: &str) -> CounterSession<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let enclave = listener.local_addr().unwrap();
+    let address = address.to_string();
+    thread::spawn(move || {
+        let mut verifier = Verifier::new(Box::new(MockQuotingService), None);
+        let mut enclave = BufReader::new(TcpStream::connect(enclave).unwrap());
+        let mut key_server = BufReader::new(TcpStream::connect(&address).unwrap());
+        verifier.relay(&mut enclave, &mut key_server).unwrap();
+        let mut from_enclave = BufReader::new(enclave.get_ref().try_clone().unwrap());
+        let mut to_key_server = key_server.get_ref().try_clone().unwrap();
+        thread::spawn(move || {
+            let _ = forward(&mut from_enclave, &mut to_key_server);
+            let _ = to_key_server.shutdown(Shutdown::Write);
+        });
+        let _ = forward(&mut key_server, enclave.get_mut());
+        let _ = enclave.get_ref().shutdown(Shutdown::Write);
+    });
+    let (stream, _) = listener.accept().unwrap();
+    let (sas, counters) = provisioning::receive(BufReader::new(stream), None).unwrap();
+    assert_eq!(sas, 0);
+    counters.unwrap()
+}
+
+#[test]
+fn checkpoints_survive_a_restart_but_not_a_rollback() {
+    let dir = env::temp_dir().join(format!("safebricks-checkpoints-{}", process::id()));
+    let key_server = key_server(&dir.join("counters"));
+    let registry = Registry::new().with_service(FileStore::new(dir.join("checkpoints")).unwrap());
+    let connect = || {
+        let stream = registry.connect("safebricks-usercall:storage").unwrap().unwrap();
+        Client::<StorageService, _>::new(stream)
+    };
+    // what the runner sees of the directory.
+    let store = FileStore::new(dir.join("checkpoints")).unwrap();
+    checkpoint::register("nat.port_map", &PORT_MAP);
+    let mut storage = connect();
+    let mut counter = counters(&key_server);
+    assert_eq!(checkpoint::restore(&mut storage, &mut counter, "nat-0").unwrap(), None);
+
+    PORT_MAP.with(|map| map.borrow_mut().insert(1024, 80));
+    assert_eq!(checkpoint::save(&mut storage, &mut counter, "nat-0").unwrap(), 1);
+    let old = store.load("nat-0").unwrap().unwrap();
+    PORT_MAP.with(|map| map.borrow_mut().insert(1025, 443));
+    checkpoint::save(&mut storage, &mut counter, "nat-0").unwrap();
+    // the counter is the key server's, out of the runner's reach.
+    assert_eq!(fs::read_to_string(dir.join("counters").join("nat-0.counter")).unwrap(), "2");
+    assert!(fs::read_dir(dir.join("checkpoints")).unwrap().all(|entry| {
+        entry.unwrap().path().extension().map_or(false, |extension| extension == "checkpoint")
+    }));
+
+    // a new enclave, on new connections.
+    PORT_MAP.with(|map| map.borrow_mut().clear());
+    let mut storage = connect();
+    let mut counter = counters(&key_server);
+    assert_eq!(checkpoint::restore(&mut storage, &mut counter, "nat-0").unwrap(), Some(1));
+    PORT_MAP.with(|map| assert_eq!(map.borrow().get(&1025), Some(&443)));
+
+    store.store("nat-0", &old).unwrap();
+    let stale = checkpoint::restore(&mut storage, &mut counter, "nat-0").unwrap_err();
+    assert!(stale.to_string().contains("counter is at 2"), "{}", stale);
+    // names stay in their directories.
+    assert!(store.load("../nat-0").is_err());
+    assert!(counter.increment("../nat-0").is_err());
+    fs::remove_dir_all(&dir).unwrap();
+}
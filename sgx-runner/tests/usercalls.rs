@@ -0,0 +1,78 @@
+extern crate hex;
+extern crate log;
+extern crate mylib;
+extern crate netbricks;
+
+use log::{Level, LevelFilter, Log, Record};
+use mylib::usercalls::{ConfigService, LogService, MetricsService, Registry, TimeService};
+use netbricks::usercalls::{self as enclave, record_mac, Client, UsercallLogger};
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Output shared with the test.
+#[derive(Clone, Default)]
+struct Lines(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn enclave_clients_reach_the_services() {
+    let lines = Lines::default();
+    let metrics = MetricsService::new();
+    let documents = ConfigService::new().with_document("nf", "rules = 3".to_string());
+    let registry = Registry::new()
+        .with_service(TimeService)
+        .with_service(LogService::new(Box::new(lines.clone())))
+        .with_service(metrics.clone())
+        .with_service(documents.clone());
+    assert_eq!(registry.names(), vec!["config", "log", "metrics", "time"]);
+    let connect = |name: &str| registry.connect(&format!("safebricks-usercall:{}", name)).unwrap().unwrap();
+
+    let mut time = Client::<enclave::TimeService, _>::new(connect("time"));
+    let now = time.now().unwrap();
+    assert!(now <= SystemTime::now().duration_since(UNIX_EPOCH).unwrap());
+
+    let mut config = Client::<enclave::ConfigService, _>::new(connect("config"));
+    assert_eq!(config.fetch("nf").unwrap(), Some("rules = 3".to_string()));
+    assert_eq!(config.fetch("other").unwrap(), None);
+    config.reject("nf", "rules must be a list").unwrap();
+    assert_eq!(documents.rejections(), vec![("nf".to_string(), "rules must be a list".to_string())]);
+    assert_eq!(config.poll("nf", 1).unwrap(), None);
+    assert_eq!(documents.publish("nf", "rules = 3".to_string()), 1);
+    assert_eq!(documents.publish("nf", "rules = [3]".to_string()), 2);
+    assert_eq!(config.poll("nf", 1).unwrap(), Some((2, "rules = [3]".to_string())));
+    assert_eq!(config.poll("nf", 2).unwrap(), None);
+    assert_eq!(config.fetch("nf").unwrap(), Some("rules = [3]".to_string()));
+
+    let mut report = Client::<enclave::MetricsService, _>::new(connect("metrics"));
+    report.report(vec![("core0.rx".to_string(), 10), ("core0.tx".to_string(), 9)]).unwrap();
+    report.report(vec![("core0.rx".to_string(), 12)]).unwrap();
+    let snapshot = metrics.snapshot();
+    assert_eq!(snapshot["core0.rx"], 12);
+    assert_eq!(snapshot["core0.tx"], 9);
+
+    let logger = UsercallLogger::new(Client::new(connect("log")), b"log key", LevelFilter::Info);
+    for message in ["started", "ports up"].iter() {
+        logger.log(&Record::builder().level(Level::Info).target("nat").args(format_args!("{}", message)).build());
+    }
+    logger.log(&Record::builder().level(Level::Debug).target("nat").args(format_args!("dropped")).build());
+    let first = record_mac(b"log key", &[0; 32], 0, "INFO", "nat", "started").unwrap();
+    let second = record_mac(b"log key", &first, 1, "INFO", "nat", "ports up").unwrap();
+    let out = String::from_utf8(lines.0.lock().unwrap().clone()).unwrap();
+    let out: Vec<_> = out.lines().collect();
+    assert_eq!(out.len(), 2);
+    assert_eq!(out[1], format!("enclave 1 INFO nat: ports up [{}]", hex::encode(second)));
+
+    // unknown services are refused, and other addresses are not ours.
+    assert!(registry.connect("safebricks-usercall:clock").is_err());
+    assert!(registry.connect("localhost:6010").unwrap().is_none());
+}
@@ -1,5 +1,9 @@
+// only the poller is of use inside an enclave, which has no shared memory of its own.
+#[cfg(not(target_env = "sgx"))]
 extern crate libc;
 #[cfg_attr(test, macro_use)]
 extern crate failure;
 
-pub mod ring_buffer;
\ No newline at end of file
+#[cfg(not(target_env = "sgx"))]
+pub mod ring_buffer;
+pub mod poll;
@@ -0,0 +1,227 @@
+use std::fmt;
+use std::sync::atomic::{self, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Number of power-of-two buckets in the queue-depth histogram; the last one is open-ended.
+pub const DEPTH_BUCKETS: usize = 12;
+/// Upper bound on the `pause` instructions issued by one backoff step.
+const MAX_PAUSES: u32 = 1024;
+
+/// How an `AdaptivePoller` backs off on an idle ring.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PollConfig {
+    /// When false, the default, the poller never backs off, i.e. plain busy polling.
+    pub adaptive: bool,
+    /// Empty polls in a row before backing off at all.
+    pub spin_polls: u32,
+    /// How long the ring must stay empty before the poller sleeps on the doorbell.
+    pub idle: Duration,
+    /// Upper bound on one sleep; also bounds the latency if the doorbell is never rung.
+    pub max_sleep: Duration,
+}
+
+impl Default for PollConfig {
+    fn default() -> PollConfig {
+        PollConfig {
+            adaptive: false,
+            spin_polls: 256,
+            idle: Duration::from_millis(1),
+            max_sleep: Duration::from_millis(1),
+        }
+    }
+}
+
+/// Counters kept by an `AdaptivePoller`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PollStats {
+    /// Polls made.
+    pub polls: u64,
+    /// Polls that moved nothing.
+    pub empty_polls: u64,
+    /// `pause` instructions issued while backing off.
+    pub pauses: u64,
+    /// Times the poller went to sleep.
+    pub sleeps: u64,
+    /// Sleeps ended by the doorbell (or new data) rather than the timeout.
+    pub wakeups: u64,
+    /// Queue-depth samples taken.
+    pub depth_samples: u64,
+    /// Sum of all depth samples.
+    pub depth_sum: u64,
+    /// Largest depth seen.
+    pub depth_max: u64,
+    /// Bucket 0 counts empty samples, bucket `i` depths in `[2^(i-1), 2^i)`.
+    pub depth_hist: [u64; DEPTH_BUCKETS],
+}
+
+impl PollStats {
+    /// Record one queue-depth sample.
+    #[inline]
+    pub fn record_depth(&mut self, depth: usize) {
+        let depth = depth as u64;
+        self.depth_samples += 1;
+        self.depth_sum += depth;
+        if depth > self.depth_max {
+            self.depth_max = depth;
+        }
+        let bucket = (64 - depth.leading_zeros()) as usize;
+        self.depth_hist[bucket.min(DEPTH_BUCKETS - 1)] += 1;
+    }
+
+    /// Mean queue depth over all samples.
+    pub fn mean_depth(&self) -> f64 {
+        if self.depth_samples == 0 {
+            0.0
+        } else {
+            self.depth_sum as f64 / self.depth_samples as f64
+        }
+    }
+}
+
+impl fmt::Display for PollStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "polls: {}, empty: {}, pauses: {}, sleeps: {}, wakeups: {}, depth mean: {:.2}, max: {}, hist: {:?}",
+            self.polls,
+            self.empty_polls,
+            self.pauses,
+            self.sleeps,
+            self.wakeups,
+            self.mean_depth(),
+            self.depth_max,
+            self.depth_hist,
+        )
+    }
+}
+
+/// `PollStats` of a ring polled from one thread and read from any, without a lock: only the
+/// polling thread may record, so plain loads and stores do.
+#[derive(Debug, Default)]
+pub struct PollCounters {
+    polls: AtomicU64,
+    empty_polls: AtomicU64,
+    depth_samples: AtomicU64,
+    depth_sum: AtomicU64,
+    depth_max: AtomicU64,
+    depth_hist: [AtomicU64; DEPTH_BUCKETS],
+}
+
+#[inline]
+fn bump(counter: &AtomicU64, by: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + by, Ordering::Relaxed);
+}
+
+impl PollCounters {
+    /// Record a poll that moved `moved` slots, with `depth` slots in the ring before it.
+    #[inline]
+    pub fn record_poll(&self, moved: usize, depth: usize) {
+        bump(&self.polls, 1);
+        if moved == 0 {
+            bump(&self.empty_polls, 1);
+        }
+        let depth = depth as u64;
+        bump(&self.depth_samples, 1);
+        bump(&self.depth_sum, depth);
+        if depth > self.depth_max.load(Ordering::Relaxed) {
+            self.depth_max.store(depth, Ordering::Relaxed);
+        }
+        let bucket = (64 - depth.leading_zeros()) as usize;
+        bump(&self.depth_hist[bucket.min(DEPTH_BUCKETS - 1)], 1);
+    }
+
+    /// The counters so far.
+    pub fn stats(&self) -> PollStats {
+        let mut depth_hist = [0; DEPTH_BUCKETS];
+        for (bucket, counter) in depth_hist.iter_mut().zip(self.depth_hist.iter()) {
+            *bucket = counter.load(Ordering::Relaxed);
+        }
+        PollStats {
+            polls: self.polls.load(Ordering::Relaxed),
+            empty_polls: self.empty_polls.load(Ordering::Relaxed),
+            depth_samples: self.depth_samples.load(Ordering::Relaxed),
+            depth_sum: self.depth_sum.load(Ordering::Relaxed),
+            depth_max: self.depth_max.load(Ordering::Relaxed),
+            depth_hist,
+            ..PollStats::default()
+        }
+    }
+}
+
+/// Busy-polls while there is work and backs off when there is none: first with growing runs of
+/// `pause`, then, once the ring has been idle for `PollConfig::idle`, by asking the caller to
+/// sleep on the ring's doorbell.
+#[derive(Clone)]
+pub struct AdaptivePoller {
+    config: PollConfig,
+    empty_polls: u32,
+    idle_since: Option<Instant>,
+    stats: PollStats,
+}
+
+impl AdaptivePoller {
+    pub fn new(config: PollConfig) -> AdaptivePoller {
+        AdaptivePoller {
+            config,
+            empty_polls: 0,
+            idle_since: None,
+            stats: PollStats::default(),
+        }
+    }
+
+    /// Record a poll that moved `moved` slots and back off if it moved none. Returns how long
+    /// the caller should sleep on the doorbell, if at all.
+    #[inline]
+    pub fn poll(&mut self, moved: usize) -> Option<Duration> {
+        self.stats.polls += 1;
+        if moved != 0 {
+            self.empty_polls = 0;
+            self.idle_since = None;
+            return None;
+        }
+        self.stats.empty_polls += 1;
+        if !self.config.adaptive {
+            return None;
+        }
+        self.empty_polls = self.empty_polls.saturating_add(1);
+        if self.empty_polls < self.config.spin_polls {
+            return None;
+        }
+        // only look at the clock once we are idle, it is not free (a usercall in the enclave).
+        let now = Instant::now();
+        let idle_since = *self.idle_since.get_or_insert(now);
+        if now.duration_since(idle_since) >= self.config.idle {
+            return Some(self.config.max_sleep);
+        }
+        let shift = (self.empty_polls - self.config.spin_polls).min(10);
+        let pauses = (1u32 << shift).min(MAX_PAUSES);
+        for _ in 0..pauses {
+            atomic::spin_loop_hint();
+        }
+        self.stats.pauses += u64::from(pauses);
+        None
+    }
+
+    /// Record the outcome of a sleep requested by `poll`.
+    #[inline]
+    pub fn slept(&mut self, woken: bool) {
+        self.stats.sleeps += 1;
+        if woken {
+            self.stats.wakeups += 1;
+        }
+    }
+
+    /// Record one queue-depth sample.
+    #[inline]
+    pub fn record_depth(&mut self, depth: usize) {
+        self.stats.record_depth(depth);
+    }
+
+    pub fn stats(&self) -> &PollStats {
+        &self.stats
+    }
+
+    pub fn config(&self) -> &PollConfig {
+        &self.config
+    }
+}
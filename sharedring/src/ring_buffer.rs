@@ -3,23 +3,39 @@ use std::io::{Read, Write};
 use std::io::Error as IOError;
 use std::ptr;
 use std::slice;
-use std::sync::atomic::compiler_fence;
-use std::sync::atomic::Ordering;
+use std::mem;
+use std::sync::atomic::{self, AtomicU32, AtomicUsize, Ordering};
 use std::ffi::CString;
 use std::result::Result;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use failure::Fail;
 use failure::Error;
-use libc::{self, c_void, close, ftruncate, mmap, munmap, shm_open, shm_unlink};
+use libc::{self, c_void, close, fstat, ftruncate, mmap, munmap, shm_open, shm_unlink};
 
 pub const SENDQ_PREFIX: &str = "/sb_sendq";
 pub const RECVQ_PREFIX: &str = "/sb_recvq";
+/// Empty mbufs dpdkIO lends to an enclave so it can build packets of its own.
+pub const MBUFQ_PREFIX: &str = "/sb_mbufq";
+/// Mbufs an enclave dropped, for dpdkIO to free.
+pub const FREEQ_PREFIX: &str = "/sb_freeq";
 
 /// Error related to the RingBuffer
 #[derive(Debug, Fail)]
 #[fail(display = "Bad ring size {}, must be a power of 2", _0)]
 struct InvalidRingSize(usize);
 
+/// The shared segment a non-master process wants to attach to does not exist.
+#[derive(Debug, Fail)]
+#[fail(display = "Shared ring {:?} does not exist, start dpdkIO first", _0)]
+struct RingNotFound(CString);
+
+/// The shared segment does not carry the layout this binary was built for.
+#[derive(Debug, Fail)]
+#[fail(display = "Shared ring header mismatch: {}", _0)]
+pub struct RingHeaderMismatch(pub String);
+
 struct SuperBox { my_box: Box<[u8]> }
 
 impl Drop for SuperBox {
@@ -53,7 +69,107 @@ impl Drop for SuperUsize {
         }
     }
 }
-pub const STOP_MARK: u32 = 0xabcdefff;
+
+/// Size of a cache line on the machines we run on.
+pub const CACHE_LINE_SIZE: usize = 64;
+const WORDS_PER_LINE: isize = (CACHE_LINE_SIZE / 8) as isize;
+
+/// "SBRING" followed by two zero bytes, stored at the very start of every shared ring.
+pub const RING_MAGIC: u64 = 0x5342_5249_4e47_0000;
+/// Bumped whenever the shared layout changes; both sides must agree on it.
+pub const RING_LAYOUT_VERSION: u32 = 3;
+/// Every slot carries one mbuf pointer.
+pub const RING_SLOT_WIDTH: u32 = 8;
+
+// Layout of the ring meta-data, in usize words from the start of the shared region.
+// The header comes first, followed by the control area and the doorbell; the consumer cursor
+// (head) and the producer cursor (tail) sit on their own cache lines so that the two sides do
+// not false-share; size and mask are read-only after creation.
+const HEADER_OFFSET: isize = 0;
+const CONTROL_OFFSET: isize = WORDS_PER_LINE;
+const DOORBELL_OFFSET: isize = 2 * WORDS_PER_LINE;
+const HEAD_OFFSET: isize = 3 * WORDS_PER_LINE;
+const TAIL_OFFSET: isize = 4 * WORDS_PER_LINE;
+const SIZE_OFFSET: isize = 5 * WORDS_PER_LINE;
+const MASK_OFFSET: isize = 5 * WORDS_PER_LINE + 1;
+const SLOTS_OFFSET: isize = 6 * WORDS_PER_LINE;
+
+/// Number of bytes in front of the slot array used by the ring meta-data.
+pub const RING_META_SIZE: usize = SLOTS_OFFSET as usize * 8;
+
+/// Self-describing header at the start of the shared region. The enclave side only gets a bare
+/// address, so this is what lets it check that the memory really is a ring it understands.
+#[repr(C)]
+pub struct RingHeader {
+    /// Always `RING_MAGIC`.
+    pub magic: u64,
+    /// Always `RING_LAYOUT_VERSION`.
+    pub version: u32,
+    /// Bytes per slot.
+    pub slot_width: u32,
+    /// Number of slots.
+    pub capacity: u64,
+    /// Pid of the process writing at the tail, 0 if not attached yet.
+    pub producer_pid: AtomicU32,
+    /// Pid of the process reading from the head, 0 if not attached yet.
+    pub consumer_pid: AtomicU32,
+    /// Set last by the creator, once everything else is initialized.
+    pub ready: AtomicU32,
+}
+
+/// States the host can ask the enclave side of a ring pair to move to.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RingState {
+    /// Normal operation.
+    Running = 0,
+    /// Stop pulling from recvq but keep the enclave alive; resume by going back to Running.
+    Paused = 1,
+    /// Process what is left in recvq, push it all to sendq, then acknowledge.
+    Draining = 2,
+    /// Shut down.
+    Stopped = 3,
+}
+
+impl RingState {
+    /// Unknown values are treated as Stopped, the only safe reading of a corrupted word.
+    #[inline]
+    pub fn from_u32(state: u32) -> RingState {
+        match state {
+            0 => RingState::Running,
+            1 => RingState::Paused,
+            2 => RingState::Draining,
+            _ => RingState::Stopped,
+        }
+    }
+}
+
+/// Control area following the header. By convention the host drives the pair through the
+/// control area of the recvq ring; the sendq ring's control area is unused.
+#[repr(C)]
+pub struct RingControl {
+    /// The `RingState` requested by the host.
+    pub state: AtomicU32,
+    /// The last `RingState` the enclave has acted upon.
+    pub ack: AtomicU32,
+}
+
+/// Lets an idle consumer sleep instead of spinning. The consumer announces itself in `sleepers`
+/// and waits for `seq` to change; the producer only bumps `seq` when it sees a sleeper, so the
+/// hot path costs one load per batch.
+///
+/// The enclave can make no futex calls, so neither side blocks in the kernel on `seq`: the
+/// host naps `DOORBELL_NAP` at a time between looks at it, the enclave yields.
+#[repr(C)]
+pub struct RingDoorbell {
+    /// Number of consumers currently waiting on `seq`.
+    pub sleepers: AtomicU32,
+    /// Bumped by every doorbell ring.
+    pub seq: AtomicU32,
+}
+
+/// How long the host sleeps between two looks at the doorbells it waits on.
+pub const DOORBELL_NAP: Duration = Duration::from_micros(20);
 
 /// A ring buffer which can be used to insert and read ordered data.
 #[derive(Clone)]
@@ -101,42 +217,63 @@ unsafe impl Send for RingBuffer {}
 #[cfg_attr(feature = "dev", allow(len_without_is_empty))]
 impl RingBuffer {
     /// Create/attach a new wrapping ring buffer. 
-    /// The ring buffer size is specified in bytes and must be a power of 2. 
-    /// we will require additional 16 bytes to store the meta-data for this ring.
+    /// The ring buffer size is specified in slots and must be a power of 2. 
+    /// we will require additional RING_META_SIZE bytes to store the meta-data for this ring.
+    ///
+    /// The master (dpdkIO) creates the segment and initializes the header; everyone else only
+    /// attaches to an existing segment and gets an error if its header does not match.
     pub unsafe fn new_in_heap(ring_size: usize, name: &str, shm_master: bool) -> Result<RingBuffer, Error>{
         if ring_size & (ring_size - 1) != 0 {
             // We need pages to be a power of 2.
             return Err(InvalidRingSize(ring_size).into());
         }
-        let size = ring_size * 8 + 16;
+        let size = ring_size * 8 + RING_META_SIZE;
 
-        // let temp_vec: Vec<u8> = vec![0; ring_size * 8 + 16];
+        // let temp_vec: Vec<u8> = vec![0; ring_size * 8 + RING_META_SIZE];
         // let mut boxed: SuperBox = SuperBox{ my_box: temp_vec.into_boxed_slice(), }; // Box<[u8]> is just like &[u8];
         let name = CString::new(name).unwrap();
-        let mut fd = shm_open(
-            name.as_ptr(),
-            libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
-            0o700,
-        );
-        if fd == -1 {
-            if let Some(e) = IOError::last_os_error().raw_os_error() {
-                if e == libc::EEXIST {
-                    // println!("unlink previous shm");
-                    // shm_unlink(name.as_ptr());
-
-                    // if already exist, we just attach to it, instead of unlinking it. 
-                    println!("attach to previous shm");
-                    fd = shm_open(
-                        name.as_ptr(),
-                        libc::O_CREAT | libc::O_RDWR,
-                        0o700,
-                    );
+        let fd = if shm_master {
+            let mut fd = shm_open(
+                name.as_ptr(),
+                libc::O_CREAT | libc::O_EXCL | libc::O_RDWR,
+                0o700,
+            );
+            if fd == -1 {
+                if let Some(e) = IOError::last_os_error().raw_os_error() {
+                    if e == libc::EEXIST {
+                        // println!("unlink previous shm");
+                        // shm_unlink(name.as_ptr());
+
+                        // if already exist, we just attach to it, instead of unlinking it. 
+                        println!("attach to previous shm");
+                        fd = shm_open(
+                            name.as_ptr(),
+                            libc::O_CREAT | libc::O_RDWR,
+                            0o700,
+                        );
+                    }
                 }
+            };
+            assert!(fd >= 0, "Could not create shared memory segment");
+            let ftret = ftruncate(fd, size as i64);
+            assert!(ftret == 0, "Could not truncate");
+            fd
+        } else {
+            let fd = shm_open(name.as_ptr(), libc::O_RDWR, 0o700);
+            if fd == -1 {
+                return Err(RingNotFound(name).into());
             }
+            // never map past the end of the segment, that would SIGBUS on first touch.
+            let mut stat: libc::stat = mem::zeroed();
+            if fstat(fd, &mut stat) != 0 || stat.st_size as usize != size {
+                close(fd);
+                return Err(RingHeaderMismatch(format!(
+                    "segment {:?} is {} bytes, expected {}",
+                    name, stat.st_size, size
+                )).into());
+            }
+            fd
         };
-        assert!(fd >= 0, "Could not create shared memory segment");
-        let ftret = ftruncate(fd, size as i64);
-        assert!(ftret == 0, "Could not truncate");
         let address = mmap(
             ptr::null_mut(),
             size,
@@ -154,33 +291,252 @@ impl RingBuffer {
 
         let address = address as *mut u8;
         // let address = &mut boxed.my_box[0] as *mut u8;
-        unsafe{
-            *(address as *mut usize) = 0;
-            *((address as *mut usize).offset(1)) = 0;
-            *((address as *mut usize).offset(2)) = ring_size;
-            *((address as *mut usize).offset(3)) = ring_size - 1;
-        }
-
-        Ok(RingBuffer {
+        let ring = RingBuffer {
             name, 
             size_shm: size, 
             mem: address, 
             shm_master, 
-            head: SuperUsize{ my_usize: (address as *mut usize) },
-            tail: SuperUsize{ my_usize: (address as *mut usize).offset(1) },
-            size: SuperUsize{ my_usize: (address as *mut usize).offset(2) },
-            mask: SuperUsize{ my_usize: (address as *mut usize).offset(3) },
-            vec: SuperVec{ my_vec: (address as *mut usize).offset(4) as (*mut u64) },
-        })
+            head: SuperUsize{ my_usize: (address as *mut usize).offset(HEAD_OFFSET) },
+            tail: SuperUsize{ my_usize: (address as *mut usize).offset(TAIL_OFFSET) },
+            size: SuperUsize{ my_usize: (address as *mut usize).offset(SIZE_OFFSET) },
+            mask: SuperUsize{ my_usize: (address as *mut usize).offset(MASK_OFFSET) },
+            vec: SuperVec{ my_vec: (address as *mut usize).offset(SLOTS_OFFSET) as (*mut u64) },
+        };
+        if shm_master {
+            ring.init_header(ring_size);
+        } else if let Err(e) = ring.check_header(ring_size) {
+            munmap(address as *mut c_void, size);
+            return Err(e);
+        }
+        Ok(ring)
+    }
+
+    /// Writes the header and resets the cursors; `ready` is published last.
+    fn init_header(&self, ring_size: usize) {
+        let header = (self.mem as *mut usize).wrapping_offset(HEADER_OFFSET) as *mut RingHeader;
+        self.header().ready.store(0, Ordering::Release);
+        unsafe {
+            (*header).magic = RING_MAGIC;
+            (*header).version = RING_LAYOUT_VERSION;
+            (*header).slot_width = RING_SLOT_WIDTH;
+            (*header).capacity = ring_size as u64;
+        }
+        self.header().producer_pid.store(0, Ordering::Relaxed);
+        self.header().consumer_pid.store(0, Ordering::Relaxed);
+        self.control().state.store(RingState::Running as u32, Ordering::Relaxed);
+        self.control().ack.store(RingState::Running as u32, Ordering::Relaxed);
+        self.doorbell().sleepers.store(0, Ordering::Relaxed);
+        self.doorbell().seq.store(0, Ordering::Relaxed);
+        self.set_size(ring_size);
+        self.set_mask(ring_size - 1);
+        self.clear();
+        self.header().ready.store(1, Ordering::Release);
+    }
+
+    /// Checks that the shared region holds a ready ring of `ring_size` slots in the layout this
+    /// binary was built for.
+    pub fn check_header(&self, ring_size: usize) -> Result<(), Error> {
+        let header = self.header();
+        if header.ready.load(Ordering::Acquire) == 0 {
+            return Err(RingHeaderMismatch("ring is not ready".to_string()).into());
+        }
+        if header.magic != RING_MAGIC {
+            return Err(RingHeaderMismatch(format!("bad magic {:#x}", header.magic)).into());
+        }
+        if header.version != RING_LAYOUT_VERSION {
+            return Err(RingHeaderMismatch(format!(
+                "layout version {}, expected {}",
+                header.version, RING_LAYOUT_VERSION
+            )).into());
+        }
+        if header.slot_width != RING_SLOT_WIDTH {
+            return Err(RingHeaderMismatch(format!(
+                "slot width {}, expected {}",
+                header.slot_width, RING_SLOT_WIDTH
+            )).into());
+        }
+        if header.capacity != ring_size as u64 || self.mask() != ring_size - 1 {
+            return Err(RingHeaderMismatch(format!(
+                "capacity {}, expected {}",
+                header.capacity, ring_size
+            )).into());
+        }
+        Ok(())
+    }
+
+    /// The header at the start of the shared region.
+    #[inline]
+    pub fn header(&self) -> &RingHeader {
+        unsafe { &*((self.mem as *mut usize).offset(HEADER_OFFSET) as *const RingHeader) }
+    }
+
+    /// The control area following the header.
+    #[inline]
+    pub fn control(&self) -> &RingControl {
+        unsafe { &*((self.mem as *mut usize).offset(CONTROL_OFFSET) as *const RingControl) }
+    }
+
+    /// The state currently requested by the host.
+    #[inline]
+    pub fn state(&self) -> RingState {
+        RingState::from_u32(self.control().state.load(Ordering::Acquire))
+    }
+
+    /// Ask the other side to move to `state`. Rings the doorbell so a sleeping consumer notices.
+    #[inline]
+    pub fn request_state(&self, state: RingState) {
+        self.control().state.store(state as u32, Ordering::Release);
+        self.ring_doorbell();
+    }
+
+    /// Ask the other side to move from `current` to `state`, unless someone else has requested
+    /// another state since `current` was read. Returns whether the request went in.
+    #[inline]
+    pub fn transition_state(&self, current: RingState, state: RingState) -> bool {
+        let swapped = self
+            .control()
+            .state
+            .compare_exchange(current as u32, state as u32, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok();
+        if swapped {
+            self.ring_doorbell();
+        }
+        swapped
+    }
+
+    /// The last state the other side has acknowledged.
+    #[inline]
+    pub fn acked_state(&self) -> RingState {
+        RingState::from_u32(self.control().ack.load(Ordering::Acquire))
+    }
+
+    /// Acknowledge that `state` has been acted upon.
+    #[inline]
+    pub fn ack_state(&self, state: RingState) {
+        self.control().ack.store(state as u32, Ordering::Release);
+    }
+
+    /// Wait until the other side acknowledges `state`. Returns false on timeout.
+    pub fn wait_for_ack(&self, state: RingState, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        while self.acked_state() != state {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        true
+    }
+
+    /// The doorbell following the control area.
+    #[inline]
+    pub fn doorbell(&self) -> &RingDoorbell {
+        unsafe { &*((self.mem as *mut usize).offset(DOORBELL_OFFSET) as *const RingDoorbell) }
+    }
+
+    /// Producer side: wake the consumer if it went to sleep. Call once per batch, after the
+    /// writes; it is a single load when nobody sleeps.
+    #[inline]
+    pub fn ring_doorbell(&self) {
+        // pairs with the fence in `wait_for_doorbell`: either we see the sleeper, or the
+        // sleeper sees our new tail before it blocks.
+        atomic::fence(Ordering::SeqCst);
+        let doorbell = self.doorbell();
+        if doorbell.sleepers.load(Ordering::Relaxed) != 0 {
+            doorbell.seq.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Consumer side: sleep until the producer rings the doorbell, the ring is not empty, or
+    /// `timeout` passes. Returns true unless it timed out.
+    pub fn wait_for_doorbell(&self, timeout: Duration) -> bool {
+        RingBuffer::wait_for_doorbells(slice::from_ref(self), timeout)
+    }
+
+    /// Consumer side of several rings: sleep until the producer of any of them rings its
+    /// doorbell or any is not empty, or `timeout` passes. Returns true unless it timed out.
+    pub fn wait_for_doorbells(rings: &[RingBuffer], timeout: Duration) -> bool {
+        let seqs: Vec<u32> = rings.iter().map(|ring| ring.doorbell().seq.load(Ordering::Acquire)).collect();
+        for ring in rings {
+            ring.doorbell().sleepers.fetch_add(1, Ordering::Relaxed);
+        }
+        // pairs with the fence in `ring_doorbell`.
+        atomic::fence(Ordering::SeqCst);
+        let deadline = Instant::now() + timeout;
+        let woken = loop {
+            let rung = rings
+                .iter()
+                .zip(&seqs)
+                .any(|(ring, &seq)| ring.doorbell().seq.load(Ordering::Acquire) != seq || ring.depth() != 0);
+            if rung {
+                break true;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                break false;
+            }
+            thread::sleep(min(DOORBELL_NAP, deadline - now));
+        };
+        for ring in rings {
+            ring.doorbell().sleepers.fetch_sub(1, Ordering::Relaxed);
+        }
+        woken
+    }
+
+    /// Number of slots currently filled, as seen by either side.
+    #[inline]
+    pub fn depth(&self) -> usize {
+        let head = self.head_atomic().load(Ordering::Acquire);
+        self.tail_atomic().load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    /// Record the pid of the process writing to this ring.
+    #[inline]
+    pub fn set_producer_pid(&self, pid: u32) {
+        self.header().producer_pid.store(pid, Ordering::Release);
+    }
+
+    /// Record the pid of the process reading from this ring.
+    #[inline]
+    pub fn set_consumer_pid(&self, pid: u32) {
+        self.header().consumer_pid.store(pid, Ordering::Release);
+    }
+
+    /// The consumer cursor. Only the consumer ever stores to it.
+    #[inline]
+    fn head_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.head.my_usize as *const AtomicUsize) }
+    }
+
+    /// The producer cursor. Only the producer ever stores to it.
+    #[inline]
+    fn tail_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.tail.my_usize as *const AtomicUsize) }
+    }
+
+    #[inline]
+    fn size_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.size.my_usize as *const AtomicUsize) }
+    }
+
+    #[inline]
+    fn mask_atomic(&self) -> &AtomicUsize {
+        unsafe { &*(self.mask.my_usize as *const AtomicUsize) }
+    }
+
+    /// Number of slots, derived from the mask so that a single word sizes every access.
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.mask_atomic().load(Ordering::Relaxed) + 1
     }
 
     #[inline]
     pub fn head(&self) -> usize{
-        unsafe{(*self.head.my_usize)}
+        self.head_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_head(&self, new_head: usize){
-        unsafe{*self.head.my_usize = new_head;}
+        self.head_atomic().store(new_head, Ordering::Release);
     }
     #[inline]
     pub fn wrapping_sub_head(&self, delta: usize)
@@ -195,11 +551,11 @@ impl RingBuffer {
 
     #[inline]
     pub fn tail(&self) -> usize{
-        unsafe{(*self.tail.my_usize)}
+        self.tail_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_tail(&self, new_tail: usize){
-        unsafe{*self.tail.my_usize = new_tail;}
+        self.tail_atomic().store(new_tail, Ordering::Release);
     }
     #[inline]
     pub fn wrapping_sub_tail(&self, delta: usize)
@@ -214,51 +570,60 @@ impl RingBuffer {
 
     #[inline]
     pub fn size(&self) -> usize{
-        unsafe{(*self.size.my_usize)}
+        self.size_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_size(&self, new_size: usize){
-        unsafe{*self.size.my_usize = new_size;}
+        self.size_atomic().store(new_size, Ordering::Release);
     }
 
     #[inline]
     pub fn mask(&self) -> usize{
-        unsafe{(*self.mask.my_usize)}
+        self.mask_atomic().load(Ordering::Acquire)
     }
     #[inline]
     pub fn set_mask(&self, new_mask: usize){
-        unsafe{*self.mask.my_usize = new_mask;}
+        self.mask_atomic().store(new_mask, Ordering::Release);
     }
     
-    /// Read from the buffer, incrementing the read head. Returns bytes read.
+    /// Read from the buffer, incrementing the read head. Returns slots read.
+    /// Must only be called by the single consumer of this ring.
     #[inline]
     pub fn read_from_head(&self, mbufs: &mut [u64]) -> usize {
-        let available = self.tail().wrapping_sub(self.head());
+        // head is ours, so a relaxed load is enough; the acquire on tail pairs with the
+        // producer's release and makes the slots it published visible to us.
+        let head = self.head_atomic().load(Ordering::Relaxed);
+        let tail = self.tail_atomic().load(Ordering::Acquire);
+        let available = tail.wrapping_sub(head);
         let to_read = min(mbufs.len(), available);
-        let offset = self.head() & self.mask();
+        let offset = head & self.mask_atomic().load(Ordering::Relaxed);
         let reads = self.wrapped_read(offset, &mut mbufs[..to_read]);
-        compiler_fence(Ordering::Release);
-        self.wrapping_add_head(reads);
+        // release: the slots must be copied out before the producer can see them as free.
+        self.head_atomic().store(head.wrapping_add(reads), Ordering::Release);
         reads
     }
 
     /// Write data at the end of the buffer. The amount of data written might be smaller than input.
+    /// Must only be called by the single producer of this ring.
     #[inline]
     pub fn write_at_tail(&self, mbufs: &[u64]) -> usize {
-        let available = self.size().wrapping_add(self.head()).wrapping_sub(self.tail());
+        // tail is ours; the acquire on head pairs with the consumer's release so we never
+        // overwrite a slot that is still being read.
+        let tail = self.tail_atomic().load(Ordering::Relaxed);
+        let head = self.head_atomic().load(Ordering::Acquire);
+        let available = self.capacity().wrapping_add(head).wrapping_sub(tail);
         let to_write = min(mbufs.len(), available);
-        let offset = self.tail() & self.mask();
+        let offset = tail & self.mask_atomic().load(Ordering::Relaxed);
         let writes = self.wrapped_write(offset, &mbufs[..to_write]);
-        compiler_fence(Ordering::Release);
-        self.wrapping_add_tail(writes);
+        // release: the slots must be filled before the consumer can see the new tail.
+        self.tail_atomic().store(tail.wrapping_add(writes), Ordering::Release);
         writes
     }
 
     /// Reads data from self.vec, wrapping around the end of the Vec if necessary. Returns the
-    /// number of bytes written.
+    /// number of slots read.
     fn wrapped_read(&self, offset: usize, mbufs: &mut [u64]) -> usize {
-        let mut bytes: usize = 0;
-        let ring_size = self.size();
+        let ring_size = self.capacity();
         assert!(offset < ring_size);
         assert!(mbufs.len() <= ring_size);
 
@@ -275,10 +640,9 @@ impl RingBuffer {
     }
 
     /// Writes data to self.vec[offset..], wrapping around the end of the Vec if necessary. Returns
-    /// the number of bytes written.
+    /// the number of slots written.
     fn wrapped_write(&self, offset: usize, mbufs: &[u64]) -> usize {
-        let mut bytes: usize = 0;
-        let ring_size = self.size();
+        let ring_size = self.capacity();
         assert!(offset < ring_size);
         assert!(mbufs.len() <= ring_size);
 
@@ -0,0 +1,321 @@
+extern crate sharedring;
+use sharedring::poll::*;
+use sharedring::ring_buffer::*;
+use std::collections::VecDeque;
+use std::mem;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn ring_name(test: &str) -> String {
+    format!("/sb_test_{}_{}", test, process::id())
+}
+
+/// Tiny xorshift so the model test is reproducible without extra dependencies.
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+#[test]
+fn alloc_test() {
+    let rb = unsafe { RingBuffer::new_in_heap(128, &ring_name("alloc"), true) }.unwrap();
+    assert_eq!(rb.size(), 128);
+    assert_eq!(rb.mask(), 127);
+    assert_eq!(rb.head(), 0);
+    assert_eq!(rb.tail(), 0);
+}
+
+#[test]
+fn bad_size_test() {
+    assert!(unsafe { RingBuffer::new_in_heap(100, &ring_name("bad_size"), true) }.is_err());
+}
+
+#[test]
+fn header_test() {
+    let rb = unsafe { RingBuffer::new_in_heap(64, &ring_name("header"), true) }.unwrap();
+    assert!(mem::size_of::<RingHeader>() <= CACHE_LINE_SIZE);
+    assert_eq!(rb.header().magic, RING_MAGIC);
+    assert_eq!(rb.header().version, RING_LAYOUT_VERSION);
+    assert_eq!(rb.header().slot_width, RING_SLOT_WIDTH);
+    assert_eq!(rb.header().capacity, 64);
+    assert!(rb.check_header(64).is_ok());
+    assert!(rb.check_header(128).is_err());
+}
+
+#[test]
+fn attach_test() {
+    let name = ring_name("attach");
+    let master = unsafe { RingBuffer::new_in_heap(32, &name, true) }.unwrap();
+    master.set_producer_pid(process::id());
+    assert_eq!(master.write_at_tail(&[7, 8, 9]), 3);
+
+    // attaching must not reset the cursors the master already moved.
+    let attached = unsafe { RingBuffer::new_in_heap(32, &name, false) }.unwrap();
+    attached.set_consumer_pid(process::id());
+    let mut out = [0u64; 3];
+    assert_eq!(attached.read_from_head(&mut out), 3);
+    assert_eq!(out, [7, 8, 9]);
+    assert_eq!(master.header().consumer_pid.load(Ordering::Acquire), process::id());
+}
+
+#[test]
+fn attach_missing_test() {
+    assert!(unsafe { RingBuffer::new_in_heap(32, &ring_name("missing"), false) }.is_err());
+}
+
+#[test]
+fn attach_mismatch_test() {
+    let name = ring_name("mismatch");
+    let master = unsafe { RingBuffer::new_in_heap(32, &name, true) }.unwrap();
+    assert!(unsafe { RingBuffer::new_in_heap(64, &name, false) }.is_err());
+
+    unsafe { *(master.mem as *mut u64) = 0xdead_beef };
+    assert!(unsafe { RingBuffer::new_in_heap(32, &name, false) }.is_err());
+}
+
+#[test]
+fn control_test() {
+    let name = ring_name("control");
+    let host = unsafe { RingBuffer::new_in_heap(32, &name, true) }.unwrap();
+    assert_eq!(host.state(), RingState::Running);
+    assert_eq!(host.acked_state(), RingState::Running);
+
+    let enclave = unsafe { RingBuffer::new_in_heap(32, &name, false) }.unwrap();
+    assert!(host.transition_state(RingState::Running, RingState::Paused));
+    // a drain requested after Running was read is not overwritten.
+    host.request_state(RingState::Draining);
+    assert!(!host.transition_state(RingState::Paused, RingState::Running));
+    assert_eq!(host.state(), RingState::Draining);
+    assert_eq!(enclave.state(), RingState::Draining);
+    assert!(!host.wait_for_ack(RingState::Draining, Duration::from_millis(5)));
+
+    let acker = thread::spawn(move || {
+        while enclave.state() != RingState::Stopped {
+            thread::yield_now();
+        }
+        enclave.ack_state(RingState::Stopped);
+    });
+    host.request_state(RingState::Stopped);
+    assert!(host.wait_for_ack(RingState::Stopped, Duration::from_secs(5)));
+    acker.join().unwrap();
+
+    assert_eq!(RingState::from_u32(RingState::Paused as u32), RingState::Paused);
+    assert_eq!(RingState::from_u32(0xabcd_efff), RingState::Stopped);
+}
+
+#[test]
+fn head_and_tail_on_separate_lines() {
+    let rb = unsafe { RingBuffer::new_in_heap(16, &ring_name("lines"), true) }.unwrap();
+    let head = rb.head.my_usize as usize;
+    let tail = rb.tail.my_usize as usize;
+    assert_eq!(head % CACHE_LINE_SIZE, 0);
+    assert_eq!(tail % CACHE_LINE_SIZE, 0);
+    assert!(tail - head >= CACHE_LINE_SIZE);
+}
+
+#[test]
+fn read_write_wrap_test() {
+    let rb = unsafe { RingBuffer::new_in_heap(8, &ring_name("wrap"), true) }.unwrap();
+    let mut out = [0u64; 8];
+    for round in 0..10u64 {
+        let input: Vec<u64> = (0..5).map(|i| round * 100 + i).collect();
+        assert_eq!(rb.write_at_tail(&input), 5);
+        assert_eq!(rb.read_from_head(&mut out[..5]), 5);
+        assert_eq!(&out[..5], &input[..]);
+    }
+    let full: Vec<u64> = (0..12).collect();
+    assert_eq!(rb.write_at_tail(&full), 8);
+    assert_eq!(rb.write_at_tail(&full), 0);
+    assert_eq!(rb.read_from_head(&mut out), 8);
+    assert_eq!(&out[..], &full[..8]);
+    assert_eq!(rb.read_from_head(&mut out), 0);
+}
+
+/// Drives the ring with random batch sizes and checks it against a `VecDeque` model.
+#[test]
+fn model_test() {
+    let rb = unsafe { RingBuffer::new_in_heap(64, &ring_name("model"), true) }.unwrap();
+    let mut model: VecDeque<u64> = VecDeque::new();
+    let mut rng = XorShift(0x2545_f491_4f6c_dd1d);
+    let mut next = 1u64;
+    let mut out = [0u64; 100];
+
+    for _ in 0..20_000 {
+        let n = (rng.next() % 100) as usize;
+        if rng.next() & 1 == 0 {
+            let input: Vec<u64> = (0..n as u64).map(|i| next + i).collect();
+            let written = rb.write_at_tail(&input);
+            assert_eq!(written, n.min(64 - model.len()));
+            model.extend(&input[..written]);
+            next += written as u64;
+        } else {
+            let read = rb.read_from_head(&mut out[..n]);
+            assert_eq!(read, n.min(model.len()));
+            for v in &out[..read] {
+                assert_eq!(Some(*v), model.pop_front());
+            }
+        }
+        assert_eq!(rb.tail().wrapping_sub(rb.head()), model.len());
+    }
+}
+
+/// A producer and a consumer on different threads must see every value exactly once, in order.
+#[test]
+fn spsc_two_threads_test() {
+    const TOTAL: u64 = 1_000_000;
+    let producer = unsafe { RingBuffer::new_in_heap(128, &ring_name("spsc"), true) }.unwrap();
+    let mut consumer = producer.clone();
+    consumer.shm_master = false;
+
+    let reader = thread::spawn(move || {
+        let mut expected = 1u64;
+        let mut out = [0u64; 32];
+        while expected <= TOTAL {
+            let read = consumer.read_from_head(&mut out);
+            if read == 0 {
+                thread::yield_now();
+            }
+            for v in &out[..read] {
+                assert_eq!(*v, expected, "lost or duplicated slot");
+                expected += 1;
+            }
+        }
+        assert_eq!(consumer.read_from_head(&mut out), 0);
+    });
+
+    let mut next = 1u64;
+    let mut batch = Vec::with_capacity(32);
+    while next <= TOTAL {
+        batch.clear();
+        let n = ((next % 31) + 1).min(TOTAL - next + 1);
+        batch.extend(next..next + n);
+        let mut sent = 0;
+        while sent < batch.len() {
+            let written = producer.write_at_tail(&batch[sent..]);
+            if written == 0 {
+                thread::yield_now();
+            }
+            sent += written;
+        }
+        next += n;
+    }
+    reader.join().unwrap();
+    assert_eq!(producer.head(), producer.tail());
+}
+
+#[test]
+fn doorbell_test() {
+    let name = ring_name("doorbell");
+    let producer = unsafe { RingBuffer::new_in_heap(32, &name, true) }.unwrap();
+    let consumer = unsafe { RingBuffer::new_in_heap(32, &name, false) }.unwrap();
+    assert_eq!(consumer.depth(), 0);
+
+    // nobody rings: the wait times out.
+    let start = Instant::now();
+    assert!(!consumer.wait_for_doorbell(Duration::from_millis(20)));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(consumer.doorbell().sleepers.load(Ordering::Acquire), 0);
+
+    // data already there: no sleep at all.
+    producer.write_at_tail(&[1]);
+    assert_eq!(consumer.depth(), 1);
+    assert!(consumer.wait_for_doorbell(Duration::from_secs(5)));
+    let mut out = [0u64; 1];
+    consumer.read_from_head(&mut out);
+
+    let sleeper = thread::spawn(move || {
+        let start = Instant::now();
+        let woken = consumer.wait_for_doorbell(Duration::from_secs(10));
+        (woken, start.elapsed(), consumer.read_from_head(&mut out))
+    });
+    while producer.doorbell().sleepers.load(Ordering::Acquire) == 0 {
+        thread::yield_now();
+    }
+    producer.write_at_tail(&[2]);
+    producer.ring_doorbell();
+    let (woken, waited, read) = sleeper.join().unwrap();
+    assert!(woken);
+    assert!(waited < Duration::from_secs(10));
+    assert_eq!(read, 1);
+
+    // a ring of the doorbell of any of the rings ends a wait on all of them.
+    let other_name = ring_name("doorbell_other");
+    let other = unsafe { RingBuffer::new_in_heap(32, &other_name, true) }.unwrap();
+    // each side maps the rings of its own, as dpdkIO and the enclave do.
+    let rings = unsafe {
+        vec![RingBuffer::new_in_heap(32, &other_name, false).unwrap(), RingBuffer::new_in_heap(32, &name, false).unwrap()]
+    };
+    let sleeper = thread::spawn(move || RingBuffer::wait_for_doorbells(&rings, Duration::from_secs(10)));
+    while producer.doorbell().sleepers.load(Ordering::Acquire) == 0 {
+        thread::yield_now();
+    }
+    producer.ring_doorbell();
+    assert!(sleeper.join().unwrap());
+    assert_eq!(other.doorbell().sleepers.load(Ordering::Acquire), 0);
+}
+
+#[test]
+fn poller_test() {
+    let config = PollConfig {
+        adaptive: true,
+        spin_polls: 4,
+        idle: Duration::from_millis(5),
+        max_sleep: Duration::from_millis(2),
+    };
+    let mut poller = AdaptivePoller::new(config);
+    for _ in 0..4 {
+        assert_eq!(poller.poll(0), None);
+    }
+    assert_eq!(poller.stats().pauses, 1);
+    let start = Instant::now();
+    let mut slept = None;
+    while slept.is_none() {
+        slept = poller.poll(0);
+    }
+    assert!(start.elapsed() >= Duration::from_millis(5));
+    assert_eq!(slept, Some(Duration::from_millis(2)));
+    poller.slept(false);
+    // any progress resets the backoff.
+    assert_eq!(poller.poll(3), None);
+    assert_eq!(poller.poll(0), None);
+    assert_eq!(poller.stats().sleeps, 1);
+    assert_eq!(poller.stats().wakeups, 0);
+    assert_eq!(poller.stats().polls, poller.stats().empty_polls + 1);
+
+    let mut busy = AdaptivePoller::new(PollConfig { adaptive: false, ..config });
+    for _ in 0..1000 {
+        assert_eq!(busy.poll(0), None);
+    }
+    assert_eq!(busy.stats().pauses, 0);
+
+    for depth in &[0, 1, 2, 3, 4, 100, 1 << 20] {
+        busy.record_depth(*depth);
+    }
+    let stats = busy.stats();
+    assert_eq!(stats.depth_samples, 7);
+    assert_eq!(stats.depth_max, 1 << 20);
+    assert_eq!(&stats.depth_hist[..4], &[1, 1, 2, 1]);
+    assert_eq!(stats.depth_hist[7], 1);
+    assert_eq!(stats.depth_hist[DEPTH_BUCKETS - 1], 1);
+}
+
+#[test]
+fn counters_test() {
+    assert!(!PollConfig::default().adaptive);
+    let counters = PollCounters::default();
+    counters.record_poll(0, 0);
+    counters.record_poll(3, 5);
+    let stats = counters.stats();
+    assert_eq!((stats.polls, stats.empty_polls), (2, 1));
+    assert_eq!((stats.depth_samples, stats.depth_sum, stats.depth_max), (2, 5, 5));
+    assert_eq!(stats.depth_hist[0], 1);
+    assert_eq!(stats.depth_hist[3], 1);
+}